@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::{entities::Attachment, error::AttachmentError};
+
+/// Metadata of the stored attachments
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait AttachmentDatabase: Send + Sync {
+    /// Inserts the attachment unless the owner's attachments would exceed `max_storage` bytes.
+    /// The check and the insert are atomic, concurrent uploads can't overshoot the quota.
+    async fn insert_attachment(
+        &self,
+        attachment: Attachment,
+        max_storage: u64,
+    ) -> Result<(), AttachmentError>;
+
+    async fn get_attachment(
+        &self,
+        attachment_id: Uuid,
+    ) -> Result<Option<Attachment>, AttachmentError>;
+
+    /// Returns the total size of the account's attachments in bytes
+    async fn get_storage_usage(&self, owner_id: Uuid) -> Result<u64, AttachmentError>;
+
+    /// Returns all attachments that expired at or before `now` (epoch milliseconds)
+    async fn get_expired_attachments(&self, now: u64) -> Result<Vec<Attachment>, AttachmentError>;
+
+    /// Records that the blob of the attachment was found in the storage
+    async fn mark_attachment_uploaded(&self, attachment_id: Uuid) -> Result<(), AttachmentError>;
+
+    async fn remove_attachment(&self, attachment_id: Uuid) -> Result<(), AttachmentError>;
+}
+
+/// Blob storage for encrypted attachments.
+/// Clients access the blobs directly through presigned URLs.
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait AttachmentStorage: Send + Sync {
+    /// Generate a URL for uploading the attachment.
+    /// The storage rejects uploads of any other size than `size` bytes.
+    async fn generate_upload_url(
+        &self,
+        attachment_id: Uuid,
+        size: u64,
+        expires_in: Duration,
+    ) -> Result<String, AttachmentError>;
+
+    /// Generate a URL for downloading the attachment
+    async fn generate_download_url(
+        &self,
+        attachment_id: Uuid,
+        expires_in: Duration,
+    ) -> Result<String, AttachmentError>;
+
+    /// Check whether the blob of the attachment was uploaded
+    async fn attachment_exists(&self, attachment_id: Uuid) -> Result<bool, AttachmentError>;
+
+    /// Delete the attachment. Deleting a blob that was never uploaded succeeds.
+    async fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), AttachmentError>;
+}
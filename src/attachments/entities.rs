@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// An attachment of a message, e.g. an image or a file.
+/// Clients encrypt it before the upload and share the key inside the message,
+/// the server only stores an opaque blob.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub id: Uuid,
+    /// Account that uploaded the attachment
+    pub owner_id: Uuid,
+    /// Size of the encrypted blob in bytes
+    pub size: u64,
+    /// Creation timestamp (epoch milliseconds)
+    pub created_at: u64,
+    /// Time after which the blob is deleted (epoch milliseconds)
+    pub expires_at: u64,
+    /// Whether the client finished uploading the blob
+    pub uploaded: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentUploadResponse {
+    pub attachment_id: Uuid,
+    /// Presigned URL to `PUT` the encrypted blob to.
+    /// Uploads of any other size than the requested one are rejected.
+    pub upload_url: String,
+    /// Seconds until the upload URL expires
+    pub expires_in: u64,
+    /// Time after which the attachment is deleted (epoch milliseconds)
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentDownloadResponse {
+    /// Presigned URL to `GET` the encrypted blob from
+    pub download_url: String,
+    /// Seconds until the download URL expires
+    pub expires_in: u64,
+    /// Size of the encrypted blob in bytes
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AttachmentLimits {
+    /// Maximum size of a single attachment in bytes
+    pub max_size: u64,
+    /// Maximum total size of the attachments an account keeps stored
+    pub max_storage_per_account: u64,
+    /// How long attachments are kept before they are deleted
+    pub retention: Duration,
+}
@@ -0,0 +1,41 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+use tracing::error;
+
+#[derive(Debug, Error)]
+pub enum AttachmentError {
+    #[error("Attachment not found")]
+    NotFound,
+
+    #[error("Attachments must not be empty")]
+    Empty,
+
+    #[error("Attachments are limited to {0} bytes")]
+    TooLarge(u64),
+
+    #[error("Attachment storage of the account is limited to {0} bytes")]
+    QuotaExceeded(u64),
+
+    #[error("Database error: {0}")]
+    Database(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl IntoResponse for AttachmentError {
+    fn into_response(self) -> Response {
+        error!("{}", self);
+        let status = match &self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Empty => StatusCode::BAD_REQUEST,
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::Database(_) | Self::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        status.into_response()
+    }
+}
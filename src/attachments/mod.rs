@@ -0,0 +1,9 @@
+pub mod database;
+pub mod entities;
+pub mod error;
+pub mod retention_service;
+pub mod service;
+
+mod router;
+
+pub use router::router;
@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::Duration};
+use tokio::time::interval;
+use tracing::{error, info, instrument, warn};
+
+use super::{
+    database::{AttachmentDatabase, AttachmentStorage},
+    error::AttachmentError,
+};
+
+/// Deletes attachments once their retention period has passed
+pub struct AttachmentRetentionService<D, S>
+where
+    D: AttachmentDatabase + 'static,
+    S: AttachmentStorage + 'static,
+{
+    attachment_db: Arc<D>,
+    storage: Arc<S>,
+    sweep_interval: Duration,
+}
+
+impl<D, S> AttachmentRetentionService<D, S>
+where
+    D: AttachmentDatabase + 'static,
+    S: AttachmentStorage + 'static,
+{
+    pub fn new(attachment_db: Arc<D>, storage: Arc<S>, sweep_interval: Duration) -> Self {
+        Self {
+            attachment_db,
+            storage,
+            sweep_interval,
+        }
+    }
+
+    /// Spawn the background task that periodically deletes expired attachments
+    #[instrument(skip(self))]
+    pub fn spawn_retention_sweeper(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            info!("Starting AttachmentRetentionService background task");
+            let mut ticker = interval(self.sweep_interval);
+
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.sweep_expired_attachments().await {
+                    error!("Error deleting expired attachments: {}", e);
+                }
+            }
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn sweep_expired_attachments(&self) -> Result<(), AttachmentError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let expired_attachments = self.attachment_db.get_expired_attachments(now).await?;
+
+        if expired_attachments.is_empty() {
+            return Ok(());
+        }
+
+        let mut deleted = 0;
+        for attachment in expired_attachments {
+            // Keep the metadata if the blob survives, so the next sweep tries again
+            if let Err(e) = self.storage.delete_attachment(attachment.id).await {
+                warn!("Failed to delete attachment {}: {}", attachment.id, e);
+                continue;
+            }
+            self.attachment_db.remove_attachment(attachment.id).await?;
+            deleted += 1;
+        }
+        info!("Deleted {} expired attachments", deleted);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attachments::{database::MockAttachmentStorage, entities::Attachment},
+        database::{inmemory::InMemoryDatabase, local::LocalStorage},
+    };
+    use uuid::Uuid;
+
+    fn create_attachment(expires_at: u64) -> Attachment {
+        Attachment {
+            id: Uuid::new_v4(),
+            owner_id: Uuid::new_v4(),
+            size: 4,
+            created_at: 0,
+            expires_at,
+            uploaded: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_deletes_expired_blobs() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let root = std::env::temp_dir().join(format!("attachments-{}", Uuid::new_v4()));
+        let storage = Arc::new(LocalStorage::new(&root));
+
+        let expired = create_attachment(1);
+        let retained = create_attachment(u64::MAX);
+        for attachment in [&expired, &retained] {
+            db.insert_attachment(attachment.clone(), u64::MAX)
+                .await
+                .unwrap();
+            std::fs::create_dir_all(storage.path(attachment.id).parent().unwrap()).unwrap();
+            std::fs::write(storage.path(attachment.id), b"blob").unwrap();
+        }
+
+        let service =
+            AttachmentRetentionService::new(db.clone(), storage.clone(), Duration::from_secs(60));
+        service
+            .sweep_expired_attachments()
+            .await
+            .expect("Failed to sweep attachments");
+
+        assert!(!storage.path(expired.id).exists());
+        assert!(db.get_attachment(expired.id).await.unwrap().is_none());
+        assert!(storage.path(retained.id).exists());
+        assert!(db.get_attachment(retained.id).await.unwrap().is_some());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sweep_keeps_metadata_when_deletion_fails() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let expired = create_attachment(1);
+        db.insert_attachment(expired.clone(), u64::MAX)
+            .await
+            .unwrap();
+
+        let mut storage = MockAttachmentStorage::new();
+        storage
+            .expect_delete_attachment()
+            .returning(|_| Err(AttachmentError::Storage("unavailable".to_string())));
+
+        let service =
+            AttachmentRetentionService::new(db.clone(), Arc::new(storage), Duration::from_secs(60));
+        service
+            .sweep_expired_attachments()
+            .await
+            .expect("Failed to sweep attachments");
+
+        assert!(db.get_attachment(expired.id).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,81 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    middleware::from_fn_with_state,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use super::{
+    entities::{AttachmentDownloadResponse, AttachmentUploadResponse},
+    error::AttachmentError,
+};
+use crate::{
+    account::{auth::middleware::require_auth, entities::Account},
+    startup::AppContext,
+};
+
+const ATTACHMENTS_TAG: &str = "attachments";
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateAttachmentRequest {
+    /// Size of the encrypted attachment in bytes
+    pub size: u64,
+}
+
+pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
+    OpenApiRouter::new()
+        .routes(routes!(create_attachment))
+        .routes(routes!(get_attachment))
+        .layer(from_fn_with_state(context.clone(), require_auth))
+}
+
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body = CreateAttachmentRequest,
+    responses(
+        (status = 200, description = "Upload URL created successfully", body = AttachmentUploadResponse),
+        (status = 400, description = "Empty attachment"),
+        (status = 413, description = "Attachment too large"),
+        (status = 500, description = "Internal server error"),
+        (status = 507, description = "Attachment storage quota of the account exceeded")
+    ),
+    tag = ATTACHMENTS_TAG
+)]
+async fn create_attachment(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<CreateAttachmentRequest>,
+) -> Result<Json<AttachmentUploadResponse>, AttachmentError> {
+    context
+        .attachment_service
+        .create_attachment(account.id, req.size)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/{attachment_id}",
+    responses(
+        (status = 200, description = "Download URL created successfully", body = AttachmentDownloadResponse),
+        (status = 404, description = "Attachment not found or expired"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = ATTACHMENTS_TAG
+)]
+async fn get_attachment(
+    State(context): State<Arc<AppContext>>,
+    Path(attachment_id): Path<Uuid>,
+) -> Result<Json<AttachmentDownloadResponse>, AttachmentError> {
+    context
+        .attachment_service
+        .get_download_url(attachment_id)
+        .await
+        .map(Json)
+}
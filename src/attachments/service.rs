@@ -0,0 +1,273 @@
+use std::{sync::Arc, time::Duration};
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::{
+    database::{AttachmentDatabase, AttachmentStorage},
+    entities::{
+        Attachment, AttachmentDownloadResponse, AttachmentLimits, AttachmentUploadResponse,
+    },
+    error::AttachmentError,
+};
+
+/// How long presigned upload and download URLs stay valid
+pub const PRESIGNED_URL_VALIDITY: Duration = Duration::from_secs(5 * 60);
+
+pub struct AttachmentService<D, S>
+where
+    D: AttachmentDatabase,
+    S: AttachmentStorage,
+{
+    attachment_db: Arc<D>,
+    storage: Arc<S>,
+    limits: AttachmentLimits,
+}
+
+impl<D, S> AttachmentService<D, S>
+where
+    D: AttachmentDatabase,
+    S: AttachmentStorage,
+{
+    pub fn new(attachment_db: Arc<D>, storage: Arc<S>, limits: AttachmentLimits) -> Self {
+        Self {
+            attachment_db,
+            storage,
+            limits,
+        }
+    }
+
+    /// Reserves storage for an attachment of the given size and returns the URL to upload it to.
+    /// The reserved size counts towards the account's quota until the attachment is deleted.
+    #[instrument(skip(self))]
+    pub async fn create_attachment(
+        &self,
+        owner_id: Uuid,
+        size: u64,
+    ) -> Result<AttachmentUploadResponse, AttachmentError> {
+        if size == 0 {
+            return Err(AttachmentError::Empty);
+        }
+        if size > self.limits.max_size {
+            return Err(AttachmentError::TooLarge(self.limits.max_size));
+        }
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            owner_id,
+            size,
+            created_at: now,
+            expires_at: now + self.limits.retention.as_millis() as u64,
+            uploaded: false,
+        };
+
+        self.attachment_db
+            .insert_attachment(attachment.clone(), self.limits.max_storage_per_account)
+            .await?;
+        let upload_url = match self
+            .storage
+            .generate_upload_url(attachment.id, size, PRESIGNED_URL_VALIDITY)
+            .await
+        {
+            Ok(upload_url) => upload_url,
+            Err(e) => {
+                // Release the reserved storage again
+                self.attachment_db.remove_attachment(attachment.id).await?;
+                return Err(e);
+            }
+        };
+
+        Ok(AttachmentUploadResponse {
+            attachment_id: attachment.id,
+            upload_url,
+            expires_in: PRESIGNED_URL_VALIDITY.as_secs(),
+            expires_at: attachment.expires_at,
+        })
+    }
+
+    /// Returns the URL to download an attachment from.
+    /// Attachment IDs are only shared inside encrypted messages, so anyone knowing one may
+    /// download the blob, which is useless without the key sent along with the ID.
+    #[instrument(skip(self))]
+    pub async fn get_download_url(
+        &self,
+        attachment_id: Uuid,
+    ) -> Result<AttachmentDownloadResponse, AttachmentError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let attachment = self
+            .attachment_db
+            .get_attachment(attachment_id)
+            .await?
+            .filter(|attachment| attachment.expires_at > now)
+            .ok_or(AttachmentError::NotFound)?;
+
+        // Attachments are created before the upload, make sure the blob actually exists
+        if !attachment.uploaded {
+            if !self.storage.attachment_exists(attachment.id).await? {
+                return Err(AttachmentError::NotFound);
+            }
+            self.attachment_db
+                .mark_attachment_uploaded(attachment.id)
+                .await?;
+        }
+
+        let download_url = self
+            .storage
+            .generate_download_url(attachment.id, PRESIGNED_URL_VALIDITY)
+            .await?;
+
+        Ok(AttachmentDownloadResponse {
+            download_url,
+            expires_in: PRESIGNED_URL_VALIDITY.as_secs(),
+            size: attachment.size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attachments::database::MockAttachmentStorage, database::inmemory::InMemoryDatabase,
+    };
+
+    fn test_limits() -> AttachmentLimits {
+        AttachmentLimits {
+            max_size: 1_000,
+            max_storage_per_account: 1_500,
+            retention: Duration::from_secs(60),
+        }
+    }
+
+    fn test_storage() -> Arc<MockAttachmentStorage> {
+        let mut storage = MockAttachmentStorage::new();
+        storage.expect_attachment_exists().returning(|_| Ok(true));
+        storage
+            .expect_generate_upload_url()
+            .returning(|id, size, _| Ok(format!("https://storage/{}?size={}", id, size)));
+        storage
+            .expect_generate_download_url()
+            .returning(|id, _| Ok(format!("https://storage/{}", id)));
+        Arc::new(storage)
+    }
+
+    #[tokio::test]
+    async fn test_create_and_download_attachment() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let service = AttachmentService::new(db.clone(), test_storage(), test_limits());
+        let owner_id = Uuid::new_v4();
+
+        let upload = service
+            .create_attachment(owner_id, 1_000)
+            .await
+            .expect("Failed to create attachment");
+        assert_eq!(
+            upload.upload_url,
+            format!("https://storage/{}?size=1000", upload.attachment_id)
+        );
+        assert_eq!(upload.expires_in, PRESIGNED_URL_VALIDITY.as_secs());
+
+        let download = service
+            .get_download_url(upload.attachment_id)
+            .await
+            .expect("Failed to get download URL");
+        assert_eq!(
+            download.download_url,
+            format!("https://storage/{}", upload.attachment_id)
+        );
+        assert_eq!(download.size, 1_000);
+        assert!(
+            db.get_attachment(upload.attachment_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .uploaded
+        );
+
+        assert!(matches!(
+            service.get_download_url(Uuid::new_v4()).await,
+            Err(AttachmentError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_of_missing_upload_fails() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let mut storage = MockAttachmentStorage::new();
+        storage
+            .expect_generate_upload_url()
+            .returning(|id, _, _| Ok(format!("https://storage/{}", id)));
+        storage.expect_attachment_exists().returning(|_| Ok(false));
+        storage.expect_generate_download_url().never();
+        let service = AttachmentService::new(db.clone(), Arc::new(storage), test_limits());
+
+        let upload = service
+            .create_attachment(Uuid::new_v4(), 100)
+            .await
+            .expect("Failed to create attachment");
+
+        assert!(matches!(
+            service.get_download_url(upload.attachment_id).await,
+            Err(AttachmentError::NotFound)
+        ));
+        assert!(
+            !db.get_attachment(upload.attachment_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .uploaded
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_attachment_releases_quota_on_storage_error() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let mut storage = MockAttachmentStorage::new();
+        storage
+            .expect_generate_upload_url()
+            .returning(|_, _, _| Err(AttachmentError::Storage("unavailable".to_string())));
+        let service = AttachmentService::new(db.clone(), Arc::new(storage), test_limits());
+        let owner_id = Uuid::new_v4();
+
+        assert!(matches!(
+            service.create_attachment(owner_id, 100).await,
+            Err(AttachmentError::Storage(_))
+        ));
+        assert_eq!(db.get_storage_usage(owner_id).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_create_attachment_enforces_limits() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let service = AttachmentService::new(db.clone(), test_storage(), test_limits());
+        let owner_id = Uuid::new_v4();
+
+        assert!(matches!(
+            service.create_attachment(owner_id, 0).await,
+            Err(AttachmentError::Empty)
+        ));
+        assert!(matches!(
+            service.create_attachment(owner_id, 1_001).await,
+            Err(AttachmentError::TooLarge(1_000))
+        ));
+
+        service
+            .create_attachment(owner_id, 1_000)
+            .await
+            .expect("Failed to create attachment");
+        service
+            .create_attachment(owner_id, 500)
+            .await
+            .expect("Failed to create attachment");
+        assert!(matches!(
+            service.create_attachment(owner_id, 1).await,
+            Err(AttachmentError::QuotaExceeded(1_500))
+        ));
+
+        // Quotas are per account
+        service
+            .create_attachment(Uuid::new_v4(), 1_000)
+            .await
+            .expect("Failed to create attachment");
+    }
+}
@@ -0,0 +1,29 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use super::{entities::BlockedAccount, error::BlockError};
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait BlockDatabase: Send + Sync {
+    /// Adds an account to the block list. Blocking an account again keeps the original block.
+    async fn insert_block(
+        &self,
+        account_id: Uuid,
+        blocked: BlockedAccount,
+    ) -> Result<(), BlockError>;
+
+    async fn remove_block(&self, account_id: Uuid, blocked_id: Uuid) -> Result<(), BlockError>;
+
+    /// Returns the accounts blocked by the account, in the order they were blocked
+    async fn get_blocked_accounts(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<BlockedAccount>, BlockError>;
+
+    /// Returns the IDs of the accounts that blocked `blocked_id`
+    async fn get_blocking_account_ids(&self, blocked_id: Uuid) -> Result<Vec<Uuid>, BlockError>;
+
+    /// Whether `account_id` blocked `blocked_id`
+    async fn is_blocked(&self, account_id: Uuid, blocked_id: Uuid) -> Result<bool, BlockError>;
+}
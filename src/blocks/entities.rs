@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// An account that was blocked. Blocked accounts can't message the blocking account,
+/// send it typing notifications or see its presence, without being told so.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockedAccount {
+    pub account_id: Uuid,
+    /// Time the account was blocked (epoch milliseconds)
+    pub blocked_at: u64,
+}
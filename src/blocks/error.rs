@@ -0,0 +1,26 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+use tracing::error;
+
+#[derive(Debug, Error)]
+pub enum BlockError {
+    #[error("Accounts cannot block themselves")]
+    CannotBlockSelf,
+
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+impl IntoResponse for BlockError {
+    fn into_response(self) -> Response {
+        error!("{}", self);
+        let status = match &self {
+            Self::CannotBlockSelf => StatusCode::BAD_REQUEST,
+            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        status.into_response()
+    }
+}
@@ -0,0 +1,89 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    middleware::from_fn_with_state,
+};
+use std::sync::Arc;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use super::{entities::BlockedAccount, error::BlockError};
+use crate::{
+    account::{auth::middleware::require_auth, entities::Account},
+    startup::AppContext,
+};
+
+const BLOCKS_TAG: &str = "blocks";
+
+pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
+    OpenApiRouter::new()
+        .routes(routes!(get_blocked_accounts))
+        .routes(routes!(block_account, unblock_account))
+        .layer(from_fn_with_state(context.clone(), require_auth))
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    responses(
+        (status = 200, description = "Blocked accounts fetched successfully", body = Vec<BlockedAccount>),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = BLOCKS_TAG
+)]
+async fn get_blocked_accounts(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<Vec<BlockedAccount>>, BlockError> {
+    context
+        .block_service
+        .get_blocked_accounts(account.id)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/{account_id}",
+    params(("account_id" = Uuid, Path, description = "Account to block")),
+    responses(
+        (status = 204, description = "Account blocked successfully"),
+        (status = 400, description = "Accounts cannot block themselves"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = BLOCKS_TAG
+)]
+async fn block_account(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(account_id): Path<Uuid>,
+) -> Result<StatusCode, BlockError> {
+    context
+        .block_service
+        .block_account(account.id, account_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{account_id}",
+    params(("account_id" = Uuid, Path, description = "Account to unblock")),
+    responses(
+        (status = 204, description = "Account unblocked successfully"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = BLOCKS_TAG
+)]
+async fn unblock_account(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(account_id): Path<Uuid>,
+) -> Result<StatusCode, BlockError> {
+    context
+        .block_service
+        .unblock_account(account.id, account_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::{database::BlockDatabase, entities::BlockedAccount, error::BlockError};
+
+pub struct BlockService<B>
+where
+    B: BlockDatabase,
+{
+    block_db: Arc<B>,
+}
+
+impl<B> BlockService<B>
+where
+    B: BlockDatabase,
+{
+    pub fn new(block_db: Arc<B>) -> Self {
+        Self { block_db }
+    }
+
+    /// Blocks an account. Blocked accounts are not told about the block,
+    /// their messages are silently dropped.
+    #[instrument(skip(self))]
+    pub async fn block_account(
+        &self,
+        account_id: Uuid,
+        blocked_id: Uuid,
+    ) -> Result<(), BlockError> {
+        if account_id == blocked_id {
+            return Err(BlockError::CannotBlockSelf);
+        }
+
+        let blocked = BlockedAccount {
+            account_id: blocked_id,
+            blocked_at: chrono::Utc::now().timestamp_millis() as u64,
+        };
+        self.block_db.insert_block(account_id, blocked).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn unblock_account(
+        &self,
+        account_id: Uuid,
+        blocked_id: Uuid,
+    ) -> Result<(), BlockError> {
+        self.block_db.remove_block(account_id, blocked_id).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_blocked_accounts(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<BlockedAccount>, BlockError> {
+        self.block_db.get_blocked_accounts(account_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::inmemory::InMemoryDatabase;
+
+    #[tokio::test]
+    async fn test_block_and_unblock_account() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let service = BlockService::new(db.clone());
+        let alice_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+
+        service
+            .block_account(alice_id, mallory_id)
+            .await
+            .expect("Failed to block account");
+        // Blocking again is a no-op
+        service
+            .block_account(alice_id, mallory_id)
+            .await
+            .expect("Failed to block account");
+
+        let blocked = service.get_blocked_accounts(alice_id).await.unwrap();
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].account_id, mallory_id);
+        assert!(db.is_blocked(alice_id, mallory_id).await.unwrap());
+        // Blocks are one-directional
+        assert!(!db.is_blocked(mallory_id, alice_id).await.unwrap());
+
+        service
+            .unblock_account(alice_id, mallory_id)
+            .await
+            .expect("Failed to unblock account");
+        assert!(
+            service
+                .get_blocked_accounts(alice_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(!db.is_blocked(alice_id, mallory_id).await.unwrap());
+
+        assert!(matches!(
+            service.block_account(alice_id, alice_id).await,
+            Err(BlockError::CannotBlockSelf)
+        ));
+    }
+}
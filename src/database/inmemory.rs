@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
@@ -8,28 +8,65 @@ use crate::{
         database::{AccountDatabase, AccountDatabaseError},
         entities::Account,
     },
+    attachments::{database::AttachmentDatabase, entities::Attachment, error::AttachmentError},
+    blocks::{database::BlockDatabase, entities::BlockedAccount, error::BlockError},
+    groups::{
+        database::GroupDatabase,
+        entities::{Group, GroupMember},
+        error::GroupError,
+    },
     keys::{
         database::KeyDatabase,
-        entities::{KeyBundle, Prekey},
+        entities::{KemPrekey, KeyBundle, Prekey, PreviousSignedPrekey, SignedPrekey},
         error::KeyError,
     },
-    messages::{database::MessageDatabase, entities::Message, error::MessagingError},
+    messages::{
+        database::MessageDatabase,
+        entities::{DeliveredMessage, Message, MessageCursor, QueueUsage, Receipt, SentMessage},
+        error::MessagingError,
+        requests::database::MessageRequestDatabase,
+        scheduled::database::ScheduledMessageDatabase,
+    },
     profiles::{database::ProfileDatabase, entities::Profile, error::ProfileError},
 };
 
 pub struct InMemoryDatabase {
+    pub accepted_senders: Mutex<HashMap<Uuid, HashSet<Uuid>>>,
     pub accounts: Mutex<HashMap<Uuid, Account>>,
+    pub attachments: Mutex<HashMap<Uuid, Attachment>>,
+    pub blocks: Mutex<HashMap<Uuid, Vec<BlockedAccount>>>,
+    pub delivered_messages: Mutex<HashMap<Uuid, DeliveredMessage>>,
+    pub groups: Mutex<HashMap<Uuid, Group>>,
+    pub group_members: Mutex<HashMap<Uuid, Vec<GroupMember>>>,
+    pub handed_out_messages: Mutex<HashMap<Uuid, u64>>,
     pub key_bundles: Mutex<HashMap<Uuid, KeyBundle>>,
     pub messages: Mutex<HashMap<Uuid, Vec<Message>>>,
+    pub previous_signed_prekeys: Mutex<HashMap<Uuid, Vec<PreviousSignedPrekey>>>,
+    pub receipts: Mutex<HashMap<Uuid, Vec<Receipt>>>,
+    pub request_messages: Mutex<HashMap<Uuid, Vec<Message>>>,
+    pub scheduled_messages: Mutex<Vec<Message>>,
+    pub sent_messages: Mutex<HashMap<(Uuid, Uuid), SentMessage>>,
     pub profiles: RwLock<HashMap<Uuid, Profile>>,
 }
 
 impl InMemoryDatabase {
     pub fn new() -> Self {
         InMemoryDatabase {
+            accepted_senders: Mutex::new(HashMap::new()),
             accounts: Mutex::new(HashMap::new()),
+            attachments: Mutex::new(HashMap::new()),
+            blocks: Mutex::new(HashMap::new()),
+            delivered_messages: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new()),
+            group_members: Mutex::new(HashMap::new()),
+            handed_out_messages: Mutex::new(HashMap::new()),
             key_bundles: Mutex::new(HashMap::new()),
             messages: Mutex::new(HashMap::new()),
+            previous_signed_prekeys: Mutex::new(HashMap::new()),
+            receipts: Mutex::new(HashMap::new()),
+            request_messages: Mutex::new(HashMap::new()),
+            scheduled_messages: Mutex::new(Vec::new()),
+            sent_messages: Mutex::new(HashMap::new()),
             profiles: RwLock::new(HashMap::new()),
         }
     }
@@ -86,6 +123,256 @@ impl AccountDatabase for InMemoryDatabase {
     }
 }
 
+#[async_trait]
+impl AttachmentDatabase for InMemoryDatabase {
+    async fn insert_attachment(
+        &self,
+        attachment: Attachment,
+        max_storage: u64,
+    ) -> Result<(), AttachmentError> {
+        let mut attachments_lock = self
+            .attachments
+            .lock()
+            .map_err(|e| AttachmentError::Database(e.to_string()))?;
+        let usage: u64 = attachments_lock
+            .values()
+            .filter(|stored| stored.owner_id == attachment.owner_id)
+            .map(|stored| stored.size)
+            .sum();
+        if usage + attachment.size > max_storage {
+            return Err(AttachmentError::QuotaExceeded(max_storage));
+        }
+        attachments_lock.insert(attachment.id, attachment);
+        Ok(())
+    }
+
+    async fn get_attachment(
+        &self,
+        attachment_id: Uuid,
+    ) -> Result<Option<Attachment>, AttachmentError> {
+        let attachments_lock = self
+            .attachments
+            .lock()
+            .map_err(|e| AttachmentError::Database(e.to_string()))?;
+        Ok(attachments_lock.get(&attachment_id).cloned())
+    }
+
+    async fn get_storage_usage(&self, owner_id: Uuid) -> Result<u64, AttachmentError> {
+        let attachments_lock = self
+            .attachments
+            .lock()
+            .map_err(|e| AttachmentError::Database(e.to_string()))?;
+        Ok(attachments_lock
+            .values()
+            .filter(|attachment| attachment.owner_id == owner_id)
+            .map(|attachment| attachment.size)
+            .sum())
+    }
+
+    async fn get_expired_attachments(&self, now: u64) -> Result<Vec<Attachment>, AttachmentError> {
+        let attachments_lock = self
+            .attachments
+            .lock()
+            .map_err(|e| AttachmentError::Database(e.to_string()))?;
+        Ok(attachments_lock
+            .values()
+            .filter(|attachment| attachment.expires_at <= now)
+            .cloned()
+            .collect())
+    }
+
+    async fn mark_attachment_uploaded(&self, attachment_id: Uuid) -> Result<(), AttachmentError> {
+        let mut attachments_lock = self
+            .attachments
+            .lock()
+            .map_err(|e| AttachmentError::Database(e.to_string()))?;
+        if let Some(attachment) = attachments_lock.get_mut(&attachment_id) {
+            attachment.uploaded = true;
+        }
+        Ok(())
+    }
+
+    async fn remove_attachment(&self, attachment_id: Uuid) -> Result<(), AttachmentError> {
+        let mut attachments_lock = self
+            .attachments
+            .lock()
+            .map_err(|e| AttachmentError::Database(e.to_string()))?;
+        attachments_lock.remove(&attachment_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockDatabase for InMemoryDatabase {
+    async fn insert_block(
+        &self,
+        account_id: Uuid,
+        blocked: BlockedAccount,
+    ) -> Result<(), BlockError> {
+        let mut blocks_lock = self
+            .blocks
+            .lock()
+            .map_err(|e| BlockError::Database(e.to_string()))?;
+        let blocked_accounts = blocks_lock.entry(account_id).or_default();
+
+        if !blocked_accounts
+            .iter()
+            .any(|b| b.account_id == blocked.account_id)
+        {
+            blocked_accounts.push(blocked);
+        }
+        Ok(())
+    }
+
+    async fn remove_block(&self, account_id: Uuid, blocked_id: Uuid) -> Result<(), BlockError> {
+        let mut blocks_lock = self
+            .blocks
+            .lock()
+            .map_err(|e| BlockError::Database(e.to_string()))?;
+        if let Some(blocked_accounts) = blocks_lock.get_mut(&account_id) {
+            blocked_accounts.retain(|b| b.account_id != blocked_id);
+        }
+        Ok(())
+    }
+
+    async fn get_blocked_accounts(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<BlockedAccount>, BlockError> {
+        let blocks_lock = self
+            .blocks
+            .lock()
+            .map_err(|e| BlockError::Database(e.to_string()))?;
+        Ok(blocks_lock.get(&account_id).cloned().unwrap_or_default())
+    }
+
+    async fn get_blocking_account_ids(&self, blocked_id: Uuid) -> Result<Vec<Uuid>, BlockError> {
+        let blocks_lock = self
+            .blocks
+            .lock()
+            .map_err(|e| BlockError::Database(e.to_string()))?;
+        Ok(blocks_lock
+            .iter()
+            .filter(|(_, blocked_accounts)| {
+                blocked_accounts.iter().any(|b| b.account_id == blocked_id)
+            })
+            .map(|(account_id, _)| *account_id)
+            .collect())
+    }
+
+    async fn is_blocked(&self, account_id: Uuid, blocked_id: Uuid) -> Result<bool, BlockError> {
+        let blocks_lock = self
+            .blocks
+            .lock()
+            .map_err(|e| BlockError::Database(e.to_string()))?;
+        Ok(blocks_lock
+            .get(&account_id)
+            .is_some_and(|blocked_accounts| {
+                blocked_accounts.iter().any(|b| b.account_id == blocked_id)
+            }))
+    }
+}
+
+#[async_trait]
+impl GroupDatabase for InMemoryDatabase {
+    async fn insert_group(
+        &self,
+        group: Group,
+        members: Vec<GroupMember>,
+    ) -> Result<(), GroupError> {
+        let mut groups_lock = self
+            .groups
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        let mut members_lock = self
+            .group_members
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+
+        members_lock.insert(group.id, members);
+        groups_lock.insert(group.id, group);
+        Ok(())
+    }
+
+    async fn get_group(&self, group_id: Uuid) -> Result<Option<Group>, GroupError> {
+        let groups_lock = self
+            .groups
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        Ok(groups_lock.get(&group_id).cloned())
+    }
+
+    async fn get_groups_for_account(&self, account_id: Uuid) -> Result<Vec<Group>, GroupError> {
+        let groups_lock = self
+            .groups
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        let members_lock = self
+            .group_members
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+
+        let mut groups: Vec<Group> = members_lock
+            .iter()
+            .filter(|(_, members)| members.iter().any(|m| m.account_id == account_id))
+            .filter_map(|(group_id, _)| groups_lock.get(group_id).cloned())
+            .collect();
+        groups.sort_by_key(|group| group.created_at);
+        Ok(groups)
+    }
+
+    async fn get_members(&self, group_id: Uuid) -> Result<Vec<GroupMember>, GroupError> {
+        let members_lock = self
+            .group_members
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        Ok(members_lock.get(&group_id).cloned().unwrap_or_default())
+    }
+
+    async fn upsert_member(&self, group_id: Uuid, member: GroupMember) -> Result<(), GroupError> {
+        let mut members_lock = self
+            .group_members
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        let members = members_lock.entry(group_id).or_default();
+
+        match members
+            .iter_mut()
+            .find(|m| m.account_id == member.account_id)
+        {
+            Some(existing) => existing.role = member.role,
+            None => members.push(member),
+        }
+        Ok(())
+    }
+
+    async fn remove_member(&self, group_id: Uuid, account_id: Uuid) -> Result<(), GroupError> {
+        let mut members_lock = self
+            .group_members
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        if let Some(members) = members_lock.get_mut(&group_id) {
+            members.retain(|m| m.account_id != account_id);
+        }
+        Ok(())
+    }
+
+    async fn remove_group(&self, group_id: Uuid) -> Result<(), GroupError> {
+        let mut groups_lock = self
+            .groups
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+        let mut members_lock = self
+            .group_members
+            .lock()
+            .map_err(|e| GroupError::Database(e.to_string()))?;
+
+        groups_lock.remove(&group_id);
+        members_lock.remove(&group_id);
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl KeyDatabase for InMemoryDatabase {
     async fn insert_keybundle(
@@ -98,6 +385,12 @@ impl KeyDatabase for InMemoryDatabase {
             .lock()
             .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
         kb_lock.insert(account_id, key_bundle);
+
+        // A new bundle starts without history
+        self.previous_signed_prekeys
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?
+            .remove(&account_id);
         Ok(())
     }
 
@@ -124,10 +417,134 @@ impl KeyDatabase for InMemoryDatabase {
             Err(KeyError::NotFound(account_id.to_string()))
         }
     }
+
+    async fn take_prekey(&self, account_id: Uuid) -> Result<Option<KeyBundle>, KeyError> {
+        let mut kb_lock = self
+            .key_bundles
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+
+        let Some(bundle) = kb_lock.get_mut(&account_id) else {
+            return Ok(None);
+        };
+        let position = bundle
+            .prekeys
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, prekey)| prekey.key_idx)
+            .map(|(position, _)| position);
+        let prekey = position.map(|position| bundle.prekeys.remove(position));
+
+        let position = bundle
+            .kem_prekeys
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, kem_prekey)| kem_prekey.key_id)
+            .map(|(position, _)| position);
+        let kem_prekey = position.map(|position| bundle.kem_prekeys.remove(position));
+
+        Ok(Some(KeyBundle {
+            prekeys: prekey.into_iter().collect(),
+            kem_prekeys: kem_prekey.into_iter().collect(),
+            ..bundle.clone()
+        }))
+    }
+
+    async fn add_kem_prekeys(
+        &self,
+        account_id: Uuid,
+        kem_prekeys: Vec<KemPrekey>,
+    ) -> Result<(), KeyError> {
+        let mut kb_lock = self
+            .key_bundles
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+
+        let bundle = kb_lock
+            .get_mut(&account_id)
+            .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+        bundle.kem_prekeys.retain(|existing| {
+            !kem_prekeys
+                .iter()
+                .any(|kem_prekey| kem_prekey.key_id == existing.key_id)
+        });
+        bundle.kem_prekeys.extend(kem_prekeys);
+        Ok(())
+    }
+
+    async fn count_prekeys(&self, account_id: Uuid) -> Result<usize, KeyError> {
+        let kb_lock = self
+            .key_bundles
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+        Ok(kb_lock
+            .get(&account_id)
+            .map_or(0, |bundle| bundle.prekeys.len()))
+    }
+
+    async fn rotate_signed_prekey(
+        &self,
+        account_id: Uuid,
+        signed_prekey: SignedPrekey,
+        replaced_at: u64,
+    ) -> Result<(), KeyError> {
+        let mut kb_lock = self
+            .key_bundles
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+        let mut previous_lock = self
+            .previous_signed_prekeys
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+
+        let bundle = kb_lock
+            .get_mut(&account_id)
+            .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+
+        let previous = previous_lock.entry(account_id).or_default();
+        previous.retain(|prekey| prekey.key_id != bundle.signed_prekey_id);
+        previous.push(PreviousSignedPrekey {
+            key_id: bundle.signed_prekey_id,
+            key: bundle.signed_prekey.clone(),
+            replaced_at,
+        });
+
+        bundle.signed_prekey_id = signed_prekey.key_id;
+        bundle.signed_prekey = signed_prekey.key;
+        bundle.signed_prekey_signature = signed_prekey.signature;
+        Ok(())
+    }
+
+    async fn get_previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<PreviousSignedPrekey>, KeyError> {
+        let previous_lock = self
+            .previous_signed_prekeys
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+        Ok(previous_lock.get(&account_id).cloned().unwrap_or_default())
+    }
+
+    async fn remove_previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+        replaced_before: u64,
+    ) -> Result<(), KeyError> {
+        let mut previous_lock = self
+            .previous_signed_prekeys
+            .lock()
+            .map_err(|_| KeyError::DatabaseError("Lock poisoned".to_string()))?;
+        if let Some(previous) = previous_lock.get_mut(&account_id) {
+            previous.retain(|prekey| prekey.replaced_at > replaced_before);
+        }
+        Ok(())
+    }
 }
 
+#[async_trait]
 impl MessageDatabase for InMemoryDatabase {
-    fn insert_message(&self, message: Message) -> Result<(), MessagingError> {
+    async fn insert_message(&self, message: Message) -> Result<(), MessagingError> {
         let mut messages_lock = self.messages.lock().map_err(|e| {
             MessagingError::DatabaseError(format!("Lock poisoned during message storage: {}", e))
         })?;
@@ -138,7 +555,7 @@ impl MessageDatabase for InMemoryDatabase {
         Ok(())
     }
 
-    fn get_all_messages(&self) -> Result<Vec<Message>, MessagingError> {
+    async fn get_all_messages(&self) -> Result<Vec<Message>, MessagingError> {
         let messages_lock = self.messages.lock().map_err(|e| {
             MessagingError::DatabaseError(format!("Lock poisoned during message retrieval: {}", e))
         })?;
@@ -146,7 +563,10 @@ impl MessageDatabase for InMemoryDatabase {
         Ok(messages_lock.values().flatten().cloned().collect())
     }
 
-    fn get_messages_for_account(&self, account_id: Uuid) -> Result<Vec<Message>, MessagingError> {
+    async fn get_messages_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError> {
         let messages_lock = self.messages.lock().map_err(|e| {
             MessagingError::DatabaseError(format!("Lock poisoned during message retrieval: {}", e))
         })?;
@@ -154,7 +574,28 @@ impl MessageDatabase for InMemoryDatabase {
         Ok(messages_lock.get(&account_id).cloned().unwrap_or_default())
     }
 
-    fn remove_messages(&self, account_id: Uuid, ids: Vec<Uuid>) -> Result<(), MessagingError> {
+    async fn get_messages_page_for_account(
+        &self,
+        account_id: Uuid,
+        after: Option<MessageCursor>,
+        limit: usize,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let mut messages: Vec<Message> = self
+            .get_messages_for_account(account_id)
+            .await?
+            .into_iter()
+            .filter(|msg| after.is_none_or(|after| MessageCursor::of(msg) > after))
+            .collect();
+        messages.sort_by_key(MessageCursor::of);
+        messages.truncate(limit);
+        Ok(messages)
+    }
+
+    async fn remove_messages(
+        &self,
+        account_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
         let mut messages_lock = self.messages.lock().map_err(|e| {
             MessagingError::DatabaseError(format!(
                 "Lock poisoned during message delivery status update: {}",
@@ -169,6 +610,422 @@ impl MessageDatabase for InMemoryDatabase {
         messages.retain(|msg| !ids.contains(&msg.message_id));
         Ok(())
     }
+
+    async fn remove_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError> {
+        let mut messages_lock = self.messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during message removal: {}", e))
+        })?;
+
+        // Checked while holding the messages lock, so the message can't be handed out meanwhile
+        let handed_out_lock = self.handed_out_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during message removal: {}", e))
+        })?;
+        if handed_out_lock.contains_key(&message_id) {
+            return Ok(None);
+        }
+
+        for messages in messages_lock.values_mut() {
+            if let Some(index) = messages.iter().position(|msg| msg.message_id == message_id) {
+                if messages[index].sender_id != Some(sender_id) {
+                    return Ok(None);
+                }
+                return Ok(Some(messages.remove(index)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn remove_expired_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError> {
+        let mut messages_lock = self.messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during message expiry: {}", e))
+        })?;
+
+        let mut expired = Vec::new();
+        for messages in messages_lock.values_mut() {
+            let (expired_messages, pending_messages): (Vec<Message>, Vec<Message>) =
+                std::mem::take(messages)
+                    .into_iter()
+                    .partition(|msg| msg.expires_at <= now);
+            *messages = pending_messages;
+            expired.extend(expired_messages);
+        }
+        messages_lock.retain(|_, messages| !messages.is_empty());
+        Ok(expired)
+    }
+
+    async fn get_queue_usage(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Option<Uuid>,
+    ) -> Result<QueueUsage, MessagingError> {
+        let messages_lock = self.messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during message retrieval: {}", e))
+        })?;
+        let Some(messages) = messages_lock.get(&recipient_id) else {
+            return Ok(QueueUsage::default());
+        };
+
+        Ok(QueueUsage {
+            recipient_messages: messages.len(),
+            recipient_bytes: messages
+                .iter()
+                .map(|msg| msg.message.ciphertext.len() as u64)
+                .sum(),
+            sender_messages: messages
+                .iter()
+                .filter(|msg| sender_id.is_some() && msg.sender_id == sender_id)
+                .count(),
+        })
+    }
+
+    async fn insert_sent_message(&self, sent_message: SentMessage) -> Result<bool, MessagingError> {
+        let mut sent_lock = self.sent_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during sent message storage: {}",
+                e
+            ))
+        })?;
+        let key = (sent_message.sender_id, sent_message.idempotency_key);
+        if sent_lock
+            .get(&key)
+            .is_some_and(|sent| sent.dedup_until > sent_message.receipt.timestamp)
+        {
+            return Ok(false);
+        }
+        sent_lock.insert(key, sent_message);
+        Ok(true)
+    }
+
+    async fn get_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<Option<SentMessage>, MessagingError> {
+        let sent_lock = self.sent_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during sent message retrieval: {}",
+                e
+            ))
+        })?;
+        Ok(sent_lock.get(&(sender_id, idempotency_key)).cloned())
+    }
+
+    async fn remove_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<(), MessagingError> {
+        let mut sent_lock = self.sent_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during sent message removal: {}",
+                e
+            ))
+        })?;
+        sent_lock.remove(&(sender_id, idempotency_key));
+        Ok(())
+    }
+
+    async fn remove_expired_sent_messages(&self, now: u64) -> Result<(), MessagingError> {
+        let mut sent_lock = self.sent_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during sent message removal: {}",
+                e
+            ))
+        })?;
+        sent_lock.retain(|_, sent| sent.dedup_until > now);
+        Ok(())
+    }
+
+    async fn insert_receipt(&self, receipt: Receipt) -> Result<(), MessagingError> {
+        let mut receipts_lock = self.receipts.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during receipt storage: {}", e))
+        })?;
+        receipts_lock
+            .entry(receipt.sender_id)
+            .or_insert_with(Vec::new)
+            .push(receipt);
+        Ok(())
+    }
+
+    async fn get_receipts_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Receipt>, MessagingError> {
+        let receipts_lock = self.receipts.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during receipt retrieval: {}", e))
+        })?;
+        Ok(receipts_lock.get(&account_id).cloned().unwrap_or_default())
+    }
+
+    async fn remove_receipts(
+        &self,
+        account_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let mut receipts_lock = self.receipts.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!("Lock poisoned during receipt removal: {}", e))
+        })?;
+        let Some(receipts) = receipts_lock.get_mut(&account_id) else {
+            return Ok(());
+        };
+
+        receipts.retain(|receipt| !ids.contains(&receipt.receipt_id));
+        Ok(())
+    }
+
+    async fn insert_handed_out_messages(
+        &self,
+        ids: Vec<Uuid>,
+        until: u64,
+    ) -> Result<(), MessagingError> {
+        let mut handed_out_lock = self.handed_out_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during handed out message storage: {}",
+                e
+            ))
+        })?;
+        for id in ids {
+            handed_out_lock.insert(id, until);
+        }
+        Ok(())
+    }
+
+    async fn is_handed_out(&self, message_id: Uuid) -> Result<bool, MessagingError> {
+        let handed_out_lock = self.handed_out_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during handed out message retrieval: {}",
+                e
+            ))
+        })?;
+        Ok(handed_out_lock.contains_key(&message_id))
+    }
+
+    async fn remove_expired_handed_out_messages(&self, now: u64) -> Result<(), MessagingError> {
+        let mut handed_out_lock = self.handed_out_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during handed out message removal: {}",
+                e
+            ))
+        })?;
+        handed_out_lock.retain(|_, until| *until > now);
+        Ok(())
+    }
+
+    async fn insert_delivered_message(
+        &self,
+        delivered_message: DeliveredMessage,
+    ) -> Result<(), MessagingError> {
+        let mut delivered_lock = self.delivered_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during delivered message storage: {}",
+                e
+            ))
+        })?;
+        delivered_lock.insert(delivered_message.message_id, delivered_message);
+        Ok(())
+    }
+
+    async fn remove_delivered_messages(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<Vec<DeliveredMessage>, MessagingError> {
+        let mut delivered_lock = self.delivered_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during delivered message removal: {}",
+                e
+            ))
+        })?;
+
+        let mut removed = Vec::new();
+        for id in ids {
+            if delivered_lock.get(&id).is_some_and(|delivered| {
+                delivered.recipient_id == recipient_id && delivered.sender_id == sender_id
+            }) {
+                removed.extend(delivered_lock.remove(&id));
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn remove_expired_delivered_messages(&self, now: u64) -> Result<(), MessagingError> {
+        let mut delivered_lock = self.delivered_messages.lock().map_err(|e| {
+            MessagingError::DatabaseError(format!(
+                "Lock poisoned during delivered message removal: {}",
+                e
+            ))
+        })?;
+        delivered_lock.retain(|_, delivered| delivered.expires_at > now);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageRequestDatabase for InMemoryDatabase {
+    async fn insert_accepted_sender(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        let mut accepted_lock = self
+            .accepted_senders
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        accepted_lock
+            .entry(account_id)
+            .or_default()
+            .insert(sender_id);
+        Ok(())
+    }
+
+    async fn is_accepted_sender(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<bool, MessagingError> {
+        let accepted_lock = self
+            .accepted_senders
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        Ok(accepted_lock
+            .get(&account_id)
+            .is_some_and(|senders| senders.contains(&sender_id)))
+    }
+
+    async fn insert_request_message(&self, message: Message) -> Result<(), MessagingError> {
+        let mut requests_lock = self
+            .request_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        requests_lock
+            .entry(message.recipient_id)
+            .or_default()
+            .push(message);
+        Ok(())
+    }
+
+    async fn get_request_messages(&self, account_id: Uuid) -> Result<Vec<Message>, MessagingError> {
+        let requests_lock = self
+            .request_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        Ok(requests_lock.get(&account_id).cloned().unwrap_or_default())
+    }
+
+    async fn count_request_messages(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<usize, MessagingError> {
+        let requests_lock = self
+            .request_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        Ok(requests_lock.get(&account_id).map_or(0, |messages| {
+            messages
+                .iter()
+                .filter(|msg| msg.sender_id == Some(sender_id))
+                .count()
+        }))
+    }
+
+    async fn remove_request_messages(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let mut requests_lock = self
+            .request_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        let Some(messages) = requests_lock.get_mut(&account_id) else {
+            return Ok(Vec::new());
+        };
+
+        let (removed, kept) = messages
+            .drain(..)
+            .partition(|msg| msg.sender_id == Some(sender_id));
+        *messages = kept;
+        Ok(removed)
+    }
+
+    async fn remove_request_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError> {
+        let mut requests_lock = self
+            .request_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+
+        for messages in requests_lock.values_mut() {
+            if let Some(index) = messages.iter().position(|msg| msg.message_id == message_id) {
+                if messages[index].sender_id != Some(sender_id) {
+                    return Ok(None);
+                }
+                return Ok(Some(messages.remove(index)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn remove_expired_request_messages(&self, now: u64) -> Result<(), MessagingError> {
+        let mut requests_lock = self
+            .request_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        for messages in requests_lock.values_mut() {
+            messages.retain(|msg| msg.expires_at > now);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ScheduledMessageDatabase for InMemoryDatabase {
+    async fn insert_scheduled_message(&self, message: Message) -> Result<(), MessagingError> {
+        let mut scheduled_lock = self
+            .scheduled_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        scheduled_lock.push(message);
+        Ok(())
+    }
+
+    async fn get_due_scheduled_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError> {
+        let scheduled_lock = self
+            .scheduled_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        let mut due: Vec<Message> = scheduled_lock
+            .iter()
+            .filter(|msg| msg.timestamp <= now)
+            .cloned()
+            .collect();
+        due.sort_by_key(MessageCursor::of);
+        Ok(due)
+    }
+
+    async fn remove_scheduled_message(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<bool, MessagingError> {
+        let mut scheduled_lock = self
+            .scheduled_messages
+            .lock()
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?;
+        let count = scheduled_lock.len();
+        scheduled_lock
+            .retain(|msg| msg.message_id != message_id || msg.sender_id != Some(sender_id));
+        Ok(scheduled_lock.len() < count)
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,71 @@
+use async_trait::async_trait;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use uuid::Uuid;
+
+use crate::attachments::{database::AttachmentStorage, error::AttachmentError};
+
+/// Stores attachments as files in a local directory, only meant for tests.
+/// The returned URLs are plain `file://` URLs, they neither expire nor enforce sizes.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Path of the file holding the attachment
+    pub fn path(&self, attachment_id: Uuid) -> PathBuf {
+        self.root
+            .join("attachments")
+            .join(attachment_id.to_string())
+    }
+
+    fn url(&self, attachment_id: Uuid) -> String {
+        format!("file://{}", self.path(attachment_id).display())
+    }
+}
+
+#[async_trait]
+impl AttachmentStorage for LocalStorage {
+    async fn generate_upload_url(
+        &self,
+        attachment_id: Uuid,
+        _size: u64,
+        _expires_in: Duration,
+    ) -> Result<String, AttachmentError> {
+        tokio::fs::create_dir_all(self.root.join("attachments"))
+            .await
+            .map_err(|e| AttachmentError::Storage(e.to_string()))?;
+        Ok(self.url(attachment_id))
+    }
+
+    async fn generate_download_url(
+        &self,
+        attachment_id: Uuid,
+        _expires_in: Duration,
+    ) -> Result<String, AttachmentError> {
+        Ok(self.url(attachment_id))
+    }
+
+    async fn attachment_exists(&self, attachment_id: Uuid) -> Result<bool, AttachmentError> {
+        tokio::fs::try_exists(self.path(attachment_id))
+            .await
+            .map_err(|e| AttachmentError::Storage(e.to_string()))
+    }
+
+    async fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), AttachmentError> {
+        match tokio::fs::remove_file(self.path(attachment_id)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(AttachmentError::Storage(e.to_string())),
+        }
+    }
+}
@@ -1,4 +1,8 @@
 pub mod inmemory;
+#[cfg(test)]
+pub mod local;
 pub mod pool;
+#[cfg(feature = "redis")]
+pub mod redis;
 pub mod s3;
 pub mod sqlite;
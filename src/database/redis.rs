@@ -0,0 +1,828 @@
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::{
+    AsyncCommands, Client, ExistenceCheck, RedisError, Script, SetExpiry, SetOptions,
+    aio::ConnectionManager,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::time::interval;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    messages::{
+        database::MessageDatabase,
+        entities::{DeliveredMessage, Message, MessageCursor, QueueUsage, Receipt, SentMessage},
+        error::MessagingError,
+    },
+    presence::{database::PresenceDatabase, entities::PresenceStatus, error::PresenceError},
+    websocket::center::WebSocketCenter,
+};
+
+/// Set containing the IDs of all accounts with pending messages
+const PENDING_RECIPIENTS_KEY: &str = "messages:recipients";
+
+/// How long an account stays present after the last heartbeat of the server it is connected to
+const PRESENCE_TTL: Duration = Duration::from_secs(60);
+
+/// How often a server refreshes the presence of the accounts connected to it
+pub const PRESENCE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Channel on which servers announce the recipients of the messages they inserted,
+/// as `{server_id}:{recipient_id}`
+const INSERTED_MESSAGES_CHANNEL: &str = "messages:inserted";
+
+/// How long to wait before subscribing again after the subscription failed
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Removes a recipient from the pending set once its queue is drained.
+/// Runs as a script, so it cannot interleave with a concurrent insert.
+const REMOVE_DRAINED_RECIPIENT_SCRIPT: &str = r#"
+if redis.call('LLEN', KEYS[1]) == 0 then
+    redis.call('SREM', KEYS[2], ARGV[1])
+end
+return 0
+"#;
+
+/// Removes a sender's pending message unless it was handed out, returning its serialized
+/// value. Runs as a script, so the message cannot be handed out while it is being removed.
+const REMOVE_MESSAGE_OF_SENDER_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return false
+end
+local recipient_id = redis.call('HGET', KEYS[2], ARGV[1])
+if not recipient_id then
+    return false
+end
+local key = ARGV[3] .. recipient_id
+for _, value in ipairs(redis.call('LRANGE', key, 0, -1)) do
+    local message = cjson.decode(value)
+    if message['messageId'] == ARGV[1] and message['senderId'] == ARGV[2] then
+        redis.call('LREM', key, 1, value)
+        redis.call('HDEL', KEYS[2], ARGV[1])
+        if redis.call('LLEN', key) == 0 then
+            redis.call('SREM', KEYS[3], recipient_id)
+        end
+        return value
+    end
+end
+return false
+"#;
+
+/// Prefix of the keys of the pending message lists, see [`messages_key`]
+const MESSAGES_KEY_PREFIX: &str = "messages:";
+
+fn messages_key(account_id: Uuid) -> String {
+    format!("{}{}", MESSAGES_KEY_PREFIX, account_id)
+}
+
+fn sent_message_key(sender_id: Uuid, idempotency_key: Uuid) -> String {
+    format!("sent:{}:{}", sender_id, idempotency_key)
+}
+
+/// Hash from the IDs of a sender's pending messages to their recipients
+fn sender_messages_key(sender_id: Uuid) -> String {
+    format!("messages-by-sender:{}", sender_id)
+}
+
+fn receipts_key(account_id: Uuid) -> String {
+    format!("receipts:{}", account_id)
+}
+
+fn handed_out_message_key(message_id: Uuid) -> String {
+    format!("handed-out:{}", message_id)
+}
+
+fn delivered_message_key(recipient_id: Uuid, sender_id: Uuid, message_id: Uuid) -> String {
+    format!("delivered:{}:{}:{}", recipient_id, sender_id, message_id)
+}
+
+fn presence_key(account_id: &Uuid) -> String {
+    format!("presence:{}", account_id)
+}
+
+/// Ephemeral database backed by redis, shareable between multiple server replicas.
+///
+/// Pending messages are kept in one list per recipient, sent messages awaiting
+/// deduplication, handed out messages and delivered messages awaiting read receipts
+/// as expiring keys, presence as one sorted set per account of the servers it is
+/// connected to, scored by when their heartbeat runs out. Inserted messages are
+/// announced via pub/sub, so the server their recipient is connected to delivers them.
+pub struct RedisDatabase {
+    client: Client,
+    connection: ConnectionManager,
+    /// Identifies this server in the presence sets
+    server_id: Uuid,
+    /// The connections of this server, which its share of the presence is derived from
+    websocket_center: Arc<WebSocketCenter>,
+}
+
+impl RedisDatabase {
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        websocket_center: Arc<WebSocketCenter>,
+    ) -> Result<Self, RedisError> {
+        let client = Client::open(format!("redis://{}:{}/", host, port))?;
+        let connection = client.get_connection_manager().await?;
+        Ok(Self {
+            client,
+            connection,
+            server_id: Uuid::new_v4(),
+            websocket_center,
+        })
+    }
+
+    /// Spawn the background task that keeps the accounts connected to this server present.
+    /// Accounts of a server that stopped are no longer present once their TTL ran out.
+    #[instrument(skip(self))]
+    pub fn spawn_presence_heartbeat(
+        self: Arc<Self>,
+        heartbeat_interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            info!("Starting presence heartbeat task");
+            let mut ticker = interval(heartbeat_interval);
+
+            loop {
+                ticker.tick().await;
+                let account_ids = self.websocket_center.connected_account_ids().await;
+                if let Err(e) = self.refresh_presence(&account_ids).await {
+                    error!("Error refreshing presence: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Spawn the background task that hands the recipients of messages inserted by other
+    /// servers to `handler`, if they are connected to this server. The inserting server
+    /// can't deliver those messages, so they would otherwise wait for the next poll.
+    #[instrument(skip(self, handler))]
+    pub fn spawn_inserted_message_listener<H>(
+        self: Arc<Self>,
+        handler: H,
+    ) -> tokio::task::JoinHandle<()>
+    where
+        H: Fn(Uuid) + Send + Sync + 'static,
+    {
+        tokio::spawn(async move {
+            info!("Starting inserted message listener task");
+
+            loop {
+                if let Err(e) = self.listen_for_inserted_messages(&handler).await {
+                    error!("Error listening for inserted messages: {}", e);
+                }
+                // Messages announced meanwhile are left to the poll
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        })
+    }
+
+    /// Runs until the subscription is lost
+    async fn listen_for_inserted_messages<H>(&self, handler: &H) -> Result<(), RedisError>
+    where
+        H: Fn(Uuid) + Sync,
+    {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(INSERTED_MESSAGES_CHANNEL).await?;
+        let mut messages = pubsub.on_message();
+
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            let Some((server_id, recipient_id)) = payload.split_once(':') else {
+                warn!("Invalid inserted message announcement: {}", payload);
+                continue;
+            };
+            // This server delivers the messages it inserted itself
+            if server_id == self.server_id.to_string() {
+                continue;
+            }
+            let Ok(recipient_id) = Uuid::parse_str(recipient_id) else {
+                warn!("Invalid inserted message announcement: {}", payload);
+                continue;
+            };
+            if self.websocket_center.has_connection(&recipient_id).await {
+                handler(recipient_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the accounts as connected to this server for another TTL
+    async fn refresh_presence(&self, account_ids: &[Uuid]) -> Result<(), RedisError> {
+        if account_ids.is_empty() {
+            return Ok(());
+        }
+        let expires_at = chrono::Utc::now().timestamp_millis() + PRESENCE_TTL.as_millis() as i64;
+
+        let mut pipe = redis::pipe();
+        for account_id in account_ids {
+            let key = presence_key(account_id);
+            pipe.zadd(&key, self.server_id.to_string(), expires_at)
+                .pexpire(&key, PRESENCE_TTL.as_millis() as i64);
+        }
+        let mut conn = self.connection.clone();
+        pipe.query_async(&mut conn).await
+    }
+}
+
+// MESSAGES
+
+impl From<RedisError> for MessagingError {
+    fn from(e: RedisError) -> Self {
+        MessagingError::DatabaseError(e.to_string())
+    }
+}
+
+fn parse_message(value: &str) -> Result<Message, MessagingError> {
+    serde_json::from_str(value).map_err(|e| MessagingError::ParseError(e.to_string()))
+}
+
+#[async_trait]
+impl MessageDatabase for RedisDatabase {
+    async fn insert_message(&self, message: Message) -> Result<(), MessagingError> {
+        let value = serde_json::to_string(&message)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .rpush(messages_key(message.recipient_id), value)
+            .sadd(PENDING_RECIPIENTS_KEY, message.recipient_id.to_string());
+        if let Some(sender_id) = message.sender_id {
+            pipe.hset(
+                sender_messages_key(sender_id),
+                message.message_id.to_string(),
+                message.recipient_id.to_string(),
+            );
+        }
+        pipe.publish(
+            INSERTED_MESSAGES_CHANNEL,
+            format!("{}:{}", self.server_id, message.recipient_id),
+        )
+        .ignore();
+
+        let mut conn = self.connection.clone();
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn get_all_messages(&self) -> Result<Vec<Message>, MessagingError> {
+        let mut conn = self.connection.clone();
+        let recipient_ids: Vec<String> = conn.smembers(PENDING_RECIPIENTS_KEY).await?;
+
+        let mut messages = Vec::new();
+        for recipient_id in recipient_ids {
+            let recipient_id = Uuid::parse_str(&recipient_id)?;
+            messages.extend(self.get_messages_for_account(recipient_id).await?);
+        }
+        Ok(messages)
+    }
+
+    async fn get_messages_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let mut conn = self.connection.clone();
+        let values: Vec<String> = conn.lrange(messages_key(account_id), 0, -1).await?;
+
+        values.iter().map(|value| parse_message(value)).collect()
+    }
+
+    async fn get_messages_page_for_account(
+        &self,
+        account_id: Uuid,
+        after: Option<MessageCursor>,
+        limit: usize,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let mut messages: Vec<Message> = self
+            .get_messages_for_account(account_id)
+            .await?
+            .into_iter()
+            .filter(|msg| after.is_none_or(|after| MessageCursor::of(msg) > after))
+            .collect();
+        messages.sort_by_key(MessageCursor::of);
+        messages.truncate(limit);
+        Ok(messages)
+    }
+
+    async fn remove_messages(
+        &self,
+        account_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let key = messages_key(account_id);
+        let mut conn = self.connection.clone();
+        let values: Vec<String> = conn.lrange(&key, 0, -1).await?;
+
+        // Remove by serialized value, as redis lists can't be filtered by field
+        let mut pipe = redis::pipe();
+        for value in values {
+            let message = parse_message(&value)?;
+            if ids.contains(&message.message_id) {
+                pipe.lrem(&key, 1, value);
+                if let Some(sender_id) = message.sender_id {
+                    pipe.hdel(
+                        sender_messages_key(sender_id),
+                        message.message_id.to_string(),
+                    );
+                }
+            }
+        }
+        let _: () = pipe.query_async(&mut conn).await?;
+
+        let _: () = Script::new(REMOVE_DRAINED_RECIPIENT_SCRIPT)
+            .key(&key)
+            .key(PENDING_RECIPIENTS_KEY)
+            .arg(account_id.to_string())
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError> {
+        let mut conn = self.connection.clone();
+        let value: Option<String> = Script::new(REMOVE_MESSAGE_OF_SENDER_SCRIPT)
+            .key(handed_out_message_key(message_id))
+            .key(sender_messages_key(sender_id))
+            .key(PENDING_RECIPIENTS_KEY)
+            .arg(message_id.to_string())
+            .arg(sender_id.to_string())
+            .arg(MESSAGES_KEY_PREFIX)
+            .invoke_async(&mut conn)
+            .await?;
+
+        value.as_deref().map(parse_message).transpose()
+    }
+
+    async fn remove_expired_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError> {
+        let mut conn = self.connection.clone();
+        let recipient_ids: Vec<String> = conn.smembers(PENDING_RECIPIENTS_KEY).await?;
+
+        let mut expired = Vec::new();
+        for recipient_id in recipient_ids {
+            let recipient_id = Uuid::parse_str(&recipient_id)?;
+            let expired_messages: Vec<Message> = self
+                .get_messages_for_account(recipient_id)
+                .await?
+                .into_iter()
+                .filter(|msg| msg.expires_at <= now)
+                .collect();
+
+            if expired_messages.is_empty() {
+                continue;
+            }
+            let ids = expired_messages.iter().map(|msg| msg.message_id).collect();
+            self.remove_messages(recipient_id, ids).await?;
+            expired.extend(expired_messages);
+        }
+        Ok(expired)
+    }
+
+    async fn get_queue_usage(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Option<Uuid>,
+    ) -> Result<QueueUsage, MessagingError> {
+        let messages = self.get_messages_for_account(recipient_id).await?;
+
+        Ok(QueueUsage {
+            recipient_messages: messages.len(),
+            recipient_bytes: messages
+                .iter()
+                .map(|msg| msg.message.ciphertext.len() as u64)
+                .sum(),
+            sender_messages: messages
+                .iter()
+                .filter(|msg| sender_id.is_some() && msg.sender_id == sender_id)
+                .count(),
+        })
+    }
+
+    async fn insert_sent_message(&self, sent_message: SentMessage) -> Result<bool, MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        if sent_message.dedup_until <= now {
+            return Ok(true);
+        }
+        let value = serde_json::to_string(&sent_message)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        // Stale entries expire by themselves, so NX only fails within the window
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::PX(sent_message.dedup_until - now));
+        let mut conn = self.connection.clone();
+        let result: Option<String> = conn
+            .set_options(
+                sent_message_key(sent_message.sender_id, sent_message.idempotency_key),
+                value,
+                options,
+            )
+            .await?;
+        Ok(result.is_some())
+    }
+
+    async fn get_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<Option<SentMessage>, MessagingError> {
+        let mut conn = self.connection.clone();
+        let value: Option<String> = conn
+            .get(sent_message_key(sender_id, idempotency_key))
+            .await?;
+
+        value
+            .map(|value| {
+                serde_json::from_str(&value).map_err(|e| MessagingError::ParseError(e.to_string()))
+            })
+            .transpose()
+    }
+
+    async fn remove_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<(), MessagingError> {
+        let mut conn = self.connection.clone();
+        let _: () = conn
+            .del(sent_message_key(sender_id, idempotency_key))
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_expired_sent_messages(&self, _now: u64) -> Result<(), MessagingError> {
+        // Sent messages are stored with a TTL, redis removes them by itself
+        Ok(())
+    }
+
+    async fn insert_receipt(&self, receipt: Receipt) -> Result<(), MessagingError> {
+        let value = serde_json::to_string(&receipt)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        let mut conn = self.connection.clone();
+        let _: () = conn.rpush(receipts_key(receipt.sender_id), value).await?;
+        Ok(())
+    }
+
+    async fn get_receipts_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Receipt>, MessagingError> {
+        let mut conn = self.connection.clone();
+        let values: Vec<String> = conn.lrange(receipts_key(account_id), 0, -1).await?;
+
+        values
+            .iter()
+            .map(|value| {
+                serde_json::from_str(value).map_err(|e| MessagingError::ParseError(e.to_string()))
+            })
+            .collect()
+    }
+
+    async fn remove_receipts(
+        &self,
+        account_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let key = receipts_key(account_id);
+        let mut conn = self.connection.clone();
+        let values: Vec<String> = conn.lrange(&key, 0, -1).await?;
+
+        let mut pipe = redis::pipe();
+        for value in values {
+            let receipt: Receipt = serde_json::from_str(&value)
+                .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+            if ids.contains(&receipt.receipt_id) {
+                pipe.lrem(&key, 1, value);
+            }
+        }
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn insert_handed_out_messages(
+        &self,
+        ids: Vec<Uuid>,
+        until: u64,
+    ) -> Result<(), MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        if until <= now || ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for id in ids {
+            pipe.pset_ex(handed_out_message_key(id), 1, until - now)
+                .ignore();
+        }
+        let mut conn = self.connection.clone();
+        let _: () = pipe.query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn is_handed_out(&self, message_id: Uuid) -> Result<bool, MessagingError> {
+        let mut conn = self.connection.clone();
+        Ok(conn.exists(handed_out_message_key(message_id)).await?)
+    }
+
+    async fn remove_expired_handed_out_messages(&self, _now: u64) -> Result<(), MessagingError> {
+        // Handed out messages are stored with a TTL, redis removes them by itself
+        Ok(())
+    }
+
+    async fn insert_delivered_message(
+        &self,
+        delivered_message: DeliveredMessage,
+    ) -> Result<(), MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        if delivered_message.expires_at <= now {
+            return Ok(());
+        }
+        let value = serde_json::to_string(&delivered_message)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        let mut conn = self.connection.clone();
+        let _: () = conn
+            .pset_ex(
+                delivered_message_key(
+                    delivered_message.recipient_id,
+                    delivered_message.sender_id,
+                    delivered_message.message_id,
+                ),
+                value,
+                delivered_message.expires_at - now,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_delivered_messages(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<Vec<DeliveredMessage>, MessagingError> {
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.get_del(delivered_message_key(recipient_id, sender_id, *id));
+        }
+        let mut conn = self.connection.clone();
+        let values: Vec<Option<String>> = pipe.query_async(&mut conn).await?;
+
+        values
+            .into_iter()
+            .flatten()
+            .map(|value| {
+                serde_json::from_str(&value).map_err(|e| MessagingError::ParseError(e.to_string()))
+            })
+            .collect()
+    }
+
+    async fn remove_expired_delivered_messages(&self, _now: u64) -> Result<(), MessagingError> {
+        // Delivered messages are stored with a TTL, redis removes them by itself
+        Ok(())
+    }
+}
+
+// PRESENCE
+
+impl From<RedisError> for PresenceError {
+    fn from(e: RedisError) -> Self {
+        PresenceError::Database(e.to_string())
+    }
+}
+
+#[async_trait]
+impl PresenceDatabase for RedisDatabase {
+    async fn is_present(&self, account_id: &Uuid) -> Result<bool, PresenceError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut conn = self.connection.clone();
+        let servers: usize = conn.zcount(presence_key(account_id), now, "+inf").await?;
+        Ok(servers > 0)
+    }
+
+    /// Updates may arrive out of order, so whether the account is connected to this
+    /// server is taken from its connections rather than from the status.
+    async fn update_presence(
+        &self,
+        account_id: &Uuid,
+        _status: &PresenceStatus,
+    ) -> Result<(), PresenceError> {
+        if self.websocket_center.has_connection(account_id).await {
+            self.refresh_presence(&[*account_id]).await?;
+        } else {
+            let mut conn = self.connection.clone();
+            let _: () = conn
+                .zrem(presence_key(account_id), self.server_id.to_string())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// These tests require a running redis-server, so they are ignored by default.
+/// Run them with `cargo test --features redis -- --ignored`.
+/// Its address can be set via `REDIS_HOST` and `REDIS_PORT` (default: 127.0.0.1:6379).
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::entities::{DoubleRatchetHeader, DoubleRatchetMessage};
+    use prism_client::SigningKey;
+
+    async fn connect_test_db(websocket_center: Arc<WebSocketCenter>) -> RedisDatabase {
+        let host = std::env::var("REDIS_HOST").unwrap_or("127.0.0.1".to_string());
+        let port = std::env::var("REDIS_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(6379);
+
+        RedisDatabase::connect(&host, port, websocket_center)
+            .await
+            .expect("Failed to connect to redis-server")
+    }
+
+    fn create_test_message(sender_id: Uuid, recipient_id: Uuid, timestamp: u64) -> Message {
+        Message {
+            message_id: Uuid::new_v4(),
+            sender_id: Some(sender_id),
+            recipient_id,
+            group_id: None,
+            message: DoubleRatchetMessage {
+                header: DoubleRatchetHeader {
+                    ephemeral_key: SigningKey::new_ed25519().verifying_key(),
+                    message_number: timestamp,
+                    previous_message_number: 0,
+                    one_time_prekey_id: None,
+                    signed_prekey_id: None,
+                },
+                ciphertext: format!("Message {}", timestamp).into_bytes(),
+                nonce: vec![0; 12],
+            },
+            timestamp,
+            expires_at: timestamp + 60_000,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis-server"]
+    async fn test_message_database_operations() {
+        let db = connect_test_db(Arc::new(WebSocketCenter::new())).await;
+
+        let sender_id = Uuid::new_v4();
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let alice_msg1 = create_test_message(sender_id, alice_id, 1);
+        let alice_msg2 = create_test_message(sender_id, alice_id, 2);
+        let bob_msg = create_test_message(sender_id, bob_id, 3);
+
+        for message in [alice_msg1.clone(), alice_msg2.clone(), bob_msg.clone()] {
+            db.insert_message(message)
+                .await
+                .expect("Failed to insert message");
+        }
+
+        // Other tests may share the redis-server, so only look at our messages
+        let all_ids: Vec<Uuid> = db
+            .get_all_messages()
+            .await
+            .expect("Failed to get all messages")
+            .iter()
+            .map(|msg| msg.message_id)
+            .collect();
+        assert!(all_ids.contains(&alice_msg1.message_id));
+        assert!(all_ids.contains(&alice_msg2.message_id));
+        assert!(all_ids.contains(&bob_msg.message_id));
+
+        let alice_messages = db
+            .get_messages_for_account(alice_id)
+            .await
+            .expect("Failed to get messages for alice");
+        assert_eq!(alice_messages.len(), 2);
+        assert_eq!(alice_messages[0].message_id, alice_msg1.message_id);
+        assert_eq!(alice_messages[1].message_id, alice_msg2.message_id);
+
+        // Messages of the same millisecond are paged by ID, whatever order they arrived in
+        let carol_id = Uuid::new_v4();
+        let mut later_id = create_test_message(sender_id, carol_id, 5);
+        later_id.message_id = Uuid::from_u128(2);
+        let mut earlier_id = create_test_message(sender_id, carol_id, 5);
+        earlier_id.message_id = Uuid::from_u128(1);
+        for message in [later_id.clone(), earlier_id.clone()] {
+            db.insert_message(message)
+                .await
+                .expect("Failed to insert message");
+        }
+        let mut paged_ids = Vec::new();
+        let mut after = None;
+        loop {
+            let page = db
+                .get_messages_page_for_account(carol_id, after, 1)
+                .await
+                .expect("Failed to get page for carol");
+            let Some(message) = page.first() else {
+                break;
+            };
+            after = Some(MessageCursor::of(message));
+            paged_ids.push(message.message_id);
+        }
+        assert_eq!(paged_ids, vec![earlier_id.message_id, later_id.message_id]);
+        db.remove_messages(carol_id, paged_ids)
+            .await
+            .expect("Failed to remove messages");
+
+        // Removing messages is scoped to the recipient
+        db.remove_messages(alice_id, vec![alice_msg1.message_id, bob_msg.message_id])
+            .await
+            .expect("Failed to remove messages");
+
+        let alice_messages = db
+            .get_messages_for_account(alice_id)
+            .await
+            .expect("Failed to get messages for alice");
+        assert_eq!(alice_messages.len(), 1);
+        assert_eq!(alice_messages[0].message_id, alice_msg2.message_id);
+
+        // Senders can only remove their own messages
+        let removed = db
+            .remove_message_of_sender(bob_id, alice_msg2.message_id)
+            .await
+            .expect("Failed to remove message of sender");
+        assert!(removed.is_none());
+        let removed = db
+            .remove_message_of_sender(sender_id, alice_msg2.message_id)
+            .await
+            .expect("Failed to remove message of sender");
+        assert_eq!(
+            removed.map(|msg| msg.message_id),
+            Some(alice_msg2.message_id)
+        );
+        let removed = db
+            .remove_message_of_sender(sender_id, alice_msg2.message_id)
+            .await
+            .expect("Failed to remove message of sender");
+        assert!(removed.is_none());
+        db.remove_messages(bob_id, vec![bob_msg.message_id])
+            .await
+            .expect("Failed to remove messages");
+
+        let all_ids: Vec<Uuid> = db
+            .get_all_messages()
+            .await
+            .expect("Failed to get all messages")
+            .iter()
+            .map(|msg| msg.message_id)
+            .collect();
+        assert!(!all_ids.contains(&alice_msg2.message_id));
+        assert!(!all_ids.contains(&bob_msg.message_id));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis-server"]
+    async fn test_presence_database_operations() {
+        let websocket_center = Arc::new(WebSocketCenter::new());
+        let db = connect_test_db(websocket_center.clone()).await;
+        let account_id = Uuid::new_v4();
+
+        assert!(!db.is_present(&account_id).await.unwrap());
+
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        websocket_center.add_connection(account_id, sender).await;
+        db.update_presence(&account_id, &PresenceStatus::Online)
+            .await
+            .expect("Failed to set presence");
+        assert!(db.is_present(&account_id).await.unwrap());
+
+        // An outdated offline update doesn't hide an account that is still connected
+        db.update_presence(&account_id, &PresenceStatus::Offline)
+            .await
+            .expect("Failed to clear presence");
+        assert!(db.is_present(&account_id).await.unwrap());
+
+        websocket_center.remove_connection(&account_id).await;
+        db.update_presence(&account_id, &PresenceStatus::Offline)
+            .await
+            .expect("Failed to clear presence");
+        assert!(!db.is_present(&account_id).await.unwrap());
+
+        // Connections to other servers are tracked separately
+        let other_websocket_center = Arc::new(WebSocketCenter::new());
+        let other_db = connect_test_db(other_websocket_center.clone()).await;
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        other_websocket_center
+            .add_connection(account_id, sender)
+            .await;
+        other_db
+            .update_presence(&account_id, &PresenceStatus::Online)
+            .await
+            .expect("Failed to set presence");
+        db.update_presence(&account_id, &PresenceStatus::Offline)
+            .await
+            .expect("Failed to clear presence");
+        assert!(db.is_present(&account_id).await.unwrap());
+    }
+}
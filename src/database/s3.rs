@@ -8,7 +8,11 @@ use aws_sdk_s3::{
 use std::time::Duration;
 use uuid::Uuid;
 
-use crate::profiles::{database::ProfilePictureStorage, error::ProfileError};
+use crate::{
+    attachments::{database::AttachmentStorage, error::AttachmentError},
+    profiles::{database::ProfilePictureStorage, error::ProfileError},
+};
+
 pub struct S3Storage {
     client: Client,
     bucket: String,
@@ -123,3 +127,98 @@ impl ProfilePictureStorage for S3Storage {
         Ok(())
     }
 }
+
+fn attachment_key(attachment_id: Uuid) -> String {
+    format!("attachments/{}", attachment_id)
+}
+
+fn presigning_config(expires_in: Duration) -> Result<PresigningConfig, AttachmentError> {
+    PresigningConfig::expires_in(expires_in).map_err(|err| {
+        AttachmentError::Storage(format!("Failed to create presign config: {}", err))
+    })
+}
+
+#[async_trait]
+impl AttachmentStorage for S3Storage {
+    async fn generate_upload_url(
+        &self,
+        attachment_id: Uuid,
+        size: u64,
+        expires_in: Duration,
+    ) -> Result<String, AttachmentError> {
+        // The content length is part of the signature, S3 rejects uploads of other sizes
+        let presigned_request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(attachment_key(attachment_id))
+            .content_type("application/octet-stream")
+            .content_length(size as i64)
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(|err| {
+                AttachmentError::Storage(format!("Failed to generate presigned URL: {}", err))
+            })?;
+
+        Ok(presigned_request.uri().to_string())
+    }
+
+    async fn generate_download_url(
+        &self,
+        attachment_id: Uuid,
+        expires_in: Duration,
+    ) -> Result<String, AttachmentError> {
+        let presigned_request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(attachment_key(attachment_id))
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(|err| {
+                AttachmentError::Storage(format!("Failed to generate presigned URL: {}", err))
+            })?;
+
+        Ok(presigned_request.uri().to_string())
+    }
+
+    async fn attachment_exists(&self, attachment_id: Uuid) -> Result<bool, AttachmentError> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(attachment_key(attachment_id))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|service_err| service_err.is_not_found()) =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(AttachmentError::Storage(format!(
+                "Failed to check attachment on S3: {}",
+                err
+            ))),
+        }
+    }
+
+    async fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), AttachmentError> {
+        // S3 treats deleting a missing object as success
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(attachment_key(attachment_id))
+            .send()
+            .await
+            .map_err(|err| {
+                AttachmentError::Storage(format!("Failed to delete attachment on S3: {}", err))
+            })?;
+
+        Ok(())
+    }
+}
@@ -1,15 +1,32 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use prism_client::{Signature, VerifyingKey};
-use sqlx::{Acquire, Row, SqlitePool};
+use sqlx::{Acquire, Row, SqliteConnection, SqlitePool, sqlite::SqliteRow};
 use uuid::Uuid;
 
 use crate::account::database::{AccountDatabase, AccountDatabaseError};
 use crate::account::entities::Account;
+use crate::attachments::database::AttachmentDatabase;
+use crate::attachments::entities::Attachment;
+use crate::attachments::error::AttachmentError;
+use crate::blocks::database::BlockDatabase;
+use crate::blocks::entities::BlockedAccount;
+use crate::blocks::error::BlockError;
 use crate::crypto::salted_hash::SaltedHash;
+use crate::groups::database::GroupDatabase;
+use crate::groups::entities::{Group, GroupMember, GroupRole};
+use crate::groups::error::GroupError;
 use crate::keys::database::KeyDatabase;
-use crate::keys::entities::{KeyBundle, Prekey};
+use crate::keys::entities::{KemPrekey, KeyBundle, Prekey, PreviousSignedPrekey, SignedPrekey};
 use crate::keys::error::KeyError;
+use crate::messages::database::MessageDatabase;
+use crate::messages::entities::{
+    DeliveredMessage, Message, MessageCursor, MessageReceipt, QueueUsage, Receipt, ReceiptKind,
+    SentMessage,
+};
+use crate::messages::error::MessagingError;
+use crate::messages::requests::database::MessageRequestDatabase;
+use crate::messages::scheduled::database::ScheduledMessageDatabase;
 use crate::profiles::database::ProfileDatabase;
 use crate::profiles::entities::Profile;
 use crate::profiles::error::ProfileError;
@@ -45,6 +62,7 @@ impl SqliteDatabase {
             CREATE TABLE IF NOT EXISTS key_bundles (
                 account_id BLOB PRIMARY KEY,
                 identity_key BLOB NOT NULL,
+                signed_prekey_id INTEGER NOT NULL DEFAULT 0,
                 signed_prekey BLOB NOT NULL,
                 signed_prekey_signature BLOB NOT NULL
             )
@@ -53,6 +71,13 @@ impl SqliteDatabase {
         .execute(&self.pool)
         .await?;
 
+        self.add_column_if_missing(
+            "key_bundles",
+            "signed_prekey_id",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+
         // Create prekeys table
         sqlx::query(
             r#"
@@ -68,6 +93,55 @@ impl SqliteDatabase {
         .execute(&self.pool)
         .await?;
 
+        // Create last_resort_kem_prekeys table, the KEM prekey used once
+        // no one-time KEM prekey is left
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS last_resort_kem_prekeys (
+                account_id BLOB PRIMARY KEY,
+                key_id INTEGER NOT NULL,
+                key BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                FOREIGN KEY (account_id) REFERENCES key_bundles(account_id) ON DELETE CASCADE
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create kem_prekeys table, one-time KEM prekeys
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS kem_prekeys (
+                account_id BLOB NOT NULL,
+                key_id INTEGER NOT NULL,
+                key BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                PRIMARY KEY (account_id, key_id),
+                FOREIGN KEY (account_id) REFERENCES key_bundles(account_id) ON DELETE CASCADE
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create previous_signed_prekeys table, signed prekeys rotated out
+        // but kept for a grace period
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS previous_signed_prekeys (
+                account_id BLOB NOT NULL,
+                key_id INTEGER NOT NULL,
+                key BLOB NOT NULL,
+                replaced_at INTEGER NOT NULL,
+                PRIMARY KEY (account_id, key_id),
+                FOREIGN KEY (account_id) REFERENCES key_bundles(account_id) ON DELETE CASCADE
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
         // Create profiles table
         sqlx::query(
             r#"
@@ -77,6 +151,7 @@ impl SqliteDatabase {
                 username TEXT NOT NULL UNIQUE,
                 display_name TEXT,
                 profile_picture_url TEXT,
+                delivery_access_key BLOB,
                 updated_at INTEGER NOT NULL,
                 FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
             )
@@ -85,6 +160,334 @@ impl SqliteDatabase {
         .execute(&self.pool)
         .await?;
 
+        self.add_column_if_missing("profiles", "delivery_access_key", "BLOB")
+            .await?;
+
+        // Create messages table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS messages (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT,
+                recipient_id TEXT NOT NULL,
+                group_id TEXT,
+                message BLOB NOT NULL,
+                ciphertext_size INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_messages_recipient_id
+            ON messages (recipient_id, timestamp)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_messages_expires_at
+            ON messages (expires_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create sent messages table, used to deduplicate retried sends
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS sent_messages (
+                sender_id TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                dedup_until INTEGER NOT NULL,
+                PRIMARY KEY (sender_id, idempotency_key)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_sent_messages_dedup_until
+            ON sent_messages (dedup_until)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create message request tables
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS accepted_senders (
+                account_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                accepted_at INTEGER NOT NULL,
+                PRIMARY KEY (account_id, sender_id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS request_messages (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                recipient_id TEXT NOT NULL,
+                group_id TEXT,
+                message BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_request_messages_recipient_id
+            ON request_messages (recipient_id, sender_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_request_messages_expires_at
+            ON request_messages (expires_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create scheduled messages table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS scheduled_messages (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                recipient_id TEXT NOT NULL,
+                group_id TEXT,
+                message BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_scheduled_messages_timestamp
+            ON scheduled_messages (timestamp)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create receipts table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS receipts (
+                receipt_id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                recipient_id TEXT NOT NULL,
+                timestamp INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_receipts_sender_id
+            ON receipts (sender_id, timestamp)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create handed out messages table, messages in it can no longer be retracted
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS handed_out_messages (
+                message_id TEXT PRIMARY KEY,
+                until INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_handed_out_messages_until
+            ON handed_out_messages (until)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create delivered messages table, used to relay read receipts
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS delivered_messages (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                recipient_id TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_delivered_messages_expires_at
+            ON delivered_messages (expires_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create groups tables
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS groups (
+                id TEXT PRIMARY KEY,
+                created_by TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS group_members (
+                group_id TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                role TEXT NOT NULL,
+                joined_at INTEGER NOT NULL,
+                PRIMARY KEY (group_id, account_id),
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_group_members_account_id
+            ON group_members (account_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create attachments table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS attachments (
+                id TEXT PRIMARY KEY,
+                owner_id TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                uploaded INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_attachments_owner_id
+            ON attachments (owner_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_attachments_expires_at
+            ON attachments (expires_at)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create blocks table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS blocks (
+                account_id TEXT NOT NULL,
+                blocked_id TEXT NOT NULL,
+                blocked_at INTEGER NOT NULL,
+                PRIMARY KEY (account_id, blocked_id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_blocks_blocked_id
+            ON blocks (blocked_id)
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Adds a column to a table created before the column existed, which
+    /// `CREATE TABLE IF NOT EXISTS` leaves untouched
+    async fn add_column_if_missing(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<(), sqlx::Error> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+                .bind(table)
+                .bind(column)
+                .fetch_one(&self.pool)
+                .await?;
+
+        if count == 0 {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, definition
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
         Ok(())
     }
 }
@@ -220,12 +623,13 @@ impl KeyDatabase for SqliteDatabase {
         // Insert the new key bundle
         sqlx::query(
             r#"
-            INSERT INTO key_bundles (account_id, identity_key, signed_prekey, signed_prekey_signature)
-            VALUES (?, ?, ?, ?)
+            INSERT INTO key_bundles (account_id, identity_key, signed_prekey_id, signed_prekey, signed_prekey_signature)
+            VALUES (?, ?, ?, ?, ?)
             "#,
         )
         .bind(account_id)
         .bind(identity_key_bytes)
+        .bind(key_bundle.signed_prekey_id as i64)
         .bind(signed_prekey_bytes)
         .bind(signature_bytes)
         .execute(&mut *tx)
@@ -248,6 +652,37 @@ impl KeyDatabase for SqliteDatabase {
             .await?;
         }
 
+        // Insert the KEM prekeys
+        if let Some(kem_prekey) = &key_bundle.last_resort_kem_prekey {
+            sqlx::query(
+                r#"
+                INSERT INTO last_resort_kem_prekeys (account_id, key_id, key, signature)
+                VALUES (?, ?, ?, ?)
+                "#,
+            )
+            .bind(account_id)
+            .bind(kem_prekey.key_id as i64)
+            .bind(&kem_prekey.key)
+            .bind(kem_prekey.signature.to_prism_der()?)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        for kem_prekey in &key_bundle.kem_prekeys {
+            sqlx::query(
+                r#"
+                INSERT INTO kem_prekeys (account_id, key_id, key, signature)
+                VALUES (?, ?, ?, ?)
+                "#,
+            )
+            .bind(account_id)
+            .bind(kem_prekey.key_id as i64)
+            .bind(&kem_prekey.key)
+            .bind(kem_prekey.signature.to_prism_der()?)
+            .execute(&mut *tx)
+            .await?;
+        }
+
         // Commit the transaction
         tx.commit()
             .await
@@ -257,64 +692,8 @@ impl KeyDatabase for SqliteDatabase {
     }
 
     async fn get_keybundle(&self, account_id: Uuid) -> Result<Option<KeyBundle>, KeyError> {
-        // First, check if the key bundle exists
-        let key_bundle_row = sqlx::query(
-            r#"
-            SELECT identity_key, signed_prekey, signed_prekey_signature
-            FROM key_bundles
-            WHERE account_id = ?
-            "#,
-        )
-        .bind(account_id)
-        .fetch_optional(&self.pool)
-        .await?;
-
-        if let Some(row) = key_bundle_row {
-            // Get the identity key
-            let identity_key_bytes: Vec<u8> = row.get("identity_key");
-            let identity_key = VerifyingKey::from_spki_der(&identity_key_bytes)?;
-
-            // Get the signed prekey
-            let signed_prekey_bytes: Vec<u8> = row.get("signed_prekey");
-            let signed_prekey = VerifyingKey::from_spki_der(&signed_prekey_bytes)?;
-
-            // Get the signature
-            let signature_bytes: Vec<u8> = row.get("signed_prekey_signature");
-            let signed_prekey_signature = Signature::from_prism_der(&signature_bytes)?;
-
-            // Get the prekeys
-            let prekeys_rows = sqlx::query(
-                r#"
-                SELECT key_idx, key
-                FROM prekeys
-                WHERE account_id = ?
-                "#,
-            )
-            .bind(account_id)
-            .fetch_all(&self.pool)
-            .await?;
-
-            let mut prekeys = Vec::new();
-            for row in prekeys_rows {
-                let key_idx: i64 = row.get("key_idx");
-                let key_bytes: Vec<u8> = row.get("key");
-                let key = VerifyingKey::from_spki_der(&key_bytes)?;
-
-                prekeys.push(Prekey {
-                    key_idx: key_idx as u64,
-                    key,
-                });
-            }
-
-            Ok(Some(KeyBundle {
-                identity_key,
-                signed_prekey,
-                signed_prekey_signature,
-                prekeys,
-            }))
-        } else {
-            Ok(None)
-        }
+        let mut conn = self.pool.acquire().await?;
+        read_keybundle(&mut conn, account_id).await
     }
 
     async fn add_prekeys(&self, account_id: Uuid, prekeys: Vec<Prekey>) -> Result<(), KeyError> {
@@ -355,354 +734,3059 @@ impl KeyDatabase for SqliteDatabase {
         tx.commit().await?;
         Ok(())
     }
-}
 
-impl From<sqlx::Error> for KeyError {
-    fn from(e: sqlx::Error) -> Self {
-        KeyError::DatabaseError(e.to_string())
-    }
-}
+    async fn take_prekey(&self, account_id: Uuid) -> Result<Option<KeyBundle>, KeyError> {
+        let mut tx = self.pool.begin().await?;
 
-#[async_trait]
-impl ProfileDatabase for SqliteDatabase {
-    async fn get_profile_by_id(&self, id: Uuid) -> Result<Option<Profile>, ProfileError> {
+        let Some(mut key_bundle) = read_keybundle(&mut tx, account_id).await? else {
+            return Ok(None);
+        };
+
+        // Removing a prekey is a single statement,
+        // so concurrent fetches can't take the same one
         let row = sqlx::query(
             r#"
-            SELECT id, account_id, username, display_name, profile_picture_url, updated_at
-            FROM profiles
-            WHERE id = ?
+            DELETE FROM prekeys
+            WHERE account_id = ?
+              AND key_idx = (SELECT MIN(key_idx) FROM prekeys WHERE account_id = ?)
+            RETURNING key_idx, key
             "#,
         )
-        .bind(id.to_string())
-        .fetch_optional(&self.pool)
+        .bind(account_id)
+        .bind(account_id)
+        .fetch_optional(&mut *tx)
         .await?;
 
-        match row {
-            Some(row) => {
-                let id_str: String = row.try_get("id")?;
-                let id =
-                    Uuid::parse_str(&id_str).map_err(|e| ProfileError::Internal(e.to_string()))?;
+        key_bundle.prekeys = Vec::new();
+        if let Some(row) = row {
+            let key_idx: i64 = row.get("key_idx");
+            let key_bytes: Vec<u8> = row.get("key");
+            key_bundle.prekeys.push(Prekey {
+                key_idx: key_idx as u64,
+                key: VerifyingKey::from_spki_der(&key_bytes)?,
+            });
+        }
 
-                let account_id_str: String = row.try_get("account_id")?;
-                let account_id = Uuid::parse_str(&account_id_str)
-                    .map_err(|e| ProfileError::Internal(e.to_string()))?;
+        let row = sqlx::query(
+            r#"
+            DELETE FROM kem_prekeys
+            WHERE account_id = ?
+              AND key_id = (SELECT MIN(key_id) FROM kem_prekeys WHERE account_id = ?)
+            RETURNING key_id, key, signature
+            "#,
+        )
+        .bind(account_id)
+        .bind(account_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let kem_prekey = row.as_ref().map(kem_prekey_from_row).transpose()?;
+        key_bundle.kem_prekeys = kem_prekey.into_iter().collect();
 
-                Ok(Some(Profile {
-                    id,
-                    account_id,
-                    username: row.try_get("username")?,
-                    display_name: row.try_get("display_name")?,
-                    profile_picture_url: row.try_get("profile_picture_url")?,
-                    updated_at: row.try_get::<i64, _>("updated_at")? as u64,
-                }))
-            }
-            None => Ok(None),
+        tx.commit().await?;
+        Ok(Some(key_bundle))
+    }
+
+    async fn add_kem_prekeys(
+        &self,
+        account_id: Uuid,
+        kem_prekeys: Vec<KemPrekey>,
+    ) -> Result<(), KeyError> {
+        let mut tx = self.pool.begin().await?;
+
+        let exists = sqlx::query("SELECT COUNT(*) as count FROM key_bundles WHERE account_id = ?")
+            .bind(account_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let count: i64 = exists.get("count");
+        if count == 0 {
+            return Err(KeyError::NotFound(account_id.to_string()));
+        }
+
+        for kem_prekey in &kem_prekeys {
+            sqlx::query(
+                r#"
+                INSERT INTO kem_prekeys (account_id, key_id, key, signature)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT(account_id, key_id) DO UPDATE SET
+                    key = excluded.key,
+                    signature = excluded.signature
+                "#,
+            )
+            .bind(account_id)
+            .bind(kem_prekey.key_id as i64)
+            .bind(&kem_prekey.key)
+            .bind(kem_prekey.signature.to_prism_der()?)
+            .execute(&mut *tx)
+            .await?;
         }
+
+        tx.commit().await?;
+        Ok(())
     }
 
-    async fn get_profile_by_account_id(
+    async fn count_prekeys(&self, account_id: Uuid) -> Result<usize, KeyError> {
+        let row = sqlx::query("SELECT COUNT(*) as count FROM prekeys WHERE account_id = ?")
+            .bind(account_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let count: i64 = row.get("count");
+        Ok(count as usize)
+    }
+
+    async fn rotate_signed_prekey(
         &self,
         account_id: Uuid,
-    ) -> Result<Option<Profile>, ProfileError> {
-        let row = sqlx::query(
+        signed_prekey: SignedPrekey,
+        replaced_at: u64,
+    ) -> Result<(), KeyError> {
+        let mut tx = self.pool.begin().await?;
+
+        let current = sqlx::query(
+            "SELECT signed_prekey_id, signed_prekey FROM key_bundles WHERE account_id = ?",
+        )
+        .bind(account_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+
+        let current_id: i64 = current.get("signed_prekey_id");
+        let current_key: Vec<u8> = current.get("signed_prekey");
+        sqlx::query(
             r#"
-            SELECT id, account_id, username, display_name, profile_picture_url, updated_at
-            FROM profiles
+            INSERT INTO previous_signed_prekeys (account_id, key_id, key, replaced_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(account_id, key_id) DO UPDATE SET
+                key = excluded.key,
+                replaced_at = excluded.replaced_at
+            "#,
+        )
+        .bind(account_id)
+        .bind(current_id)
+        .bind(current_key)
+        .bind(replaced_at as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE key_bundles
+            SET signed_prekey_id = ?, signed_prekey = ?, signed_prekey_signature = ?
             WHERE account_id = ?
             "#,
         )
-        .bind(account_id.to_string())
-        .fetch_optional(&self.pool)
+        .bind(signed_prekey.key_id as i64)
+        .bind(signed_prekey.key.to_spki_der()?)
+        .bind(signed_prekey.signature.to_prism_der()?)
+        .bind(account_id)
+        .execute(&mut *tx)
         .await?;
 
-        match row {
-            Some(row) => {
-                let id_str: String = row.try_get("id")?;
-                let id =
-                    Uuid::parse_str(&id_str).map_err(|e| ProfileError::Internal(e.to_string()))?;
+        tx.commit().await?;
+        Ok(())
+    }
 
-                let account_id_str: String = row.try_get("account_id")?;
-                let account_id = Uuid::parse_str(&account_id_str)
-                    .map_err(|e| ProfileError::Internal(e.to_string()))?;
+    async fn get_previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<PreviousSignedPrekey>, KeyError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT key_id, key, replaced_at
+            FROM previous_signed_prekeys
+            WHERE account_id = ?
+            ORDER BY replaced_at, key_id
+            "#,
+        )
+        .bind(account_id)
+        .fetch_all(&self.pool)
+        .await?;
 
-                Ok(Some(Profile {
-                    id,
-                    account_id,
-                    username: row.try_get("username")?,
-                    display_name: row.try_get("display_name")?,
-                    profile_picture_url: row.try_get("profile_picture_url")?,
-                    updated_at: row.try_get::<i64, _>("updated_at")? as u64,
-                }))
-            }
-            None => Ok(None),
+        let mut previous_signed_prekeys = Vec::new();
+        for row in rows {
+            let key_id: i64 = row.get("key_id");
+            let key_bytes: Vec<u8> = row.get("key");
+            let replaced_at: i64 = row.get("replaced_at");
+
+            previous_signed_prekeys.push(PreviousSignedPrekey {
+                key_id: key_id as u64,
+                key: VerifyingKey::from_spki_der(&key_bytes)?,
+                replaced_at: replaced_at as u64,
+            });
         }
+        Ok(previous_signed_prekeys)
     }
 
-    async fn upsert_profile(&self, profile: Profile) -> Result<(), ProfileError> {
+    async fn remove_previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+        replaced_before: u64,
+    ) -> Result<(), KeyError> {
         sqlx::query(
+            "DELETE FROM previous_signed_prekeys WHERE account_id = ? AND replaced_at <= ?",
+        )
+        .bind(account_id)
+        .bind(replaced_before as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Reads the key bundle with all of its prekeys
+async fn read_keybundle(
+    conn: &mut SqliteConnection,
+    account_id: Uuid,
+) -> Result<Option<KeyBundle>, KeyError> {
+    // First, check if the key bundle exists
+    let key_bundle_row = sqlx::query(
+        r#"
+        SELECT identity_key, signed_prekey_id, signed_prekey, signed_prekey_signature
+        FROM key_bundles
+        WHERE account_id = ?
+        "#,
+    )
+    .bind(account_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if let Some(row) = key_bundle_row {
+        // Get the identity key
+        let identity_key_bytes: Vec<u8> = row.get("identity_key");
+        let identity_key = VerifyingKey::from_spki_der(&identity_key_bytes)?;
+
+        // Get the signed prekey
+        let signed_prekey_id: i64 = row.get("signed_prekey_id");
+        let signed_prekey_bytes: Vec<u8> = row.get("signed_prekey");
+        let signed_prekey = VerifyingKey::from_spki_der(&signed_prekey_bytes)?;
+
+        // Get the signature
+        let signature_bytes: Vec<u8> = row.get("signed_prekey_signature");
+        let signed_prekey_signature = Signature::from_prism_der(&signature_bytes)?;
+
+        // Get the prekeys
+        let prekeys_rows = sqlx::query(
             r#"
-            INSERT INTO profiles (id, account_id, username, display_name, profile_picture_url, updated_at)
-            VALUES (?, ?, ?, ?, ?, ?)
-            ON CONFLICT(id) DO UPDATE SET
-                account_id = excluded.account_id,
-                username = excluded.username,
-                display_name = excluded.display_name,
-                profile_picture_url = excluded.profile_picture_url,
-                updated_at = excluded.updated_at
+            SELECT key_idx, key
+            FROM prekeys
+            WHERE account_id = ?
             "#,
         )
-        .bind(profile.id.to_string())
-        .bind(profile.account_id.to_string())
-        .bind(&profile.username)
-        .bind(&profile.display_name)
-        .bind(&profile.profile_picture_url)
-        .bind(profile.updated_at as i64)
+        .bind(account_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut prekeys = Vec::new();
+        for row in prekeys_rows {
+            let key_idx: i64 = row.get("key_idx");
+            let key_bytes: Vec<u8> = row.get("key");
+            let key = VerifyingKey::from_spki_der(&key_bytes)?;
+
+            prekeys.push(Prekey {
+                key_idx: key_idx as u64,
+                key,
+            });
+        }
+
+        // Get the KEM prekeys
+        let last_resort_kem_prekey = sqlx::query(
+            "SELECT key_id, key, signature FROM last_resort_kem_prekeys WHERE account_id = ?",
+        )
+        .bind(account_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .as_ref()
+        .map(kem_prekey_from_row)
+        .transpose()?;
+
+        let kem_prekeys = sqlx::query(
+            r#"
+            SELECT key_id, key, signature
+            FROM kem_prekeys
+            WHERE account_id = ?
+            ORDER BY key_id
+            "#,
+        )
+        .bind(account_id)
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(kem_prekey_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(KeyBundle {
+            identity_key,
+            signed_prekey_id: signed_prekey_id as u64,
+            signed_prekey,
+            signed_prekey_signature,
+            prekeys,
+            last_resort_kem_prekey,
+            kem_prekeys,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+fn kem_prekey_from_row(row: &SqliteRow) -> Result<KemPrekey, KeyError> {
+    let key_id: i64 = row.try_get("key_id")?;
+    let signature_bytes: Vec<u8> = row.try_get("signature")?;
+
+    Ok(KemPrekey {
+        key_id: key_id as u64,
+        key: row.try_get("key")?,
+        signature: Signature::from_prism_der(&signature_bytes)?,
+    })
+}
+
+impl From<sqlx::Error> for KeyError {
+    fn from(e: sqlx::Error) -> Self {
+        KeyError::DatabaseError(e.to_string())
+    }
+}
+
+// MESSAGES
+
+impl From<sqlx::Error> for MessagingError {
+    fn from(e: sqlx::Error) -> Self {
+        MessagingError::DatabaseError(e.to_string())
+    }
+}
+
+fn message_from_row(row: &SqliteRow) -> Result<Message, MessagingError> {
+    let message_id: String = row.try_get("message_id")?;
+    let sender_id: Option<String> = row.try_get("sender_id")?;
+    let recipient_id: String = row.try_get("recipient_id")?;
+    let group_id: Option<String> = row.try_get("group_id")?;
+    let message_bytes: Vec<u8> = row.try_get("message")?;
+    let timestamp: i64 = row.try_get("timestamp")?;
+    let expires_at: i64 = row.try_get("expires_at")?;
+
+    let message = serde_json::from_slice(&message_bytes)
+        .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+    Ok(Message {
+        message_id: Uuid::parse_str(&message_id)?,
+        sender_id: sender_id.as_deref().map(Uuid::parse_str).transpose()?,
+        recipient_id: Uuid::parse_str(&recipient_id)?,
+        group_id: group_id.as_deref().map(Uuid::parse_str).transpose()?,
+        message,
+        timestamp: timestamp as u64,
+        expires_at: expires_at as u64,
+    })
+}
+
+fn receipt_from_row(row: &SqliteRow) -> Result<Receipt, MessagingError> {
+    let receipt_id: String = row.try_get("receipt_id")?;
+    let kind: String = row.try_get("kind")?;
+    let message_id: String = row.try_get("message_id")?;
+    let sender_id: String = row.try_get("sender_id")?;
+    let recipient_id: String = row.try_get("recipient_id")?;
+    let timestamp: i64 = row.try_get("timestamp")?;
+
+    let kind = match kind.as_str() {
+        "delivered" => ReceiptKind::Delivered,
+        "read" => ReceiptKind::Read,
+        _ => {
+            return Err(MessagingError::ParseError(format!(
+                "Unknown receipt kind: {}",
+                kind
+            )));
+        }
+    };
+
+    Ok(Receipt {
+        receipt_id: Uuid::parse_str(&receipt_id)?,
+        kind,
+        message_id: Uuid::parse_str(&message_id)?,
+        sender_id: Uuid::parse_str(&sender_id)?,
+        recipient_id: Uuid::parse_str(&recipient_id)?,
+        timestamp: timestamp as u64,
+    })
+}
+
+fn sent_message_from_row(row: &SqliteRow) -> Result<SentMessage, MessagingError> {
+    let sender_id: String = row.try_get("sender_id")?;
+    let idempotency_key: String = row.try_get("idempotency_key")?;
+    let message_id: String = row.try_get("message_id")?;
+    let timestamp: i64 = row.try_get("timestamp")?;
+    let expires_at: i64 = row.try_get("expires_at")?;
+    let dedup_until: i64 = row.try_get("dedup_until")?;
+
+    Ok(SentMessage {
+        sender_id: Uuid::parse_str(&sender_id)?,
+        idempotency_key: Uuid::parse_str(&idempotency_key)?,
+        receipt: MessageReceipt {
+            message_id: Uuid::parse_str(&message_id)?,
+            timestamp: timestamp as u64,
+            expires_at: expires_at as u64,
+        },
+        dedup_until: dedup_until as u64,
+    })
+}
+
+#[async_trait]
+impl MessageDatabase for SqliteDatabase {
+    async fn insert_message(&self, message: Message) -> Result<(), MessagingError> {
+        // The double ratchet message is opaque to the server, store it serialized
+        let message_bytes = serde_json::to_vec(&message.message)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO messages (
+                message_id, sender_id, recipient_id, group_id, message, ciphertext_size, timestamp,
+                expires_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(message.message_id.to_string())
+        .bind(message.sender_id.map(|id| id.to_string()))
+        .bind(message.recipient_id.to_string())
+        .bind(message.group_id.map(|id| id.to_string()))
+        .bind(message_bytes)
+        .bind(message.message.ciphertext.len() as i64)
+        .bind(message.timestamp as i64)
+        .bind(message.expires_at as i64)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    async fn get_profile_by_username(
+    async fn get_all_messages(&self) -> Result<Vec<Message>, MessagingError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            FROM messages
+            ORDER BY timestamp, rowid
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn get_messages_for_account(
         &self,
-        username: &str,
-    ) -> Result<Option<Profile>, ProfileError> {
-        let row = sqlx::query(
+        account_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let rows = sqlx::query(
             r#"
-            SELECT id, account_id, username, display_name, profile_picture_url, updated_at
-            FROM profiles
-            WHERE username = ?
+            SELECT message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            FROM messages
+            WHERE recipient_id = ?
+            ORDER BY timestamp, rowid
             "#,
         )
-        .bind(username)
-        .fetch_optional(&self.pool)
+        .bind(account_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn get_messages_page_for_account(
+        &self,
+        account_id: Uuid,
+        after: Option<MessageCursor>,
+        limit: usize,
+    ) -> Result<Vec<Message>, MessagingError> {
+        // Without a cursor, start before the first possible message
+        let (after_timestamp, after_message_id) = match after {
+            Some(cursor) => (cursor.timestamp as i64, cursor.message_id.to_string()),
+            None => (-1, String::new()),
+        };
+
+        // Hyphenated UUID strings sort the same way as the UUIDs themselves
+        let rows = sqlx::query(
+            r#"
+            SELECT message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            FROM messages
+            WHERE recipient_id = ?
+              AND (timestamp > ? OR (timestamp = ? AND message_id > ?))
+            ORDER BY timestamp, message_id
+            LIMIT ?
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(after_timestamp)
+        .bind(after_timestamp)
+        .bind(after_message_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
         .await?;
 
-        match row {
-            Some(row) => {
-                let id_str: String = row.try_get("id")?;
-                let id =
-                    Uuid::parse_str(&id_str).map_err(|e| ProfileError::Internal(e.to_string()))?;
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn remove_messages(
+        &self,
+        account_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let mut tx = self.pool.begin().await?;
+
+        for id in ids {
+            sqlx::query("DELETE FROM messages WHERE recipient_id = ? AND message_id = ?")
+                .bind(account_id.to_string())
+                .bind(id.to_string())
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError> {
+        let row = sqlx::query(
+            r#"
+            DELETE FROM messages
+            WHERE message_id = ? AND sender_id = ?
+            AND message_id NOT IN (SELECT message_id FROM handed_out_messages)
+            RETURNING message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            "#,
+        )
+        .bind(message_id.to_string())
+        .bind(sender_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(message_from_row).transpose()
+    }
+
+    async fn remove_expired_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError> {
+        let rows = sqlx::query(
+            r#"
+            DELETE FROM messages
+            WHERE expires_at <= ?
+            RETURNING message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            "#,
+        )
+        .bind(now as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn get_queue_usage(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Option<Uuid>,
+    ) -> Result<QueueUsage, MessagingError> {
+        let row = sqlx::query(
+            r#"
+            SELECT
+                COUNT(*) AS recipient_messages,
+                COALESCE(SUM(ciphertext_size), 0) AS recipient_bytes,
+                COALESCE(SUM(sender_id = ?), 0) AS sender_messages
+            FROM messages
+            WHERE recipient_id = ?
+            "#,
+        )
+        // Comparing with NULL never matches, so sealed senders count as no one
+        .bind(sender_id.map(|id| id.to_string()))
+        .bind(recipient_id.to_string())
+        .fetch_one(&self.pool)
+        .await?;
+
+        let recipient_messages: i64 = row.try_get("recipient_messages")?;
+        let recipient_bytes: i64 = row.try_get("recipient_bytes")?;
+        let sender_messages: i64 = row.try_get("sender_messages")?;
+
+        Ok(QueueUsage {
+            recipient_messages: recipient_messages as usize,
+            recipient_bytes: recipient_bytes as u64,
+            sender_messages: sender_messages as usize,
+        })
+    }
+
+    async fn insert_sent_message(&self, sent_message: SentMessage) -> Result<bool, MessagingError> {
+        let mut tx = self.pool.begin().await?;
+
+        // A stale entry of the same key may remain until the next sweep
+        sqlx::query(
+            r#"
+            DELETE FROM sent_messages
+            WHERE sender_id = ? AND idempotency_key = ? AND dedup_until <= ?
+            "#,
+        )
+        .bind(sent_message.sender_id.to_string())
+        .bind(sent_message.idempotency_key.to_string())
+        .bind(sent_message.receipt.timestamp as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO sent_messages
+                (sender_id, idempotency_key, message_id, timestamp, expires_at, dedup_until)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(sender_id, idempotency_key) DO NOTHING
+            "#,
+        )
+        .bind(sent_message.sender_id.to_string())
+        .bind(sent_message.idempotency_key.to_string())
+        .bind(sent_message.receipt.message_id.to_string())
+        .bind(sent_message.receipt.timestamp as i64)
+        .bind(sent_message.receipt.expires_at as i64)
+        .bind(sent_message.dedup_until as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<Option<SentMessage>, MessagingError> {
+        let row = sqlx::query(
+            r#"
+            SELECT sender_id, idempotency_key, message_id, timestamp, expires_at, dedup_until
+            FROM sent_messages
+            WHERE sender_id = ? AND idempotency_key = ?
+            "#,
+        )
+        .bind(sender_id.to_string())
+        .bind(idempotency_key.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(sent_message_from_row).transpose()
+    }
+
+    async fn remove_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<(), MessagingError> {
+        sqlx::query("DELETE FROM sent_messages WHERE sender_id = ? AND idempotency_key = ?")
+            .bind(sender_id.to_string())
+            .bind(idempotency_key.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_expired_sent_messages(&self, now: u64) -> Result<(), MessagingError> {
+        sqlx::query("DELETE FROM sent_messages WHERE dedup_until <= ?")
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_receipt(&self, receipt: Receipt) -> Result<(), MessagingError> {
+        let kind = match receipt.kind {
+            ReceiptKind::Delivered => "delivered",
+            ReceiptKind::Read => "read",
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO receipts (receipt_id, kind, message_id, sender_id, recipient_id, timestamp)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(receipt.receipt_id.to_string())
+        .bind(kind)
+        .bind(receipt.message_id.to_string())
+        .bind(receipt.sender_id.to_string())
+        .bind(receipt.recipient_id.to_string())
+        .bind(receipt.timestamp as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_receipts_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Receipt>, MessagingError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT receipt_id, kind, message_id, sender_id, recipient_id, timestamp
+            FROM receipts
+            WHERE sender_id = ?
+            ORDER BY timestamp, rowid
+            "#,
+        )
+        .bind(account_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(receipt_from_row).collect()
+    }
+
+    async fn remove_receipts(
+        &self,
+        account_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let mut tx = self.pool.begin().await?;
+
+        for id in ids {
+            sqlx::query("DELETE FROM receipts WHERE sender_id = ? AND receipt_id = ?")
+                .bind(account_id.to_string())
+                .bind(id.to_string())
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_handed_out_messages(
+        &self,
+        ids: Vec<Uuid>,
+        until: u64,
+    ) -> Result<(), MessagingError> {
+        let mut tx = self.pool.begin().await?;
+
+        for id in ids {
+            sqlx::query(
+                r#"
+                INSERT INTO handed_out_messages (message_id, until)
+                VALUES (?, ?)
+                ON CONFLICT(message_id) DO NOTHING
+                "#,
+            )
+            .bind(id.to_string())
+            .bind(until as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn is_handed_out(&self, message_id: Uuid) -> Result<bool, MessagingError> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM handed_out_messages WHERE message_id = ?")
+                .bind(message_id.to_string())
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count > 0)
+    }
+
+    async fn remove_expired_handed_out_messages(&self, now: u64) -> Result<(), MessagingError> {
+        sqlx::query("DELETE FROM handed_out_messages WHERE until <= ?")
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_delivered_message(
+        &self,
+        delivered_message: DeliveredMessage,
+    ) -> Result<(), MessagingError> {
+        sqlx::query(
+            r#"
+            INSERT INTO delivered_messages (message_id, sender_id, recipient_id, expires_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(message_id) DO NOTHING
+            "#,
+        )
+        .bind(delivered_message.message_id.to_string())
+        .bind(delivered_message.sender_id.to_string())
+        .bind(delivered_message.recipient_id.to_string())
+        .bind(delivered_message.expires_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_delivered_messages(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<Vec<DeliveredMessage>, MessagingError> {
+        let mut tx = self.pool.begin().await?;
+
+        let mut removed = Vec::new();
+        for id in ids {
+            let row = sqlx::query(
+                r#"
+                DELETE FROM delivered_messages
+                WHERE message_id = ? AND sender_id = ? AND recipient_id = ?
+                RETURNING expires_at
+                "#,
+            )
+            .bind(id.to_string())
+            .bind(sender_id.to_string())
+            .bind(recipient_id.to_string())
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            if let Some(row) = row {
+                let expires_at: i64 = row.try_get("expires_at")?;
+                removed.push(DeliveredMessage {
+                    message_id: id,
+                    sender_id,
+                    recipient_id,
+                    expires_at: expires_at as u64,
+                });
+            }
+        }
+
+        tx.commit().await?;
+        Ok(removed)
+    }
+
+    async fn remove_expired_delivered_messages(&self, now: u64) -> Result<(), MessagingError> {
+        sqlx::query("DELETE FROM delivered_messages WHERE expires_at <= ?")
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageRequestDatabase for SqliteDatabase {
+    async fn insert_accepted_sender(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        sqlx::query(
+            r#"
+            INSERT INTO accepted_senders (account_id, sender_id, accepted_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(account_id, sender_id) DO NOTHING
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(sender_id.to_string())
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn is_accepted_sender(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<bool, MessagingError> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1
+            FROM accepted_senders
+            WHERE account_id = ? AND sender_id = ?
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(sender_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn insert_request_message(&self, message: Message) -> Result<(), MessagingError> {
+        let Some(sender_id) = message.sender_id else {
+            return Err(MessagingError::InvalidMessage(
+                "message requests need a sender".to_string(),
+            ));
+        };
+        let message_bytes = serde_json::to_vec(&message.message)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO request_messages (
+                message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(message.message_id.to_string())
+        .bind(sender_id.to_string())
+        .bind(message.recipient_id.to_string())
+        .bind(message.group_id.map(|id| id.to_string()))
+        .bind(message_bytes)
+        .bind(message.timestamp as i64)
+        .bind(message.expires_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_request_messages(&self, account_id: Uuid) -> Result<Vec<Message>, MessagingError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            FROM request_messages
+            WHERE recipient_id = ?
+            ORDER BY timestamp, rowid
+            "#,
+        )
+        .bind(account_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn count_request_messages(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<usize, MessagingError> {
+        let row = sqlx::query(
+            r#"
+            SELECT COUNT(*) AS count
+            FROM request_messages
+            WHERE recipient_id = ? AND sender_id = ?
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(sender_id.to_string())
+        .fetch_one(&self.pool)
+        .await?;
+
+        let count: i64 = row.try_get("count")?;
+        Ok(count as usize)
+    }
+
+    async fn remove_request_messages(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let rows = sqlx::query(
+            r#"
+            DELETE FROM request_messages
+            WHERE recipient_id = ? AND sender_id = ?
+            RETURNING message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(sender_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        // RETURNING does not guarantee any order
+        let mut messages = rows
+            .iter()
+            .map(message_from_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        messages.sort_by_key(MessageCursor::of);
+        Ok(messages)
+    }
+
+    async fn remove_request_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError> {
+        let row = sqlx::query(
+            r#"
+            DELETE FROM request_messages
+            WHERE message_id = ? AND sender_id = ?
+            RETURNING message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            "#,
+        )
+        .bind(message_id.to_string())
+        .bind(sender_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(message_from_row).transpose()
+    }
+
+    async fn remove_expired_request_messages(&self, now: u64) -> Result<(), MessagingError> {
+        sqlx::query(
+            r#"
+            DELETE FROM request_messages
+            WHERE expires_at <= ?
+            "#,
+        )
+        .bind(now as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ScheduledMessageDatabase for SqliteDatabase {
+    async fn insert_scheduled_message(&self, message: Message) -> Result<(), MessagingError> {
+        let Some(sender_id) = message.sender_id else {
+            return Err(MessagingError::InvalidMessage(
+                "scheduled messages need a sender".to_string(),
+            ));
+        };
+        let message_bytes = serde_json::to_vec(&message.message)
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_messages (
+                message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(message.message_id.to_string())
+        .bind(sender_id.to_string())
+        .bind(message.recipient_id.to_string())
+        .bind(message.group_id.map(|id| id.to_string()))
+        .bind(message_bytes)
+        .bind(message.timestamp as i64)
+        .bind(message.expires_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_due_scheduled_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT message_id, sender_id, recipient_id, group_id, message, timestamp, expires_at
+            FROM scheduled_messages
+            WHERE timestamp <= ?
+            ORDER BY timestamp, rowid
+            "#,
+        )
+        .bind(now as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(message_from_row).collect()
+    }
+
+    async fn remove_scheduled_message(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<bool, MessagingError> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM scheduled_messages
+            WHERE message_id = ? AND sender_id = ?
+            "#,
+        )
+        .bind(message_id.to_string())
+        .bind(sender_id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// ATTACHMENTS
+
+impl From<sqlx::Error> for AttachmentError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err.to_string())
+    }
+}
+
+impl From<uuid::Error> for AttachmentError {
+    fn from(err: uuid::Error) -> Self {
+        Self::Database(err.to_string())
+    }
+}
+
+fn attachment_from_row(row: &SqliteRow) -> Result<Attachment, AttachmentError> {
+    let id: String = row.try_get("id")?;
+    let owner_id: String = row.try_get("owner_id")?;
+    let size: i64 = row.try_get("size")?;
+    let created_at: i64 = row.try_get("created_at")?;
+    let expires_at: i64 = row.try_get("expires_at")?;
+    let uploaded: bool = row.try_get("uploaded")?;
+
+    Ok(Attachment {
+        id: Uuid::parse_str(&id)?,
+        owner_id: Uuid::parse_str(&owner_id)?,
+        size: size as u64,
+        created_at: created_at as u64,
+        expires_at: expires_at as u64,
+        uploaded,
+    })
+}
+
+#[async_trait]
+impl AttachmentDatabase for SqliteDatabase {
+    async fn insert_attachment(
+        &self,
+        attachment: Attachment,
+        max_storage: u64,
+    ) -> Result<(), AttachmentError> {
+        // A single statement, the quota check can't race with concurrent inserts
+        let result = sqlx::query(
+            r#"
+            INSERT INTO attachments (id, owner_id, size, created_at, expires_at, uploaded)
+            SELECT ?, ?, ?, ?, ?, ?
+            WHERE (SELECT COALESCE(SUM(size), 0) FROM attachments WHERE owner_id = ?) + ? <= ?
+            "#,
+        )
+        .bind(attachment.id.to_string())
+        .bind(attachment.owner_id.to_string())
+        .bind(attachment.size as i64)
+        .bind(attachment.created_at as i64)
+        .bind(attachment.expires_at as i64)
+        .bind(attachment.uploaded)
+        .bind(attachment.owner_id.to_string())
+        .bind(attachment.size as i64)
+        .bind(max_storage.min(i64::MAX as u64) as i64)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AttachmentError::QuotaExceeded(max_storage));
+        }
+        Ok(())
+    }
+
+    async fn get_attachment(
+        &self,
+        attachment_id: Uuid,
+    ) -> Result<Option<Attachment>, AttachmentError> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, owner_id, size, created_at, expires_at, uploaded
+            FROM attachments
+            WHERE id = ?
+            "#,
+        )
+        .bind(attachment_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(attachment_from_row).transpose()
+    }
+
+    async fn get_storage_usage(&self, owner_id: Uuid) -> Result<u64, AttachmentError> {
+        let usage: i64 =
+            sqlx::query_scalar("SELECT COALESCE(SUM(size), 0) FROM attachments WHERE owner_id = ?")
+                .bind(owner_id.to_string())
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(usage as u64)
+    }
+
+    async fn get_expired_attachments(&self, now: u64) -> Result<Vec<Attachment>, AttachmentError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, owner_id, size, created_at, expires_at, uploaded
+            FROM attachments
+            WHERE expires_at <= ?
+            "#,
+        )
+        .bind(now as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(attachment_from_row).collect()
+    }
+
+    async fn mark_attachment_uploaded(&self, attachment_id: Uuid) -> Result<(), AttachmentError> {
+        sqlx::query("UPDATE attachments SET uploaded = 1 WHERE id = ?")
+            .bind(attachment_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_attachment(&self, attachment_id: Uuid) -> Result<(), AttachmentError> {
+        sqlx::query("DELETE FROM attachments WHERE id = ?")
+            .bind(attachment_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// BLOCKS
+
+impl From<sqlx::Error> for BlockError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err.to_string())
+    }
+}
+
+impl From<uuid::Error> for BlockError {
+    fn from(err: uuid::Error) -> Self {
+        Self::Database(err.to_string())
+    }
+}
+
+fn blocked_account_from_row(row: &SqliteRow) -> Result<BlockedAccount, BlockError> {
+    let blocked_id: String = row.try_get("blocked_id")?;
+    let blocked_at: i64 = row.try_get("blocked_at")?;
+
+    Ok(BlockedAccount {
+        account_id: Uuid::parse_str(&blocked_id)?,
+        blocked_at: blocked_at as u64,
+    })
+}
+
+#[async_trait]
+impl BlockDatabase for SqliteDatabase {
+    async fn insert_block(
+        &self,
+        account_id: Uuid,
+        blocked: BlockedAccount,
+    ) -> Result<(), BlockError> {
+        sqlx::query(
+            r#"
+            INSERT INTO blocks (account_id, blocked_id, blocked_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(account_id, blocked_id) DO NOTHING
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(blocked.account_id.to_string())
+        .bind(blocked.blocked_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_block(&self, account_id: Uuid, blocked_id: Uuid) -> Result<(), BlockError> {
+        sqlx::query(
+            r#"
+            DELETE FROM blocks
+            WHERE account_id = ? AND blocked_id = ?
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(blocked_id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_blocked_accounts(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<BlockedAccount>, BlockError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT blocked_id, blocked_at
+            FROM blocks
+            WHERE account_id = ?
+            ORDER BY blocked_at, rowid
+            "#,
+        )
+        .bind(account_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(blocked_account_from_row).collect()
+    }
+
+    async fn get_blocking_account_ids(&self, blocked_id: Uuid) -> Result<Vec<Uuid>, BlockError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT account_id
+            FROM blocks
+            WHERE blocked_id = ?
+            "#,
+        )
+        .bind(blocked_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| -> Result<Uuid, BlockError> {
+                let account_id: String = row.try_get("account_id")?;
+                Ok(Uuid::parse_str(&account_id)?)
+            })
+            .collect()
+    }
+
+    async fn is_blocked(&self, account_id: Uuid, blocked_id: Uuid) -> Result<bool, BlockError> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1
+            FROM blocks
+            WHERE account_id = ? AND blocked_id = ?
+            "#,
+        )
+        .bind(account_id.to_string())
+        .bind(blocked_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+}
+
+// GROUPS
+
+impl From<sqlx::Error> for GroupError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err.to_string())
+    }
+}
+
+impl From<uuid::Error> for GroupError {
+    fn from(err: uuid::Error) -> Self {
+        Self::Database(err.to_string())
+    }
+}
+
+fn group_from_row(row: &SqliteRow) -> Result<Group, GroupError> {
+    let id: String = row.try_get("id")?;
+    let created_by: String = row.try_get("created_by")?;
+    let created_at: i64 = row.try_get("created_at")?;
+
+    Ok(Group {
+        id: Uuid::parse_str(&id)?,
+        created_by: Uuid::parse_str(&created_by)?,
+        created_at: created_at as u64,
+    })
+}
+
+fn group_member_from_row(row: &SqliteRow) -> Result<GroupMember, GroupError> {
+    let account_id: String = row.try_get("account_id")?;
+    let role: String = row.try_get("role")?;
+    let joined_at: i64 = row.try_get("joined_at")?;
+
+    let role = match role.as_str() {
+        "admin" => GroupRole::Admin,
+        "member" => GroupRole::Member,
+        _ => {
+            return Err(GroupError::Database(format!(
+                "Unknown group role: {}",
+                role
+            )));
+        }
+    };
+
+    Ok(GroupMember {
+        account_id: Uuid::parse_str(&account_id)?,
+        role,
+        joined_at: joined_at as u64,
+    })
+}
+
+fn group_role_str(role: GroupRole) -> &'static str {
+    match role {
+        GroupRole::Admin => "admin",
+        GroupRole::Member => "member",
+    }
+}
+
+#[async_trait]
+impl GroupDatabase for SqliteDatabase {
+    async fn insert_group(
+        &self,
+        group: Group,
+        members: Vec<GroupMember>,
+    ) -> Result<(), GroupError> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO groups (id, created_by, created_at)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(group.id.to_string())
+        .bind(group.created_by.to_string())
+        .bind(group.created_at as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        for member in members {
+            sqlx::query(
+                r#"
+                INSERT INTO group_members (group_id, account_id, role, joined_at)
+                VALUES (?, ?, ?, ?)
+                "#,
+            )
+            .bind(group.id.to_string())
+            .bind(member.account_id.to_string())
+            .bind(group_role_str(member.role))
+            .bind(member.joined_at as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_group(&self, group_id: Uuid) -> Result<Option<Group>, GroupError> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, created_by, created_at
+            FROM groups
+            WHERE id = ?
+            "#,
+        )
+        .bind(group_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(group_from_row).transpose()
+    }
+
+    async fn get_groups_for_account(&self, account_id: Uuid) -> Result<Vec<Group>, GroupError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT groups.id, groups.created_by, groups.created_at
+            FROM groups
+            JOIN group_members ON group_members.group_id = groups.id
+            WHERE group_members.account_id = ?
+            ORDER BY groups.created_at
+            "#,
+        )
+        .bind(account_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(group_from_row).collect()
+    }
+
+    async fn get_members(&self, group_id: Uuid) -> Result<Vec<GroupMember>, GroupError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT account_id, role, joined_at
+            FROM group_members
+            WHERE group_id = ?
+            ORDER BY joined_at, rowid
+            "#,
+        )
+        .bind(group_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(group_member_from_row).collect()
+    }
+
+    async fn upsert_member(&self, group_id: Uuid, member: GroupMember) -> Result<(), GroupError> {
+        sqlx::query(
+            r#"
+            INSERT INTO group_members (group_id, account_id, role, joined_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(group_id, account_id) DO UPDATE SET
+                role = excluded.role
+            "#,
+        )
+        .bind(group_id.to_string())
+        .bind(member.account_id.to_string())
+        .bind(group_role_str(member.role))
+        .bind(member.joined_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_member(&self, group_id: Uuid, account_id: Uuid) -> Result<(), GroupError> {
+        sqlx::query(
+            r#"
+            DELETE FROM group_members
+            WHERE group_id = ? AND account_id = ?
+            "#,
+        )
+        .bind(group_id.to_string())
+        .bind(account_id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_group(&self, group_id: Uuid) -> Result<(), GroupError> {
+        // Foreign keys are not enforced by default, so remove members explicitly
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM group_members
+            WHERE group_id = ?
+            "#,
+        )
+        .bind(group_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM groups
+            WHERE id = ?
+            "#,
+        )
+        .bind(group_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProfileDatabase for SqliteDatabase {
+    async fn get_profile_by_id(&self, id: Uuid) -> Result<Option<Profile>, ProfileError> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, account_id, username, display_name, profile_picture_url,
+                delivery_access_key, updated_at
+            FROM profiles
+            WHERE id = ?
+            "#,
+        )
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => {
+                let id_str: String = row.try_get("id")?;
+                let id =
+                    Uuid::parse_str(&id_str).map_err(|e| ProfileError::Internal(e.to_string()))?;
+
+                let account_id_str: String = row.try_get("account_id")?;
+                let account_id = Uuid::parse_str(&account_id_str)
+                    .map_err(|e| ProfileError::Internal(e.to_string()))?;
+
+                Ok(Some(Profile {
+                    id,
+                    account_id,
+                    username: row.try_get("username")?,
+                    display_name: row.try_get("display_name")?,
+                    profile_picture_url: row.try_get("profile_picture_url")?,
+                    delivery_access_key: row.try_get("delivery_access_key")?,
+                    updated_at: row.try_get::<i64, _>("updated_at")? as u64,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_profile_by_account_id(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Option<Profile>, ProfileError> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, account_id, username, display_name, profile_picture_url,
+                delivery_access_key, updated_at
+            FROM profiles
+            WHERE account_id = ?
+            "#,
+        )
+        .bind(account_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => {
+                let id_str: String = row.try_get("id")?;
+                let id =
+                    Uuid::parse_str(&id_str).map_err(|e| ProfileError::Internal(e.to_string()))?;
+
+                let account_id_str: String = row.try_get("account_id")?;
+                let account_id = Uuid::parse_str(&account_id_str)
+                    .map_err(|e| ProfileError::Internal(e.to_string()))?;
+
+                Ok(Some(Profile {
+                    id,
+                    account_id,
+                    username: row.try_get("username")?,
+                    display_name: row.try_get("display_name")?,
+                    profile_picture_url: row.try_get("profile_picture_url")?,
+                    delivery_access_key: row.try_get("delivery_access_key")?,
+                    updated_at: row.try_get::<i64, _>("updated_at")? as u64,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn upsert_profile(&self, profile: Profile) -> Result<(), ProfileError> {
+        sqlx::query(
+            r#"
+            INSERT INTO profiles (
+                id, account_id, username, display_name, profile_picture_url, delivery_access_key,
+                updated_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+                account_id = excluded.account_id,
+                username = excluded.username,
+                display_name = excluded.display_name,
+                profile_picture_url = excluded.profile_picture_url,
+                delivery_access_key = excluded.delivery_access_key,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(profile.id.to_string())
+        .bind(profile.account_id.to_string())
+        .bind(&profile.username)
+        .bind(&profile.display_name)
+        .bind(&profile.profile_picture_url)
+        .bind(profile.delivery_access_key.as_deref())
+        .bind(profile.updated_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_profile_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<Profile>, ProfileError> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, account_id, username, display_name, profile_picture_url,
+                delivery_access_key, updated_at
+            FROM profiles
+            WHERE username = ?
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => {
+                let id_str: String = row.try_get("id")?;
+                let id =
+                    Uuid::parse_str(&id_str).map_err(|e| ProfileError::Internal(e.to_string()))?;
+
+                let account_id_str: String = row.try_get("account_id")?;
+                let account_id = Uuid::parse_str(&account_id_str)
+                    .map_err(|e| ProfileError::Internal(e.to_string()))?;
+
+                Ok(Some(Profile {
+                    id,
+                    account_id,
+                    username: row.try_get("username")?,
+                    display_name: row.try_get("display_name")?,
+                    profile_picture_url: row.try_get("profile_picture_url")?,
+                    delivery_access_key: row.try_get("delivery_access_key")?,
+                    updated_at: row.try_get::<i64, _>("updated_at")? as u64,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::entities::{DoubleRatchetHeader, DoubleRatchetMessage};
+    use prism_client::SigningKey;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn create_test_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .connect(":memory:")
+            .await
+            .expect("Failed to connect to in-memory SQLite")
+    }
+
+    #[tokio::test]
+    async fn test_account_crud_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        // Create a test account
+        let account = Account::new("password123", Some(b"test_apns_token".to_vec()), None);
+        let account_id = account.id;
+
+        // Test upsert_account
+        db.upsert_account(account.clone())
+            .await
+            .expect("Failed to upsert account");
+
+        // Test fetch_account
+        let fetched = db
+            .fetch_account(account_id)
+            .await
+            .expect("Failed to fetch account")
+            .expect("Account should exist");
+        assert_eq!(fetched.apns_token, Some(b"test_apns_token".to_vec()));
+
+        // Test remove_account
+        db.remove_account(account_id)
+            .await
+            .expect("Failed to remove account");
+
+        // Verify the account was removed
+        let result = db
+            .fetch_account(account_id)
+            .await
+            .expect("Failed to fetch account");
+        assert!(
+            result.is_none(),
+            "Account result should be None after deletion"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_apns_token() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        // Create a test account
+        let account = Account::new("password123", None, None);
+        let account_id = account.id;
+
+        db.upsert_account(account)
+            .await
+            .expect("Failed to insert account");
+
+        // Update the APNS token
+        let new_token = vec![1, 2, 3, 4, 5];
+        db.update_apns_token(account_id, new_token.clone())
+            .await
+            .expect("Failed to update APNS token");
+
+        // Verify the token was updated
+        let updated_account = db
+            .fetch_account(account_id)
+            .await
+            .expect("Failed to fetch updated account")
+            .expect("Account should exist");
+        assert_eq!(updated_account.apns_token, Some(new_token));
+
+        // Test updating non-existent account
+        let non_existent_id = Uuid::new_v4();
+        let result = db
+            .update_apns_token(non_existent_id, vec![5, 6, 7, 8])
+            .await;
+        assert!(result.is_err());
+        if let Err(AccountDatabaseError::NotFound(id)) = result {
+            assert_eq!(id, non_existent_id.to_string());
+        } else {
+            panic!("Expected AccountDatabaseError::NotFound");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_database_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let account_id = Uuid::new_v4();
+
+        // Create a test key bundle
+        let identity_signing_key = SigningKey::new_ed25519();
+        let identity_key = identity_signing_key.verifying_key();
+        let signed_prekey = SigningKey::new_ed25519().verifying_key();
+        let signed_prekey_signature = identity_signing_key
+            .sign(signed_prekey.to_spki_der().unwrap())
+            .unwrap();
+        let prekey1 = Prekey {
+            key_idx: 1,
+            key: SigningKey::new_ed25519().verifying_key(),
+        };
+        let prekey2 = Prekey {
+            key_idx: 2,
+            key: SigningKey::new_ed25519().verifying_key(),
+        };
+
+        let create_kem_prekey = |key_id: u64| {
+            let key = format!("KEM public key {}", key_id).into_bytes();
+            let signature = identity_signing_key.sign(&key).unwrap();
+            KemPrekey {
+                key_id,
+                key,
+                signature,
+            }
+        };
+        let last_resort_kem_prekey = create_kem_prekey(100);
+        let kem_prekey2 = create_kem_prekey(2);
+
+        let key_bundle = KeyBundle {
+            identity_key: identity_key.clone(),
+            signed_prekey_id: 1,
+            signed_prekey: signed_prekey.clone(),
+            signed_prekey_signature: signed_prekey_signature.clone(),
+            prekeys: vec![prekey1.clone(), prekey2.clone()],
+            last_resort_kem_prekey: Some(last_resort_kem_prekey.clone()),
+            kem_prekeys: vec![kem_prekey2.clone()],
+        };
+
+        // Test inserting a key bundle
+        db.insert_keybundle(account_id, key_bundle)
+            .await
+            .expect("Failed to insert key bundle");
+
+        // Test retrieving the key bundle
+        let retrieved_bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should exist");
+
+        // Directly compare the cryptographic types
+        assert_eq!(&retrieved_bundle.identity_key, &identity_key);
+        assert_eq!(retrieved_bundle.signed_prekey_id, 1);
+        assert_eq!(retrieved_bundle.signed_prekey, signed_prekey);
+        assert_eq!(
+            retrieved_bundle.signed_prekey_signature,
+            signed_prekey_signature
+        );
+        assert_eq!(retrieved_bundle.prekeys.len(), 2);
+        assert_eq!(retrieved_bundle.prekeys[0], prekey1);
+        assert_eq!(retrieved_bundle.prekeys[1], prekey2);
+        assert_eq!(
+            retrieved_bundle.last_resort_kem_prekey,
+            Some(last_resort_kem_prekey.clone())
+        );
+        assert_eq!(retrieved_bundle.kem_prekeys, vec![kem_prekey2.clone()]);
+
+        // Test adding additional prekeys
+        let prekey3 = Prekey {
+            key_idx: 3,
+            key: SigningKey::new_ed25519().verifying_key(),
+        };
+        let prekey4 = Prekey {
+            key_idx: 4,
+            key: SigningKey::new_ed25519().verifying_key(),
+        };
+
+        let additional_prekeys = vec![prekey3.clone(), prekey4.clone()];
+
+        db.add_prekeys(account_id, additional_prekeys)
+            .await
+            .expect("Failed to add prekeys");
+
+        // Verify the updated bundle has all prekeys
+        let updated_bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get updated key bundle")
+            .expect("Updated key bundle should exist");
+
+        // Check the number of prekeys
+        assert_eq!(updated_bundle.prekeys.len(), 4);
+        assert_eq!(updated_bundle.prekeys[2], prekey3);
+        assert_eq!(updated_bundle.prekeys[3], prekey4);
+
+        // Test adding prekeys for non-existent user
+        let non_existent_account_id = Uuid::new_v4();
+        let result = db
+            .add_prekeys(
+                non_existent_account_id,
+                vec![Prekey {
+                    key_idx: 1,
+                    key: SigningKey::new_ed25519().verifying_key(),
+                }],
+            )
+            .await;
+
+        assert!(result.is_err());
+        if let Err(KeyError::NotFound(user)) = result {
+            assert_eq!(user, non_existent_account_id.to_string());
+        } else {
+            panic!("Expected KeyError::NotFound");
+        }
+
+        // Test getting a non-existent key bundle
+        let non_existent_bundle = db
+            .get_keybundle(non_existent_account_id)
+            .await
+            .expect("get_keybundle should not fail for non-existent user");
+
+        assert!(non_existent_bundle.is_none(), "Bundle should not exist");
+
+        let count = db
+            .count_prekeys(account_id)
+            .await
+            .expect("Failed to count prekeys");
+        assert_eq!(count, 4);
+
+        // Prekeys are taken one per bundle, lowest index first
+        for expected_prekey in [prekey1, prekey2, prekey3, prekey4] {
+            let bundle = db
+                .take_prekey(account_id)
+                .await
+                .expect("Failed to take key bundle")
+                .expect("Key bundle should exist");
+            assert_eq!(bundle.identity_key, identity_key);
+            assert_eq!(bundle.prekeys, vec![expected_prekey]);
+        }
+        let bundle = db
+            .take_prekey(account_id)
+            .await
+            .expect("Failed to take key bundle")
+            .expect("Key bundle should outlast its prekeys");
+        assert!(bundle.prekeys.is_empty());
+        let count = db
+            .count_prekeys(account_id)
+            .await
+            .expect("Failed to count prekeys");
+        assert_eq!(count, 0);
+
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should outlast its prekeys");
+        assert!(bundle.prekeys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_kem_prekey_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let account_id = Uuid::new_v4();
+        let identity_signing_key = SigningKey::new_ed25519();
+        let signed_prekey = SigningKey::new_ed25519().verifying_key();
+        let signed_prekey_signature = identity_signing_key
+            .sign(signed_prekey.to_spki_der().unwrap())
+            .unwrap();
+        let create_kem_prekey = |key_id: u64| {
+            let mut key = vec![0; 1184];
+            key[1183] = key_id as u8;
+            let signature = identity_signing_key.sign(&key).unwrap();
+            KemPrekey {
+                key_id,
+                key,
+                signature,
+            }
+        };
+        let last_resort_kem_prekey = create_kem_prekey(100);
+        let kem_prekey1 = create_kem_prekey(1);
+        let kem_prekey2 = create_kem_prekey(2);
+        let kem_prekey3 = create_kem_prekey(3);
+
+        // Adding KEM prekeys requires a key bundle
+        let result = db
+            .add_kem_prekeys(account_id, vec![kem_prekey1.clone()])
+            .await;
+        assert!(matches!(result, Err(KeyError::NotFound(_))));
+        let bundle = db
+            .take_prekey(account_id)
+            .await
+            .expect("Failed to take key bundle");
+        assert!(bundle.is_none());
+
+        db.insert_keybundle(
+            account_id,
+            KeyBundle {
+                identity_key: identity_signing_key.verifying_key(),
+                signed_prekey_id: 1,
+                signed_prekey,
+                signed_prekey_signature,
+                prekeys: vec![],
+                last_resort_kem_prekey: Some(last_resort_kem_prekey.clone()),
+                kem_prekeys: vec![kem_prekey2.clone()],
+            },
+        )
+        .await
+        .expect("Failed to insert key bundle");
+        db.add_kem_prekeys(account_id, vec![kem_prekey3.clone(), kem_prekey1.clone()])
+            .await
+            .expect("Failed to add KEM prekeys");
+
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should exist");
+        assert_eq!(
+            bundle.last_resort_kem_prekey,
+            Some(last_resort_kem_prekey.clone())
+        );
+        assert_eq!(
+            bundle.kem_prekeys,
+            vec![
+                kem_prekey1.clone(),
+                kem_prekey2.clone(),
+                kem_prekey3.clone()
+            ]
+        );
+
+        // One-time KEM prekeys are taken one per bundle, lowest ID first
+        for expected_kem_prekey in [kem_prekey1, kem_prekey2, kem_prekey3] {
+            let bundle = db
+                .take_prekey(account_id)
+                .await
+                .expect("Failed to take key bundle")
+                .expect("Key bundle should exist");
+            assert_eq!(bundle.kem_prekeys, vec![expected_kem_prekey]);
+            assert_eq!(
+                bundle.last_resort_kem_prekey,
+                Some(last_resort_kem_prekey.clone())
+            );
+        }
+
+        // Once they run out, only the last-resort KEM prekey is left, and it stays
+        for _ in 0..2 {
+            let bundle = db
+                .take_prekey(account_id)
+                .await
+                .expect("Failed to take key bundle")
+                .expect("Key bundle should outlast its KEM prekeys");
+            assert!(bundle.kem_prekeys.is_empty());
+            assert_eq!(
+                bundle.last_resort_kem_prekey,
+                Some(last_resort_kem_prekey.clone())
+            );
+        }
+
+        // Replacing the key bundle replaces its KEM prekeys
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should exist");
+        db.insert_keybundle(
+            account_id,
+            KeyBundle {
+                last_resort_kem_prekey: None,
+                kem_prekeys: vec![],
+                ..bundle
+            },
+        )
+        .await
+        .expect("Failed to replace key bundle");
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should exist");
+        assert!(bundle.last_resort_kem_prekey.is_none());
+        assert!(bundle.kem_prekeys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_key_bundles_migration() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+
+        // key_bundles as created before signed prekeys had IDs
+        sqlx::query(
+            r#"
+            CREATE TABLE key_bundles (
+                account_id BLOB PRIMARY KEY,
+                identity_key BLOB NOT NULL,
+                signed_prekey BLOB NOT NULL,
+                signed_prekey_signature BLOB NOT NULL
+            )
+            "#,
+        )
+        .execute(&db.pool)
+        .await
+        .expect("Failed to create baseline key_bundles table");
+
+        let account_id = Uuid::new_v4();
+        let identity_signing_key = SigningKey::new_ed25519();
+        let signed_prekey = SigningKey::new_ed25519().verifying_key();
+        let signed_prekey_signature = identity_signing_key
+            .sign(signed_prekey.to_spki_der().unwrap())
+            .unwrap();
+        sqlx::query(
+            r#"
+            INSERT INTO key_bundles (account_id, identity_key, signed_prekey, signed_prekey_signature)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(account_id)
+        .bind(identity_signing_key.verifying_key().to_spki_der().unwrap())
+        .bind(signed_prekey.to_spki_der().unwrap())
+        .bind(signed_prekey_signature.to_prism_der().unwrap())
+        .execute(&db.pool)
+        .await
+        .expect("Failed to insert baseline key bundle");
+
+        // Migrating twice must not fail
+        db.init().await.expect("Failed to initialize database");
+        db.init()
+            .await
+            .expect("Failed to initialize database again");
+
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should survive the migration");
+        assert_eq!(bundle.signed_prekey_id, 0);
+        assert_eq!(bundle.signed_prekey, signed_prekey);
+
+        let second = SigningKey::new_ed25519().verifying_key();
+        let signature = identity_signing_key
+            .sign(second.to_spki_der().unwrap())
+            .unwrap();
+        db.rotate_signed_prekey(
+            account_id,
+            SignedPrekey {
+                key_id: 1,
+                key: second.clone(),
+                signature,
+            },
+            1_000,
+        )
+        .await
+        .expect("Failed to rotate signed prekey");
+
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should exist");
+        assert_eq!(bundle.signed_prekey_id, 1);
+        assert_eq!(bundle.signed_prekey, second);
+    }
+
+    #[tokio::test]
+    async fn test_signed_prekey_rotation() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let account_id = Uuid::new_v4();
+        let identity_signing_key = SigningKey::new_ed25519();
+        let create_signed_prekey = |key_id: u64| {
+            let key = SigningKey::new_ed25519().verifying_key();
+            let signature = identity_signing_key
+                .sign(key.to_spki_der().unwrap())
+                .unwrap();
+            SignedPrekey {
+                key_id,
+                key,
+                signature,
+            }
+        };
+
+        // Rotating requires a key bundle
+        let result = db
+            .rotate_signed_prekey(account_id, create_signed_prekey(2), 1_000)
+            .await;
+        assert!(matches!(result, Err(KeyError::NotFound(_))));
+
+        let first = create_signed_prekey(1);
+        db.insert_keybundle(
+            account_id,
+            KeyBundle {
+                identity_key: identity_signing_key.verifying_key(),
+                signed_prekey_id: first.key_id,
+                signed_prekey: first.key.clone(),
+                signed_prekey_signature: first.signature.clone(),
+                prekeys: vec![],
+                last_resort_kem_prekey: None,
+                kem_prekeys: vec![],
+            },
+        )
+        .await
+        .expect("Failed to insert key bundle");
+
+        let second = create_signed_prekey(2);
+        db.rotate_signed_prekey(account_id, second.clone(), 1_000)
+            .await
+            .expect("Failed to rotate signed prekey");
+        let third = create_signed_prekey(3);
+        db.rotate_signed_prekey(account_id, third.clone(), 2_000)
+            .await
+            .expect("Failed to rotate signed prekey");
+
+        let bundle = db
+            .get_keybundle(account_id)
+            .await
+            .expect("Failed to get key bundle")
+            .expect("Key bundle should exist");
+        assert_eq!(bundle.signed_prekey_id, 3);
+        assert_eq!(bundle.signed_prekey, third.key);
+        assert_eq!(bundle.signed_prekey_signature, third.signature);
+
+        let previous = db
+            .get_previous_signed_prekeys(account_id)
+            .await
+            .expect("Failed to get previous signed prekeys");
+        assert_eq!(
+            previous,
+            vec![
+                PreviousSignedPrekey {
+                    key_id: 1,
+                    key: first.key,
+                    replaced_at: 1_000,
+                },
+                PreviousSignedPrekey {
+                    key_id: 2,
+                    key: second.key,
+                    replaced_at: 2_000,
+                },
+            ]
+        );
+
+        db.remove_previous_signed_prekeys(account_id, 1_000)
+            .await
+            .expect("Failed to remove previous signed prekeys");
+        let previous = db
+            .get_previous_signed_prekeys(account_id)
+            .await
+            .expect("Failed to get previous signed prekeys");
+        assert_eq!(previous.len(), 1);
+        assert_eq!(previous[0].key_id, 2);
+    }
+
+    #[tokio::test]
+    async fn test_message_database_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let sender_id = Uuid::new_v4();
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let create_message = |recipient_id: Uuid, timestamp: u64| Message {
+            message_id: Uuid::new_v4(),
+            sender_id: Some(sender_id),
+            recipient_id,
+            group_id: None,
+            message: DoubleRatchetMessage {
+                header: DoubleRatchetHeader {
+                    ephemeral_key: SigningKey::new_ed25519().verifying_key(),
+                    message_number: timestamp,
+                    previous_message_number: 0,
+                    one_time_prekey_id: None,
+                    signed_prekey_id: None,
+                },
+                ciphertext: format!("Message {}", timestamp).into_bytes(),
+                nonce: vec![0; 12],
+            },
+            timestamp,
+            expires_at: timestamp + 60_000,
+        };
+
+        let alice_msg1 = create_message(alice_id, 1);
+        let alice_msg2 = create_message(alice_id, 2);
+        let bob_msg = create_message(bob_id, 3);
+
+        for message in [alice_msg1.clone(), alice_msg2.clone(), bob_msg.clone()] {
+            db.insert_message(message)
+                .await
+                .expect("Failed to insert message");
+        }
+
+        // Test get_all_messages
+        let all_messages = db
+            .get_all_messages()
+            .await
+            .expect("Failed to get all messages");
+        assert_eq!(all_messages.len(), 3);
+
+        // Test get_messages_for_account keeps the order of arrival
+        let alice_messages = db
+            .get_messages_for_account(alice_id)
+            .await
+            .expect("Failed to get messages for alice");
+        assert_eq!(alice_messages.len(), 2);
+        assert_eq!(alice_messages[0].message_id, alice_msg1.message_id);
+        assert_eq!(alice_messages[0].sender_id, Some(sender_id));
+        assert_eq!(alice_messages[0].recipient_id, alice_id);
+        assert_eq!(alice_messages[0].timestamp, 1);
+        assert_eq!(alice_messages[0].expires_at, 60_001);
+        assert_eq!(
+            alice_messages[0].message.ciphertext,
+            alice_msg1.message.ciphertext
+        );
+        assert_eq!(
+            alice_messages[0].message.header.ephemeral_key,
+            alice_msg1.message.header.ephemeral_key
+        );
+        assert_eq!(alice_messages[1].message_id, alice_msg2.message_id);
+
+        // Pages continue strictly after the cursor
+        let first_page = db
+            .get_messages_page_for_account(alice_id, None, 1)
+            .await
+            .expect("Failed to get first page for alice");
+        assert_eq!(first_page.len(), 1);
+        assert_eq!(first_page[0].message_id, alice_msg1.message_id);
+
+        let second_page = db
+            .get_messages_page_for_account(alice_id, Some(MessageCursor::of(&first_page[0])), 10)
+            .await
+            .expect("Failed to get second page for alice");
+        assert_eq!(second_page.len(), 1);
+        assert_eq!(second_page[0].message_id, alice_msg2.message_id);
+
+        // Messages of the same millisecond are paged by ID, whatever order they arrived in
+        let carol_id = Uuid::new_v4();
+        let mut later_id = create_message(carol_id, 5);
+        later_id.message_id = Uuid::from_u128(2);
+        let mut earlier_id = create_message(carol_id, 5);
+        earlier_id.message_id = Uuid::from_u128(1);
+        for message in [later_id.clone(), earlier_id.clone()] {
+            db.insert_message(message)
+                .await
+                .expect("Failed to insert message");
+        }
+        let mut paged_ids = Vec::new();
+        let mut after = None;
+        loop {
+            let page = db
+                .get_messages_page_for_account(carol_id, after, 1)
+                .await
+                .expect("Failed to get page for carol");
+            let Some(message) = page.first() else {
+                break;
+            };
+            after = Some(MessageCursor::of(message));
+            paged_ids.push(message.message_id);
+        }
+        assert_eq!(paged_ids, vec![earlier_id.message_id, later_id.message_id]);
+        db.remove_messages(carol_id, paged_ids)
+            .await
+            .expect("Failed to remove messages");
+
+        // Removing messages is scoped to the recipient
+        db.remove_messages(alice_id, vec![alice_msg1.message_id, bob_msg.message_id])
+            .await
+            .expect("Failed to remove messages");
+
+        let alice_messages = db
+            .get_messages_for_account(alice_id)
+            .await
+            .expect("Failed to get messages for alice");
+        assert_eq!(alice_messages.len(), 1);
+        assert_eq!(alice_messages[0].message_id, alice_msg2.message_id);
+
+        let bob_messages = db
+            .get_messages_for_account(bob_id)
+            .await
+            .expect("Failed to get messages for bob");
+        assert_eq!(bob_messages.len(), 1);
+
+        // Only the sender can remove a message of their own
+        let removed = db
+            .remove_message_of_sender(Uuid::new_v4(), bob_msg.message_id)
+            .await
+            .expect("Failed to remove message of sender");
+        assert!(removed.is_none());
+
+        let bob_retracted_msg = create_message(bob_id, 4);
+        db.insert_message(bob_retracted_msg.clone())
+            .await
+            .expect("Failed to insert message");
+        let removed = db
+            .remove_message_of_sender(sender_id, bob_retracted_msg.message_id)
+            .await
+            .expect("Failed to remove message of sender");
+        assert_eq!(
+            removed.map(|msg| msg.message_id),
+            Some(bob_retracted_msg.message_id)
+        );
+        let bob_messages = db
+            .get_messages_for_account(bob_id)
+            .await
+            .expect("Failed to get messages for bob");
+        assert_eq!(bob_messages.len(), 1);
+
+        // Handed out messages can no longer be removed by their sender
+        for _ in 0..2 {
+            db.insert_handed_out_messages(vec![bob_msg.message_id], bob_msg.expires_at)
+                .await
+                .expect("Failed to mark message handed out");
+        }
+        assert!(db.is_handed_out(bob_msg.message_id).await.unwrap());
+        let removed = db
+            .remove_message_of_sender(sender_id, bob_msg.message_id)
+            .await
+            .expect("Failed to remove message of sender");
+        assert!(removed.is_none());
+
+        db.remove_expired_handed_out_messages(bob_msg.expires_at - 1)
+            .await
+            .expect("Failed to remove expired handed out messages");
+        assert!(db.is_handed_out(bob_msg.message_id).await.unwrap());
+        db.remove_expired_handed_out_messages(bob_msg.expires_at)
+            .await
+            .expect("Failed to remove expired handed out messages");
+        assert!(!db.is_handed_out(bob_msg.message_id).await.unwrap());
+
+        // Queue usage counts messages and ciphertext bytes per recipient
+        let usage = db
+            .get_queue_usage(alice_id, Some(sender_id))
+            .await
+            .expect("Failed to get queue usage");
+        assert_eq!(usage.recipient_messages, 1);
+        assert_eq!(
+            usage.recipient_bytes,
+            alice_msg2.message.ciphertext.len() as u64
+        );
+        assert_eq!(usage.sender_messages, 1);
+
+        let usage = db
+            .get_queue_usage(alice_id, Some(Uuid::new_v4()))
+            .await
+            .expect("Failed to get queue usage");
+        assert_eq!(usage.recipient_messages, 1);
+        assert_eq!(usage.sender_messages, 0);
+
+        let usage = db
+            .get_queue_usage(Uuid::new_v4(), Some(sender_id))
+            .await
+            .expect("Failed to get queue usage");
+        assert_eq!(usage, QueueUsage::default());
+
+        // Only messages that expired are removed and returned
+        let expired = db
+            .remove_expired_messages(60_002)
+            .await
+            .expect("Failed to remove expired messages");
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].message_id, alice_msg2.message_id);
+
+        let all_messages = db
+            .get_all_messages()
+            .await
+            .expect("Failed to get all messages");
+        assert_eq!(all_messages.len(), 1);
+        assert_eq!(all_messages[0].message_id, bob_msg.message_id);
+    }
+
+    #[tokio::test]
+    async fn test_message_request_database_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+
+        db.insert_accepted_sender(alice_id, bob_id)
+            .await
+            .expect("Failed to accept sender");
+        // Accepting again is a no-op
+        db.insert_accepted_sender(alice_id, bob_id)
+            .await
+            .expect("Failed to accept sender");
+        assert!(db.is_accepted_sender(alice_id, bob_id).await.unwrap());
+        assert!(!db.is_accepted_sender(bob_id, alice_id).await.unwrap());
+
+        let create_message = |sender_id: Uuid, timestamp: u64| Message {
+            message_id: Uuid::new_v4(),
+            sender_id: Some(sender_id),
+            recipient_id: alice_id,
+            group_id: None,
+            message: DoubleRatchetMessage {
+                header: DoubleRatchetHeader {
+                    ephemeral_key: SigningKey::new_ed25519().verifying_key(),
+                    message_number: timestamp,
+                    previous_message_number: 0,
+                    one_time_prekey_id: None,
+                    signed_prekey_id: None,
+                },
+                ciphertext: format!("Message {}", timestamp).into_bytes(),
+                nonce: vec![0; 12],
+            },
+            timestamp,
+            expires_at: timestamp + 60_000,
+        };
+
+        let mallory_msg1 = create_message(mallory_id, 1);
+        let mallory_msg2 = create_message(mallory_id, 2);
+        let bob_msg = create_message(bob_id, 3);
+        for message in [mallory_msg1.clone(), mallory_msg2.clone(), bob_msg.clone()] {
+            db.insert_request_message(message)
+                .await
+                .expect("Failed to insert request message");
+        }
+
+        let requests = db
+            .get_request_messages(alice_id)
+            .await
+            .expect("Failed to get request messages");
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].message_id, mallory_msg1.message_id);
+        assert_eq!(
+            db.count_request_messages(alice_id, mallory_id)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            db.count_request_messages(bob_id, mallory_id).await.unwrap(),
+            0
+        );
+
+        let removed = db
+            .remove_request_messages(alice_id, mallory_id)
+            .await
+            .expect("Failed to remove request messages");
+        assert_eq!(removed.len(), 2);
+        assert_eq!(removed[0].message_id, mallory_msg1.message_id);
+        assert_eq!(removed[1].message_id, mallory_msg2.message_id);
+
+        // Senders can only remove their own message requests
+        let removed = db
+            .remove_request_message_of_sender(mallory_id, bob_msg.message_id)
+            .await
+            .expect("Failed to remove request message");
+        assert!(removed.is_none());
+        let bob_msg2 = create_message(bob_id, 4);
+        db.insert_request_message(bob_msg2.clone())
+            .await
+            .expect("Failed to insert request message");
+        let removed = db
+            .remove_request_message_of_sender(bob_id, bob_msg2.message_id)
+            .await
+            .expect("Failed to remove request message");
+        assert_eq!(removed.map(|msg| msg.message_id), Some(bob_msg2.message_id));
+
+        db.remove_expired_request_messages(bob_msg.expires_at)
+            .await
+            .expect("Failed to remove expired request messages");
+        assert!(db.get_request_messages(alice_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_message_database_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let sender_id = Uuid::new_v4();
+        let recipient_id = Uuid::new_v4();
+
+        let create_message = |timestamp: u64| Message {
+            message_id: Uuid::now_v7(),
+            sender_id: Some(sender_id),
+            recipient_id,
+            group_id: None,
+            message: DoubleRatchetMessage {
+                header: DoubleRatchetHeader {
+                    ephemeral_key: SigningKey::new_ed25519().verifying_key(),
+                    message_number: timestamp,
+                    previous_message_number: 0,
+                    one_time_prekey_id: None,
+                    signed_prekey_id: None,
+                },
+                ciphertext: format!("Message {}", timestamp).into_bytes(),
+                nonce: vec![0; 12],
+            },
+            timestamp,
+            expires_at: timestamp + 60_000,
+        };
+
+        let later = create_message(3000);
+        let sooner = create_message(1000);
+        for message in [later.clone(), sooner.clone()] {
+            db.insert_scheduled_message(message)
+                .await
+                .expect("Failed to insert scheduled message");
+        }
+
+        assert!(db.get_due_scheduled_messages(999).await.unwrap().is_empty());
+        let due = db
+            .get_due_scheduled_messages(3000)
+            .await
+            .expect("Failed to get due scheduled messages");
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].message_id, sooner.message_id);
+        assert_eq!(due[1].message_id, later.message_id);
+
+        // Only the sender can remove its scheduled messages
+        assert!(
+            !db.remove_scheduled_message(recipient_id, sooner.message_id)
+                .await
+                .unwrap()
+        );
+        assert!(
+            db.remove_scheduled_message(sender_id, sooner.message_id)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !db.remove_scheduled_message(sender_id, sooner.message_id)
+                .await
+                .unwrap()
+        );
+        assert_eq!(db.get_due_scheduled_messages(3000).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_receipt_database_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
 
-                let account_id_str: String = row.try_get("account_id")?;
-                let account_id = Uuid::parse_str(&account_id_str)
-                    .map_err(|e| ProfileError::Internal(e.to_string()))?;
+        let sender_id = Uuid::new_v4();
+        let recipient_id = Uuid::new_v4();
 
-                Ok(Some(Profile {
-                    id,
-                    account_id,
-                    username: row.try_get("username")?,
-                    display_name: row.try_get("display_name")?,
-                    profile_picture_url: row.try_get("profile_picture_url")?,
-                    updated_at: row.try_get::<i64, _>("updated_at")? as u64,
-                }))
-            }
-            None => Ok(None),
+        let create_receipt = |kind: ReceiptKind, timestamp: u64| Receipt {
+            receipt_id: Uuid::new_v4(),
+            kind,
+            message_id: Uuid::new_v4(),
+            sender_id,
+            recipient_id,
+            timestamp,
+        };
+
+        let delivered = create_receipt(ReceiptKind::Delivered, 1);
+        let read = create_receipt(ReceiptKind::Read, 2);
+
+        for receipt in [delivered.clone(), read.clone()] {
+            db.insert_receipt(receipt)
+                .await
+                .expect("Failed to insert receipt");
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use prism_client::SigningKey;
-    use sqlx::sqlite::SqlitePoolOptions;
+        let receipts = db
+            .get_receipts_for_account(sender_id)
+            .await
+            .expect("Failed to get receipts");
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receipt_id, delivered.receipt_id);
+        assert_eq!(receipts[0].kind, ReceiptKind::Delivered);
+        assert_eq!(receipts[0].message_id, delivered.message_id);
+        assert_eq!(receipts[0].recipient_id, recipient_id);
+        assert_eq!(receipts[1].kind, ReceiptKind::Read);
+
+        // Receipts are queued for the sender only
+        db.remove_receipts(recipient_id, vec![delivered.receipt_id])
+            .await
+            .expect("Failed to remove receipts");
+        assert_eq!(
+            db.get_receipts_for_account(sender_id).await.unwrap().len(),
+            2
+        );
 
-    async fn create_test_pool() -> SqlitePool {
-        SqlitePoolOptions::new()
-            .connect(":memory:")
+        db.remove_receipts(sender_id, vec![delivered.receipt_id])
             .await
-            .expect("Failed to connect to in-memory SQLite")
+            .expect("Failed to remove receipts");
+        let receipts = db
+            .get_receipts_for_account(sender_id)
+            .await
+            .expect("Failed to get receipts");
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receipt_id, read.receipt_id);
     }
 
     #[tokio::test]
-    async fn test_account_crud_operations() {
+    async fn test_sent_message_database_operations() {
         let pool = create_test_pool().await;
         let db = SqliteDatabase::new(pool);
         db.init().await.expect("Failed to initialize database");
 
-        // Create a test account
-        let account = Account::new("password123", Some(b"test_apns_token".to_vec()), None);
-        let account_id = account.id;
+        let sender_id = Uuid::new_v4();
+        let idempotency_key = Uuid::new_v4();
+        let sent_message = SentMessage {
+            sender_id,
+            idempotency_key,
+            receipt: MessageReceipt {
+                message_id: Uuid::new_v4(),
+                timestamp: 1_000,
+                expires_at: 61_000,
+            },
+            dedup_until: 2_000,
+        };
 
-        // Test upsert_account
-        db.upsert_account(account.clone())
-            .await
-            .expect("Failed to upsert account");
+        assert!(
+            db.insert_sent_message(sent_message.clone())
+                .await
+                .expect("Failed to insert sent message")
+        );
+
+        // The key stays claimed until the deduplication window ends
+        let mut retry = sent_message.clone();
+        retry.receipt.message_id = Uuid::new_v4();
+        retry.receipt.timestamp = 1_999;
+        assert!(!db.insert_sent_message(retry.clone()).await.unwrap());
 
-        // Test fetch_account
         let fetched = db
-            .fetch_account(account_id)
+            .get_sent_message(sender_id, idempotency_key)
             .await
-            .expect("Failed to fetch account")
-            .expect("Account should exist");
-        assert_eq!(fetched.apns_token, Some(b"test_apns_token".to_vec()));
+            .expect("Failed to get sent message")
+            .expect("Sent message not found");
+        assert_eq!(fetched.receipt.message_id, sent_message.receipt.message_id);
+        assert_eq!(fetched.receipt.timestamp, 1_000);
+        assert_eq!(fetched.receipt.expires_at, 61_000);
+        assert_eq!(fetched.dedup_until, 2_000);
+
+        // Keys are scoped to their sender
+        assert!(
+            db.get_sent_message(Uuid::new_v4(), idempotency_key)
+                .await
+                .expect("Failed to get sent message")
+                .is_none()
+        );
 
-        // Test remove_account
-        db.remove_account(account_id)
+        db.remove_expired_sent_messages(1_999)
             .await
-            .expect("Failed to remove account");
+            .expect("Failed to remove expired sent messages");
+        assert!(
+            db.get_sent_message(sender_id, idempotency_key)
+                .await
+                .unwrap()
+                .is_some()
+        );
 
-        // Verify the account was removed
-        let result = db
-            .fetch_account(account_id)
+        db.remove_expired_sent_messages(2_000)
             .await
-            .expect("Failed to fetch account");
+            .expect("Failed to remove expired sent messages");
         assert!(
-            result.is_none(),
-            "Account result should be None after deletion"
+            db.get_sent_message(sender_id, idempotency_key)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // Stale entries are replaced even before the sweep
+        assert!(db.insert_sent_message(sent_message.clone()).await.unwrap());
+        retry.receipt.timestamp = 2_000;
+        assert!(db.insert_sent_message(retry.clone()).await.unwrap());
+        assert_eq!(
+            db.get_sent_message(sender_id, idempotency_key)
+                .await
+                .unwrap()
+                .unwrap()
+                .receipt
+                .message_id,
+            retry.receipt.message_id
+        );
+
+        db.remove_sent_message(sender_id, idempotency_key)
+            .await
+            .expect("Failed to remove sent message");
+        assert!(
+            db.get_sent_message(sender_id, idempotency_key)
+                .await
+                .unwrap()
+                .is_none()
         );
     }
 
     #[tokio::test]
-    async fn test_update_apns_token() {
+    async fn test_attachment_database_operations() {
         let pool = create_test_pool().await;
         let db = SqliteDatabase::new(pool);
         db.init().await.expect("Failed to initialize database");
 
-        // Create a test account
-        let account = Account::new("password123", None, None);
-        let account_id = account.id;
+        let owner_id = Uuid::new_v4();
+        let create_attachment = |size: u64, expires_at: u64| Attachment {
+            id: Uuid::new_v4(),
+            owner_id,
+            size,
+            created_at: 0,
+            expires_at,
+            uploaded: false,
+        };
 
-        db.upsert_account(account)
-            .await
-            .expect("Failed to insert account");
+        let expired = create_attachment(100, 1_000);
+        let retained = create_attachment(250, 2_000);
+        for attachment in [expired.clone(), retained.clone()] {
+            db.insert_attachment(attachment, 400)
+                .await
+                .expect("Failed to insert attachment");
+        }
+        assert!(matches!(
+            db.insert_attachment(create_attachment(51, 2_000), 400)
+                .await,
+            Err(AttachmentError::QuotaExceeded(400))
+        ));
 
-        // Update the APNS token
-        let new_token = vec![1, 2, 3, 4, 5];
-        db.update_apns_token(account_id, new_token.clone())
+        let fetched = db
+            .get_attachment(retained.id)
             .await
-            .expect("Failed to update APNS token");
+            .expect("Failed to get attachment")
+            .expect("Attachment not found");
+        assert_eq!(fetched.owner_id, owner_id);
+        assert_eq!(fetched.size, 250);
+        assert_eq!(fetched.expires_at, 2_000);
+        assert!(!fetched.uploaded);
+
+        db.mark_attachment_uploaded(retained.id)
+            .await
+            .expect("Failed to mark attachment uploaded");
+        assert!(
+            db.get_attachment(retained.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .uploaded
+        );
 
-        // Verify the token was updated
-        let updated_account = db
-            .fetch_account(account_id)
+        assert_eq!(db.get_storage_usage(owner_id).await.unwrap(), 350);
+        assert_eq!(db.get_storage_usage(Uuid::new_v4()).await.unwrap(), 0);
+
+        let expired_attachments = db
+            .get_expired_attachments(1_000)
             .await
-            .expect("Failed to fetch updated account")
-            .expect("Account should exist");
-        assert_eq!(updated_account.apns_token, Some(new_token));
+            .expect("Failed to get expired attachments");
+        assert_eq!(expired_attachments.len(), 1);
+        assert_eq!(expired_attachments[0].id, expired.id);
 
-        // Test updating non-existent account
-        let non_existent_id = Uuid::new_v4();
-        let result = db
-            .update_apns_token(non_existent_id, vec![5, 6, 7, 8])
-            .await;
-        assert!(result.is_err());
-        if let Err(AccountDatabaseError::NotFound(id)) = result {
-            assert_eq!(id, non_existent_id.to_string());
-        } else {
-            panic!("Expected AccountDatabaseError::NotFound");
-        }
+        db.remove_attachment(expired.id)
+            .await
+            .expect("Failed to remove attachment");
+        assert!(db.get_attachment(expired.id).await.unwrap().is_none());
+        assert_eq!(db.get_storage_usage(owner_id).await.unwrap(), 250);
     }
 
     #[tokio::test]
-    async fn test_key_database_operations() {
+    async fn test_block_database_operations() {
         let pool = create_test_pool().await;
         let db = SqliteDatabase::new(pool);
         db.init().await.expect("Failed to initialize database");
 
-        let account_id = Uuid::new_v4();
-
-        // Create a test key bundle
-        let identity_signing_key = SigningKey::new_ed25519();
-        let identity_key = identity_signing_key.verifying_key();
-        let signed_prekey = SigningKey::new_ed25519().verifying_key();
-        let signed_prekey_signature = identity_signing_key
-            .sign(signed_prekey.to_spki_der().unwrap())
-            .unwrap();
-        let prekey1 = Prekey {
-            key_idx: 1,
-            key: SigningKey::new_ed25519().verifying_key(),
-        };
-        let prekey2 = Prekey {
-            key_idx: 2,
-            key: SigningKey::new_ed25519().verifying_key(),
-        };
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
 
-        let key_bundle = KeyBundle {
-            identity_key: identity_key.clone(),
-            signed_prekey: signed_prekey.clone(),
-            signed_prekey_signature: signed_prekey_signature.clone(),
-            prekeys: vec![prekey1.clone(), prekey2.clone()],
-        };
+        db.insert_block(
+            alice_id,
+            BlockedAccount {
+                account_id: mallory_id,
+                blocked_at: 1000,
+            },
+        )
+        .await
+        .expect("Failed to insert block");
+        db.insert_block(
+            alice_id,
+            BlockedAccount {
+                account_id: bob_id,
+                blocked_at: 2000,
+            },
+        )
+        .await
+        .expect("Failed to insert block");
+        db.insert_block(
+            bob_id,
+            BlockedAccount {
+                account_id: mallory_id,
+                blocked_at: 3000,
+            },
+        )
+        .await
+        .expect("Failed to insert block");
+
+        // Blocking again keeps the original block
+        db.insert_block(
+            alice_id,
+            BlockedAccount {
+                account_id: mallory_id,
+                blocked_at: 4000,
+            },
+        )
+        .await
+        .expect("Failed to insert block");
 
-        // Test inserting a key bundle
-        db.insert_keybundle(account_id, key_bundle)
+        let blocked = db
+            .get_blocked_accounts(alice_id)
             .await
-            .expect("Failed to insert key bundle");
-
-        // Test retrieving the key bundle
-        let retrieved_bundle = db
-            .get_keybundle(account_id)
+            .expect("Failed to get blocked accounts");
+        assert_eq!(blocked.len(), 2);
+        assert_eq!(blocked[0].account_id, mallory_id);
+        assert_eq!(blocked[0].blocked_at, 1000);
+        assert_eq!(blocked[1].account_id, bob_id);
+
+        let mut blocking = db
+            .get_blocking_account_ids(mallory_id)
             .await
-            .expect("Failed to get key bundle")
-            .expect("Key bundle should exist");
+            .expect("Failed to get blocking accounts");
+        blocking.sort();
+        let mut expected = vec![alice_id, bob_id];
+        expected.sort();
+        assert_eq!(blocking, expected);
 
-        // Directly compare the cryptographic types
-        assert_eq!(&retrieved_bundle.identity_key, &identity_key);
-        assert_eq!(retrieved_bundle.signed_prekey, signed_prekey);
+        assert!(db.is_blocked(alice_id, mallory_id).await.unwrap());
+        assert!(!db.is_blocked(mallory_id, alice_id).await.unwrap());
+
+        db.remove_block(alice_id, mallory_id)
+            .await
+            .expect("Failed to remove block");
+        assert!(!db.is_blocked(alice_id, mallory_id).await.unwrap());
+        assert_eq!(db.get_blocked_accounts(alice_id).await.unwrap().len(), 1);
         assert_eq!(
-            retrieved_bundle.signed_prekey_signature,
-            signed_prekey_signature
+            db.get_blocking_account_ids(mallory_id).await.unwrap(),
+            vec![bob_id]
         );
-        assert_eq!(retrieved_bundle.prekeys.len(), 2);
-        assert_eq!(retrieved_bundle.prekeys[0], prekey1);
-        assert_eq!(retrieved_bundle.prekeys[1], prekey2);
+    }
 
-        // Test adding additional prekeys
-        let prekey3 = Prekey {
-            key_idx: 3,
-            key: SigningKey::new_ed25519().verifying_key(),
-        };
-        let prekey4 = Prekey {
-            key_idx: 4,
-            key: SigningKey::new_ed25519().verifying_key(),
+    #[tokio::test]
+    async fn test_group_database_operations() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
+        db.init().await.expect("Failed to initialize database");
+
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let group = Group {
+            id: Uuid::new_v4(),
+            created_by: alice_id,
+            created_at: 1000,
         };
+        let members = vec![
+            GroupMember {
+                account_id: alice_id,
+                role: GroupRole::Admin,
+                joined_at: 1000,
+            },
+            GroupMember {
+                account_id: bob_id,
+                role: GroupRole::Member,
+                joined_at: 1000,
+            },
+        ];
+
+        db.insert_group(group.clone(), members)
+            .await
+            .expect("Failed to insert group");
 
-        let additional_prekeys = vec![prekey3.clone(), prekey4.clone()];
+        let fetched = db
+            .get_group(group.id)
+            .await
+            .expect("Failed to get group")
+            .expect("Group not found");
+        assert_eq!(fetched.created_by, alice_id);
+        assert!(db.get_group(Uuid::new_v4()).await.unwrap().is_none());
+
+        // Members keep the order they joined in
+        let members = db
+            .get_members(group.id)
+            .await
+            .expect("Failed to get members");
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].account_id, alice_id);
+        assert_eq!(members[0].role, GroupRole::Admin);
+        assert_eq!(members[1].account_id, bob_id);
+
+        let groups = db
+            .get_groups_for_account(bob_id)
+            .await
+            .expect("Failed to get groups");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].id, group.id);
+
+        // Upserting an existing member only changes the role
+        db.upsert_member(
+            group.id,
+            GroupMember {
+                account_id: bob_id,
+                role: GroupRole::Admin,
+                joined_at: 2000,
+            },
+        )
+        .await
+        .expect("Failed to update member");
+        let members = db.get_members(group.id).await.unwrap();
+        assert_eq!(members[1].role, GroupRole::Admin);
+        assert_eq!(members[1].joined_at, 1000);
 
-        db.add_prekeys(account_id, additional_prekeys)
+        db.remove_member(group.id, bob_id)
             .await
-            .expect("Failed to add prekeys");
+            .expect("Failed to remove member");
+        assert!(db.get_groups_for_account(bob_id).await.unwrap().is_empty());
 
-        // Verify the updated bundle has all prekeys
-        let updated_bundle = db
-            .get_keybundle(account_id)
+        db.remove_group(group.id)
             .await
-            .expect("Failed to get updated key bundle")
-            .expect("Updated key bundle should exist");
+            .expect("Failed to remove group");
+        assert!(db.get_group(group.id).await.unwrap().is_none());
+        assert!(db.get_members(group.id).await.unwrap().is_empty());
+    }
 
-        // Check the number of prekeys
-        assert_eq!(updated_bundle.prekeys.len(), 4);
-        assert_eq!(updated_bundle.prekeys[2], prekey3);
-        assert_eq!(updated_bundle.prekeys[3], prekey4);
+    #[tokio::test]
+    async fn test_profiles_migration() {
+        let pool = create_test_pool().await;
+        let db = SqliteDatabase::new(pool);
 
-        // Test adding prekeys for non-existent user
-        let non_existent_account_id = Uuid::new_v4();
-        let result = db
-            .add_prekeys(
-                non_existent_account_id,
-                vec![Prekey {
-                    key_idx: 1,
-                    key: SigningKey::new_ed25519().verifying_key(),
-                }],
+        // profiles as created before delivery access keys existed
+        sqlx::query(
+            r#"
+            CREATE TABLE profiles (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL UNIQUE,
+                username TEXT NOT NULL UNIQUE,
+                display_name TEXT,
+                profile_picture_url TEXT,
+                updated_at INTEGER NOT NULL
             )
-            .await;
+            "#,
+        )
+        .execute(&db.pool)
+        .await
+        .expect("Failed to create baseline profiles table");
 
-        assert!(result.is_err());
-        if let Err(KeyError::NotFound(user)) = result {
-            assert_eq!(user, non_existent_account_id.to_string());
-        } else {
-            panic!("Expected KeyError::NotFound");
-        }
+        let existing = Profile::new(Uuid::new_v4(), "existinguser".to_string());
+        sqlx::query(
+            r#"
+            INSERT INTO profiles (id, account_id, username, updated_at)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(existing.id.to_string())
+        .bind(existing.account_id.to_string())
+        .bind(&existing.username)
+        .bind(existing.updated_at as i64)
+        .execute(&db.pool)
+        .await
+        .expect("Failed to insert baseline profile");
 
-        // Test getting a non-existent key bundle
-        let non_existent_bundle = db
-            .get_keybundle(non_existent_account_id)
+        // Migrating twice must not fail
+        db.init().await.expect("Failed to initialize database");
+        db.init()
             .await
-            .expect("get_keybundle should not fail for non-existent user");
+            .expect("Failed to initialize database again");
 
-        assert!(non_existent_bundle.is_none(), "Bundle should not exist");
+        let fetched = db
+            .get_profile_by_account_id(existing.account_id)
+            .await
+            .expect("Failed to get profile")
+            .expect("Profile should survive the migration");
+        assert_eq!(fetched.username, existing.username);
+        assert_eq!(fetched.delivery_access_key, None);
+
+        let updated = Profile {
+            delivery_access_key: Some(vec![7; 16]),
+            ..fetched
+        };
+        db.upsert_profile(updated)
+            .await
+            .expect("Failed to update profile");
+        let fetched = db
+            .get_profile_by_username(&existing.username)
+            .await
+            .expect("Failed to get profile")
+            .expect("Profile should exist");
+        assert_eq!(fetched.delivery_access_key, Some(vec![7; 16]));
     }
 
     #[tokio::test]
@@ -729,6 +3813,7 @@ mod tests {
             username: username.to_string(),
             display_name: Some("Test User".to_string()),
             profile_picture_url: Some("https://example.com/image.jpg".to_string()),
+            delivery_access_key: Some(vec![7; 16]),
             updated_at: 1234567890,
         };
 
@@ -750,6 +3835,10 @@ mod tests {
             fetched_by_id.profile_picture_url,
             profile.profile_picture_url
         );
+        assert_eq!(
+            fetched_by_id.delivery_access_key,
+            profile.delivery_access_key
+        );
         assert_eq!(fetched_by_id.updated_at, profile.updated_at);
 
         // Test get_profile_by_account_id
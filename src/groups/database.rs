@@ -0,0 +1,31 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use super::{
+    entities::{Group, GroupMember},
+    error::GroupError,
+};
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait GroupDatabase: Send + Sync {
+    /// Stores a new group together with its initial members
+    async fn insert_group(&self, group: Group, members: Vec<GroupMember>)
+    -> Result<(), GroupError>;
+
+    async fn get_group(&self, group_id: Uuid) -> Result<Option<Group>, GroupError>;
+
+    /// Returns all groups the account is a member of
+    async fn get_groups_for_account(&self, account_id: Uuid) -> Result<Vec<Group>, GroupError>;
+
+    /// Returns the members of a group in the order they joined
+    async fn get_members(&self, group_id: Uuid) -> Result<Vec<GroupMember>, GroupError>;
+
+    /// Adds a member or updates the role of an existing one
+    async fn upsert_member(&self, group_id: Uuid, member: GroupMember) -> Result<(), GroupError>;
+
+    async fn remove_member(&self, group_id: Uuid, account_id: Uuid) -> Result<(), GroupError>;
+
+    /// Removes a group and all of its members
+    async fn remove_group(&self, group_id: Uuid) -> Result<(), GroupError>;
+}
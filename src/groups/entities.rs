@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// A group of accounts that messages can be fanned out to.
+/// Names, avatars and the like are shared end-to-end encrypted between the members,
+/// the server only knows who belongs to the group.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    pub id: Uuid,
+    /// Account that created the group
+    pub created_by: Uuid,
+    /// Creation timestamp (epoch milliseconds)
+    pub created_at: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    /// May add and remove members and change their roles
+    Admin,
+    Member,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMember {
+    pub account_id: Uuid,
+    pub role: GroupRole,
+    /// Time the account joined the group (epoch milliseconds)
+    pub joined_at: u64,
+}
+
+/// A group including its current members
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupDetails {
+    #[serde(flatten)]
+    pub group: Group,
+    pub members: Vec<GroupMember>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum GroupUpdateKind {
+    Created,
+    MembersAdded,
+    MemberRemoved,
+    RoleChanged,
+}
+
+/// Tells the members of a group that its membership changed
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupUpdate {
+    pub group_id: Uuid,
+    pub kind: GroupUpdateKind,
+    /// The members that were affected by the change
+    pub account_ids: Vec<Uuid>,
+    /// The new role, if roles were changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<GroupRole>,
+    /// The member who made the change
+    pub updated_by: Uuid,
+    /// Server timestamp (epoch milliseconds)
+    pub timestamp: u64,
+}
@@ -0,0 +1,68 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{account::database::AccountDatabaseError, messages::error::MessagingError};
+
+#[derive(Debug, Error)]
+pub enum GroupError {
+    #[error("Group not found")]
+    NotFound,
+
+    #[error("Account {0} is not a member of the group")]
+    NotMember(Uuid),
+
+    #[error("Account {0} is not an admin of the group")]
+    NotAdmin(Uuid),
+
+    #[error("Account {0} not found")]
+    AccountNotFound(Uuid),
+
+    #[error("The last admin cannot leave or be demoted while other members remain")]
+    LastAdmin,
+
+    #[error("Groups are limited to {0} members")]
+    TooManyMembers(usize),
+
+    #[error("Database error: {0}")]
+    Database(String),
+
+    #[error("Sending failed: {0}")]
+    SendingFailed(String),
+
+    #[error(transparent)]
+    Messaging(#[from] MessagingError),
+}
+
+impl From<AccountDatabaseError> for GroupError {
+    fn from(err: AccountDatabaseError) -> Self {
+        match err {
+            AccountDatabaseError::NotFound(id) => match Uuid::parse_str(&id) {
+                Ok(id) => Self::AccountNotFound(id),
+                Err(_) => Self::Database(format!("Account {} not found", id)),
+            },
+            AccountDatabaseError::OperationFailed => {
+                Self::Database("Account database operation failed".to_string())
+            }
+        }
+    }
+}
+
+impl IntoResponse for GroupError {
+    fn into_response(self) -> Response {
+        error!("{}", self);
+        let status = match &self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::NotMember(_) | Self::NotAdmin(_) => StatusCode::FORBIDDEN,
+            Self::AccountNotFound(_) | Self::TooManyMembers(_) => StatusCode::BAD_REQUEST,
+            Self::LastAdmin => StatusCode::CONFLICT,
+            Self::Messaging(e) => e.status_code(),
+            Self::Database(_) | Self::SendingFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        status.into_response()
+    }
+}
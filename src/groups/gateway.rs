@@ -0,0 +1,15 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use super::{entities::GroupUpdate, error::GroupError};
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait GroupGateway: Send + Sync {
+    /// Pushes a membership change to a member of the group
+    async fn send_group_update(
+        &self,
+        account_id: Uuid,
+        update: &GroupUpdate,
+    ) -> Result<(), GroupError>;
+}
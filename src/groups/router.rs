@@ -0,0 +1,249 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    middleware::from_fn_with_state,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use super::{
+    entities::{Group, GroupDetails, GroupRole},
+    error::GroupError,
+};
+use crate::{
+    account::{auth::middleware::require_auth, entities::Account},
+    messages::entities::{BatchMessageResult, DoubleRatchetMessage},
+    startup::AppContext,
+};
+
+const GROUPS_TAG: &str = "groups";
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateGroupRequest {
+    /// Accounts to add besides the creator, who becomes the group's admin
+    pub member_ids: Vec<Uuid>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AddMembersRequest {
+    pub account_ids: Vec<Uuid>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRoleRequest {
+    pub role: GroupRole,
+}
+
+/// The message is fanned out unchanged to all other members of the group
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SendGroupMessageRequest {
+    pub message: DoubleRatchetMessage,
+    /// Seconds until the message expires if undelivered.
+    /// Capped by the server's time to live.
+    pub ttl: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SendGroupMessageResponse {
+    /// Results for each member the message was fanned out to
+    pub results: Vec<BatchMessageResult>,
+}
+
+pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
+    OpenApiRouter::new()
+        .routes(routes!(create_group, get_groups))
+        .routes(routes!(get_group))
+        .routes(routes!(add_members))
+        .routes(routes!(remove_member))
+        .routes(routes!(set_role))
+        .routes(routes!(send_group_message))
+        .layer(from_fn_with_state(context.clone(), require_auth))
+}
+
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body = CreateGroupRequest,
+    responses(
+        (status = 200, description = "Group created successfully", body = GroupDetails),
+        (status = 400, description = "Unknown account or too many members"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn create_group(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<CreateGroupRequest>,
+) -> Result<Json<GroupDetails>, GroupError> {
+    context
+        .group_service
+        .create_group(account.id, req.member_ids)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    responses(
+        (status = 200, description = "Groups of the account fetched successfully", body = Vec<Group>),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn get_groups(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<Vec<Group>>, GroupError> {
+    context.group_service.get_groups(account.id).await.map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/{group_id}",
+    responses(
+        (status = 200, description = "Group fetched successfully", body = GroupDetails),
+        (status = 403, description = "Not a member of the group"),
+        (status = 404, description = "Group not found"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn get_group(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(group_id): Path<Uuid>,
+) -> Result<Json<GroupDetails>, GroupError> {
+    context
+        .group_service
+        .get_group(group_id, account.id)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/{group_id}/members",
+    request_body = AddMembersRequest,
+    responses(
+        (status = 200, description = "Members added successfully", body = GroupDetails),
+        (status = 400, description = "Unknown account or too many members"),
+        (status = 403, description = "Not an admin of the group"),
+        (status = 404, description = "Group not found"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn add_members(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(group_id): Path<Uuid>,
+    Json(req): Json<AddMembersRequest>,
+) -> Result<Json<GroupDetails>, GroupError> {
+    context
+        .group_service
+        .add_members(group_id, account.id, req.account_ids)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{group_id}/members/{account_id}",
+    responses(
+        (status = 204, description = "Member removed successfully"),
+        (status = 403, description = "Not allowed to remove the member"),
+        (status = 404, description = "Group not found"),
+        (status = 409, description = "The last admin cannot leave while other members remain"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn remove_member(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path((group_id, account_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, GroupError> {
+    context
+        .group_service
+        .remove_member(group_id, account.id, account_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    put,
+    path = "/{group_id}/members/{account_id}/role",
+    request_body = SetRoleRequest,
+    responses(
+        (status = 200, description = "Role changed successfully", body = GroupDetails),
+        (status = 403, description = "Not an admin of the group"),
+        (status = 404, description = "Group not found"),
+        (status = 409, description = "The last admin cannot be demoted"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn set_role(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path((group_id, account_id)): Path<(Uuid, Uuid)>,
+    Json(req): Json<SetRoleRequest>,
+) -> Result<Json<GroupDetails>, GroupError> {
+    context
+        .group_service
+        .set_role(group_id, account.id, account_id, req.role)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/{group_id}/send",
+    request_body = SendGroupMessageRequest,
+    responses(
+        (status = 200, description = "Message fanned out, see the results for the individual members", body = SendGroupMessageResponse),
+        (status = 403, description = "Not a member of the group"),
+        (status = 404, description = "Group not found"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = GROUPS_TAG
+)]
+async fn send_group_message(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(group_id): Path<Uuid>,
+    Json(req): Json<SendGroupMessageRequest>,
+) -> Result<Json<SendGroupMessageResponse>, GroupError> {
+    let recipient_ids = context
+        .group_service
+        .get_message_recipients(group_id, account.id)
+        .await?;
+
+    let results = context
+        .messaging_service
+        .send_group_message(
+            account.id,
+            group_id,
+            recipient_ids.clone(),
+            req.message,
+            req.ttl.map(Duration::from_secs),
+        )
+        .await?
+        .into_iter()
+        .zip(recipient_ids)
+        .map(|(result, recipient_id)| BatchMessageResult::new(recipient_id, result))
+        .collect();
+
+    Ok(Json(SendGroupMessageResponse { results }))
+}
@@ -0,0 +1,546 @@
+use std::sync::Arc;
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+use super::{
+    database::GroupDatabase,
+    entities::{Group, GroupDetails, GroupMember, GroupRole, GroupUpdate, GroupUpdateKind},
+    error::GroupError,
+    gateway::GroupGateway,
+};
+use crate::account::database::AccountDatabase;
+
+/// Upper bound for the number of members per group, as every group message
+/// is fanned out to each of them
+pub const MAX_GROUP_MEMBERS: usize = 256;
+
+pub struct GroupService<D, A, G>
+where
+    D: GroupDatabase,
+    A: AccountDatabase,
+    G: GroupGateway,
+{
+    group_db: Arc<D>,
+    account_db: Arc<A>,
+    group_gateway: Arc<G>,
+}
+
+impl<D, A, G> GroupService<D, A, G>
+where
+    D: GroupDatabase,
+    A: AccountDatabase,
+    G: GroupGateway,
+{
+    pub fn new(group_db: Arc<D>, account_db: Arc<A>, group_gateway: Arc<G>) -> Self {
+        Self {
+            group_db,
+            account_db,
+            group_gateway,
+        }
+    }
+
+    /// Creates a group with the creator as its first admin
+    #[instrument(skip(self, member_ids))]
+    pub async fn create_group(
+        &self,
+        creator_id: Uuid,
+        member_ids: Vec<Uuid>,
+    ) -> Result<GroupDetails, GroupError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+
+        let mut members = vec![GroupMember {
+            account_id: creator_id,
+            role: GroupRole::Admin,
+            joined_at: now,
+        }];
+        for account_id in member_ids {
+            if members.iter().any(|member| member.account_id == account_id) {
+                continue;
+            }
+            self.ensure_account_exists(account_id).await?;
+            members.push(GroupMember {
+                account_id,
+                role: GroupRole::Member,
+                joined_at: now,
+            });
+        }
+        if members.len() > MAX_GROUP_MEMBERS {
+            return Err(GroupError::TooManyMembers(MAX_GROUP_MEMBERS));
+        }
+
+        let group = Group {
+            id: Uuid::new_v4(),
+            created_by: creator_id,
+            created_at: now,
+        };
+        self.group_db
+            .insert_group(group.clone(), members.clone())
+            .await?;
+
+        let account_ids = members.iter().map(|member| member.account_id).collect();
+        self.notify_members(
+            &members,
+            None,
+            GroupUpdate {
+                group_id: group.id,
+                kind: GroupUpdateKind::Created,
+                account_ids,
+                role: None,
+                updated_by: creator_id,
+                timestamp: now,
+            },
+        )
+        .await;
+
+        Ok(GroupDetails { group, members })
+    }
+
+    /// Returns all groups the account is a member of
+    pub async fn get_groups(&self, account_id: Uuid) -> Result<Vec<Group>, GroupError> {
+        self.group_db.get_groups_for_account(account_id).await
+    }
+
+    /// Returns a group and its members, if the account is one of them
+    pub async fn get_group(
+        &self,
+        group_id: Uuid,
+        account_id: Uuid,
+    ) -> Result<GroupDetails, GroupError> {
+        let (group, members) = self.load_group(group_id).await?;
+        require_member(&members, account_id)?;
+
+        Ok(GroupDetails { group, members })
+    }
+
+    /// Adds accounts to a group. Only admins may add members,
+    /// accounts that are members already are skipped.
+    #[instrument(skip(self, account_ids))]
+    pub async fn add_members(
+        &self,
+        group_id: Uuid,
+        admin_id: Uuid,
+        account_ids: Vec<Uuid>,
+    ) -> Result<GroupDetails, GroupError> {
+        let (group, mut members) = self.load_group(group_id).await?;
+        require_admin(&members, admin_id)?;
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let mut new_members = Vec::new();
+        for account_id in account_ids {
+            if members.iter().any(|member| member.account_id == account_id)
+                || new_members
+                    .iter()
+                    .any(|member: &GroupMember| member.account_id == account_id)
+            {
+                continue;
+            }
+            self.ensure_account_exists(account_id).await?;
+            new_members.push(GroupMember {
+                account_id,
+                role: GroupRole::Member,
+                joined_at: now,
+            });
+        }
+        if new_members.is_empty() {
+            return Ok(GroupDetails { group, members });
+        }
+        if members.len() + new_members.len() > MAX_GROUP_MEMBERS {
+            return Err(GroupError::TooManyMembers(MAX_GROUP_MEMBERS));
+        }
+
+        for member in &new_members {
+            self.group_db
+                .upsert_member(group_id, member.clone())
+                .await?;
+        }
+
+        let account_ids = new_members.iter().map(|member| member.account_id).collect();
+        members.extend(new_members);
+        self.notify_members(
+            &members,
+            None,
+            GroupUpdate {
+                group_id,
+                kind: GroupUpdateKind::MembersAdded,
+                account_ids,
+                role: None,
+                updated_by: admin_id,
+                timestamp: now,
+            },
+        )
+        .await;
+
+        Ok(GroupDetails { group, members })
+    }
+
+    /// Removes a member from a group. Members may remove themselves,
+    /// only admins may remove others. The group is deleted once its last member left.
+    #[instrument(skip(self))]
+    pub async fn remove_member(
+        &self,
+        group_id: Uuid,
+        actor_id: Uuid,
+        account_id: Uuid,
+    ) -> Result<(), GroupError> {
+        let (_, mut members) = self.load_group(group_id).await?;
+        if actor_id == account_id {
+            require_member(&members, actor_id)?;
+        } else {
+            require_admin(&members, actor_id)?;
+        }
+
+        let removed_member = require_member(&members, account_id)?.clone();
+        members.retain(|member| member.account_id != account_id);
+
+        if members.is_empty() {
+            self.group_db.remove_group(group_id).await?;
+            return Ok(());
+        }
+        if removed_member.role == GroupRole::Admin && !has_admin(&members) {
+            return Err(GroupError::LastAdmin);
+        }
+
+        self.group_db.remove_member(group_id, account_id).await?;
+
+        self.notify_members(
+            &members,
+            Some(account_id),
+            GroupUpdate {
+                group_id,
+                kind: GroupUpdateKind::MemberRemoved,
+                account_ids: vec![account_id],
+                role: None,
+                updated_by: actor_id,
+                timestamp: chrono::Utc::now().timestamp_millis() as u64,
+            },
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Promotes or demotes a member. Only admins may change roles.
+    #[instrument(skip(self))]
+    pub async fn set_role(
+        &self,
+        group_id: Uuid,
+        admin_id: Uuid,
+        account_id: Uuid,
+        role: GroupRole,
+    ) -> Result<GroupDetails, GroupError> {
+        let (group, mut members) = self.load_group(group_id).await?;
+        require_admin(&members, admin_id)?;
+
+        let member = members
+            .iter_mut()
+            .find(|member| member.account_id == account_id)
+            .ok_or(GroupError::NotMember(account_id))?;
+        if member.role == role {
+            return Ok(GroupDetails { group, members });
+        }
+        member.role = role;
+        let member = member.clone();
+
+        if !has_admin(&members) {
+            return Err(GroupError::LastAdmin);
+        }
+
+        self.group_db.upsert_member(group_id, member).await?;
+
+        self.notify_members(
+            &members,
+            None,
+            GroupUpdate {
+                group_id,
+                kind: GroupUpdateKind::RoleChanged,
+                account_ids: vec![account_id],
+                role: Some(role),
+                updated_by: admin_id,
+                timestamp: chrono::Utc::now().timestamp_millis() as u64,
+            },
+        )
+        .await;
+
+        Ok(GroupDetails { group, members })
+    }
+
+    /// Returns the accounts a message of the sender to the group is fanned out to
+    pub async fn get_message_recipients(
+        &self,
+        group_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<Vec<Uuid>, GroupError> {
+        let (_, members) = self.load_group(group_id).await?;
+        require_member(&members, sender_id)?;
+
+        Ok(members
+            .into_iter()
+            .map(|member| member.account_id)
+            .filter(|account_id| *account_id != sender_id)
+            .collect())
+    }
+
+    async fn load_group(&self, group_id: Uuid) -> Result<(Group, Vec<GroupMember>), GroupError> {
+        let group = self
+            .group_db
+            .get_group(group_id)
+            .await?
+            .ok_or(GroupError::NotFound)?;
+        let members = self.group_db.get_members(group_id).await?;
+        Ok((group, members))
+    }
+
+    async fn ensure_account_exists(&self, account_id: Uuid) -> Result<(), GroupError> {
+        self.account_db
+            .fetch_account(account_id)
+            .await?
+            .ok_or(GroupError::AccountNotFound(account_id))?;
+        Ok(())
+    }
+
+    /// Pushes the update to the members and, if given, to a member that just left.
+    /// Members that are not connected learn about changes when fetching the group.
+    async fn notify_members(
+        &self,
+        members: &[GroupMember],
+        removed_id: Option<Uuid>,
+        update: GroupUpdate,
+    ) {
+        let account_ids = members
+            .iter()
+            .map(|member| member.account_id)
+            .chain(removed_id);
+
+        for account_id in account_ids {
+            if let Err(e) = self
+                .group_gateway
+                .send_group_update(account_id, &update)
+                .await
+            {
+                debug!(
+                    "Could not push update of group {} to {}: {}",
+                    update.group_id, account_id, e
+                );
+            }
+        }
+    }
+}
+
+fn require_member(members: &[GroupMember], account_id: Uuid) -> Result<&GroupMember, GroupError> {
+    members
+        .iter()
+        .find(|member| member.account_id == account_id)
+        .ok_or(GroupError::NotMember(account_id))
+}
+
+fn require_admin(members: &[GroupMember], account_id: Uuid) -> Result<(), GroupError> {
+    match require_member(members, account_id)?.role {
+        GroupRole::Admin => Ok(()),
+        GroupRole::Member => Err(GroupError::NotAdmin(account_id)),
+    }
+}
+
+fn has_admin(members: &[GroupMember]) -> bool {
+    members.iter().any(|member| member.role == GroupRole::Admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        account::entities::Account, crypto::salted_hash::SaltedHash,
+        database::inmemory::InMemoryDatabase, groups::gateway::MockGroupGateway,
+    };
+
+    async fn create_accounts(db: &InMemoryDatabase, count: usize) -> Vec<Uuid> {
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            let account = Account {
+                id: Uuid::new_v4(),
+                auth_password_hash: SaltedHash::generate_from("password"),
+                apns_token: None,
+                gcm_token: None,
+            };
+            ids.push(account.id);
+            db.upsert_account(account).await.unwrap();
+        }
+        ids
+    }
+
+    fn silent_gateway() -> Arc<MockGroupGateway> {
+        let mut gateway = MockGroupGateway::new();
+        gateway.expect_send_group_update().returning(|_, _| Ok(()));
+        Arc::new(gateway)
+    }
+
+    #[tokio::test]
+    async fn test_create_group_and_fetch_recipients() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let ids = create_accounts(&db, 3).await;
+        let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+
+        // Every initial member is told about the new group
+        let mut gateway = MockGroupGateway::new();
+        gateway
+            .expect_send_group_update()
+            .withf(|_, update| update.kind == GroupUpdateKind::Created)
+            .times(3)
+            .returning(|_, _| Ok(()));
+
+        let service = GroupService::new(db.clone(), db.clone(), Arc::new(gateway));
+
+        let details = service
+            .create_group(alice, vec![bob, carol, bob])
+            .await
+            .expect("Failed to create group");
+        assert_eq!(details.members.len(), 3);
+        assert_eq!(details.members[0].account_id, alice);
+        assert_eq!(details.members[0].role, GroupRole::Admin);
+        assert_eq!(details.members[1].role, GroupRole::Member);
+
+        let recipients = service
+            .get_message_recipients(details.group.id, bob)
+            .await
+            .expect("Failed to get recipients");
+        assert_eq!(recipients, vec![alice, carol]);
+
+        let outsider = Uuid::new_v4();
+        assert!(matches!(
+            service.get_message_recipients(details.group.id, outsider).await,
+            Err(GroupError::NotMember(id)) if id == outsider
+        ));
+
+        let groups = service.get_groups(carol).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].id, details.group.id);
+    }
+
+    #[tokio::test]
+    async fn test_create_group_with_unknown_account_fails() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let ids = create_accounts(&db, 1).await;
+        let service = GroupService::new(db.clone(), db.clone(), silent_gateway());
+
+        let unknown = Uuid::new_v4();
+        let result = service.create_group(ids[0], vec![unknown]).await;
+        assert!(matches!(result, Err(GroupError::AccountNotFound(id)) if id == unknown));
+    }
+
+    #[tokio::test]
+    async fn test_only_admins_manage_members() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let ids = create_accounts(&db, 3).await;
+        let (alice, bob, carol) = (ids[0], ids[1], ids[2]);
+        let service = GroupService::new(db.clone(), db.clone(), silent_gateway());
+
+        let group_id = service
+            .create_group(alice, vec![bob])
+            .await
+            .unwrap()
+            .group
+            .id;
+
+        assert!(matches!(
+            service.add_members(group_id, bob, vec![carol]).await,
+            Err(GroupError::NotAdmin(_))
+        ));
+        assert!(matches!(
+            service.remove_member(group_id, bob, alice).await,
+            Err(GroupError::NotAdmin(_))
+        ));
+
+        let details = service
+            .add_members(group_id, alice, vec![carol])
+            .await
+            .unwrap();
+        assert_eq!(details.members.len(), 3);
+
+        let details = service
+            .set_role(group_id, alice, bob, GroupRole::Admin)
+            .await
+            .unwrap();
+        assert_eq!(details.members[1].role, GroupRole::Admin);
+
+        // Bob is an admin now and may remove Carol
+        service.remove_member(group_id, bob, carol).await.unwrap();
+        let details = service.get_group(group_id, alice).await.unwrap();
+        assert_eq!(details.members.len(), 2);
+        assert!(matches!(
+            service.get_group(group_id, carol).await,
+            Err(GroupError::NotMember(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_last_admin_cannot_leave() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let ids = create_accounts(&db, 2).await;
+        let (alice, bob) = (ids[0], ids[1]);
+        let service = GroupService::new(db.clone(), db.clone(), silent_gateway());
+
+        let group_id = service
+            .create_group(alice, vec![bob])
+            .await
+            .unwrap()
+            .group
+            .id;
+
+        assert!(matches!(
+            service.remove_member(group_id, alice, alice).await,
+            Err(GroupError::LastAdmin)
+        ));
+        assert!(matches!(
+            service
+                .set_role(group_id, alice, alice, GroupRole::Member)
+                .await,
+            Err(GroupError::LastAdmin)
+        ));
+
+        // Members may leave on their own, the group is gone once everyone left
+        service.remove_member(group_id, bob, bob).await.unwrap();
+        service.remove_member(group_id, alice, alice).await.unwrap();
+        assert!(matches!(
+            service.get_group(group_id, alice).await,
+            Err(GroupError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_removed_member_is_notified() {
+        let db = Arc::new(InMemoryDatabase::new());
+        let ids = create_accounts(&db, 2).await;
+        let (alice, bob) = (ids[0], ids[1]);
+
+        let mut gateway = MockGroupGateway::new();
+        gateway
+            .expect_send_group_update()
+            .withf(|_, update| update.kind == GroupUpdateKind::Created)
+            .returning(|_, _| Ok(()));
+        gateway
+            .expect_send_group_update()
+            .withf(move |account_id, update| {
+                *account_id == bob && update.kind == GroupUpdateKind::MemberRemoved
+            })
+            .times(1)
+            .returning(|account_id, _| Err(GroupError::SendingFailed(account_id.to_string())));
+        gateway
+            .expect_send_group_update()
+            .withf(move |account_id, update| {
+                *account_id == alice && update.kind == GroupUpdateKind::MemberRemoved
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let service = GroupService::new(db.clone(), db.clone(), Arc::new(gateway));
+
+        let group_id = service
+            .create_group(alice, vec![bob])
+            .await
+            .unwrap()
+            .group
+            .id;
+
+        // Failing to reach a member does not fail the change
+        service.remove_member(group_id, alice, bob).await.unwrap();
+    }
+}
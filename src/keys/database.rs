@@ -3,7 +3,7 @@ use async_trait::async_trait;
 use uuid::Uuid;
 
 use super::{
-    entities::{KeyBundle, Prekey},
+    entities::{KemPrekey, KeyBundle, Prekey, PreviousSignedPrekey, SignedPrekey},
     error::KeyError,
 };
 
@@ -18,4 +18,42 @@ pub trait KeyDatabase: Send + Sync {
     async fn get_keybundle(&self, account_id: Uuid) -> Result<Option<KeyBundle>, KeyError>;
 
     async fn add_prekeys(&self, account_id: Uuid, prekeys: Vec<Prekey>) -> Result<(), KeyError>;
+
+    /// Returns the key bundle handed to an initiator. Its only one-time prekey and
+    /// one-time KEM prekey, the unused ones with the lowest index and ID, are removed
+    /// in the same transaction, so that no two initiators are handed the same ones.
+    /// The last-resort KEM prekey is never taken.
+    async fn take_prekey(&self, account_id: Uuid) -> Result<Option<KeyBundle>, KeyError>;
+
+    async fn add_kem_prekeys(
+        &self,
+        account_id: Uuid,
+        kem_prekeys: Vec<KemPrekey>,
+    ) -> Result<(), KeyError>;
+
+    /// Returns how many unused one-time prekeys are left
+    async fn count_prekeys(&self, account_id: Uuid) -> Result<usize, KeyError>;
+
+    /// Replaces the signed prekey of the bundle. The current one is kept as previous
+    /// signed prekey, replaced at `replaced_at` (epoch milliseconds).
+    async fn rotate_signed_prekey(
+        &self,
+        account_id: Uuid,
+        signed_prekey: SignedPrekey,
+        replaced_at: u64,
+    ) -> Result<(), KeyError>;
+
+    /// Returns the signed prekeys that were rotated out, oldest first
+    async fn get_previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<PreviousSignedPrekey>, KeyError>;
+
+    /// Forgets the previous signed prekeys replaced at or before `replaced_before`
+    /// (epoch milliseconds)
+    async fn remove_previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+        replaced_before: u64,
+    ) -> Result<(), KeyError>;
 }
@@ -1,8 +1,9 @@
-use std::fmt::Display;
+use std::{collections::HashSet, fmt::Display};
 
 use anyhow::{Result, anyhow};
 use prism_client::{Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
 use utoipa::ToSchema;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
@@ -18,23 +19,82 @@ impl Display for Prekey {
     }
 }
 
+/// Length of an ML-KEM-768 encapsulation key: three encoded polynomials
+/// of 384 bytes each, followed by a 32 byte seed
+const ML_KEM_768_ENCAPSULATION_KEY_LEN: usize = 1184;
+const ML_KEM_SEED_LEN: usize = 32;
+/// Modulus every coefficient of an encapsulation key must be reduced by
+const ML_KEM_Q: u16 = 3329;
+
+/// A post-quantum KEM (ML-KEM-768) pre-key for PQXDH, signed by the identity key
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KemPrekey {
+    pub key_id: u64,
+    /// Encoded ML-KEM-768 encapsulation key
+    #[serde_as(as = "Base64")]
+    #[schema(value_type = String)]
+    pub key: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl KemPrekey {
+    pub fn verify(&self, identity_key: &VerifyingKey) -> Result<()> {
+        if !is_ml_kem_768_encapsulation_key(&self.key) {
+            return Err(anyhow!(
+                "KEM prekey {} is not an ML-KEM-768 encapsulation key",
+                self.key_id
+            ));
+        }
+        identity_key.verify_signature(&self.key, &self.signature)?;
+        Ok(())
+    }
+}
+
+/// Performs the input check of FIPS 203: the key must have the expected length
+/// and all of its 12 bit coefficients must be reduced modulo q
+fn is_ml_kem_768_encapsulation_key(key: &[u8]) -> bool {
+    if key.len() != ML_KEM_768_ENCAPSULATION_KEY_LEN {
+        return false;
+    }
+    key[..ML_KEM_768_ENCAPSULATION_KEY_LEN - ML_KEM_SEED_LEN]
+        .chunks_exact(3)
+        .all(|bytes| {
+            let first = u16::from(bytes[0]) | (u16::from(bytes[1] & 0x0f) << 8);
+            let second = u16::from(bytes[1] >> 4) | (u16::from(bytes[2]) << 4);
+            first < ML_KEM_Q && second < ML_KEM_Q
+        })
+}
+
 /// The complete key bundle contains the long-term identity key,
 /// the signed pre-key (with its signature), and a list of one-time pre-keys.
+/// For PQXDH, it also carries a signed last-resort KEM pre-key
+/// and a list of signed one-time KEM pre-keys.
 #[derive(Clone, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct KeyBundle {
     pub identity_key: VerifyingKey,
+    /// Identifier of the signed pre-key, referenced by the headers of handshake messages
+    #[serde(default)]
+    pub signed_prekey_id: u64,
     pub signed_prekey: VerifyingKey,
     pub signed_prekey_signature: Signature,
     pub prekeys: Vec<Prekey>,
+    /// Used when no one-time KEM pre-key is left, it is never used up
+    #[serde(default)]
+    pub last_resort_kem_prekey: Option<KemPrekey>,
+    #[serde(default)]
+    pub kem_prekeys: Vec<KemPrekey>,
 }
 
 impl KeyBundle {
     pub fn verify(&self) -> Result<()> {
-        // Ensure signature is signed_prekey signed by identity_key
-        let msg = self.signed_prekey.to_spki_der()?;
-        self.identity_key
-            .verify_signature(&msg, &self.signed_prekey_signature)?;
+        verify_signed_prekey(
+            &self.identity_key,
+            &self.signed_prekey,
+            &self.signed_prekey_signature,
+        )?;
         // Ensure prekeys have no duplicate IDs
         for prekey in &self.prekeys {
             if self
@@ -47,6 +107,142 @@ impl KeyBundle {
                 return Err(anyhow!("Duplicate prekey ID"));
             }
         }
-        Ok(())
+        verify_kem_prekeys(
+            &self.identity_key,
+            self.last_resort_kem_prekey.iter().chain(&self.kem_prekeys),
+        )
+    }
+}
+
+/// A signed pre-key replacing the current one of a key bundle
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedPrekey {
+    pub key_id: u64,
+    pub key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl SignedPrekey {
+    pub fn verify(&self, identity_key: &VerifyingKey) -> Result<()> {
+        verify_signed_prekey(identity_key, &self.key, &self.signature)
+    }
+}
+
+/// A signed pre-key that was rotated out. It is kept for a grace period,
+/// so that handshakes started with it can still be completed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviousSignedPrekey {
+    pub key_id: u64,
+    pub key: VerifyingKey,
+    /// When the pre-key was rotated out (epoch milliseconds)
+    pub replaced_at: u64,
+}
+
+/// Ensures the KEM pre-keys were signed by the identity key and have no duplicate IDs
+pub fn verify_kem_prekeys<'a>(
+    identity_key: &VerifyingKey,
+    kem_prekeys: impl IntoIterator<Item = &'a KemPrekey>,
+) -> Result<()> {
+    let mut key_ids = HashSet::new();
+    for kem_prekey in kem_prekeys {
+        if !key_ids.insert(kem_prekey.key_id) {
+            return Err(anyhow!("Duplicate KEM prekey ID"));
+        }
+        kem_prekey.verify(identity_key)?;
+    }
+    Ok(())
+}
+
+/// Ensures the signed pre-key was signed by the identity key
+fn verify_signed_prekey(
+    identity_key: &VerifyingKey,
+    signed_prekey: &VerifyingKey,
+    signature: &Signature,
+) -> Result<()> {
+    let msg = signed_prekey.to_spki_der()?;
+    identity_key.verify_signature(&msg, signature)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use prism_client::SigningKey;
+
+    use super::*;
+
+    fn kem_prekey(identity_signing_key: &SigningKey, key_id: u64, key: Vec<u8>) -> KemPrekey {
+        let signature = identity_signing_key.sign(&key).unwrap();
+        KemPrekey {
+            key_id,
+            key,
+            signature,
+        }
+    }
+
+    fn encapsulation_key(seed: u8) -> Vec<u8> {
+        let mut key = vec![0; ML_KEM_768_ENCAPSULATION_KEY_LEN];
+        key[ML_KEM_768_ENCAPSULATION_KEY_LEN - ML_KEM_SEED_LEN..].fill(seed);
+        // Largest valid coefficient
+        key[0] = 0x00;
+        key[1] = 0x0d;
+        key
+    }
+
+    #[test]
+    fn test_verify_kem_prekeys() {
+        let identity_signing_key = SigningKey::new_ed25519();
+        let identity_key = identity_signing_key.verifying_key();
+        let first = kem_prekey(&identity_signing_key, 1, encapsulation_key(1));
+        let second = kem_prekey(&identity_signing_key, 2, encapsulation_key(2));
+
+        assert!(verify_kem_prekeys(&identity_key, [&first, &second]).is_ok());
+        assert!(verify_kem_prekeys(&identity_key, []).is_ok());
+
+        // Duplicate IDs
+        let duplicate = kem_prekey(&identity_signing_key, 1, encapsulation_key(3));
+        assert!(verify_kem_prekeys(&identity_key, [&first, &duplicate]).is_err());
+
+        // Signed by another identity key
+        let foreign = kem_prekey(&SigningKey::new_ed25519(), 3, encapsulation_key(3));
+        assert!(verify_kem_prekeys(&identity_key, [&foreign]).is_err());
+
+        // Signature not matching the key
+        let tampered = KemPrekey {
+            key: encapsulation_key(4),
+            ..second.clone()
+        };
+        assert!(verify_kem_prekeys(&identity_key, [&tampered]).is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_invalid_encapsulation_keys() {
+        let identity_signing_key = SigningKey::new_ed25519();
+        let identity_key = identity_signing_key.verifying_key();
+
+        for key in [
+            vec![],
+            vec![0; ML_KEM_768_ENCAPSULATION_KEY_LEN - 1],
+            vec![0; ML_KEM_768_ENCAPSULATION_KEY_LEN + 1],
+            // ML-KEM-1024 encapsulation key
+            vec![0; 1568],
+        ] {
+            let prekey = kem_prekey(&identity_signing_key, 1, key);
+            assert!(prekey.verify(&identity_key).is_err());
+        }
+
+        // First coefficient equal to q
+        let mut key = encapsulation_key(1);
+        key[0] = 0x01;
+        key[1] = 0x0d;
+        let prekey = kem_prekey(&identity_signing_key, 1, key);
+        assert!(prekey.verify(&identity_key).is_err());
+
+        // Last coefficient of the last polynomial above q
+        let mut key = encapsulation_key(1);
+        key[ML_KEM_768_ENCAPSULATION_KEY_LEN - ML_KEM_SEED_LEN - 1] = 0xff;
+        let prekey = kem_prekey(&identity_signing_key, 1, key);
+        assert!(prekey.verify(&identity_key).is_err());
     }
 }
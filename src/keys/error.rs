@@ -4,6 +4,7 @@ use axum::{
 };
 use thiserror::Error;
 use tracing::error;
+use uuid::Uuid;
 
 #[derive(Debug, Error)]
 pub enum KeyError {
@@ -16,12 +17,24 @@ pub enum KeyError {
     #[error("Duplicate prekey with index {0}")]
     DuplicatePrekey(u64),
 
+    #[error("Duplicate signed prekey with ID {0}")]
+    DuplicateSignedPrekey(u64),
+
+    #[error("Duplicate KEM prekey with ID {0}")]
+    DuplicateKemPrekey(u64),
+
+    #[error("Identity key is not registered in prism for account {0}")]
+    UnregisteredIdentityKey(Uuid),
+
     #[error("Database operation failed: {0}")]
     DatabaseError(String),
 
     #[error("Prism client error: {0}")]
     PrismClientError(String),
 
+    #[error("Sending failed: {0}")]
+    SendingFailed(String),
+
     #[error("Unspecified error: {0}")]
     UnspecifiedError(String),
 }
@@ -39,6 +52,9 @@ impl IntoResponse for KeyError {
             KeyError::ValidationError(_) => StatusCode::BAD_REQUEST,
             KeyError::NotFound(_) => StatusCode::NOT_FOUND,
             KeyError::DuplicatePrekey(_) => StatusCode::CONFLICT,
+            KeyError::DuplicateSignedPrekey(_) => StatusCode::CONFLICT,
+            KeyError::DuplicateKemPrekey(_) => StatusCode::CONFLICT,
+            KeyError::UnregisteredIdentityKey(_) => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
         status.into_response()
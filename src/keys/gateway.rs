@@ -0,0 +1,12 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use super::error::KeyError;
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait KeyGateway: Send + Sync {
+    /// Tells a connected account that its one-time prekeys are running low
+    async fn send_prekeys_low(&self, account_id: Uuid, prekey_count: usize)
+    -> Result<(), KeyError>;
+}
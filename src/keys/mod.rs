@@ -1,6 +1,7 @@
 pub mod database;
 pub mod entities;
 pub mod error;
+pub mod gateway;
 pub mod service;
 
 mod router;
@@ -12,8 +12,8 @@ use utoipa_axum::{router::OpenApiRouter, routes};
 use uuid::Uuid;
 
 use super::{
-    entities::{KeyBundle, Prekey},
-    service::KeyBundleResponse,
+    entities::{KemPrekey, KeyBundle, Prekey, SignedPrekey},
+    service::{KeyBundleResponse, PrekeyCountResponse, SignedPrekeysResponse},
 };
 use crate::{
     account::{auth::middleware::require_auth, entities::Account},
@@ -34,11 +34,26 @@ pub struct UploadPrekeysRequest {
     pub prekeys: Vec<Prekey>,
 }
 
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadKemPrekeysRequest {
+    pub kem_prekeys: Vec<KemPrekey>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RotateSignedPrekeyRequest {
+    pub signed_prekey: SignedPrekey,
+}
+
 pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
     OpenApiRouter::new()
         .routes(routes!(post_keybundle))
         .routes(routes!(get_keybundle))
         .routes(routes!(post_prekeys))
+        .routes(routes!(post_kem_prekeys))
+        .routes(routes!(get_prekey_count))
+        .routes(routes!(rotate_signed_prekey, get_signed_prekeys))
         .layer(from_fn_with_state(context.clone(), require_auth))
 }
 
@@ -86,6 +101,31 @@ async fn post_prekeys(
         .map(|_| StatusCode::OK)
 }
 
+#[utoipa::path(
+    post,
+    path = "/upload_kem_prekeys",
+    request_body = UploadKemPrekeysRequest,
+    responses(
+        (status = 200, description = "KEM prekey upload successful"),
+        (status = 400, description = "KEM prekey not signed by the identity key"),
+        (status = 404, description = "No key bundle uploaded yet"),
+        (status = 409, description = "KEM prekey ID is in use already"),
+        (status = 500, description = "KEM prekey upload failed unexpectedly")
+    ),
+    tag = KEY_TAG
+)]
+async fn post_kem_prekeys(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<UploadKemPrekeysRequest>,
+) -> Result<StatusCode, impl IntoResponse> {
+    context
+        .key_service
+        .add_kem_prekeys(account.id, req.kem_prekeys)
+        .await
+        .map(|_| StatusCode::OK)
+}
+
 #[utoipa::path(
     get,
     path = "/bundle/{account_id}",
@@ -109,3 +149,69 @@ async fn get_keybundle(
         .map(Json)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
+
+#[utoipa::path(
+    get,
+    path = "/prekeys/count",
+    responses(
+        (status = 200, description = "Number of unused one-time prekeys", body = PrekeyCountResponse),
+        (status = 500, description = "Prekey count failed unexpectedly")
+    ),
+    tag = KEY_TAG
+)]
+async fn get_prekey_count(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    context
+        .key_service
+        .count_prekeys(account.id)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/signed_prekey",
+    request_body = RotateSignedPrekeyRequest,
+    responses(
+        (status = 200, description = "Signed prekey rotated successfully"),
+        (status = 400, description = "Signature does not match the identity key"),
+        (status = 404, description = "No key bundle uploaded yet"),
+        (status = 409, description = "Signed prekey ID is in use already"),
+        (status = 500, description = "Signed prekey rotation failed unexpectedly")
+    ),
+    tag = KEY_TAG
+)]
+async fn rotate_signed_prekey(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<RotateSignedPrekeyRequest>,
+) -> Result<StatusCode, impl IntoResponse> {
+    context
+        .key_service
+        .rotate_signed_prekey(account.id, req.signed_prekey)
+        .await
+        .map(|_| StatusCode::OK)
+}
+
+#[utoipa::path(
+    get,
+    path = "/signed_prekey",
+    responses(
+        (status = 200, description = "Current and previous signed prekeys", body = SignedPrekeysResponse),
+        (status = 404, description = "No key bundle uploaded yet"),
+        (status = 500, description = "Signed prekey retrieval failed unexpectedly")
+    ),
+    tag = KEY_TAG
+)]
+async fn get_signed_prekeys(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    context
+        .key_service
+        .get_signed_prekeys(account.id)
+        .await
+        .map(Json)
+}
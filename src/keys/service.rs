@@ -1,15 +1,27 @@
-use prism_client::{Account as PrismAccount, HashedMerkleProof, PrismApi};
+use prism_client::{Account as PrismAccount, HashedMerkleProof, PrismApi, VerifyingKey};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, warn};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
 use super::{
     database::KeyDatabase,
-    entities::{KeyBundle, Prekey},
+    entities::{
+        KemPrekey, KeyBundle, Prekey, PreviousSignedPrekey, SignedPrekey, verify_kem_prekeys,
+    },
     error::KeyError,
+    gateway::KeyGateway,
+};
+use crate::{
+    account::database::AccountDatabase,
+    notifications::{gateway::NotificationGateway, service::NotificationService},
+    profiles::database::ProfileDatabase,
 };
 
+/// The key bundle handed to an initiator contains at most one one-time prekey and
+/// one one-time KEM prekey, which are never handed out again. Without one, only the
+/// signed prekey or the last-resort KEM prekey is used respectively.
 #[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct KeyBundleResponse {
@@ -18,22 +30,68 @@ pub struct KeyBundleResponse {
     pub proof: HashedMerkleProof,
 }
 
-pub struct KeyService<P, D>
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PrekeyCountResponse {
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedPrekeysResponse {
+    pub signed_prekey_id: u64,
+    /// Signed prekeys rotated out within the grace period, oldest first
+    pub previous_signed_prekeys: Vec<PreviousSignedPrekey>,
+}
+
+pub struct KeyService<P, D, F, G, A, N>
 where
     P: PrismApi,
     D: KeyDatabase,
+    F: ProfileDatabase,
+    G: KeyGateway,
+    A: AccountDatabase,
+    N: NotificationGateway,
 {
     prism: Arc<P>,
     db: Arc<D>,
+    /// Resolves the username under which an account is registered in prism
+    profile_db: Arc<F>,
+    gateway: Arc<G>,
+    notification_service: Arc<NotificationService<A, N>>,
+    /// Owners are asked to upload more prekeys once fewer are left
+    prekey_refill_threshold: usize,
+    /// How long rotated out signed prekeys are kept
+    signed_prekey_grace_period: Duration,
 }
 
-impl<P, D> KeyService<P, D>
+impl<P, D, F, G, A, N> KeyService<P, D, F, G, A, N>
 where
     P: PrismApi,
     D: KeyDatabase,
+    F: ProfileDatabase,
+    G: KeyGateway,
+    A: AccountDatabase,
+    N: NotificationGateway,
 {
-    pub fn new(prism: Arc<P>, db: Arc<D>) -> Self {
-        Self { prism, db }
+    pub fn new(
+        prism: Arc<P>,
+        db: Arc<D>,
+        profile_db: Arc<F>,
+        gateway: Arc<G>,
+        notification_service: Arc<NotificationService<A, N>>,
+        prekey_refill_threshold: usize,
+        signed_prekey_grace_period: Duration,
+    ) -> Self {
+        Self {
+            prism,
+            db,
+            profile_db,
+            gateway,
+            notification_service,
+            prekey_refill_threshold,
+            signed_prekey_grace_period,
+        }
     }
 
     pub async fn upload_key_bundle(
@@ -44,12 +102,42 @@ where
         bundle
             .verify()
             .map_err(|e| KeyError::ValidationError(e.to_string()))?;
+        self.verify_identity_key(account_id, &bundle.identity_key)
+            .await?;
 
-        // A key bundle can be inserted before the user has been successfully
-        // added to prism's state.
         self.db.insert_keybundle(account_id, bundle).await
     }
 
+    /// Ensures that the identity key is registered for the account in prism,
+    /// so that being authenticated is not enough to replace someone's identity.
+    /// Until the account has been added to prism's state, no key bundle is accepted.
+    async fn verify_identity_key(
+        &self,
+        account_id: Uuid,
+        identity_key: &VerifyingKey,
+    ) -> Result<(), KeyError> {
+        let profile = self
+            .profile_db
+            .get_profile_by_account_id(account_id)
+            .await
+            .map_err(|e| KeyError::DatabaseError(e.to_string()))?
+            .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+
+        let account_response = self
+            .prism
+            .get_account(&profile.username)
+            .await
+            .map_err(|e| KeyError::PrismClientError(e.to_string()))?;
+
+        let is_registered = account_response
+            .account
+            .is_some_and(|account| account.valid_keys().contains(identity_key));
+        if !is_registered {
+            return Err(KeyError::UnregisteredIdentityKey(account_id));
+        }
+        Ok(())
+    }
+
     // Note: There is no extra security assumption here: Even if the server is
     // malicious and adds extra prekeys for a user, the server will still be
     // unable to decrypt anything, and the receiver simply won't be able to
@@ -83,9 +171,38 @@ where
         self.db.add_prekeys(account_id, prekeys).await
     }
 
-    pub async fn get_keybundle(&self, account_id: Uuid) -> Result<KeyBundleResponse, KeyError> {
-        let keybundle = self.db.get_keybundle(account_id).await?;
+    /// Adds one-time KEM prekeys, which must be signed by the identity key of the bundle
+    pub async fn add_kem_prekeys(
+        &self,
+        account_id: Uuid,
+        kem_prekeys: Vec<KemPrekey>,
+    ) -> Result<(), KeyError> {
+        let key_bundle = self
+            .db
+            .get_keybundle(account_id)
+            .await?
+            .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+
+        verify_kem_prekeys(&key_bundle.identity_key, &kem_prekeys)
+            .map_err(|e| KeyError::ValidationError(e.to_string()))?;
 
+        let existing_ids = key_bundle
+            .last_resort_kem_prekey
+            .iter()
+            .chain(&key_bundle.kem_prekeys)
+            .map(|kem_prekey| kem_prekey.key_id)
+            .collect::<Vec<_>>();
+        if let Some(duplicate_key_id) = kem_prekeys
+            .iter()
+            .map(|kem_prekey| kem_prekey.key_id)
+            .find(|key_id| existing_ids.contains(key_id))
+        {
+            return Err(KeyError::DuplicateKemPrekey(duplicate_key_id));
+        }
+        self.db.add_kem_prekeys(account_id, kem_prekeys).await
+    }
+
+    pub async fn get_keybundle(&self, account_id: Uuid) -> Result<KeyBundleResponse, KeyError> {
         // Convert UUID to string for prism API call
         let account_id_str = account_id.to_string();
         // TODO: clarify whether prism will store account_id or username
@@ -95,6 +212,25 @@ where
             .await
             .map_err(|e| KeyError::PrismClientError(e.to_string()))?;
 
+        // No prekey is burned for an account the caller can't verify the bundle of
+        if account_response.account.is_none() {
+            return Ok(KeyBundleResponse {
+                key_bundle: None,
+                account: None,
+                proof: account_response.proof,
+            });
+        }
+
+        // One-time prekeys are consumed only once the lookup succeeded, so that
+        // failing fetches don't drain them, and right away, so that no two
+        // initiators share one.
+        // Initiators fall back to the last-resort KEM prekey once the one-time ones run out
+        let keybundle = self.db.take_prekey(account_id).await?;
+        // Checked on every fetch, so accounts that ran out are reminded as well
+        if keybundle.is_some() {
+            self.check_prekey_count(account_id).await;
+        }
+
         let response = KeyBundleResponse {
             key_bundle: keybundle,
             account: account_response.account,
@@ -102,7 +238,558 @@ where
         };
         Ok(response)
     }
+
+    /// Replaces the signed prekey of the account's key bundle, without touching
+    /// its one-time prekeys. The previous signed prekey is kept for the grace period.
+    pub async fn rotate_signed_prekey(
+        &self,
+        account_id: Uuid,
+        signed_prekey: SignedPrekey,
+    ) -> Result<(), KeyError> {
+        let key_bundle = self
+            .db
+            .get_keybundle(account_id)
+            .await?
+            .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+
+        signed_prekey
+            .verify(&key_bundle.identity_key)
+            .map_err(|e| KeyError::ValidationError(e.to_string()))?;
+
+        // IDs must stay unambiguous while previous signed prekeys are around
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let previous_signed_prekeys = self.previous_signed_prekeys(account_id, now).await?;
+        if signed_prekey.key_id == key_bundle.signed_prekey_id
+            || previous_signed_prekeys
+                .iter()
+                .any(|previous| previous.key_id == signed_prekey.key_id)
+        {
+            return Err(KeyError::DuplicateSignedPrekey(signed_prekey.key_id));
+        }
+
+        self.db
+            .rotate_signed_prekey(account_id, signed_prekey, now)
+            .await?;
+        self.db
+            .remove_previous_signed_prekeys(account_id, self.grace_period_start(now))
+            .await
+    }
+
+    pub async fn get_signed_prekeys(
+        &self,
+        account_id: Uuid,
+    ) -> Result<SignedPrekeysResponse, KeyError> {
+        let key_bundle = self
+            .db
+            .get_keybundle(account_id)
+            .await?
+            .ok_or_else(|| KeyError::NotFound(account_id.to_string()))?;
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        Ok(SignedPrekeysResponse {
+            signed_prekey_id: key_bundle.signed_prekey_id,
+            previous_signed_prekeys: self.previous_signed_prekeys(account_id, now).await?,
+        })
+    }
+
+    /// Previous signed prekeys that are still within the grace period
+    async fn previous_signed_prekeys(
+        &self,
+        account_id: Uuid,
+        now: u64,
+    ) -> Result<Vec<PreviousSignedPrekey>, KeyError> {
+        let grace_period_start = self.grace_period_start(now);
+        Ok(self
+            .db
+            .get_previous_signed_prekeys(account_id)
+            .await?
+            .into_iter()
+            .filter(|previous| previous.replaced_at > grace_period_start)
+            .collect())
+    }
+
+    fn grace_period_start(&self, now: u64) -> u64 {
+        now.saturating_sub(self.signed_prekey_grace_period.as_millis() as u64)
+    }
+
+    pub async fn count_prekeys(&self, account_id: Uuid) -> Result<PrekeyCountResponse, KeyError> {
+        let count = self.db.count_prekeys(account_id).await?;
+        Ok(PrekeyCountResponse { count })
+    }
+
+    /// Asks the owner to upload more prekeys if they are running low.
+    /// Offline owners are woken up to do so. Failing to reach them is not
+    /// an error, they are asked again after the next fetch.
+    async fn check_prekey_count(&self, account_id: Uuid) {
+        let count = match self.db.count_prekeys(account_id).await {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("Failed to count prekeys of {}: {}", account_id, e);
+                return;
+            }
+        };
+        if count >= self.prekey_refill_threshold {
+            return;
+        }
+
+        debug!("{} has {} prekeys left, asking for more", account_id, count);
+        if let Err(e) = self.gateway.send_prekeys_low(account_id, count).await {
+            debug!(
+                "Could not tell {} about low prekeys right away: {}",
+                account_id, e
+            );
+            if let Err(e) = self
+                .notification_service
+                .send_wakeup_notification(account_id)
+                .await
+            {
+                warn!(
+                    "Failed to send wake-up notification to {}: {}",
+                    account_id, e
+                );
+            }
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use mockall::predicate::eq;
+    use prism_client::{
+        Account, AccountResponse, PrismApiError, SignatureBundle, SigningKey, mock::MockPrismApi,
+    };
+
+    use super::*;
+    use crate::{
+        PRISM_MESSENGER_SERVICE_ID,
+        account::database::MockAccountDatabase,
+        crypto::salted_hash::SaltedHash,
+        database::inmemory::InMemoryDatabase,
+        keys::gateway::MockKeyGateway,
+        notifications::gateway::MockNotificationGateway,
+        profiles::{database::MockProfileDatabase, entities::Profile},
+    };
+
+    fn key_bundle(identity_signing_key: &SigningKey) -> KeyBundle {
+        let signed_prekey = SigningKey::new_ed25519().verifying_key();
+        let signed_prekey_signature = identity_signing_key
+            .sign(signed_prekey.to_spki_der().unwrap())
+            .unwrap();
+        KeyBundle {
+            identity_key: identity_signing_key.verifying_key(),
+            signed_prekey_id: 1,
+            signed_prekey,
+            signed_prekey_signature,
+            prekeys: vec![],
+            last_resort_kem_prekey: None,
+            kem_prekeys: vec![],
+        }
+    }
+
+    /// Prism account as created by the registration, with the identity key as its only key
+    fn prism_account(username: &str, identity_signing_key: &SigningKey) -> Account {
+        let identity_key = identity_signing_key.verifying_key();
+        let request = Arc::new(MockPrismApi::new())
+            .build_request()
+            .create_account()
+            .with_id(username.to_string())
+            .with_key(identity_key.clone())
+            .for_service_with_id(PRISM_MESSENGER_SERVICE_ID.to_string())
+            .meeting_signed_challenge(&SigningKey::new_ed25519())
+            .unwrap();
+        let signature = identity_signing_key
+            .sign(request.transaction().signing_payload().unwrap())
+            .unwrap();
+        let transaction = request
+            .with_external_signature(SignatureBundle::new(identity_key, signature))
+            .transaction();
+
+        let mut account = Account::default();
+        account.process_transaction(&transaction).unwrap();
+        account
+    }
+
+    #[tokio::test]
+    async fn test_upload_key_bundle_accepts_registered_identity_key() {
+        let account_id = Uuid::new_v4();
+        let identity_signing_key = SigningKey::new_ed25519();
+
+        let mut mock_profile_db = MockProfileDatabase::new();
+        mock_profile_db
+            .expect_get_profile_by_account_id()
+            .with(eq(account_id))
+            .returning(|account_id| Ok(Some(Profile::new(account_id, "alice".to_string()))));
+
+        let account = prism_account("alice", &identity_signing_key);
+        assert!(
+            account
+                .valid_keys()
+                .contains(&identity_signing_key.verifying_key())
+        );
+        let mut mock_prism = MockPrismApi::new();
+        mock_prism
+            .expect_get_account()
+            .with(eq("alice"))
+            .times(1)
+            .returning(move |_| {
+                Ok(AccountResponse {
+                    account: Some(account.clone()),
+                    proof: HashedMerkleProof::empty(),
+                })
+            });
+
+        let db = Arc::new(InMemoryDatabase::new());
+        let notification_service = NotificationService::new(
+            Arc::new(MockAccountDatabase::new()),
+            Arc::new(MockNotificationGateway::new()),
+        );
+        let service = KeyService::new(
+            Arc::new(mock_prism),
+            db.clone(),
+            Arc::new(mock_profile_db),
+            Arc::new(MockKeyGateway::new()),
+            Arc::new(notification_service),
+            10,
+            Duration::from_secs(60),
+        );
+
+        service
+            .upload_key_bundle(account_id, key_bundle(&identity_signing_key))
+            .await
+            .expect("Failed to upload key bundle");
+
+        let stored = db
+            .get_keybundle(account_id)
+            .await
+            .unwrap()
+            .expect("Key bundle not stored");
+        assert_eq!(stored.identity_key, identity_signing_key.verifying_key());
+    }
+
+    #[tokio::test]
+    async fn test_upload_key_bundle_rejects_unregistered_identity_key() {
+        let account_id = Uuid::new_v4();
+
+        let mut mock_profile_db = MockProfileDatabase::new();
+        mock_profile_db
+            .expect_get_profile_by_account_id()
+            .with(eq(account_id))
+            .returning(|account_id| Ok(Some(Profile::new(account_id, "alice".to_string()))));
+
+        // First not yet added to prism's state, then without the uploaded identity key
+        let mut mock_prism = MockPrismApi::new();
+        let mut responses = vec![Some(Account::default()), None];
+        mock_prism
+            .expect_get_account()
+            .with(eq("alice"))
+            .times(2)
+            .returning(move |_| {
+                Ok(AccountResponse {
+                    account: responses.pop().unwrap(),
+                    proof: HashedMerkleProof::empty(),
+                })
+            });
+
+        let db = Arc::new(InMemoryDatabase::new());
+        let notification_service = NotificationService::new(
+            Arc::new(MockAccountDatabase::new()),
+            Arc::new(MockNotificationGateway::new()),
+        );
+        let service = KeyService::new(
+            Arc::new(mock_prism),
+            db.clone(),
+            Arc::new(mock_profile_db),
+            Arc::new(MockKeyGateway::new()),
+            Arc::new(notification_service),
+            10,
+            Duration::from_secs(60),
+        );
+
+        let identity_signing_key = SigningKey::new_ed25519();
+        for _ in 0..2 {
+            let result = service
+                .upload_key_bundle(account_id, key_bundle(&identity_signing_key))
+                .await;
+            assert!(matches!(
+                result,
+                Err(KeyError::UnregisteredIdentityKey(id)) if id == account_id
+            ));
+        }
+        assert!(db.get_keybundle(account_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_keybundle_hands_out_each_prekey_once() {
+        let account_id = Uuid::new_v4();
+
+        // The first lookup fails, the second finds no account, the following ones succeed
+        let mut mock_prism = MockPrismApi::new();
+        let mut lookups = 0;
+        mock_prism
+            .expect_get_account()
+            .times(4)
+            .returning(move |_| {
+                lookups += 1;
+                if lookups == 1 {
+                    return Err(PrismApiError::Unknown("unreachable".to_string()));
+                }
+                Ok(AccountResponse {
+                    account: (lookups > 2).then(Account::default),
+                    proof: HashedMerkleProof::empty(),
+                })
+            });
+
+        let db = Arc::new(InMemoryDatabase::new());
+        let identity_signing_key = SigningKey::new_ed25519();
+        let prekeys = (1..=3)
+            .map(|key_idx| Prekey {
+                key_idx,
+                key: SigningKey::new_ed25519().verifying_key(),
+            })
+            .collect::<Vec<_>>();
+        db.insert_keybundle(
+            account_id,
+            KeyBundle {
+                prekeys: prekeys.clone(),
+                ..key_bundle(&identity_signing_key)
+            },
+        )
+        .await
+        .unwrap();
+
+        let notification_service = NotificationService::new(
+            Arc::new(MockAccountDatabase::new()),
+            Arc::new(MockNotificationGateway::new()),
+        );
+        let service = KeyService::new(
+            Arc::new(mock_prism),
+            db.clone(),
+            Arc::new(MockProfileDatabase::new()),
+            Arc::new(MockKeyGateway::new()),
+            Arc::new(notification_service),
+            0,
+            Duration::from_secs(60),
+        );
+
+        let result = service.get_keybundle(account_id).await;
+        assert!(matches!(result, Err(KeyError::PrismClientError(_))));
+        assert_eq!(db.count_prekeys(account_id).await.unwrap(), 3);
+
+        let response = service.get_keybundle(account_id).await.unwrap();
+        assert!(response.account.is_none());
+        assert!(response.key_bundle.is_none());
+        assert_eq!(db.count_prekeys(account_id).await.unwrap(), 3);
+
+        for expected_prekey in &prekeys[..2] {
+            let response = service.get_keybundle(account_id).await.unwrap();
+            let bundle = response.key_bundle.expect("Key bundle should exist");
+            assert_eq!(&bundle.prekeys, std::slice::from_ref(expected_prekey));
+        }
+        assert_eq!(db.count_prekeys(account_id).await.unwrap(), 1);
+    }
+
+    fn key_service_with_gateway(
+        db: Arc<InMemoryDatabase>,
+        gateway: MockKeyGateway,
+        notification_service: NotificationService<MockAccountDatabase, MockNotificationGateway>,
+        prekey_refill_threshold: usize,
+    ) -> KeyService<
+        MockPrismApi,
+        InMemoryDatabase,
+        MockProfileDatabase,
+        MockKeyGateway,
+        MockAccountDatabase,
+        MockNotificationGateway,
+    > {
+        let mut mock_prism = MockPrismApi::new();
+        mock_prism.expect_get_account().returning(|_| {
+            Ok(AccountResponse {
+                account: Some(Account::default()),
+                proof: HashedMerkleProof::empty(),
+            })
+        });
+
+        KeyService::new(
+            Arc::new(mock_prism),
+            db,
+            Arc::new(MockProfileDatabase::new()),
+            Arc::new(gateway),
+            Arc::new(notification_service),
+            prekey_refill_threshold,
+            Duration::from_secs(60),
+        )
+    }
+
+    async fn insert_key_bundle_with_prekeys(
+        db: &InMemoryDatabase,
+        account_id: Uuid,
+        prekey_count: u64,
+    ) {
+        let prekeys = (1..=prekey_count)
+            .map(|key_idx| Prekey {
+                key_idx,
+                key: SigningKey::new_ed25519().verifying_key(),
+            })
+            .collect();
+        db.insert_keybundle(
+            account_id,
+            KeyBundle {
+                prekeys,
+                ..key_bundle(&SigningKey::new_ed25519())
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_keybundle_asks_for_prekeys_below_threshold() {
+        let account_id = Uuid::new_v4();
+        let db = Arc::new(InMemoryDatabase::new());
+        insert_key_bundle_with_prekeys(&db, account_id, 4).await;
+
+        // The first fetch leaves enough prekeys, the others ask for more
+        let mut mock_gateway = MockKeyGateway::new();
+        mock_gateway
+            .expect_send_prekeys_low()
+            .with(eq(account_id), eq(2))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_gateway
+            .expect_send_prekeys_low()
+            .with(eq(account_id), eq(1))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_gateway
+            .expect_send_prekeys_low()
+            .with(eq(account_id), eq(0))
+            .times(2)
+            .returning(|_, _| Ok(()));
+
+        // Connected accounts are not woken up
+        let notification_service = NotificationService::new(
+            Arc::new(MockAccountDatabase::new()),
+            Arc::new(MockNotificationGateway::new()),
+        );
+        let service = key_service_with_gateway(db, mock_gateway, notification_service, 3);
+
+        for _ in 0..5 {
+            service.get_keybundle(account_id).await.unwrap();
+        }
+
+        // Accounts without a key bundle have nothing to refill
+        let response = service.get_keybundle(Uuid::new_v4()).await.unwrap();
+        assert!(response.key_bundle.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_keybundle_wakes_up_account_with_low_prekeys() {
+        let account_id = Uuid::new_v4();
+        let db = Arc::new(InMemoryDatabase::new());
+        insert_key_bundle_with_prekeys(&db, account_id, 1).await;
+
+        // The account is not connected
+        let mut mock_gateway = MockKeyGateway::new();
+        mock_gateway
+            .expect_send_prekeys_low()
+            .with(eq(account_id), eq(0))
+            .times(1)
+            .returning(|account_id, _| Err(KeyError::SendingFailed(account_id.to_string())));
+
+        let apns_token = vec![1, 2, 3, 4];
+        let account = crate::account::entities::Account {
+            id: account_id,
+            auth_password_hash: SaltedHash::generate_from("password"),
+            apns_token: Some(apns_token.clone()),
+            gcm_token: None,
+        };
+        let mut mock_account_db = MockAccountDatabase::new();
+        mock_account_db
+            .expect_fetch_account()
+            .with(eq(account_id))
+            .returning(move |_| Ok(Some(account.clone())));
+        let mut mock_notification_gateway = MockNotificationGateway::new();
+        mock_notification_gateway
+            .expect_send_silent_notification()
+            .with(eq(apns_token))
+            .times(1)
+            .returning(|_| Ok(()));
+        let notification_service = NotificationService::new(
+            Arc::new(mock_account_db),
+            Arc::new(mock_notification_gateway),
+        );
+        let service = key_service_with_gateway(db, mock_gateway, notification_service, 1);
+
+        service.get_keybundle(account_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_keybundle_falls_back_to_last_resort_kem_prekey() {
+        let account_id = Uuid::new_v4();
+
+        let mut mock_prism = MockPrismApi::new();
+        mock_prism.expect_get_account().returning(|_| {
+            Ok(AccountResponse {
+                account: Some(Account::default()),
+                proof: HashedMerkleProof::empty(),
+            })
+        });
+
+        let db = Arc::new(InMemoryDatabase::new());
+        let identity_signing_key = SigningKey::new_ed25519();
+        let create_kem_prekey = |key_id: u64| {
+            let key = vec![key_id as u8; 1184];
+            let signature = identity_signing_key.sign(&key).unwrap();
+            KemPrekey {
+                key_id,
+                key,
+                signature,
+            }
+        };
+        let last_resort_kem_prekey = create_kem_prekey(0);
+        let kem_prekey = create_kem_prekey(1);
+        db.insert_keybundle(
+            account_id,
+            KeyBundle {
+                last_resort_kem_prekey: Some(last_resort_kem_prekey.clone()),
+                kem_prekeys: vec![kem_prekey.clone()],
+                ..key_bundle(&identity_signing_key)
+            },
+        )
+        .await
+        .unwrap();
+
+        let notification_service = NotificationService::new(
+            Arc::new(MockAccountDatabase::new()),
+            Arc::new(MockNotificationGateway::new()),
+        );
+        let service = KeyService::new(
+            Arc::new(mock_prism),
+            db.clone(),
+            Arc::new(MockProfileDatabase::new()),
+            Arc::new(MockKeyGateway::new()),
+            Arc::new(notification_service),
+            0,
+            Duration::from_secs(60),
+        );
+
+        let response = service.get_keybundle(account_id).await.unwrap();
+        let bundle = response.key_bundle.expect("Key bundle should exist");
+        assert_eq!(bundle.kem_prekeys, vec![kem_prekey]);
+        assert_eq!(
+            bundle.last_resort_kem_prekey,
+            Some(last_resort_kem_prekey.clone())
+        );
+
+        // The one-time KEM prekey is used up, the last-resort one is handed out again
+        for _ in 0..2 {
+            let response = service.get_keybundle(account_id).await.unwrap();
+            let bundle = response.key_bundle.expect("Key bundle should exist");
+            assert!(bundle.kem_prekeys.is_empty());
+            assert_eq!(
+                bundle.last_resort_kem_prekey,
+                Some(last_resort_kem_prekey.clone())
+            );
+        }
+    }
+}
@@ -1,6 +1,9 @@
 mod account;
+mod attachments;
+mod blocks;
 mod crypto;
 mod database;
+mod groups;
 mod keys;
 mod messages;
 mod notifications;
@@ -28,7 +31,13 @@ use crate::telemetry::init::init;
 use crate::telemetry::metrics_registry::get_metrics;
 
 pub static PRISM_MESSENGER_SERVICE_ID: &str = "prism_messenger";
-pub static MESSAGE_SENDER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+pub static MESSAGE_SENDER_POLL_INTERVAL: Duration = Duration::from_secs(30);
+pub static MESSAGE_ACK_TIMEOUT: Duration = Duration::from_secs(20);
+pub static MESSAGE_EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+pub static SCHEDULED_MESSAGE_RELEASE_INTERVAL: Duration = Duration::from_secs(1);
+pub static TYPING_EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+pub static SENDER_CERTIFICATE_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+pub static ATTACHMENT_RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 /// Command line arguments for the Prism Messenger Server
 #[derive(Parser, Debug)]
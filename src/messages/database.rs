@@ -1,12 +1,95 @@
+use async_trait::async_trait;
 use uuid::Uuid;
 
-use super::entities::Message;
+use super::entities::{DeliveredMessage, Message, MessageCursor, QueueUsage, Receipt, SentMessage};
 use super::error::MessagingError;
 
 #[cfg_attr(test, mockall::automock)]
+#[async_trait]
 pub trait MessageDatabase: Send + Sync {
-    fn insert_message(&self, message: Message) -> Result<(), MessagingError>;
-    fn get_all_messages(&self) -> Result<Vec<Message>, MessagingError>;
-    fn get_messages_for_account(&self, account_id: Uuid) -> Result<Vec<Message>, MessagingError>;
-    fn remove_messages(&self, account_id: Uuid, ids: Vec<Uuid>) -> Result<(), MessagingError>;
+    async fn insert_message(&self, message: Message) -> Result<(), MessagingError>;
+    async fn get_all_messages(&self) -> Result<Vec<Message>, MessagingError>;
+    async fn get_messages_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError>;
+    /// Returns up to `limit` messages for the account that come after the cursor,
+    /// ordered by timestamp and message ID
+    async fn get_messages_page_for_account(
+        &self,
+        account_id: Uuid,
+        after: Option<MessageCursor>,
+        limit: usize,
+    ) -> Result<Vec<Message>, MessagingError>;
+    async fn remove_messages(&self, account_id: Uuid, ids: Vec<Uuid>)
+    -> Result<(), MessagingError>;
+    /// Removes a queued message, provided it was sent by the given sender
+    /// and was not handed out to its recipient. Returns the removed message, if there was one.
+    async fn remove_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError>;
+    /// Removes all messages that expired at or before `now` (epoch milliseconds)
+    /// and returns them.
+    async fn remove_expired_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError>;
+    /// Returns how much of the recipient's queue is in use, overall and by the given sender.
+    /// Sealed sender messages are not attributed to any sender.
+    async fn get_queue_usage(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Option<Uuid>,
+    ) -> Result<QueueUsage, MessagingError>;
+
+    /// Claims the idempotency key for the sent message. Returns `false` without storing it if
+    /// an earlier send with the same key is still within its deduplication window.
+    async fn insert_sent_message(&self, sent_message: SentMessage) -> Result<bool, MessagingError>;
+    async fn get_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<Option<SentMessage>, MessagingError>;
+    async fn remove_sent_message(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Uuid,
+    ) -> Result<(), MessagingError>;
+    /// Forgets all sent messages whose deduplication window ended at or before `now`
+    /// (epoch milliseconds)
+    async fn remove_expired_sent_messages(&self, now: u64) -> Result<(), MessagingError>;
+
+    async fn insert_receipt(&self, receipt: Receipt) -> Result<(), MessagingError>;
+    /// Returns the receipts queued for the given account, i.e. the sender of the messages
+    async fn get_receipts_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Receipt>, MessagingError>;
+    async fn remove_receipts(&self, account_id: Uuid, ids: Vec<Uuid>)
+    -> Result<(), MessagingError>;
+
+    /// Remembers that the messages were handed out to their recipient, until `until`
+    /// (epoch milliseconds). Handed out messages can no longer be retracted.
+    async fn insert_handed_out_messages(
+        &self,
+        ids: Vec<Uuid>,
+        until: u64,
+    ) -> Result<(), MessagingError>;
+    async fn is_handed_out(&self, message_id: Uuid) -> Result<bool, MessagingError>;
+    /// Forgets all handed out messages remembered until `now` (epoch milliseconds) or before
+    async fn remove_expired_handed_out_messages(&self, now: u64) -> Result<(), MessagingError>;
+
+    async fn insert_delivered_message(
+        &self,
+        delivered_message: DeliveredMessage,
+    ) -> Result<(), MessagingError>;
+    /// Forgets the given messages delivered from the sender to the recipient
+    /// and returns those that were remembered
+    async fn remove_delivered_messages(
+        &self,
+        recipient_id: Uuid,
+        sender_id: Uuid,
+        ids: Vec<Uuid>,
+    ) -> Result<Vec<DeliveredMessage>, MessagingError>;
+    /// Forgets all delivered messages that expired at or before `now` (epoch milliseconds)
+    async fn remove_expired_delivered_messages(&self, now: u64) -> Result<(), MessagingError>;
 }
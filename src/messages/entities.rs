@@ -1,9 +1,13 @@
-use prism_client::VerifyingKey;
+use prism_client::{Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
-use serde_with::{base64::Base64, serde_as};
+use serde_with::{DisplayFromStr, base64::Base64, serde_as};
+use std::{fmt, str::FromStr};
+use tracing::warn;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
+use super::error::{MessagingError, QueueQuota};
+
 /// The header provides the recipient with the context needed to update
 /// its ratchet state. It includes the sender’s ephemeral public key and
 /// message counters.
@@ -18,6 +22,8 @@ pub struct DoubleRatchetHeader {
     pub previous_message_number: u64,
     /// Identifier of the one-time prekey used in the handshake
     pub one_time_prekey_id: Option<u64>,
+    /// Identifier of the signed prekey used in the handshake
+    pub signed_prekey_id: Option<u64>,
 }
 
 /// The complete double ratchet message.
@@ -41,6 +47,75 @@ pub struct MessageReceipt {
     pub message_id: Uuid,
     /// Server timestamp (epoch milliseconds)
     pub timestamp: u64,
+    /// Time after which the message is discarded if undelivered (epoch milliseconds)
+    pub expires_at: u64,
+}
+
+impl MessageReceipt {
+    pub fn of(message: &Message) -> Self {
+        Self {
+            message_id: message.message_id,
+            timestamp: message.timestamp,
+            expires_at: message.expires_at,
+        }
+    }
+}
+
+/// A message sent with an idempotency key, remembered for the deduplication window
+/// so that retries of the send get the original receipt.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SentMessage {
+    pub sender_id: Uuid,
+    pub idempotency_key: Uuid,
+    pub receipt: MessageReceipt,
+    /// Time after which retries are sent as new messages (epoch milliseconds)
+    pub dedup_until: u64,
+}
+
+/// Outcome of a single message of a batch or group fan-out.
+/// Contains either the receipt or the status code the message failed with.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchMessageResult {
+    pub recipient_id: Uuid,
+    pub receipt: Option<MessageReceipt>,
+    pub error_status: Option<u16>,
+}
+
+impl BatchMessageResult {
+    pub fn new(recipient_id: Uuid, result: Result<MessageReceipt, MessagingError>) -> Self {
+        match result {
+            Ok(receipt) => Self {
+                recipient_id,
+                receipt: Some(receipt),
+                error_status: None,
+            },
+            Err(e) => {
+                warn!("Failed to send message to {}: {}", recipient_id, e);
+                Self {
+                    recipient_id,
+                    receipt: None,
+                    error_status: Some(e.status_code().as_u16()),
+                }
+            }
+        }
+    }
+}
+
+/// A message sent through a gateway instead of the HTTP API.
+/// The outcome is returned to the sender under the client's request ID.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendRequest {
+    pub request_id: Uuid,
+    pub recipient_id: Uuid,
+    pub message: DoubleRatchetMessage,
+    /// Seconds until the message expires if undelivered
+    pub ttl: Option<u64>,
+    pub idempotency_key: Option<Uuid>,
+    /// Epoch milliseconds to hold the message back until
+    pub deliver_at: Option<u64>,
 }
 
 /// The message delivered to a client includes sender/recipient metadata.
@@ -48,8 +123,220 @@ pub struct MessageReceipt {
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     pub message_id: Uuid,
-    pub sender_id: Uuid,
+    /// Absent for sealed sender messages, whose sender only the recipient knows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<Uuid>,
     pub recipient_id: Uuid,
+    /// The group the message was sent to, if it was fanned out to a group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<Uuid>,
     pub message: DoubleRatchetMessage,
     pub timestamp: u64,
+    /// Time after which the message is discarded if undelivered (epoch milliseconds)
+    pub expires_at: u64,
+}
+
+/// Vouches for the identity of a sealed sender towards the recipient.
+/// Senders put it inside the encrypted message, recipients verify it
+/// with the messenger service's key as registered in prism.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SenderCertificate {
+    pub sender_id: Uuid,
+    pub identity_key: VerifyingKey,
+    /// Time after which recipients reject the certificate (epoch milliseconds)
+    pub expires_at: u64,
+    /// Signature of the service over `SenderCertificate::signed_data`
+    pub signature: Signature,
+}
+
+impl SenderCertificate {
+    /// The signed bytes: sender ID, big endian expiry and the DER encoded identity key
+    pub fn signed_data(
+        sender_id: Uuid,
+        identity_key: &VerifyingKey,
+        expires_at: u64,
+    ) -> Result<Vec<u8>, MessagingError> {
+        let identity_key_der = identity_key
+            .to_spki_der()
+            .map_err(|e| MessagingError::ParseError(e.to_string()))?;
+
+        let mut data = Vec::with_capacity(16 + 8 + identity_key_der.len());
+        data.extend_from_slice(sender_id.as_bytes());
+        data.extend_from_slice(&expires_at.to_be_bytes());
+        data.extend_from_slice(&identity_key_der);
+        Ok(data)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptKind {
+    /// The message reached the recipient's device
+    Delivered,
+    /// The recipient read the message, as reported by its client
+    Read,
+}
+
+/// Tells the sender of a message what happened to it on the recipient's side.
+/// Receipts are queued for the sender until acknowledged, like messages.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub receipt_id: Uuid,
+    pub kind: ReceiptKind,
+    pub message_id: Uuid,
+    /// Sender of the message, who receives the receipt
+    pub sender_id: Uuid,
+    /// Recipient of the message, who issued the receipt
+    pub recipient_id: Uuid,
+    /// Server timestamp (epoch milliseconds)
+    pub timestamp: u64,
+}
+
+/// A message that reached its recipient, remembered so that the recipient can
+/// send a single read receipt for it to its sender
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveredMessage {
+    pub message_id: Uuid,
+    pub sender_id: Uuid,
+    pub recipient_id: Uuid,
+    /// Read receipts are relayed until then (epoch milliseconds)
+    pub expires_at: u64,
+}
+
+/// Pending messages of a recipient, used to enforce queue quotas
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueUsage {
+    /// Number of messages queued for the recipient
+    pub recipient_messages: usize,
+    /// Total ciphertext bytes queued for the recipient
+    pub recipient_bytes: u64,
+    /// Number of messages queued for the recipient by a single sender
+    pub sender_messages: usize,
+}
+
+/// Limits of a recipient's queue of pending messages
+#[derive(Clone, Debug)]
+pub struct QueueLimits {
+    pub max_messages: usize,
+    pub max_bytes: u64,
+    pub max_messages_per_sender: usize,
+}
+
+impl QueueLimits {
+    /// Checks whether a message with the given ciphertext size still fits into the queue
+    pub fn check(&self, usage: &QueueUsage, ciphertext_size: u64) -> Result<(), QueueQuota> {
+        if usage.sender_messages >= self.max_messages_per_sender {
+            return Err(QueueQuota::SenderMessages);
+        }
+        if usage.recipient_messages >= self.max_messages {
+            return Err(QueueQuota::RecipientMessages);
+        }
+        if usage.recipient_bytes + ciphertext_size > self.max_bytes {
+            return Err(QueueQuota::RecipientBytes);
+        }
+        Ok(())
+    }
+}
+
+/// Size of the AEAD nonce of a double ratchet message in bytes
+pub const NONCE_SIZE: usize = 12;
+
+/// Bounds that well-formed double ratchet messages stay within
+#[derive(Clone, Debug)]
+pub struct MessageLimits {
+    pub max_ciphertext_size: u64,
+    /// Upper bound for the counters in the header. Clients derive a key for every
+    /// skipped message, so huge counters would make recipients do excessive work.
+    pub max_message_number: u64,
+}
+
+impl MessageLimits {
+    /// Checks the parts of a message that the server can see without decrypting it
+    pub fn check(&self, message: &DoubleRatchetMessage) -> Result<(), MessagingError> {
+        if message.ciphertext.is_empty() {
+            return Err(MessagingError::InvalidMessage(
+                "ciphertext is empty".to_string(),
+            ));
+        }
+        if message.ciphertext.len() as u64 > self.max_ciphertext_size {
+            return Err(MessagingError::MessageTooLarge(self.max_ciphertext_size));
+        }
+        if message.nonce.len() != NONCE_SIZE {
+            return Err(MessagingError::InvalidMessage(format!(
+                "nonce must be {} bytes",
+                NONCE_SIZE
+            )));
+        }
+        if message.header.message_number > self.max_message_number
+            || message.header.previous_message_number > self.max_message_number
+        {
+            return Err(MessagingError::InvalidMessage(format!(
+                "message counters must not exceed {}",
+                self.max_message_number
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Position in a recipient's queue. Pending messages are ordered by
+/// server timestamp first and message ID second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageCursor {
+    pub timestamp: u64,
+    pub message_id: Uuid,
+}
+
+impl MessageCursor {
+    pub fn of(message: &Message) -> Self {
+        Self {
+            timestamp: message.timestamp,
+            message_id: message.message_id,
+        }
+    }
+}
+
+impl fmt::Display for MessageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.timestamp, self.message_id)
+    }
+}
+
+impl FromStr for MessageCursor {
+    type Err = MessagingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MessagingError::InvalidCursor(s.to_string());
+
+        let (timestamp, message_id) = s.split_once('_').ok_or_else(invalid)?;
+        Ok(Self {
+            timestamp: timestamp.parse().map_err(|_| invalid())?,
+            message_id: message_id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A page of the messages pending for an account
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingMessagesPage {
+    pub messages: Vec<Message>,
+    /// Cursor to fetch the following page with, absent on the last page
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[schema(value_type = Option<String>)]
+    pub next_cursor: Option<MessageCursor>,
+}
+
+/// Tells a sender that one of its messages expired before it could be delivered
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageExpiry {
+    pub message_id: Uuid,
+    pub recipient_id: Uuid,
+    /// Time the message expired at (epoch milliseconds)
+    pub expired_at: u64,
 }
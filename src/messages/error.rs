@@ -4,15 +4,27 @@ use axum::{
 };
 use thiserror::Error;
 use tracing::error;
-use uuid::Error as UuidError;
+use uuid::{Error as UuidError, Uuid};
 
-use crate::{account::database::AccountDatabaseError, notifications::gateway::NotificationError};
+use crate::{
+    account::database::AccountDatabaseError, blocks::error::BlockError,
+    notifications::gateway::NotificationError, profiles::error::ProfileError,
+};
 
 #[derive(Debug, Error)]
 pub enum MessagingError {
     #[error("User not found: {0}")]
     UserNotFound(String),
 
+    #[error("Recipient not found: {0}")]
+    RecipientNotFound(Uuid),
+
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+
+    #[error("Message too large, ciphertexts are limited to {0} bytes")]
+    MessageTooLarge(u64),
+
     #[error("Database operation failed: {0}")]
     DatabaseError(String),
 
@@ -22,8 +34,45 @@ pub enum MessagingError {
     #[error("Parse error: {0}")]
     ParseError(String),
 
+    #[error("Invalid message cursor: {0}")]
+    InvalidCursor(String),
+
     #[error("Sending failed: {0}")]
     SendingFailed(String),
+
+    #[error("Queue quota exceeded: {0}")]
+    QuotaExceeded(QueueQuota),
+
+    #[error("Batch too large: {0} messages")]
+    BatchTooLarge(usize),
+
+    #[error("Invalid delivery access key")]
+    InvalidAccessKey,
+
+    #[error("Invalid delivery time: {0}")]
+    InvalidDeliveryTime(String),
+
+    #[error("Scheduled message not found: {0}")]
+    ScheduledMessageNotFound(Uuid),
+
+    #[error("Message not found: {0}")]
+    MessageNotFound(Uuid),
+
+    #[error("Message handed out to its recipient already: {0}")]
+    MessageAlreadyDelivered(Uuid),
+}
+
+/// The quota of a recipient's message queue that a message would exceed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum QueueQuota {
+    #[error("too many pending messages from this sender")]
+    SenderMessages,
+
+    #[error("too many pending messages for this recipient")]
+    RecipientMessages,
+
+    #[error("too many pending bytes for this recipient")]
+    RecipientBytes,
 }
 
 impl From<AccountDatabaseError> for MessagingError {
@@ -37,6 +86,26 @@ impl From<AccountDatabaseError> for MessagingError {
     }
 }
 
+impl From<ProfileError> for MessagingError {
+    fn from(err: ProfileError) -> Self {
+        match err {
+            // Don't tell unknown senders whether the recipient exists
+            ProfileError::NotFound | ProfileError::InvalidAccessKey => {
+                MessagingError::InvalidAccessKey
+            }
+            ProfileError::Database(msg) | ProfileError::Internal(msg) => {
+                MessagingError::DatabaseError(msg)
+            }
+        }
+    }
+}
+
+impl From<BlockError> for MessagingError {
+    fn from(err: BlockError) -> Self {
+        MessagingError::DatabaseError(err.to_string())
+    }
+}
+
 impl From<NotificationError> for MessagingError {
     fn from(err: NotificationError) -> Self {
         MessagingError::NotificationError(err.to_string())
@@ -49,14 +118,34 @@ impl From<UuidError> for MessagingError {
     }
 }
 
+impl MessagingError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            MessagingError::UserNotFound(_) => StatusCode::BAD_REQUEST,
+            MessagingError::RecipientNotFound(_) => StatusCode::NOT_FOUND,
+            MessagingError::InvalidMessage(_) | MessagingError::MessageTooLarge(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            MessagingError::InvalidCursor(_) => StatusCode::BAD_REQUEST,
+            MessagingError::BatchTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            MessagingError::InvalidAccessKey => StatusCode::UNAUTHORIZED,
+            MessagingError::InvalidDeliveryTime(_) => StatusCode::BAD_REQUEST,
+            MessagingError::ScheduledMessageNotFound(_) => StatusCode::NOT_FOUND,
+            MessagingError::MessageNotFound(_) => StatusCode::NOT_FOUND,
+            MessagingError::MessageAlreadyDelivered(_) => StatusCode::CONFLICT,
+            // The sender is flooding the recipient and should back off
+            MessagingError::QuotaExceeded(QueueQuota::SenderMessages) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            MessagingError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl IntoResponse for MessagingError {
     fn into_response(self) -> Response {
         error!("{}", self);
-        let status = match self {
-            MessagingError::UserNotFound(_) => StatusCode::BAD_REQUEST,
-            MessagingError::ParseError(_) => StatusCode::BAD_REQUEST,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
-        status.into_response()
+        self.status_code().into_response()
     }
 }
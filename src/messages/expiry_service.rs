@@ -0,0 +1,250 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+use tracing::{debug, error, info, instrument, warn};
+
+use super::{
+    database::MessageDatabase, entities::MessageExpiry, error::MessagingError,
+    gateway::MessageGateway, requests::database::MessageRequestDatabase,
+};
+use crate::telemetry::metrics_registry::get_metrics;
+
+/// Purges messages that expired before they could be delivered
+/// and tells their senders about it. Also forgets sent messages
+/// whose deduplication window has passed, that can no longer be marked
+/// as read or retracted, and purges expired message requests.
+pub struct MessageExpiryService<D, R, G>
+where
+    D: MessageDatabase + ?Sized + 'static,
+    R: MessageRequestDatabase + 'static,
+    G: MessageGateway + 'static,
+{
+    messages_db: Arc<D>,
+    request_db: Arc<R>,
+    message_gateway: Arc<G>,
+    sweep_interval: Duration,
+}
+
+impl<D, R, G> MessageExpiryService<D, R, G>
+where
+    D: MessageDatabase + ?Sized + 'static,
+    R: MessageRequestDatabase + 'static,
+    G: MessageGateway + 'static,
+{
+    pub fn new(
+        messages_db: Arc<D>,
+        request_db: Arc<R>,
+        message_gateway: Arc<G>,
+        sweep_interval: Duration,
+    ) -> Self {
+        Self {
+            messages_db,
+            request_db,
+            message_gateway,
+            sweep_interval,
+        }
+    }
+
+    /// Spawn the background task that periodically removes expired messages
+    #[instrument(skip(self))]
+    pub fn spawn_expiry_sweeper(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            info!("Starting MessageExpiryService background task");
+            let mut ticker = interval(self.sweep_interval);
+
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.sweep_expired_messages().await {
+                    error!("Error removing expired messages: {}", e);
+                }
+                let now = chrono::Utc::now().timestamp_millis() as u64;
+                if let Err(e) = self.messages_db.remove_expired_sent_messages(now).await {
+                    error!("Error removing expired sent messages: {}", e);
+                }
+                if let Err(e) = self
+                    .messages_db
+                    .remove_expired_delivered_messages(now)
+                    .await
+                {
+                    error!("Error removing expired delivered messages: {}", e);
+                }
+                if let Err(e) = self
+                    .messages_db
+                    .remove_expired_handed_out_messages(now)
+                    .await
+                {
+                    error!("Error removing expired handed out messages: {}", e);
+                }
+                // Message requests expire silently, their senders must not learn
+                // whether they were ignored
+                if let Err(e) = self.request_db.remove_expired_request_messages(now).await {
+                    error!("Error removing expired message requests: {}", e);
+                }
+            }
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn sweep_expired_messages(&self) -> Result<(), MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let expired_messages = self.messages_db.remove_expired_messages(now).await?;
+
+        if expired_messages.is_empty() {
+            return Ok(());
+        }
+        info!("Removed {} expired messages", expired_messages.len());
+
+        if let Some(metrics) = get_metrics() {
+            metrics.record_expired_messages(expired_messages.len() as u64);
+        }
+
+        for message in expired_messages {
+            // Sealed senders are unknown, so they cannot be told
+            let Some(sender_id) = message.sender_id else {
+                continue;
+            };
+            let expiry = MessageExpiry {
+                message_id: message.message_id,
+                recipient_id: message.recipient_id,
+                expired_at: message.expires_at,
+            };
+
+            match self
+                .message_gateway
+                .send_message_expiry(sender_id, expiry)
+                .await
+            {
+                Ok(()) => {}
+                Err(MessagingError::UserNotFound(account_id)) => {
+                    debug!(
+                        "Sender {} not connected, dropping expiry notice",
+                        account_id
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to notify sender {} about expired message {}: {}",
+                        sender_id, message.message_id, e
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{
+        database::MockMessageDatabase, entities::*, gateway::MockMessageGateway,
+        requests::database::MockMessageRequestDatabase,
+    };
+    use mockall::predicate::*;
+    use prism_client::SigningKey;
+    use uuid::Uuid;
+
+    fn create_expired_message(sender_id: Uuid) -> Message {
+        Message {
+            message_id: Uuid::new_v4(),
+            sender_id: Some(sender_id),
+            recipient_id: Uuid::new_v4(),
+            group_id: None,
+            message: DoubleRatchetMessage {
+                header: DoubleRatchetHeader {
+                    ephemeral_key: SigningKey::new_secp256r1().verifying_key(),
+                    message_number: 1,
+                    previous_message_number: 0,
+                    one_time_prekey_id: None,
+                    signed_prekey_id: None,
+                },
+                ciphertext: vec![1, 2, 3, 4],
+                nonce: vec![5, 6, 7, 8],
+            },
+            timestamp: 1000,
+            expires_at: 2000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_notifies_senders_of_expired_messages() {
+        let sender_id = Uuid::new_v4();
+        let message = create_expired_message(sender_id);
+        let message_id = message.message_id;
+
+        let mut mock_db = MockMessageDatabase::new();
+        mock_db
+            .expect_remove_expired_messages()
+            .once()
+            .returning(move |_| Ok(vec![message.clone()]));
+
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_send_message_expiry()
+            .once()
+            .withf(move |id, expiry| {
+                *id == sender_id && expiry.message_id == message_id && expiry.expired_at == 2000
+            })
+            .returning(|_, _| Ok(()));
+
+        let service = MessageExpiryService::new(
+            Arc::new(mock_db),
+            Arc::new(MockMessageRequestDatabase::new()),
+            Arc::new(mock_gateway),
+            Duration::from_secs(60),
+        );
+
+        let result = service.sweep_expired_messages().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_ignores_disconnected_senders() {
+        let message = create_expired_message(Uuid::new_v4());
+
+        let mut mock_db = MockMessageDatabase::new();
+        mock_db
+            .expect_remove_expired_messages()
+            .once()
+            .returning(move |_| Ok(vec![message.clone()]));
+
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_send_message_expiry()
+            .once()
+            .with(always(), always())
+            .returning(|id, _| Err(MessagingError::UserNotFound(id.to_string())));
+
+        let service = MessageExpiryService::new(
+            Arc::new(mock_db),
+            Arc::new(MockMessageRequestDatabase::new()),
+            Arc::new(mock_gateway),
+            Duration::from_secs(60),
+        );
+
+        let result = service.sweep_expired_messages().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_database_error_propagates() {
+        let mut mock_db = MockMessageDatabase::new();
+        mock_db
+            .expect_remove_expired_messages()
+            .once()
+            .returning(|_| Err(MessagingError::DatabaseError("Sweep failed".to_string())));
+
+        let service = MessageExpiryService::new(
+            Arc::new(mock_db),
+            Arc::new(MockMessageRequestDatabase::new()),
+            Arc::new(MockMessageGateway::new()),
+            Duration::from_secs(60),
+        );
+
+        let result = service.sweep_expired_messages().await;
+        assert!(matches!(
+            result.unwrap_err(),
+            MessagingError::DatabaseError(_)
+        ));
+    }
+}
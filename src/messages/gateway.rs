@@ -1,9 +1,58 @@
 use async_trait::async_trait;
+use uuid::Uuid;
 
-use super::{entities::Message, error::MessagingError};
+use super::{
+    entities::{Message, MessageExpiry, MessageReceipt, Receipt, SendRequest},
+    error::MessagingError,
+};
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait MessageGateway: Send + Sync {
     async fn send_message(&self, message: Message) -> Result<(), MessagingError>;
+
+    /// Tells the sender of a message that it expired undelivered
+    async fn send_message_expiry(
+        &self,
+        sender_id: Uuid,
+        expiry: MessageExpiry,
+    ) -> Result<(), MessagingError>;
+
+    /// Sends a receipt to the sender of the message it refers to
+    async fn send_receipt(&self, receipt: Receipt) -> Result<(), MessagingError>;
+
+    /// Returns the outcome of a send request to the account that made it
+    async fn reply_to_send(
+        &self,
+        account_id: Uuid,
+        request_id: Uuid,
+        result: Result<MessageReceipt, MessagingError>,
+    ) -> Result<(), MessagingError>;
+
+    /// Returns the accounts currently reachable through this gateway
+    async fn get_connected_recipient_ids(&self) -> Vec<Uuid>;
+
+    /// Register a handler that is called with the account ID whenever
+    /// a recipient becomes reachable through this gateway
+    async fn register_recipient_connected_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid) + Send + Sync + 'static;
+
+    /// Register a handler that is called with the account ID and the message IDs
+    /// whenever a recipient acknowledges the receipt of messages
+    async fn register_ack_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid, Vec<Uuid>) + Send + Sync + 'static;
+
+    /// Register a handler that is called with the reader's account ID, the sender's
+    /// account ID and the message IDs whenever a recipient reports messages as read
+    async fn register_read_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid, Uuid, Vec<Uuid>) + Send + Sync + 'static;
+
+    /// Register a handler that is called with the sender's account ID and the request
+    /// whenever an account sends a message through this gateway
+    async fn register_send_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid, SendRequest) + Send + Sync + 'static;
 }
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Keeps track of messages that were handed to a recipient but not yet acknowledged.
+///
+/// In-flight messages stay in the message database and are only sent again
+/// once their acknowledgement is overdue.
+pub struct InFlightMessages {
+    ack_timeout: Duration,
+    sent_at: Mutex<HashMap<Uuid, Instant>>,
+}
+
+impl InFlightMessages {
+    pub fn new(ack_timeout: Duration) -> Self {
+        Self {
+            ack_timeout,
+            sent_at: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Marks a message as in flight, unless it already is and its
+    /// acknowledgement is not overdue yet. Returns whether it was marked.
+    pub fn try_mark(&self, message_id: Uuid) -> bool {
+        let now = Instant::now();
+        let mut sent_at = self.sent_at.lock().unwrap();
+
+        // Overdue messages can be sent again, no need to keep them around
+        sent_at.retain(|_, sent| now.duration_since(*sent) < self.ack_timeout);
+
+        if sent_at.contains_key(&message_id) {
+            return false;
+        }
+        sent_at.insert(message_id, now);
+        true
+    }
+
+    /// Marks a message as in flight, regardless of an earlier delivery attempt
+    pub fn mark(&self, message_id: Uuid) {
+        self.sent_at
+            .lock()
+            .unwrap()
+            .insert(message_id, Instant::now());
+    }
+
+    /// Forgets about messages, e.g. after they were acknowledged or failed to send
+    pub fn clear(&self, message_ids: &[Uuid]) {
+        let mut sent_at = self.sent_at.lock().unwrap();
+        for message_id in message_ids {
+            sent_at.remove(message_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_is_only_marked_once_until_cleared() {
+        let in_flight = InFlightMessages::new(Duration::from_secs(60));
+        let message_id = Uuid::new_v4();
+
+        assert!(in_flight.try_mark(message_id));
+        assert!(!in_flight.try_mark(message_id));
+
+        in_flight.clear(&[message_id]);
+        assert!(in_flight.try_mark(message_id));
+    }
+
+    #[test]
+    fn test_overdue_message_can_be_marked_again() {
+        let in_flight = InFlightMessages::new(Duration::from_millis(10));
+        let message_id = Uuid::new_v4();
+
+        assert!(in_flight.try_mark(message_id));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(in_flight.try_mark(message_id));
+    }
+}
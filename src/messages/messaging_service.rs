@@ -1,299 +1,2749 @@
-use std::sync::Arc;
-use tracing::{debug, error, instrument};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::interval;
+use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
 
 use crate::{
     account::database::AccountDatabase,
+    blocks::database::BlockDatabase,
     notifications::{gateway::NotificationGateway, service::NotificationService},
     presence::database::PresenceDatabase,
 };
 
 use super::{
     database::MessageDatabase,
-    entities::{DoubleRatchetMessage, Message, MessageReceipt},
-    error::MessagingError,
+    entities::{
+        DeliveredMessage, DoubleRatchetMessage, Message, MessageCursor, MessageExpiry,
+        MessageLimits, MessageReceipt, PendingMessagesPage, QueueLimits, Receipt, ReceiptKind,
+        SendRequest, SentMessage,
+    },
+    error::{MessagingError, QueueQuota},
+    gateway::MessageGateway,
+    in_flight::InFlightMessages,
+    requests::database::MessageRequestDatabase,
+    scheduled::database::ScheduledMessageDatabase,
 };
 
-pub struct MessagingService<M, P, A, N>
+/// Upper bound for the number of messages returned per page
+pub const MAX_PENDING_MESSAGES_PAGE_SIZE: usize = 500;
+
+/// Upper bound for the number of messages sent in one batch
+pub const MAX_SEND_BATCH_SIZE: usize = 100;
+
+/// How long retries of a send with the same idempotency key return the original receipt
+pub const MESSAGE_DEDUP_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How far into the future messages can be scheduled
+pub const MAX_DELIVERY_DELAY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+pub struct MessagingService<M, P, G, A, B, R, S, N>
 where
-    M: MessageDatabase,
-    P: PresenceDatabase,
-    A: AccountDatabase,
-    N: NotificationGateway,
+    M: MessageDatabase + ?Sized + 'static,
+    P: PresenceDatabase + ?Sized + 'static,
+    G: MessageGateway + 'static,
+    A: AccountDatabase + 'static,
+    B: BlockDatabase + 'static,
+    R: MessageRequestDatabase + 'static,
+    S: ScheduledMessageDatabase + 'static,
+    N: NotificationGateway + 'static,
 {
     messages_db: Arc<M>,
     presence_db: Arc<P>,
+    account_db: Arc<A>,
+    block_db: Arc<B>,
+    request_db: Arc<R>,
+    schedule_db: Arc<S>,
+    message_gateway: Arc<G>,
+    in_flight: Arc<InFlightMessages>,
     notification_service: Arc<NotificationService<A, N>>,
+    message_ttl: Duration,
+    queue_limits: QueueLimits,
+    message_limits: MessageLimits,
 }
 
-impl<M, P, A, N> MessagingService<M, P, A, N>
+impl<M, P, G, A, B, R, S, N> MessagingService<M, P, G, A, B, R, S, N>
 where
-    M: MessageDatabase,
-    P: PresenceDatabase,
-    A: AccountDatabase,
-    N: NotificationGateway,
+    M: MessageDatabase + ?Sized + 'static,
+    P: PresenceDatabase + ?Sized + 'static,
+    G: MessageGateway + 'static,
+    A: AccountDatabase + 'static,
+    B: BlockDatabase + 'static,
+    R: MessageRequestDatabase + 'static,
+    S: ScheduledMessageDatabase + 'static,
+    N: NotificationGateway + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         messages_db: Arc<M>,
         presence_db: Arc<P>,
+        account_db: Arc<A>,
+        block_db: Arc<B>,
+        request_db: Arc<R>,
+        schedule_db: Arc<S>,
+        message_gateway: Arc<G>,
+        in_flight: Arc<InFlightMessages>,
         notification_service: Arc<NotificationService<A, N>>,
-    ) -> MessagingService<M, P, A, N> {
+        message_ttl: Duration,
+        queue_limits: QueueLimits,
+        message_limits: MessageLimits,
+    ) -> MessagingService<M, P, G, A, B, R, S, N> {
         MessagingService {
             messages_db,
             presence_db,
+            account_db,
+            block_db,
+            request_db,
+            schedule_db,
+            message_gateway,
+            in_flight,
             notification_service,
+            message_ttl,
+            queue_limits,
+            message_limits,
         }
     }
 
+    /// Queues a message for delivery. It expires after the configured TTL,
+    /// or after `requested_ttl` if the sender asks for a shorter one.
+    ///
+    /// Sends with an `idempotency_key` can safely be retried, a retry within the
+    /// deduplication window returns the original receipt instead of queuing a duplicate.
+    ///
+    /// Messages to recipients that blocked the sender are dropped, but the sender
+    /// gets a receipt as if the message was queued.
+    ///
+    /// Until the recipient accepts the sender, its messages are held back as message
+    /// requests, which are neither delivered nor wake up the recipient's device.
+    /// Sending a message accepts the recipient's replies.
     #[instrument(skip(self, message), fields(sender_id, recipient_id))]
     pub async fn send_message(
         &self,
         sender_id: Uuid,
         recipient_id: Uuid,
         message: DoubleRatchetMessage,
+        requested_ttl: Option<Duration>,
+        idempotency_key: Option<Uuid>,
     ) -> Result<MessageReceipt, MessagingError> {
-        let is_recipient_present = match self.presence_db.is_present(&recipient_id).await {
-            Ok(present) => {
-                debug!("Recipient {} presence status: {}", recipient_id, present);
-                present
-            }
-            Err(e) => {
-                error!(
-                    "Failed to check recipient presence for {}: {}",
-                    recipient_id, e
-                );
-                true
-            }
-        };
-
         let timestamp = chrono::Utc::now().timestamp_millis() as u64;
-        let message = Message {
-            message_id: Uuid::new_v4(),
-            sender_id,
+        let message = self.build_message(
+            Some(sender_id),
             recipient_id,
-            message: message.clone(),
+            None,
+            message,
+            requested_ttl,
             timestamp,
+        );
+        let receipt = MessageReceipt::of(&message);
+        if let Some(sent_receipt) = self
+            .claim_idempotency_key(sender_id, idempotency_key, &receipt)
+            .await?
+        {
+            return Ok(sent_receipt);
+        }
+
+        let message = match self.enqueue_message(message).await {
+            Ok(message) => message,
+            Err(e) => {
+                self.release_idempotency_key(sender_id, idempotency_key)
+                    .await;
+                return Err(e);
+            }
         };
 
-        self.messages_db.insert_message(message.clone())?;
+        if let Some(message) = message {
+            self.deliver(message).await?;
+        }
+        Ok(receipt)
+    }
 
-        if !is_recipient_present {
-            self.notification_service
-                .send_wakeup_notification(recipient_id)
-                .await?;
+    /// Holds a message back until `deliver_at` (epoch milliseconds), then queues and
+    /// delivers it like `send_message` does. Its TTL starts at the delivery time.
+    /// Messages that are due already are sent right away.
+    #[instrument(skip(self, message), fields(sender_id, recipient_id))]
+    pub async fn schedule_message(
+        &self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        message: DoubleRatchetMessage,
+        requested_ttl: Option<Duration>,
+        deliver_at: u64,
+        idempotency_key: Option<Uuid>,
+    ) -> Result<MessageReceipt, MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        if deliver_at <= now {
+            return self
+                .send_message(
+                    sender_id,
+                    recipient_id,
+                    message,
+                    requested_ttl,
+                    idempotency_key,
+                )
+                .await;
+        }
+        if deliver_at > now + MAX_DELIVERY_DELAY.as_millis() as u64 {
+            return Err(MessagingError::InvalidDeliveryTime(format!(
+                "messages can be scheduled at most {} days ahead",
+                MAX_DELIVERY_DELAY.as_secs() / (24 * 60 * 60)
+            )));
         }
 
-        Ok(MessageReceipt {
-            message_id: message.message_id,
-            timestamp,
-        })
+        // Checked again on release, but senders should learn about these right away
+        self.message_limits.check(&message)?;
+        if self.account_db.fetch_account(recipient_id).await?.is_none() {
+            return Err(MessagingError::RecipientNotFound(recipient_id));
+        }
+
+        let message = self.build_message(
+            Some(sender_id),
+            recipient_id,
+            None,
+            message,
+            requested_ttl,
+            deliver_at,
+        );
+        let receipt = MessageReceipt::of(&message);
+        if let Some(sent_receipt) = self
+            .claim_idempotency_key(sender_id, idempotency_key, &receipt)
+            .await?
+        {
+            return Ok(sent_receipt);
+        }
+        if let Err(e) = self.schedule_db.insert_scheduled_message(message).await {
+            self.release_idempotency_key(sender_id, idempotency_key)
+                .await;
+            return Err(e);
+        }
+
+        debug!(
+            "Scheduled message {} for delivery at {}",
+            receipt.message_id, deliver_at
+        );
+        Ok(receipt)
     }
 
-    pub async fn get_pending_messages(
+    /// Cancels a scheduled message of the sender that has not been released yet
+    #[instrument(skip(self))]
+    pub async fn cancel_scheduled_message(
         &self,
-        account_id: Uuid,
-    ) -> Result<Vec<Message>, MessagingError> {
-        self.messages_db.get_messages_for_account(account_id)
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        if self
+            .schedule_db
+            .remove_scheduled_message(sender_id, message_id)
+            .await?
+        {
+            Ok(())
+        } else {
+            Err(MessagingError::ScheduledMessageNotFound(message_id))
+        }
     }
 
-    pub async fn mark_delivered(
+    /// Pulls a queued message or message request of the sender back before it reaches
+    /// its recipient. Fails if the message was handed out to the recipient already,
+    /// even if not acknowledged yet, or if the sender has no such message queued.
+    #[instrument(skip(self))]
+    pub async fn retract_message(
         &self,
-        account_id: Uuid,
-        message_ids: Vec<Uuid>,
+        sender_id: Uuid,
+        message_id: Uuid,
     ) -> Result<(), MessagingError> {
-        self.messages_db.remove_messages(account_id, message_ids)
-    }
-}
+        if self.messages_db.is_handed_out(message_id).await? {
+            return Err(MessagingError::MessageAlreadyDelivered(message_id));
+        }
 
-#[cfg(test)]
-mod tests {
-    use mockall::predicate::eq;
-    use prism_client::SigningKey;
-    use std::sync::Arc;
-    use uuid::Uuid;
+        let retracted = match self
+            .messages_db
+            .remove_message_of_sender(sender_id, message_id)
+            .await?
+        {
+            Some(message) => Some(message),
+            None => {
+                self.request_db
+                    .remove_request_message_of_sender(sender_id, message_id)
+                    .await?
+            }
+        };
 
-    use super::MessagingService;
-    use crate::account::database::MockAccountDatabase;
-    use crate::database::inmemory::InMemoryDatabase;
-    use crate::messages::{
-        database::MockMessageDatabase,
-        entities::{DoubleRatchetHeader, DoubleRatchetMessage},
-        error::MessagingError,
-    };
-    use crate::notifications::{gateway::MockNotificationGateway, service::NotificationService};
-    use crate::presence::database::MockPresenceDatabase;
-    use crate::presence::error::PresenceError;
+        match retracted {
+            Some(message) => {
+                debug!(
+                    "Retracted message {} to {}",
+                    message_id, message.recipient_id
+                );
+                Ok(())
+            }
+            // It may have been handed out since the first check
+            None if self.messages_db.is_handed_out(message_id).await? => {
+                Err(MessagingError::MessageAlreadyDelivered(message_id))
+            }
+            None => Err(MessagingError::MessageNotFound(message_id)),
+        }
+    }
 
-    #[tokio::test]
-    async fn test_send_and_get_message() {
-        let alice_id = Uuid::new_v4();
-        let bob_id = Uuid::new_v4();
+    /// Spawn the background task that releases scheduled messages once they are due
+    #[instrument(skip(self))]
+    pub fn spawn_scheduled_message_releaser(
+        self: Arc<Self>,
+        release_interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            info!("Starting scheduled message release task");
+            let mut ticker = interval(release_interval);
+
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.release_scheduled_messages().await {
+                    error!("Error releasing scheduled messages: {}", e);
+                }
+            }
+        })
+    }
 
-        let message_db_arc = Arc::new(InMemoryDatabase::new());
+    /// Queues the scheduled messages that are due and delivers them like freshly sent ones.
+    /// Messages that failed to queue because of a database error stay scheduled for the next
+    /// run, senders are told about messages that can never be queued.
+    async fn release_scheduled_messages(&self) -> Result<(), MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let due_messages = self.schedule_db.get_due_scheduled_messages(now).await?;
 
-        let mut mock_presence_db = MockPresenceDatabase::new();
-        mock_presence_db
-            .expect_is_present()
-            .with(eq(alice_id))
-            .times(1)
-            .returning(|_| Ok(true));
-        let presence_db_arc = Arc::new(mock_presence_db);
+        for message in due_messages {
+            let Some(sender_id) = message.sender_id else {
+                continue;
+            };
+            let message_id = message.message_id;
+
+            // Removed first, so a message canceled meanwhile is not released
+            if !self
+                .schedule_db
+                .remove_scheduled_message(sender_id, message_id)
+                .await?
+            {
+                continue;
+            }
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
-        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
-        let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
-        let service = MessagingService::new(
-            message_db_arc,
-            presence_db_arc,
-            Arc::new(notification_service),
-        );
+            match self.enqueue_message(message.clone()).await {
+                Ok(Some(message)) => {
+                    if let Err(e) = self.deliver(message).await {
+                        warn!("Failed to deliver scheduled message {}: {}", message_id, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e @ MessagingError::DatabaseError(_)) => {
+                    warn!(
+                        "Failed to release scheduled message {}, retrying later: {}",
+                        message_id, e
+                    );
+                    if let Err(e) = self.schedule_db.insert_scheduled_message(message).await {
+                        error!("Lost scheduled message {}: {}", message_id, e);
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to release scheduled message {}: {}", message_id, e);
+                    self.send_release_failure(sender_id, message).await;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let bob_ephemeral_key = SigningKey::new_secp256r1().verifying_key();
+    /// Tells the sender that a scheduled message will never be delivered
+    async fn send_release_failure(&self, sender_id: Uuid, message: Message) {
+        let expiry = MessageExpiry {
+            message_id: message.message_id,
+            recipient_id: message.recipient_id,
+            expired_at: chrono::Utc::now().timestamp_millis() as u64,
+        };
+        match self
+            .message_gateway
+            .send_message_expiry(sender_id, expiry)
+            .await
+        {
+            Ok(()) => {}
+            Err(MessagingError::UserNotFound(account_id)) => {
+                debug!(
+                    "Sender {} not connected, dropping expiry notice",
+                    account_id
+                );
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to notify sender {} about scheduled message {}: {}",
+                    sender_id, message.message_id, e
+                );
+            }
+        }
+    }
 
-        let header = DoubleRatchetHeader {
-            ephemeral_key: bob_ephemeral_key,
-            message_number: 0,
-            previous_message_number: 0,
-            one_time_prekey_id: Some(0),
+    /// Claims the idempotency key for the receipt of a new send before the message is stored,
+    /// so concurrent retries can't both queue it. Returns the receipt of the earlier send
+    /// if the key is taken within the deduplication window.
+    async fn claim_idempotency_key(
+        &self,
+        sender_id: Uuid,
+        idempotency_key: Option<Uuid>,
+        receipt: &MessageReceipt,
+    ) -> Result<Option<MessageReceipt>, MessagingError> {
+        let Some(idempotency_key) = idempotency_key else {
+            return Ok(None);
         };
 
-        let message = DoubleRatchetMessage {
-            header,
-            ciphertext: "Hello, Alice".as_bytes().to_vec(),
-            nonce: vec![0; 12],
+        let sent_message = SentMessage {
+            sender_id,
+            idempotency_key,
+            receipt: receipt.clone(),
+            dedup_until: receipt.timestamp + MESSAGE_DEDUP_WINDOW.as_millis() as u64,
         };
+        if self.messages_db.insert_sent_message(sent_message).await? {
+            return Ok(None);
+        }
 
-        let receipt = service
-            .send_message(bob_id, alice_id, message)
-            .await
-            .expect("Could not send Bob's message to Alice");
+        // The earlier send may have been forgotten meanwhile, then this is a new message
+        let sent_message = self
+            .messages_db
+            .get_sent_message(sender_id, idempotency_key)
+            .await?;
+        Ok(sent_message.map(|sent| {
+            debug!(
+                "Message {} was sent before, returning its receipt",
+                sent.receipt.message_id
+            );
+            sent.receipt
+        }))
+    }
 
-        // Verify receipt contains message_id and timestamp
-        assert!(receipt.timestamp > 0);
+    /// Releases the idempotency key of a send that failed, so it can be retried
+    async fn release_idempotency_key(&self, sender_id: Uuid, idempotency_key: Option<Uuid>) {
+        let Some(idempotency_key) = idempotency_key else {
+            return;
+        };
 
-        let retrieved_messages = service
-            .get_pending_messages(alice_id)
+        if let Err(e) = self
+            .messages_db
+            .remove_sent_message(sender_id, idempotency_key)
             .await
-            .expect("Could not fetch message for Alice");
+        {
+            warn!(
+                "Failed to release idempotency key {} of {}: {}",
+                idempotency_key, sender_id, e
+            );
+        }
+    }
 
-        assert_eq!(retrieved_messages.len(), 1);
-        let alices_msg = retrieved_messages.first().unwrap();
-        assert_eq!(alices_msg.message_id, receipt.message_id);
-        assert_eq!(alices_msg.sender_id, bob_id);
-        assert_eq!(alices_msg.recipient_id, alice_id);
-        assert_eq!(alices_msg.timestamp, receipt.timestamp);
-        assert_eq!(
-            alices_msg.message.ciphertext,
-            "Hello, Alice".as_bytes().to_vec()
-        );
+    /// Queues a message without recording its sender, who is only revealed to
+    /// the recipient inside the encrypted message. Callers must have checked the
+    /// recipient's delivery access key. Sealed senders receive neither delivery
+    /// receipts nor expiry notices.
+    ///
+    /// The server can't tell whether the recipient blocked a sealed sender,
+    /// recipients have to reset their delivery access key instead.
+    #[instrument(skip(self, message), fields(recipient_id))]
+    pub async fn send_sealed_message(
+        &self,
+        recipient_id: Uuid,
+        message: DoubleRatchetMessage,
+        requested_ttl: Option<Duration>,
+    ) -> Result<MessageReceipt, MessagingError> {
+        let (receipt, message) = self
+            .queue_message(None, recipient_id, None, message, requested_ttl)
+            .await?;
+
+        if let Some(message) = message {
+            self.deliver(message).await?;
+        }
+        Ok(receipt)
     }
 
-    #[tokio::test]
-    async fn test_mark_messages_delivered() {
-        let alice_id = Uuid::new_v4();
-        let bob_id = Uuid::new_v4();
+    /// Delivers a queued message directly if the recipient is connected,
+    /// otherwise wakes up the recipient's device to fetch it.
+    async fn deliver(&self, message: Message) -> Result<(), MessagingError> {
+        let recipient_id = message.recipient_id;
 
-        let message_db_arc = Arc::new(InMemoryDatabase::new());
+        if self.is_recipient_present(recipient_id).await {
+            self.try_deliver_directly(message).await
+        } else {
+            self.notification_service
+                .send_wakeup_notification(recipient_id)
+                .await?;
+            Ok(())
+        }
+    }
 
-        let mut mock_presence_db = MockPresenceDatabase::new();
-        mock_presence_db
-            .expect_is_present()
-            .with(eq(alice_id))
-            .times(20)
-            .returning(|_| Ok(true));
-        let presence_db_arc = Arc::new(mock_presence_db);
+    /// Queues one message per recipient, like `send_message`.
+    /// Each message succeeds or fails on its own, results are in the order of the batch.
+    #[instrument(skip(self, messages), fields(sender_id, batch_size = messages.len()))]
+    pub async fn send_messages(
+        &self,
+        sender_id: Uuid,
+        messages: Vec<(Uuid, DoubleRatchetMessage)>,
+        requested_ttl: Option<Duration>,
+    ) -> Result<Vec<Result<MessageReceipt, MessagingError>>, MessagingError> {
+        if messages.len() > MAX_SEND_BATCH_SIZE {
+            return Err(MessagingError::BatchTooLarge(messages.len()));
+        }
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
-        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
-        let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
-        let service = MessagingService::new(
-            message_db_arc,
-            presence_db_arc,
-            Arc::new(notification_service),
-        );
+        self.fan_out(sender_id, None, messages, requested_ttl).await
+    }
 
-        let bob_ephemeral_key = SigningKey::new_secp256r1().verifying_key();
+    /// Queues a copy of the message for each of the group's members.
+    /// The ciphertext is opaque to the server, e.g. encrypted with the sender's sender key.
+    /// Members who have not accepted the sender get the message as message request.
+    #[instrument(skip(self, recipient_ids, message), fields(sender_id, group_id))]
+    pub async fn send_group_message(
+        &self,
+        sender_id: Uuid,
+        group_id: Uuid,
+        recipient_ids: Vec<Uuid>,
+        message: DoubleRatchetMessage,
+        requested_ttl: Option<Duration>,
+    ) -> Result<Vec<Result<MessageReceipt, MessagingError>>, MessagingError> {
+        let messages = recipient_ids
+            .into_iter()
+            .map(|recipient_id| (recipient_id, message.clone()))
+            .collect();
+
+        self.fan_out(sender_id, Some(group_id), messages, requested_ttl)
+            .await
+    }
 
-        let mut message_ids = Vec::new();
-        for i in 0..20 {
-            let new_header = DoubleRatchetHeader {
-                ephemeral_key: bob_ephemeral_key.clone(),
-                message_number: i + 1,
-                previous_message_number: i,
-                one_time_prekey_id: None,
+    /// Absent recipients get a single wake-up notification, however many messages they
+    /// receive. Failing to wake them up does not fail their messages, which are
+    /// delivered on their next connect anyway.
+    async fn fan_out(
+        &self,
+        sender_id: Uuid,
+        group_id: Option<Uuid>,
+        messages: Vec<(Uuid, DoubleRatchetMessage)>,
+        requested_ttl: Option<Duration>,
+    ) -> Result<Vec<Result<MessageReceipt, MessagingError>>, MessagingError> {
+        let mut presence = HashMap::new();
+        let mut results = Vec::with_capacity(messages.len());
+
+        for (recipient_id, message) in messages {
+            let message = match self
+                .queue_message(
+                    Some(sender_id),
+                    recipient_id,
+                    group_id,
+                    message,
+                    requested_ttl,
+                )
+                .await
+            {
+                Ok((receipt, Some(message))) => {
+                    results.push(Ok(receipt));
+                    message
+                }
+                // Dropped or held back as message request
+                Ok((receipt, None)) => {
+                    results.push(Ok(receipt));
+                    continue;
+                }
+                Err(e) => {
+                    results.push(Err(e));
+                    continue;
+                }
             };
-            let new_message = DoubleRatchetMessage {
-                header: new_header,
-                ciphertext: format!("Hello, Alice {}", i).as_bytes().to_vec(),
-                nonce: vec![0; 12],
+
+            let is_recipient_present = match presence.get(&recipient_id) {
+                Some(present) => *present,
+                None => {
+                    let present = self.is_recipient_present(recipient_id).await;
+                    presence.insert(recipient_id, present);
+                    present
+                }
             };
 
-            let receipt = service
-                .send_message(bob_id, alice_id, new_message)
+            if is_recipient_present {
+                self.try_deliver_directly(message).await?;
+            }
+        }
+
+        for (recipient_id, _) in presence.into_iter().filter(|(_, present)| !present) {
+            if let Err(e) = self
+                .notification_service
+                .send_wakeup_notification(recipient_id)
+                .await
+            {
+                warn!(
+                    "Failed to send wake-up notification to {}: {}",
+                    recipient_id, e
+                );
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Builds a message sent now and queues it, see `enqueue_message`
+    async fn queue_message(
+        &self,
+        sender_id: Option<Uuid>,
+        recipient_id: Uuid,
+        group_id: Option<Uuid>,
+        message: DoubleRatchetMessage,
+        requested_ttl: Option<Duration>,
+    ) -> Result<(MessageReceipt, Option<Message>), MessagingError> {
+        let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+        let message = self.build_message(
+            sender_id,
+            recipient_id,
+            group_id,
+            message,
+            requested_ttl,
+            timestamp,
+        );
+        let receipt = MessageReceipt::of(&message);
+        let message = self.enqueue_message(message).await?;
+        Ok((receipt, message))
+    }
+
+    fn build_message(
+        &self,
+        sender_id: Option<Uuid>,
+        recipient_id: Uuid,
+        group_id: Option<Uuid>,
+        message: DoubleRatchetMessage,
+        requested_ttl: Option<Duration>,
+        timestamp: u64,
+    ) -> Message {
+        let ttl = requested_ttl.map_or(self.message_ttl, |ttl| ttl.min(self.message_ttl));
+        Message {
+            // Time-ordered, so messages of the same millisecond keep their order
+            message_id: Uuid::now_v7(),
+            sender_id,
+            recipient_id,
+            group_id,
+            message,
+            timestamp,
+            expires_at: timestamp + ttl.as_millis() as u64,
+        }
+    }
+
+    /// Checks the recipient's queue quotas and stores the message.
+    /// Returns the queued message, which is `None` if the message is not to be
+    /// delivered, because the recipient blocked the sender or has yet to accept
+    /// the sender's message request.
+    async fn enqueue_message(&self, message: Message) -> Result<Option<Message>, MessagingError> {
+        let Message {
+            sender_id,
+            recipient_id,
+            group_id,
+            ..
+        } = message;
+
+        self.message_limits.check(&message.message)?;
+        if self.account_db.fetch_account(recipient_id).await?.is_none() {
+            return Err(MessagingError::RecipientNotFound(recipient_id));
+        }
+
+        let is_blocked = match sender_id {
+            Some(sender_id) => self.block_db.is_blocked(recipient_id, sender_id).await?,
+            None => false,
+        };
+
+        // Sealed senders, who know the recipient's delivery access key, don't need to be
+        // accepted. Group members do, anyone can add the recipient to a group.
+        let request_sender_id = match sender_id {
+            Some(sender_id) if !is_blocked => {
+                // Only writing to someone directly accepts their replies
+                if group_id.is_none() {
+                    self.request_db
+                        .insert_accepted_sender(sender_id, recipient_id)
+                        .await?;
+                }
+                let is_accepted = self
+                    .request_db
+                    .is_accepted_sender(recipient_id, sender_id)
+                    .await?;
+                (!is_accepted).then_some(sender_id)
+            }
+            _ => None,
+        };
+
+        // Checked before inserting, so concurrent sends may slightly overshoot the limits.
+        // Also checked for blocked senders, who must not tell the block from the errors.
+        if let Some(sender_id) = request_sender_id {
+            let requests = self
+                .request_db
+                .count_request_messages(recipient_id, sender_id)
+                .await?;
+            if requests >= self.queue_limits.max_messages_per_sender {
+                return Err(MessagingError::QuotaExceeded(QueueQuota::SenderMessages));
+            }
+        } else {
+            let usage = self
+                .messages_db
+                .get_queue_usage(recipient_id, sender_id)
+                .await?;
+            self.queue_limits
+                .check(&usage, message.message.ciphertext.len() as u64)
+                .map_err(MessagingError::QuotaExceeded)?;
+        }
+
+        if is_blocked {
+            debug!(
+                "Recipient {} blocked the sender, dropping message {}",
+                recipient_id, message.message_id
+            );
+            return Ok(None);
+        }
+
+        if let Some(sender_id) = request_sender_id {
+            debug!(
+                "Recipient {} has not accepted {} yet, holding back message {} as request",
+                recipient_id, sender_id, message.message_id
+            );
+            self.request_db.insert_request_message(message).await?;
+            return Ok(None);
+        }
+
+        self.messages_db.insert_message(message.clone()).await?;
+        Ok(Some(message))
+    }
+
+    /// Recipients are assumed to be present if their presence is unknown,
+    /// as their messages are delivered on the next connect anyway
+    async fn is_recipient_present(&self, recipient_id: Uuid) -> bool {
+        match self.presence_db.is_present(&recipient_id).await {
+            Ok(present) => {
+                debug!("Recipient {} presence status: {}", recipient_id, present);
+                present
+            }
+            Err(e) => {
+                error!(
+                    "Failed to check recipient presence for {}: {}",
+                    recipient_id, e
+                );
+                true
+            }
+        }
+    }
+
+    /// Pushes a queued message to its connected recipient right away.
+    /// The message stays queued until the recipient acknowledges it. If sending
+    /// fails, it is delivered once the recipient (re)connects.
+    async fn try_deliver_directly(&self, message: Message) -> Result<(), MessagingError> {
+        let recipient_id = message.recipient_id;
+        let message_id = message.message_id;
+
+        let expires_at = message.expires_at;
+        self.in_flight.mark(message_id);
+        match self.message_gateway.send_message(message).await {
+            Ok(()) => {
+                debug!(
+                    "Delivered message {} directly to recipient {}, awaiting ack",
+                    message_id, recipient_id
+                );
+                // Only marked once sent, so messages never handed out can still be retracted
+                if let Err(e) = self
+                    .messages_db
+                    .insert_handed_out_messages(vec![message_id], expires_at)
+                    .await
+                {
+                    warn!("Failed to mark message {} handed out: {}", message_id, e);
+                }
+                Ok(())
+            }
+            Err(MessagingError::UserNotFound(_)) => {
+                // Recipient is connected to another server instance or just left
+                debug!(
+                    "Recipient {} not connected here, keeping message queued",
+                    recipient_id
+                );
+                self.in_flight.clear(&[message_id]);
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to deliver message {} directly to recipient {}: {}",
+                    message_id, recipient_id, e
+                );
+                self.in_flight.clear(&[message_id]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remembers that the messages were handed out, so their senders can no longer retract them
+    async fn mark_handed_out(&self, messages: &[Message]) -> Result<(), MessagingError> {
+        let Some(until) = messages.iter().map(|message| message.expires_at).max() else {
+            return Ok(());
+        };
+        let ids = messages.iter().map(|message| message.message_id).collect();
+        self.messages_db
+            .insert_handed_out_messages(ids, until)
+            .await
+    }
+
+    /// Returns up to `limit` pending messages that come after the cursor,
+    /// and the cursor to continue with if there are more.
+    /// The returned messages count as handed out.
+    pub async fn get_pending_messages(
+        &self,
+        account_id: Uuid,
+        after: Option<MessageCursor>,
+        limit: usize,
+    ) -> Result<PendingMessagesPage, MessagingError> {
+        let limit = limit.clamp(1, MAX_PENDING_MESSAGES_PAGE_SIZE);
+
+        // Fetch one more message to find out whether there is another page
+        let mut messages = self
+            .messages_db
+            .get_messages_page_for_account(account_id, after, limit + 1)
+            .await?;
+
+        let next_cursor = if messages.len() > limit {
+            messages.truncate(limit);
+            messages.last().map(MessageCursor::of)
+        } else {
+            None
+        };
+        self.mark_handed_out(&messages).await?;
+        Ok(PendingMessagesPage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    /// Returns the account's message requests that have not expired yet.
+    /// The returned messages count as handed out.
+    pub async fn get_message_requests(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let messages: Vec<Message> = self
+            .request_db
+            .get_request_messages(account_id)
+            .await?
+            .into_iter()
+            .filter(|message| message.expires_at > now)
+            .collect();
+        self.mark_handed_out(&messages).await?;
+        Ok(messages)
+    }
+
+    /// Accepts messages of the sender. The sender's message requests move to the
+    /// account's queue, later messages are queued right away.
+    #[instrument(skip(self))]
+    pub async fn accept_message_request(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        self.request_db
+            .insert_accepted_sender(account_id, sender_id)
+            .await?;
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let messages: Vec<Message> = self
+            .request_db
+            .remove_request_messages(account_id, sender_id)
+            .await?
+            .into_iter()
+            .filter(|message| message.expires_at > now)
+            .collect();
+        for message in &messages {
+            self.messages_db.insert_message(message.clone()).await?;
+        }
+
+        // The account just accepted, so there's no need to wake up its device
+        if !messages.is_empty() && self.is_recipient_present(account_id).await {
+            for message in messages {
+                self.try_deliver_directly(message).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the sender's message requests without telling the sender.
+    /// Later messages of the sender are message requests again.
+    #[instrument(skip(self))]
+    pub async fn decline_message_request(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        self.request_db
+            .remove_request_messages(account_id, sender_id)
+            .await?;
+        Ok(())
+    }
+
+    /// Acknowledges messages and receipts received by the given account.
+    /// Removes them from the queue and tells the senders of the messages
+    /// that they were delivered. Delivered messages are remembered for the
+    /// message TTL so that the account can mark them as read.
+    pub async fn mark_delivered(
+        &self,
+        account_id: Uuid,
+        message_ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let delivered_messages: Vec<Message> = self
+            .messages_db
+            .get_messages_for_account(account_id)
+            .await?
+            .into_iter()
+            .filter(|message| message_ids.contains(&message.message_id))
+            .collect();
+
+        self.messages_db
+            .remove_messages(account_id, message_ids.clone())
+            .await?;
+        self.messages_db
+            .remove_receipts(account_id, message_ids.clone())
+            .await?;
+        self.in_flight.clear(&message_ids);
+
+        let expires_at =
+            chrono::Utc::now().timestamp_millis() as u64 + self.message_ttl.as_millis() as u64;
+        for message in delivered_messages {
+            // Sealed senders cannot be reached
+            let Some(sender_id) = message.sender_id else {
+                continue;
+            };
+            self.messages_db
+                .insert_delivered_message(DeliveredMessage {
+                    message_id: message.message_id,
+                    sender_id,
+                    recipient_id: account_id,
+                    expires_at,
+                })
+                .await?;
+
+            match self
+                .send_receipt(
+                    ReceiptKind::Delivered,
+                    message.message_id,
+                    sender_id,
+                    account_id,
+                )
+                .await
+            {
+                // The messages are acknowledged already, a full receipt queue must not fail that
+                Err(MessagingError::QuotaExceeded(quota)) => {
+                    debug!(
+                        "Dropping delivery receipt for message {} to {}: {}",
+                        message.message_id, sender_id, quota
+                    );
+                }
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Relays read receipts of the given account to the sender of the messages.
+    /// Only messages delivered from the sender to the account are relayed, each at most once,
+    /// other message IDs are ignored.
+    pub async fn mark_read(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+        message_ids: Vec<Uuid>,
+    ) -> Result<(), MessagingError> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let read_messages = self
+            .messages_db
+            .remove_delivered_messages(account_id, sender_id, message_ids)
+            .await?;
+
+        for message in read_messages {
+            if message.expires_at <= now {
+                continue;
+            }
+            self.send_receipt(ReceiptKind::Read, message.message_id, sender_id, account_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Queues a receipt for the sender of a message and pushes it right away
+    /// if possible. It stays queued until the sender acknowledges it.
+    /// Receipts are dropped if the sender blocked the recipient and count
+    /// against the sender's queue limits.
+    async fn send_receipt(
+        &self,
+        kind: ReceiptKind,
+        message_id: Uuid,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        let receipt = Receipt {
+            receipt_id: Uuid::new_v4(),
+            kind,
+            message_id,
+            sender_id,
+            recipient_id,
+            timestamp: chrono::Utc::now().timestamp_millis() as u64,
+        };
+
+        if self.block_db.is_blocked(sender_id, recipient_id).await? {
+            debug!(
+                "Dropping receipt for message {}: {} blocked {}",
+                message_id, sender_id, recipient_id
+            );
+            return Ok(());
+        }
+
+        // Checked before inserting, so concurrent receipts may slightly overshoot the limits
+        let receipts = self.messages_db.get_receipts_for_account(sender_id).await?;
+        let from_recipient = receipts
+            .iter()
+            .filter(|receipt| receipt.recipient_id == recipient_id)
+            .count();
+        if from_recipient >= self.queue_limits.max_messages_per_sender {
+            return Err(MessagingError::QuotaExceeded(QueueQuota::SenderMessages));
+        }
+        if receipts.len() >= self.queue_limits.max_messages {
+            return Err(MessagingError::QuotaExceeded(QueueQuota::RecipientMessages));
+        }
+
+        self.messages_db.insert_receipt(receipt.clone()).await?;
+
+        if let Err(e) = self.message_gateway.send_receipt(receipt).await {
+            debug!(
+                "Could not send receipt for message {} to {} right away: {}",
+                message_id, sender_id, e
+            );
+        }
+        Ok(())
+    }
+
+    /// Handle acknowledgements of messages and receipts sent through the gateway
+    #[instrument(skip(self))]
+    pub async fn handle_acks(self: Arc<Self>) {
+        let service = self.clone();
+        self.message_gateway
+            .register_ack_handler(move |account_id, message_ids| {
+                let service = service.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = service.mark_delivered(account_id, message_ids).await {
+                        error!("Error acknowledging messages for {}: {}", account_id, e);
+                    }
+                });
+            })
+            .await;
+    }
+
+    /// Handle read receipts sent through the gateway
+    #[instrument(skip(self))]
+    pub async fn handle_read_receipts(self: Arc<Self>) {
+        let service = self.clone();
+        self.message_gateway
+            .register_read_handler(move |account_id, sender_id, message_ids| {
+                let service = service.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = service.mark_read(account_id, sender_id, message_ids).await {
+                        error!("Error relaying read receipts of {}: {}", account_id, e);
+                    }
+                });
+            })
+            .await;
+    }
+
+    /// Handle messages sent through the gateway, replying with their outcome
+    #[instrument(skip(self))]
+    pub async fn handle_sends(self: Arc<Self>) {
+        let service = self.clone();
+        self.message_gateway
+            .register_send_handler(move |account_id, request| {
+                let service = service.clone();
+                tokio::spawn(async move {
+                    service.handle_send(account_id, request).await;
+                });
+            })
+            .await;
+    }
+
+    async fn handle_send(&self, account_id: Uuid, request: SendRequest) {
+        let ttl = request.ttl.map(Duration::from_secs);
+        let result = match request.deliver_at {
+            Some(deliver_at) => {
+                self.schedule_message(
+                    account_id,
+                    request.recipient_id,
+                    request.message,
+                    ttl,
+                    deliver_at,
+                    request.idempotency_key,
+                )
+                .await
+            }
+            None => {
+                self.send_message(
+                    account_id,
+                    request.recipient_id,
+                    request.message,
+                    ttl,
+                    request.idempotency_key,
+                )
+                .await
+            }
+        };
+
+        if let Err(e) = self
+            .message_gateway
+            .reply_to_send(account_id, request.request_id, result)
+            .await
+        {
+            warn!(
+                "Failed to reply to send request {} of {}: {}",
+                request.request_id, account_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse};
+    use mockall::predicate::eq;
+    use prism_client::SigningKey;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    use super::{MAX_DELIVERY_DELAY, MAX_SEND_BATCH_SIZE, MESSAGE_DEDUP_WINDOW, MessagingService};
+    use crate::account::{
+        database::{AccountDatabase, MockAccountDatabase},
+        entities::Account,
+    };
+    use crate::blocks::{database::BlockDatabase, entities::BlockedAccount};
+    use crate::crypto::salted_hash::SaltedHash;
+    use crate::database::inmemory::InMemoryDatabase;
+    use crate::messages::{
+        database::{MessageDatabase, MockMessageDatabase},
+        entities::{
+            DoubleRatchetHeader, DoubleRatchetMessage, Message, MessageCursor, MessageLimits,
+            QueueLimits, QueueUsage, Receipt, ReceiptKind, SendRequest,
+        },
+        error::{MessagingError, QueueQuota},
+        gateway::MockMessageGateway,
+        in_flight::InFlightMessages,
+        requests::database::MockMessageRequestDatabase,
+        scheduled::database::ScheduledMessageDatabase,
+    };
+    use crate::notifications::{gateway::MockNotificationGateway, service::NotificationService};
+    use crate::presence::database::MockPresenceDatabase;
+    use crate::presence::error::PresenceError;
+
+    #[tokio::test]
+    async fn test_send_and_get_message() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db
+            .expect_is_present()
+            .with(eq(alice_id))
+            .times(1)
+            .returning(|_| Ok(true));
+        let presence_db_arc = Arc::new(mock_presence_db);
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc,
+            presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let bob_ephemeral_key = SigningKey::new_secp256r1().verifying_key();
+
+        let header = DoubleRatchetHeader {
+            ephemeral_key: bob_ephemeral_key,
+            message_number: 0,
+            previous_message_number: 0,
+            one_time_prekey_id: Some(0),
+            signed_prekey_id: None,
+        };
+
+        let message = DoubleRatchetMessage {
+            header,
+            ciphertext: "Hello, Alice".as_bytes().to_vec(),
+            nonce: vec![0; 12],
+        };
+
+        let receipt = service
+            .send_message(bob_id, alice_id, message, None, None)
+            .await
+            .expect("Could not send Bob's message to Alice");
+
+        // Verify receipt contains message_id and timestamp
+        assert!(receipt.timestamp > 0);
+
+        let retrieved_messages = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch message for Alice")
+            .messages;
+
+        assert_eq!(retrieved_messages.len(), 1);
+        let alices_msg = retrieved_messages.first().unwrap();
+        assert_eq!(alices_msg.message_id, receipt.message_id);
+        assert_eq!(alices_msg.sender_id, Some(bob_id));
+        assert_eq!(alices_msg.recipient_id, alice_id);
+        assert_eq!(alices_msg.timestamp, receipt.timestamp);
+        assert_eq!(
+            alices_msg.message.ciphertext,
+            "Hello, Alice".as_bytes().to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mark_messages_delivered() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db
+            .expect_is_present()
+            .with(eq(alice_id))
+            .times(20)
+            .returning(|_| Ok(true));
+        let presence_db_arc = Arc::new(mock_presence_db);
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc,
+            presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let bob_ephemeral_key = SigningKey::new_secp256r1().verifying_key();
+
+        let mut message_ids = Vec::new();
+        for i in 0..20 {
+            let new_header = DoubleRatchetHeader {
+                ephemeral_key: bob_ephemeral_key.clone(),
+                message_number: i + 1,
+                previous_message_number: i,
+                one_time_prekey_id: None,
+                signed_prekey_id: None,
+            };
+            let new_message = DoubleRatchetMessage {
+                header: new_header,
+                ciphertext: format!("Hello, Alice {}", i).as_bytes().to_vec(),
+                nonce: vec![0; 12],
+            };
+
+            let receipt = service
+                .send_message(bob_id, alice_id, new_message, None, None)
+                .await
+                .expect("Could not send message");
+
+            message_ids.push(receipt.message_id);
+        }
+
+        let retrieved = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+
+        let ids: Vec<Uuid> = retrieved.iter().map(|msg| msg.message_id).collect();
+        let delivered = ids[5..10].to_vec();
+        service
+            .mark_delivered(alice_id, delivered.clone())
+            .await
+            .expect("Could not set messages delivered");
+
+        let rest = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        let rest_ids: Vec<Uuid> = rest.iter().map(|msg| msg.message_id).collect();
+
+        // 15 messages left to mark as delivered
+        assert_eq!(rest_ids.len(), 15);
+
+        // rest_uuids shouldn't contain any from delivered
+        assert!(!rest_ids.iter().any(|uuid| delivered.contains(uuid)));
+
+        service
+            .mark_delivered(alice_id, rest_ids)
+            .await
+            .expect("Could not set messages delivered");
+        let final_messages = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert_eq!(final_messages.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mark_delivered_sends_receipt_to_sender() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        // Alice is connected elsewhere, Bob is connected here
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message()
+            .returning(|message| {
+                Err(MessagingError::UserNotFound(
+                    message.recipient_id.to_string(),
+                ))
+            });
+        mock_message_gateway
+            .expect_send_receipt()
+            .withf(move |receipt| {
+                receipt.kind == ReceiptKind::Delivered
+                    && receipt.sender_id == bob_id
+                    && receipt.recipient_id == alice_id
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+
+        // Unknown message IDs don't produce receipts
+        service
+            .mark_delivered(alice_id, vec![receipt.message_id, Uuid::new_v4()])
+            .await
+            .expect("Could not mark message delivered");
+
+        // The receipt stays queued for Bob until he acknowledges it
+        let receipts = message_db_arc
+            .get_receipts_for_account(bob_id)
+            .await
+            .expect("Could not fetch receipts for Bob");
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].message_id, receipt.message_id);
+
+        service
+            .mark_delivered(bob_id, vec![receipts[0].receipt_id])
+            .await
+            .expect("Could not acknowledge receipt");
+        let receipts = message_db_arc
+            .get_receipts_for_account(bob_id)
+            .await
+            .expect("Could not fetch receipts for Bob");
+        assert!(receipts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sealed_message_hides_sender() {
+        let alice_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        // No delivery receipt can be sent, as the server doesn't know the sender
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message()
+            .returning(|message| {
+                Err(MessagingError::UserNotFound(
+                    message.recipient_id.to_string(),
+                ))
+            });
+        mock_message_gateway.expect_send_receipt().never();
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let receipt = service
+            .send_sealed_message(alice_id, create_test_message(), None)
+            .await
+            .expect("Could not send sealed message");
+
+        let page = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not get pending messages");
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].message_id, receipt.message_id);
+        assert_eq!(page.messages[0].sender_id, None);
+
+        service
+            .mark_delivered(alice_id, vec![receipt.message_id])
+            .await
+            .expect("Could not mark message delivered");
+        let page = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not get pending messages");
+        assert!(page.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mark_read_queues_receipts_for_offline_sender() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let mut message_ids = Vec::new();
+        for _ in 0..2 {
+            let receipt = service
+                .send_message(bob_id, alice_id, create_test_message(), None, None)
+                .await
+                .expect("Could not send message");
+            message_ids.push(receipt.message_id);
+        }
+        service
+            .mark_delivered(alice_id, message_ids.clone())
+            .await
+            .expect("Could not mark messages delivered");
+
+        // Unknown message IDs and messages not from Bob are ignored
+        let mut read_ids = message_ids.clone();
+        read_ids.push(Uuid::new_v4());
+        service
+            .mark_read(alice_id, bob_id, read_ids.clone())
+            .await
+            .expect("Could not mark messages read");
+        service
+            .mark_read(alice_id, Uuid::new_v4(), message_ids.clone())
+            .await
+            .expect("Could not mark messages read");
+        // Messages are marked read only once
+        service
+            .mark_read(alice_id, bob_id, read_ids)
+            .await
+            .expect("Could not mark messages read");
+
+        let receipts: Vec<Receipt> = message_db_arc
+            .get_receipts_for_account(bob_id)
+            .await
+            .expect("Could not fetch receipts for Bob")
+            .into_iter()
+            .filter(|receipt| receipt.kind == ReceiptKind::Read)
+            .collect();
+        assert_eq!(receipts.len(), 2);
+        for (receipt, message_id) in receipts.iter().zip(message_ids) {
+            assert_eq!(receipt.message_id, message_id);
+            assert_eq!(receipt.recipient_id, alice_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receipts_to_blocking_sender_are_dropped() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        // Bob blocked Alice after sending her a message
+        let block_db_arc = Arc::new(InMemoryDatabase::new());
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            block_db_arc.clone(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        block_db_arc
+            .insert_block(
+                bob_id,
+                BlockedAccount {
+                    account_id: alice_id,
+                    blocked_at: 0,
+                },
+            )
+            .await
+            .unwrap();
+
+        service
+            .mark_delivered(alice_id, vec![receipt.message_id])
+            .await
+            .expect("Could not mark message delivered");
+        service
+            .mark_read(alice_id, bob_id, vec![receipt.message_id])
+            .await
+            .expect("Could not mark message read");
+
+        let receipts = message_db_arc
+            .get_receipts_for_account(bob_id)
+            .await
+            .expect("Could not fetch receipts for Bob");
+        assert!(receipts.is_empty());
+    }
+
+    // Helper function to create a gateway the recipient is not connected to
+    fn disconnected_gateway() -> Arc<MockMessageGateway> {
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway.expect_send_message().returning(|message| {
+            Err(MessagingError::UserNotFound(
+                message.recipient_id.to_string(),
+            ))
+        });
+        mock_gateway
+            .expect_send_receipt()
+            .returning(|receipt| Err(MessagingError::UserNotFound(receipt.sender_id.to_string())));
+        Arc::new(mock_gateway)
+    }
+
+    fn test_queue_limits() -> QueueLimits {
+        QueueLimits {
+            max_messages: 100,
+            max_bytes: 1024,
+            max_messages_per_sender: 50,
+        }
+    }
+
+    fn test_message_limits() -> MessageLimits {
+        MessageLimits {
+            max_ciphertext_size: 4096,
+            max_message_number: 1000,
+        }
+    }
+
+    fn test_account(id: Uuid) -> Account {
+        Account {
+            id,
+            auth_password_hash: SaltedHash::generate_from("password"),
+            apns_token: None,
+            gcm_token: None,
+        }
+    }
+
+    // Helper function to create an account database in which every account exists
+    fn existing_accounts() -> Arc<MockAccountDatabase> {
+        let mut mock_account_db = MockAccountDatabase::new();
+        mock_account_db
+            .expect_fetch_account()
+            .returning(|id| Ok(Some(test_account(id))));
+        Arc::new(mock_account_db)
+    }
+
+    fn no_blocks() -> Arc<InMemoryDatabase> {
+        Arc::new(InMemoryDatabase::new())
+    }
+
+    fn no_scheduled_messages() -> Arc<InMemoryDatabase> {
+        Arc::new(InMemoryDatabase::new())
+    }
+
+    fn accepted_senders() -> Arc<MockMessageRequestDatabase> {
+        let mut mock_request_db = MockMessageRequestDatabase::new();
+        mock_request_db
+            .expect_insert_accepted_sender()
+            .returning(|_, _| Ok(()));
+        mock_request_db
+            .expect_is_accepted_sender()
+            .returning(|_, _| Ok(true));
+        Arc::new(mock_request_db)
+    }
+
+    fn test_in_flight_messages() -> Arc<InFlightMessages> {
+        Arc::new(InFlightMessages::new(Duration::from_secs(30)))
+    }
+
+    // Helper function to create a test message
+    fn create_test_message() -> DoubleRatchetMessage {
+        let ephemeral_key = SigningKey::new_secp256r1().verifying_key();
+        let header = DoubleRatchetHeader {
+            ephemeral_key,
+            message_number: 0,
+            previous_message_number: 0,
+            one_time_prekey_id: None,
+            signed_prekey_id: None,
+        };
+
+        DoubleRatchetMessage {
+            header,
+            ciphertext: "Test message".as_bytes().to_vec(),
+            nonce: vec![0; 12],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_message_ttl_is_capped_by_service() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        assert_eq!(receipt.expires_at, receipt.timestamp + 60_000);
+
+        let receipt = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                Some(Duration::from_secs(10)),
+                None,
+            )
+            .await
+            .expect("Could not send message");
+        assert_eq!(receipt.expires_at, receipt.timestamp + 10_000);
+
+        // Longer TTLs than the configured one are not granted
+        let receipt = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                Some(Duration::from_secs(3600)),
+                None,
+            )
+            .await
+            .expect("Could not send message");
+        assert_eq!(receipt.expires_at, receipt.timestamp + 60_000);
+
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert_eq!(pending[1].expires_at, pending[1].timestamp + 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_retry_returns_original_receipt() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let idempotency_key = Uuid::new_v4();
+        let receipt = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                Some(idempotency_key),
+            )
+            .await
+            .expect("Could not send message");
+        let retried = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                Some(idempotency_key),
+            )
+            .await
+            .expect("Could not retry message");
+        assert_eq!(retried.message_id, receipt.message_id);
+        assert_eq!(retried.timestamp, receipt.timestamp);
+
+        // Another key, or the same key of another sender, is a new message
+        let other = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                Some(Uuid::new_v4()),
+            )
+            .await
+            .expect("Could not send message");
+        assert_ne!(other.message_id, receipt.message_id);
+        let other = service
+            .send_message(
+                Uuid::new_v4(),
+                alice_id,
+                create_test_message(),
+                None,
+                Some(idempotency_key),
+            )
+            .await
+            .expect("Could not send message");
+        assert_ne!(other.message_id, receipt.message_id);
+
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert_eq!(pending.len(), 3);
+
+        // Once the deduplication window has passed, the key is forgotten
+        message_db_arc
+            .remove_expired_sent_messages(
+                receipt.timestamp + MESSAGE_DEDUP_WINDOW.as_millis() as u64,
+            )
+            .await
+            .expect("Could not remove expired sent messages");
+        let resent = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                Some(idempotency_key),
+            )
+            .await
+            .expect("Could not send message");
+        assert_ne!(resent.message_id, receipt.message_id);
+
+        // A failed send releases its key for the retry
+        let idempotency_key = Uuid::new_v4();
+        let mut oversized = create_test_message();
+        oversized.ciphertext = vec![0; 4097];
+        assert!(
+            service
+                .send_message(bob_id, alice_id, oversized, None, Some(idempotency_key))
+                .await
+                .is_err()
+        );
+        let retried = service
+            .send_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                Some(idempotency_key),
+            )
+            .await
+            .expect("Could not retry message");
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert!(
+            pending
+                .iter()
+                .any(|msg| msg.message_id == retried.message_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_message_from_blocked_sender_is_dropped() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+
+        let block_db_arc = Arc::new(InMemoryDatabase::new());
+        block_db_arc
+            .insert_block(
+                alice_id,
+                BlockedAccount {
+                    account_id: mallory_id,
+                    blocked_at: 0,
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db
+            .expect_is_present()
+            .with(eq(alice_id))
+            .times(1)
+            .returning(|_| Ok(true));
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            block_db_arc,
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        // Mallory can't tell the message was dropped
+        let receipt = service
+            .send_message(mallory_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Blocked sender should get a receipt");
+        assert!(receipt.timestamp > 0);
+        assert!(receipt.expires_at > receipt.timestamp);
+
+        let results = service
+            .send_messages(mallory_id, vec![(alice_id, create_test_message())], None)
+            .await
+            .expect("Could not send batch");
+        assert!(results[0].is_ok());
+
+        // Blocks are one-directional, Bob's message still arrives
+        let bobs_receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message_id, bobs_receipt.message_id);
+
+        // Mallory runs into the same quotas as Bob
+        for sender_id in [bob_id, mallory_id] {
+            let mut large_message = create_test_message();
+            large_message.ciphertext = vec![0; 2000];
+            let result = service
+                .send_message(sender_id, alice_id, large_message, None, None)
+                .await;
+            assert!(matches!(
+                result,
+                Err(MessagingError::QuotaExceeded(QueueQuota::RecipientBytes))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retract_undelivered_message() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            Arc::new(InMemoryDatabase::new()),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        // Alice has not accepted Bob yet, so his messages are message requests
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        service
+            .retract_message(bob_id, receipt.message_id)
+            .await
+            .expect("Could not retract message request");
+        let requests = service.get_message_requests(alice_id).await.unwrap();
+        assert!(requests.is_empty());
+
+        // Message requests Alice looked at can no longer be retracted
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        let requests = service.get_message_requests(alice_id).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        let result = service.retract_message(bob_id, receipt.message_id).await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::MessageAlreadyDelivered(_))
+        ));
+        service
+            .accept_message_request(alice_id, bob_id)
+            .await
+            .expect("Could not accept message request");
+        service
+            .mark_delivered(alice_id, vec![receipt.message_id])
+            .await
+            .expect("Could not mark message delivered");
+
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+
+        // Only the sender can retract the message
+        let result = service.retract_message(alice_id, receipt.message_id).await;
+        assert!(matches!(result, Err(MessagingError::MessageNotFound(_))));
+
+        service
+            .retract_message(bob_id, receipt.message_id)
+            .await
+            .expect("Could not retract message");
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert!(pending.is_empty());
+
+        let result = service.retract_message(bob_id, receipt.message_id).await;
+        assert!(matches!(result, Err(MessagingError::MessageNotFound(_))));
+        let result = service.retract_message(bob_id, Uuid::new_v4()).await;
+        assert!(matches!(result, Err(MessagingError::MessageNotFound(_))));
+
+        // Fetched messages are handed out, even if not acknowledged yet
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(pending.len(), 1);
+        let result = service.retract_message(bob_id, receipt.message_id).await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::MessageAlreadyDelivered(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pushed_messages_can_not_be_retracted() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        // Alice is connected, so the message is pushed to her right away
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        let result = service.retract_message(bob_id, receipt.message_id).await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::MessageAlreadyDelivered(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_messages_are_released_when_due() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let too_late = now + MAX_DELIVERY_DELAY.as_millis() as u64 + 60_000;
+        let result = service
+            .schedule_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                too_late,
+                None,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::InvalidDeliveryTime(_))
+        ));
+
+        let deliver_at = now + 50;
+        let receipt = service
+            .schedule_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                deliver_at,
+                None,
+            )
+            .await
+            .expect("Could not schedule message");
+        assert_eq!(receipt.timestamp, deliver_at);
+        let canceled_receipt = service
+            .schedule_message(
+                bob_id,
+                alice_id,
+                create_test_message(),
+                None,
+                deliver_at,
+                None,
+            )
+            .await
+            .expect("Could not schedule message");
+
+        // Only the sender can cancel their scheduled messages
+        let result = service
+            .cancel_scheduled_message(alice_id, canceled_receipt.message_id)
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::ScheduledMessageNotFound(_))
+        ));
+        service
+            .cancel_scheduled_message(bob_id, canceled_receipt.message_id)
+            .await
+            .expect("Could not cancel scheduled message");
+
+        // Nothing is released before the delivery time
+        service.release_scheduled_messages().await.unwrap();
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert!(pending.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        service.release_scheduled_messages().await.unwrap();
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message_id, receipt.message_id);
+        assert_eq!(pending[0].timestamp, deliver_at);
+
+        // Released messages can no longer be canceled
+        let result = service
+            .cancel_scheduled_message(bob_id, receipt.message_id)
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::ScheduledMessageNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failed_scheduled_messages_are_kept_or_reported() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+
+        let scheduled_message = |ciphertext_len: usize, timestamp: u64| {
+            let mut message = create_test_message();
+            message.ciphertext = vec![0; ciphertext_len];
+            Message {
+                message_id: Uuid::now_v7(),
+                sender_id: Some(bob_id),
+                recipient_id: alice_id,
+                group_id: None,
+                message,
+                timestamp,
+                expires_at: timestamp + 60_000,
+            }
+        };
+        // Can never be queued, as it exceeds the message limits
+        let oversized = scheduled_message(8192, now - 2);
+        // Fails to queue because the database is unavailable
+        let retried = scheduled_message(16, now - 1);
+
+        let schedule_db_arc = Arc::new(InMemoryDatabase::new());
+        for message in [oversized.clone(), retried.clone()] {
+            schedule_db_arc
+                .insert_scheduled_message(message)
+                .await
+                .unwrap();
+        }
+
+        let mut mock_message_db = MockMessageDatabase::new();
+        mock_message_db
+            .expect_get_queue_usage()
+            .times(1)
+            .returning(|_, _| Err(MessagingError::DatabaseError("Database error".to_string())));
+
+        let oversized_id = oversized.message_id;
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message_expiry()
+            .withf(move |sender_id, expiry| {
+                *sender_id == bob_id
+                    && expiry.message_id == oversized_id
+                    && expiry.recipient_id == alice_id
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(mock_message_db),
+            Arc::new(MockPresenceDatabase::new()),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            schedule_db_arc.clone(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        service.release_scheduled_messages().await.unwrap();
+
+        // Only the message that failed on the database stays scheduled
+        let scheduled = schedule_db_arc
+            .get_due_scheduled_messages(now)
+            .await
+            .unwrap();
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0].message_id, retried.message_id);
+    }
+
+    #[tokio::test]
+    async fn test_messages_of_unaccepted_senders_are_requests() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
+        // No wake-up notifications are expected for message requests
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            Arc::new(InMemoryDatabase::new()),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let request_receipt = service
+            .send_message(mallory_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message request");
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert!(pending.is_empty());
+        let requests = service.get_message_requests(alice_id).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].message_id, request_receipt.message_id);
+
+        // Writing to Bob accepts his replies
+        service
+            .send_message(alice_id, bob_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].sender_id, Some(bob_id));
+
+        // Accepting moves the requests to the queue, later messages are queued right away
+        service
+            .accept_message_request(alice_id, mallory_id)
+            .await
+            .expect("Could not accept message request");
+        assert!(
+            service
+                .get_message_requests(alice_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        service
+            .send_message(mallory_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Could not send message");
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(pending.len(), 3);
+        assert!(
+            pending
+                .iter()
+                .any(|message| message.message_id == request_receipt.message_id)
+        );
+
+        // Bob has yet to accept Alice, declining discards her request
+        assert_eq!(service.get_message_requests(bob_id).await.unwrap().len(), 1);
+        service
+            .decline_message_request(bob_id, alice_id)
+            .await
+            .expect("Could not decline message request");
+        assert!(
+            service
+                .get_message_requests(bob_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            service
+                .get_pending_messages(bob_id, None, 100)
+                .await
+                .unwrap()
+                .messages
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_messages_of_unaccepted_senders_are_requests() {
+        let group_id = Uuid::new_v4();
+        let alice_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+
+        // Alice is offline and can be woken up
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db
+            .expect_is_present()
+            .returning(move |id| Ok(*id != alice_id));
+        let mut mock_account_db = MockAccountDatabase::new();
+        mock_account_db.expect_fetch_account().returning(|id| {
+            Ok(Some(Account {
+                apns_token: Some(b"apns_token".to_vec()),
+                ..test_account(id)
+            }))
+        });
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = Arc::new(mock_account_db);
+        // No wake-up notifications are expected for message requests
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            Arc::new(InMemoryDatabase::new()),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        // Being added to Mallory's group doesn't let Mallory into Alice's queue
+        let results = service
+            .send_group_message(
+                mallory_id,
+                group_id,
+                vec![alice_id],
+                create_test_message(),
+                None,
+            )
+            .await
+            .expect("Could not send group message");
+        assert!(results[0].is_ok());
+        assert!(
+            message_db_arc
+                .get_messages_for_account(alice_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let requests = service.get_message_requests(alice_id).await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].group_id, Some(group_id));
+
+        // Nor does writing to the group accept its members
+        service
+            .accept_message_request(mallory_id, alice_id)
+            .await
+            .expect("Could not accept message request");
+        service
+            .send_group_message(
+                alice_id,
+                group_id,
+                vec![mallory_id],
+                create_test_message(),
+                None,
+            )
+            .await
+            .expect("Could not send group message");
+        service
+            .send_group_message(
+                mallory_id,
+                group_id,
+                vec![alice_id],
+                create_test_message(),
+                None,
+            )
+            .await
+            .expect("Could not send group message");
+        assert_eq!(
+            service.get_message_requests(alice_id).await.unwrap().len(),
+            2
+        );
+
+        // Accepting Mallory releases the group messages
+        service
+            .accept_message_request(alice_id, mallory_id)
+            .await
+            .expect("Could not accept message request");
+        let messages = message_db_arc
+            .get_messages_for_account(alice_id)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(
+            messages
+                .iter()
+                .all(|message| message.group_id == Some(group_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_send_replies_with_result() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let request_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message()
+            .returning(|message| {
+                Err(MessagingError::UserNotFound(
+                    message.recipient_id.to_string(),
+                ))
+            });
+        mock_message_gateway
+            .expect_reply_to_send()
+            .withf(move |account_id, id, result| {
+                *account_id == bob_id && *id == request_id && result.is_ok()
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mock_message_gateway
+            .expect_reply_to_send()
+            .withf(move |account_id, _, result| {
+                *account_id == bob_id && matches!(result, Err(MessagingError::QuotaExceeded(_)))
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            QueueLimits {
+                max_messages: 1,
+                ..test_queue_limits()
+            },
+            test_message_limits(),
+        );
+
+        let create_request = |request_id| SendRequest {
+            request_id,
+            recipient_id: alice_id,
+            message: create_test_message(),
+            ttl: None,
+            idempotency_key: None,
+            deliver_at: None,
+        };
+
+        service
+            .handle_send(bob_id, create_request(request_id))
+            .await;
+        // Alice's queue is full now
+        service
+            .handle_send(bob_id, create_request(Uuid::new_v4()))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_send_message_queue_quotas() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let carol_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            QueueLimits {
+                max_messages: 3,
+                max_bytes: 40,
+                max_messages_per_sender: 2,
+            },
+            test_message_limits(),
+        );
+
+        // "Test message" has 12 bytes of ciphertext
+        for _ in 0..2 {
+            service
+                .send_message(bob_id, alice_id, create_test_message(), None, None)
                 .await
                 .expect("Could not send message");
-
-            message_ids.push(receipt.message_id);
         }
 
-        let retrieved = service
-            .get_pending_messages(alice_id)
-            .await
-            .expect("Could not fetch messages for Alice");
+        let result = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::QuotaExceeded(QueueQuota::SenderMessages))
+        ));
 
-        let ids: Vec<Uuid> = retrieved.iter().map(|msg| msg.message_id).collect();
-        let delivered = ids[5..10].to_vec();
-        service
-            .mark_delivered(alice_id, delivered.clone())
-            .await
-            .expect("Could not set messages delivered");
+        // Other senders are not affected by Bob's quota
+        let mut large_message = create_test_message();
+        large_message.ciphertext = vec![0; 20];
+        let result = service
+            .send_message(carol_id, alice_id, large_message, None, None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::QuotaExceeded(QueueQuota::RecipientBytes))
+        ));
 
-        let rest = service
-            .get_pending_messages(alice_id)
+        service
+            .send_message(carol_id, alice_id, create_test_message(), None, None)
             .await
-            .expect("Could not fetch messages for Alice");
-        let rest_ids: Vec<Uuid> = rest.iter().map(|msg| msg.message_id).collect();
-
-        // 15 messages left to mark as delivered
-        assert_eq!(rest_ids.len(), 15);
+            .expect("Could not send message");
 
-        // rest_uuids shouldn't contain any from delivered
-        assert!(!rest_ids.iter().any(|uuid| delivered.contains(uuid)));
+        let result = service
+            .send_message(carol_id, alice_id, create_test_message(), None, None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessagingError::QuotaExceeded(QueueQuota::RecipientMessages))
+        ));
 
+        // Quotas are per recipient
         service
-            .mark_delivered(alice_id, rest_ids)
-            .await
-            .expect("Could not set messages delivered");
-        let final_messages = service
-            .get_pending_messages(alice_id)
+            .send_message(bob_id, carol_id, create_test_message(), None, None)
             .await
-            .expect("Could not fetch messages for Alice");
-        assert_eq!(final_messages.len(), 0);
+            .expect("Could not send message");
     }
 
-    // Helper function to create a test message
-    fn create_test_message() -> DoubleRatchetMessage {
-        let ephemeral_key = SigningKey::new_secp256r1().verifying_key();
-        let header = DoubleRatchetHeader {
-            ephemeral_key,
-            message_number: 0,
-            previous_message_number: 0,
-            one_time_prekey_id: None,
-        };
+    #[tokio::test]
+    async fn test_send_message_validates_message_and_recipient() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
 
-        DoubleRatchetMessage {
-            header,
-            ciphertext: "Test message".as_bytes().to_vec(),
-            nonce: vec![0; 12],
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        account_db_arc
+            .upsert_account(test_account(alice_id))
+            .await
+            .unwrap();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let result = service
+            .send_message(alice_id, bob_id, create_test_message(), None, None)
+            .await;
+        let err = result.unwrap_err();
+        assert!(matches!(err, MessagingError::RecipientNotFound(id) if id == bob_id));
+        assert_eq!(err.into_response().status(), StatusCode::NOT_FOUND);
+
+        let mut empty_message = create_test_message();
+        empty_message.ciphertext = vec![];
+        let mut malformed_nonce = create_test_message();
+        malformed_nonce.nonce = vec![0; 8];
+        let mut huge_counter = create_test_message();
+        huge_counter.header.message_number = 1001;
+        for message in [empty_message, malformed_nonce, huge_counter] {
+            let err = service
+                .send_message(bob_id, alice_id, message, None, None)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, MessagingError::InvalidMessage(_)));
+            assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
         }
+
+        let mut oversized_message = create_test_message();
+        oversized_message.ciphertext = vec![0; 4097];
+        let err = service
+            .send_message(bob_id, alice_id, oversized_message, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, MessagingError::MessageTooLarge(4096)));
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+        // Nothing invalid was queued
+        let pending = service
+            .get_pending_messages(alice_id, None, 10)
+            .await
+            .expect("Failed to get pending messages");
+        assert!(pending.messages.is_empty());
     }
 
     #[tokio::test]
     async fn test_send_message_database_error() {
         // Setup mock message database that returns an error
         let mut mock_message_db = MockMessageDatabase::new();
+        mock_message_db
+            .expect_get_queue_usage()
+            .returning(|_, _| Ok(QueueUsage::default()));
         mock_message_db
             .expect_insert_message()
             .times(1)
@@ -304,14 +2754,23 @@ mod tests {
         mock_presence_db.expect_is_present().returning(|_| Ok(true));
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
         let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             Arc::new(mock_message_db),
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         // Create a test message
@@ -319,7 +2778,7 @@ mod tests {
 
         // Call service.send_message
         let result = service
-            .send_message(Uuid::new_v4(), Uuid::new_v4(), message)
+            .send_message(Uuid::new_v4(), Uuid::new_v4(), message, None, None)
             .await;
 
         // Verify we get DatabaseError
@@ -334,27 +2793,36 @@ mod tests {
         // Setup mock message database that returns an error
         let mut mock_message_db = MockMessageDatabase::new();
         mock_message_db
-            .expect_get_messages_for_account()
-            .with(eq(account_id))
+            .expect_get_messages_page_for_account()
+            .with(eq(account_id), eq(None), eq(101))
             .times(1)
-            .returning(|_| Err(MessagingError::DatabaseError("Database error".to_string())));
+            .returning(|_, _, _| Err(MessagingError::DatabaseError("Database error".to_string())));
 
         // Create the service with our mocks
         let mock_presence_db = MockPresenceDatabase::new();
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
         let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             Arc::new(mock_message_db),
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         // Call service.get_pending_messages
-        let result = service.get_pending_messages(account_id).await;
+        let result = service.get_pending_messages(account_id, None, 100).await;
 
         // Verify we get DatabaseError
         assert!(result.is_err());
@@ -367,6 +2835,10 @@ mod tests {
         let account_id = Uuid::new_v4();
         // Setup mock message database that returns an error
         let mut mock_message_db = MockMessageDatabase::new();
+        mock_message_db
+            .expect_get_messages_for_account()
+            .with(eq(account_id))
+            .returning(|_| Ok(vec![]));
         mock_message_db
             .expect_remove_messages()
             .with(eq(account_id), eq(vec![Uuid::nil()]))
@@ -377,14 +2849,23 @@ mod tests {
         let mock_presence_db = MockPresenceDatabase::new();
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
         let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             Arc::new(mock_message_db),
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         // Call service.mark_delivered
@@ -407,14 +2888,23 @@ mod tests {
         mock_presence_db.expect_is_present().returning(|_| Ok(true));
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
         let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             message_db_arc,
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         let bob_ephemeral_key = SigningKey::new_secp256r1().verifying_key();
@@ -426,6 +2916,7 @@ mod tests {
                 message_number: i,
                 previous_message_number: if i > 0 { i - 1 } else { 0 },
                 one_time_prekey_id: None,
+                signed_prekey_id: None,
             };
 
             let message = DoubleRatchetMessage {
@@ -437,7 +2928,7 @@ mod tests {
             };
 
             service
-                .send_message(bob_id, alice_id, message)
+                .send_message(bob_id, alice_id, message, None, None)
                 .await
                 .expect("Could not send message");
         }
@@ -450,6 +2941,7 @@ mod tests {
                 message_number: i,
                 previous_message_number: if i > 0 { i - 1 } else { 0 },
                 one_time_prekey_id: None,
+                signed_prekey_id: None,
             };
 
             let message = DoubleRatchetMessage {
@@ -461,27 +2953,29 @@ mod tests {
             };
 
             service
-                .send_message(alice_id, bob_id, message)
+                .send_message(alice_id, bob_id, message, None, None)
                 .await
                 .expect("Could not send message");
         }
 
         // Get all messages for Alice
         let alice_messages = service
-            .get_pending_messages(alice_id)
+            .get_pending_messages(alice_id, None, 100)
             .await
-            .expect("Could not fetch messages for Alice");
+            .expect("Could not fetch messages for Alice")
+            .messages;
 
         // Get all messages for Bob
         let bob_messages = service
-            .get_pending_messages(bob_id)
+            .get_pending_messages(bob_id, None, 100)
             .await
-            .expect("Could not fetch messages for Bob");
+            .expect("Could not fetch messages for Bob")
+            .messages;
 
         // Verify Alice received 5 messages from Bob
         assert_eq!(alice_messages.len(), 5);
         for (i, msg) in alice_messages.iter().enumerate() {
-            assert_eq!(msg.sender_id, bob_id);
+            assert_eq!(msg.sender_id, Some(bob_id));
             assert_eq!(msg.recipient_id, alice_id);
             assert_eq!(
                 msg.message.ciphertext,
@@ -494,7 +2988,7 @@ mod tests {
         // Verify Bob received 3 messages from Alice
         assert_eq!(bob_messages.len(), 3);
         for (i, msg) in bob_messages.iter().enumerate() {
-            assert_eq!(msg.sender_id, alice_id);
+            assert_eq!(msg.sender_id, Some(alice_id));
             assert_eq!(msg.recipient_id, bob_id);
             assert_eq!(
                 msg.message.ciphertext,
@@ -506,7 +3000,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_send_message_with_recipient_present_no_notification() {
+    async fn test_send_message_with_recipient_present_delivers_directly() {
         let alice_id = Uuid::new_v4();
         let bob_id = Uuid::new_v4();
 
@@ -521,24 +3015,109 @@ mod tests {
             .returning(|_| Ok(true));
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        // Mock gateway to accept the message for the connected recipient
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message()
+            .withf(move |message| message.recipient_id == alice_id)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let account_db_arc = existing_accounts();
 
         // Mock notification gateway should not be called
         let mock_notification_gateway = MockNotificationGateway::new();
         let notification_gateway_arc = Arc::new(mock_notification_gateway);
 
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             message_db_arc,
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         let message = create_test_message();
 
-        let result = service.send_message(bob_id, alice_id, message).await;
+        let result = service
+            .send_message(bob_id, alice_id, message, None, None)
+            .await;
         assert!(result.is_ok());
+
+        // Delivered messages stay queued until acknowledged
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_direct_delivery_failure_keeps_message_queued() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db
+            .expect_is_present()
+            .with(eq(alice_id))
+            .times(1)
+            .returning(|_| Ok(true));
+        let presence_db_arc = Arc::new(mock_presence_db);
+
+        let mut mock_message_gateway = MockMessageGateway::new();
+        mock_message_gateway
+            .expect_send_message()
+            .times(1)
+            .returning(|_| {
+                Err(MessagingError::SendingFailed(
+                    "Connection closed".to_string(),
+                ))
+            });
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc,
+            presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_message_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let receipt = service
+            .send_message(bob_id, alice_id, create_test_message(), None, None)
+            .await
+            .expect("Sending should succeed even if direct delivery fails");
+
+        let pending = service
+            .get_pending_messages(alice_id, None, 100)
+            .await
+            .expect("Could not fetch messages for Alice")
+            .messages;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message_id, receipt.message_id);
     }
 
     #[tokio::test]
@@ -557,12 +3136,12 @@ mod tests {
             .returning(|_| Ok(false));
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        // Mock account database for notification service
+        // Alice's account is looked up when queueing and when waking her up
         let mut mock_account_db = MockAccountDatabase::new();
         mock_account_db
             .expect_fetch_account()
             .with(eq(alice_id))
-            .times(1)
+            .times(2)
             .returning(|id| {
                 Ok(Some(crate::account::entities::Account {
                     id,
@@ -584,16 +3163,27 @@ mod tests {
         let notification_gateway_arc = Arc::new(mock_notification_gateway);
 
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             message_db_arc,
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         let message = create_test_message();
 
-        let result = service.send_message(bob_id, alice_id, message).await;
+        let result = service
+            .send_message(bob_id, alice_id, message, None, None)
+            .await;
         assert!(result.is_ok());
     }
 
@@ -613,23 +3203,34 @@ mod tests {
             .returning(|_| Err(PresenceError::Database("DB error".to_string())));
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
 
         // Mock notification gateway should not be called (defaults to present)
         let mock_notification_gateway = MockNotificationGateway::new();
         let notification_gateway_arc = Arc::new(mock_notification_gateway);
 
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             message_db_arc,
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         let message = create_test_message();
 
-        let result = service.send_message(bob_id, alice_id, message).await;
+        let result = service
+            .send_message(bob_id, alice_id, message, None, None)
+            .await;
         assert!(result.is_ok());
     }
 
@@ -649,30 +3250,41 @@ mod tests {
             .returning(|_| Ok(false));
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        // Mock account database that returns no account
+        // Alice exists, but has no APNS token to wake her up with
         let mut mock_account_db = MockAccountDatabase::new();
         mock_account_db
             .expect_fetch_account()
             .with(eq(alice_id))
-            .times(1)
-            .returning(|_| Ok(None));
+            .times(2)
+            .returning(|id| Ok(Some(test_account(id))));
         let account_db_arc = Arc::new(mock_account_db);
 
         let mock_notification_gateway = MockNotificationGateway::new();
         let notification_gateway_arc = Arc::new(mock_notification_gateway);
 
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             message_db_arc,
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
         let message = create_test_message();
 
         // Should fail when notification fails
-        let result = service.send_message(bob_id, alice_id, message).await;
+        let result = service
+            .send_message(bob_id, alice_id, message, None, None)
+            .await;
         assert!(result.is_err()); // Should fail due to notification error
         assert!(matches!(
             result.unwrap_err(),
@@ -680,6 +3292,288 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn test_send_messages_coalesces_wakeup_notifications() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let carol_id = Uuid::new_v4();
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+
+        // Alice is connected, Carol is not
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db
+            .expect_is_present()
+            .with(eq(alice_id))
+            .times(1)
+            .returning(|_| Ok(true));
+        mock_presence_db
+            .expect_is_present()
+            .with(eq(carol_id))
+            .times(1)
+            .returning(|_| Ok(false));
+
+        // Carol's account is also looked up to wake her up
+        let mut mock_account_db = MockAccountDatabase::new();
+        mock_account_db
+            .expect_fetch_account()
+            .with(eq(alice_id))
+            .times(2)
+            .returning(|id| Ok(Some(test_account(id))));
+        mock_account_db
+            .expect_fetch_account()
+            .with(eq(carol_id))
+            .times(3)
+            .returning(|id| {
+                Ok(Some(crate::account::entities::Account {
+                    id,
+                    auth_password_hash: crate::crypto::salted_hash::SaltedHash::generate_from(
+                        "password",
+                    ),
+                    apns_token: Some(vec![1, 2, 3, 4, 5]),
+                    gcm_token: None,
+                }))
+            });
+
+        // Carol receives two messages, but is woken up once
+        let mut mock_notification_gateway = MockNotificationGateway::new();
+        mock_notification_gateway
+            .expect_send_silent_notification()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_send_message()
+            .withf(move |message| message.recipient_id == alice_id)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let account_db_arc = Arc::new(mock_account_db);
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), Arc::new(mock_notification_gateway));
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            Arc::new(mock_gateway),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        // The message for Alice exceeds the byte quota
+        let mut oversized_message = create_test_message();
+        oversized_message.ciphertext = vec![0; 2048];
+
+        let results = service
+            .send_messages(
+                bob_id,
+                vec![
+                    (alice_id, create_test_message()),
+                    (carol_id, create_test_message()),
+                    (alice_id, oversized_message),
+                    (carol_id, create_test_message()),
+                ],
+                None,
+            )
+            .await
+            .expect("Could not send batch");
+
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(MessagingError::QuotaExceeded(QueueQuota::RecipientBytes))
+        ));
+        assert!(results[3].is_ok());
+
+        let alice_messages = message_db_arc
+            .get_messages_for_account(alice_id)
+            .await
+            .unwrap();
+        assert_eq!(alice_messages.len(), 1);
+        let carol_messages = message_db_arc
+            .get_messages_for_account(carol_id)
+            .await
+            .unwrap();
+        assert_eq!(carol_messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_group_message_fans_out_to_members() {
+        let group_id = Uuid::new_v4();
+        let sender_id = Uuid::new_v4();
+        let member_ids = vec![Uuid::new_v4(), Uuid::new_v4()];
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let message_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            message_db_arc.clone(),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let results = service
+            .send_group_message(
+                sender_id,
+                group_id,
+                member_ids.clone(),
+                create_test_message(),
+                None,
+            )
+            .await
+            .expect("Could not send group message");
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        // Every member gets their own copy, tagged with the group
+        for member_id in member_ids {
+            let messages = message_db_arc
+                .get_messages_for_account(member_id)
+                .await
+                .unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].sender_id, Some(sender_id));
+            assert_eq!(messages[0].group_id, Some(group_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_messages_rejects_oversized_batch() {
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(MockPresenceDatabase::new()),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let messages = (0..=MAX_SEND_BATCH_SIZE)
+            .map(|_| (Uuid::new_v4(), create_test_message()))
+            .collect();
+
+        let result = service.send_messages(Uuid::new_v4(), messages, None).await;
+        assert!(matches!(
+            result.unwrap_err(),
+            MessagingError::BatchTooLarge(size) if size == MAX_SEND_BATCH_SIZE + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_pending_messages_pagination() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        let mut mock_presence_db = MockPresenceDatabase::new();
+        mock_presence_db.expect_is_present().returning(|_| Ok(true));
+
+        let account_db_arc = existing_accounts();
+        let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
+        let notification_service =
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
+        let service = MessagingService::new(
+            Arc::new(InMemoryDatabase::new()),
+            Arc::new(mock_presence_db),
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
+            Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
+        );
+
+        let mut sent_ids = Vec::new();
+        for _ in 0..5 {
+            let receipt = service
+                .send_message(bob_id, alice_id, create_test_message(), None, None)
+                .await
+                .expect("Could not send message");
+            sent_ids.push(receipt.message_id);
+        }
+
+        let mut fetched_ids = Vec::new();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let page = service
+                .get_pending_messages(alice_id, cursor, 2)
+                .await
+                .expect("Could not fetch messages for Alice");
+            assert!(page.messages.len() <= 2);
+            fetched_ids.extend(page.messages.iter().map(|msg| msg.message_id));
+            pages += 1;
+
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => break,
+            }
+        }
+
+        // Pages cover all messages in the order they were sent
+        assert_eq!(pages, 3);
+        assert_eq!(fetched_ids, sent_ids);
+
+        // Cursors survive a round trip through their string form
+        let cursor: MessageCursor = cursor.unwrap().to_string().parse().unwrap();
+        let page = service
+            .get_pending_messages(alice_id, Some(cursor), 2)
+            .await
+            .expect("Could not fetch messages for Alice");
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].message_id, sent_ids[4]);
+
+        assert!(matches!(
+            "not-a-cursor".parse::<MessageCursor>(),
+            Err(MessagingError::InvalidCursor(_))
+        ));
+        // Bad cursors are the client's fault, undecodable stored messages are not
+        assert_eq!(
+            MessagingError::InvalidCursor(String::new()).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            MessagingError::ParseError(String::new()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
     #[tokio::test]
     async fn test_get_pending_messages_empty() {
         let account_id = Uuid::new_v4();
@@ -689,20 +3583,30 @@ mod tests {
         let mock_presence_db = MockPresenceDatabase::new();
         let presence_db_arc = Arc::new(mock_presence_db);
 
-        let account_db_arc = Arc::new(InMemoryDatabase::new());
+        let account_db_arc = existing_accounts();
         let notification_gateway_arc = Arc::new(MockNotificationGateway::new());
         let notification_service =
-            NotificationService::new(account_db_arc, notification_gateway_arc);
+            NotificationService::new(account_db_arc.clone(), notification_gateway_arc);
         let service = MessagingService::new(
             message_db_arc,
             presence_db_arc,
+            account_db_arc,
+            no_blocks(),
+            accepted_senders(),
+            no_scheduled_messages(),
+            disconnected_gateway(),
+            test_in_flight_messages(),
             Arc::new(notification_service),
+            Duration::from_secs(60),
+            test_queue_limits(),
+            test_message_limits(),
         );
 
-        let result = service.get_pending_messages(account_id).await;
+        let result = service.get_pending_messages(account_id, None, 100).await;
 
         assert!(result.is_ok());
-        let messages = result.unwrap();
-        assert_eq!(messages.len(), 0);
+        let page = result.unwrap();
+        assert_eq!(page.messages.len(), 0);
+        assert!(page.next_cursor.is_none());
     }
 }
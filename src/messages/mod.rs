@@ -1,8 +1,13 @@
 pub mod database;
 pub mod entities;
 pub mod error;
+pub mod expiry_service;
 pub mod gateway;
+pub mod in_flight;
 pub mod messaging_service;
+pub mod requests;
+pub mod scheduled;
+pub mod sealed_sender;
 pub mod sender_service;
 pub mod typing;
 
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::messages::{entities::Message, error::MessagingError};
+
+/// Stores which senders accounts accept messages from, and the message requests
+/// of senders they have yet to accept
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait MessageRequestDatabase: Send + Sync {
+    /// Remembers that `account_id` accepts messages from `sender_id`
+    async fn insert_accepted_sender(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<(), MessagingError>;
+
+    /// Whether `account_id` accepts messages from `sender_id`
+    async fn is_accepted_sender(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<bool, MessagingError>;
+
+    async fn insert_request_message(&self, message: Message) -> Result<(), MessagingError>;
+
+    /// Returns the message requests of the account, ordered by timestamp
+    async fn get_request_messages(&self, account_id: Uuid) -> Result<Vec<Message>, MessagingError>;
+
+    /// Returns how many message requests of the sender the account has
+    async fn count_request_messages(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<usize, MessagingError>;
+
+    /// Removes the account's message requests of the sender and returns them
+    async fn remove_request_messages(
+        &self,
+        account_id: Uuid,
+        sender_id: Uuid,
+    ) -> Result<Vec<Message>, MessagingError>;
+
+    /// Removes a message request, provided it was sent by the given sender.
+    /// Returns the removed message, if there was one.
+    async fn remove_request_message_of_sender(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Option<Message>, MessagingError>;
+
+    /// Removes all message requests that expired at or before `now` (epoch milliseconds)
+    async fn remove_expired_request_messages(&self, now: u64) -> Result<(), MessagingError>;
+}
@@ -1,21 +1,34 @@
 use axum::{
-    Extension, Json, extract::State, middleware::from_fn_with_state, response::IntoResponse,
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware::from_fn_with_state,
+    response::IntoResponse,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use utoipa::ToSchema;
+use serde_with::{base64::Base64, serde_as};
+use std::{sync::Arc, time::Duration};
+use utoipa::{IntoParams, ToSchema};
 use utoipa_axum::{router::OpenApiRouter, routes};
 use uuid::Uuid;
 
 use super::entities::DoubleRatchetMessage;
 use crate::{
     account::{auth::middleware::require_auth, entities::Account},
-    messages::entities::{Message, MessageReceipt},
+    messages::{
+        entities::{
+            BatchMessageResult, Message, MessageCursor, MessageReceipt, PendingMessagesPage,
+            SenderCertificate,
+        },
+        error::MessagingError,
+    },
     startup::AppContext,
 };
 
 const MESSAGING_TAG: &str = "messaging";
 
+const DEFAULT_PENDING_MESSAGES_PAGE_SIZE: usize = 100;
+
 /// When sending a message, the sender includes a full double ratchet message.
 /// The server attaches the sender's identity based on the auth token.
 #[derive(Clone, Serialize, Deserialize, ToSchema)]
@@ -23,6 +36,66 @@ const MESSAGING_TAG: &str = "messaging";
 pub struct SendMessageRequest {
     pub recipient_id: Uuid,
     pub message: DoubleRatchetMessage,
+    /// Seconds until the message expires if undelivered.
+    /// Capped by the server's time to live.
+    pub ttl: Option<u64>,
+    /// Client-generated key identifying this send. Retries with the same key
+    /// return the original receipt instead of sending the message again.
+    pub idempotency_key: Option<Uuid>,
+    /// Epoch milliseconds to hold the message back until, at most 30 days ahead.
+    /// The time to live starts at delivery.
+    pub deliver_at: Option<u64>,
+}
+
+/// Sealed sender messages are sent without authentication, so the server never
+/// learns who sent them. The sender's identity travels inside the encrypted
+/// message, together with a sender certificate.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SendSealedMessageRequest {
+    pub recipient_id: Uuid,
+    /// The recipient's delivery access key, known to their contacts
+    #[serde_as(as = "Base64")]
+    #[schema(value_type = String)]
+    pub access_key: Vec<u8>,
+    pub message: DoubleRatchetMessage,
+    /// Seconds until the message expires if undelivered.
+    /// Capped by the server's time to live.
+    pub ttl: Option<u64>,
+}
+
+/// A message of a batch, addressed to a single recipient
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchMessage {
+    pub recipient_id: Uuid,
+    pub message: DoubleRatchetMessage,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SendMessageBatchRequest {
+    pub messages: Vec<BatchMessage>,
+    /// Seconds until the messages expire if undelivered.
+    /// Capped by the server's time to live.
+    pub ttl: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SendMessageBatchResponse {
+    /// Results in the order of the requested messages
+    pub results: Vec<BatchMessageResult>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PendingMessagesQuery {
+    /// Cursor returned with the previous page, omit to start at the oldest message
+    pub after: Option<String>,
+    /// Maximum number of messages to return
+    pub limit: Option<usize>,
 }
 
 #[derive(Clone, Serialize, Deserialize, ToSchema)]
@@ -31,12 +104,29 @@ pub struct MarkDeliveredRequest {
     pub message_ids: Vec<Uuid>,
 }
 
+/// Read receipts are passed on to the sender as is
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkReadRequest {
+    pub sender_id: Uuid,
+    pub message_ids: Vec<Uuid>,
+}
+
 pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
     OpenApiRouter::new()
         .routes(routes!(send_message))
+        .routes(routes!(send_message_batch))
         .routes(routes!(fetch_messages))
         .routes(routes!(mark_delivered))
+        .routes(routes!(mark_read))
+        .routes(routes!(get_message_requests))
+        .routes(routes!(accept_message_request))
+        .routes(routes!(decline_message_request))
+        .routes(routes!(cancel_scheduled_message))
+        .routes(routes!(retract_message))
+        .routes(routes!(get_sender_certificate))
         .layer(from_fn_with_state(context.clone(), require_auth))
+        .routes(routes!(send_sealed_message))
 }
 
 #[utoipa::path(
@@ -44,9 +134,12 @@ pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
     path = "/send",
     request_body = SendMessageRequest,
     responses(
-        (status = 200, description = "Message sent successfully", body = MessageReceipt),
-        (status = 400, description = "Bad rquest"),
-        (status = 500, description = "Internal server error")
+        (status = 200, description = "Message sent or scheduled successfully", body = MessageReceipt),
+        (status = 400, description = "Malformed or oversized message, or invalid delivery time"),
+        (status = 404, description = "Recipient not found"),
+        (status = 429, description = "Too many pending messages from this sender"),
+        (status = 500, description = "Internal server error"),
+        (status = 507, description = "Recipient's message queue is full")
     ),
     tag = MESSAGING_TAG
 )]
@@ -55,19 +148,135 @@ async fn send_message(
     Extension(account): Extension<Account>,
     Json(req): Json<SendMessageRequest>,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
+    let ttl = req.ttl.map(Duration::from_secs);
+    match req.deliver_at {
+        Some(deliver_at) => {
+            context
+                .messaging_service
+                .schedule_message(
+                    account.id,
+                    req.recipient_id,
+                    req.message,
+                    ttl,
+                    deliver_at,
+                    req.idempotency_key,
+                )
+                .await
+        }
+        None => {
+            context
+                .messaging_service
+                .send_message(
+                    account.id,
+                    req.recipient_id,
+                    req.message,
+                    ttl,
+                    req.idempotency_key,
+                )
+                .await
+        }
+    }
+    .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/send-sealed",
+    request_body = SendSealedMessageRequest,
+    responses(
+        (status = 200, description = "Message sent successfully", body = MessageReceipt),
+        (status = 400, description = "Malformed or oversized message"),
+        (status = 401, description = "Invalid delivery access key"),
+        (status = 404, description = "Recipient not found"),
+        (status = 500, description = "Internal server error"),
+        (status = 507, description = "Recipient's message queue is full")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn send_sealed_message(
+    State(context): State<Arc<AppContext>>,
+    Json(req): Json<SendSealedMessageRequest>,
+) -> Result<Json<MessageReceipt>, MessagingError> {
+    context
+        .profile_service
+        .verify_delivery_access_key(req.recipient_id, &req.access_key)
+        .await?;
+
     context
         .messaging_service
-        .send_message(account.id, req.recipient_id, req.message)
+        .send_sealed_message(
+            req.recipient_id,
+            req.message,
+            req.ttl.map(Duration::from_secs),
+        )
         .await
         .map(Json)
 }
 
+#[utoipa::path(
+    get,
+    path = "/sender-certificate",
+    responses(
+        (status = 200, description = "Sender certificate issued successfully", body = SenderCertificate),
+        (status = 400, description = "No identity key registered for the account"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn get_sender_certificate(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<SenderCertificate>, MessagingError> {
+    context
+        .sender_certificate_service
+        .issue_certificate(account.id)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/send-batch",
+    request_body = SendMessageBatchRequest,
+    responses(
+        (status = 200, description = "Batch processed, see the results of the individual messages", body = SendMessageBatchResponse),
+        (status = 400, description = "Bad request"),
+        (status = 413, description = "Too many messages in the batch"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn send_message_batch(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<SendMessageBatchRequest>,
+) -> Result<Json<SendMessageBatchResponse>, MessagingError> {
+    let recipient_ids: Vec<Uuid> = req.messages.iter().map(|msg| msg.recipient_id).collect();
+    let messages = req
+        .messages
+        .into_iter()
+        .map(|msg| (msg.recipient_id, msg.message))
+        .collect();
+
+    let results = context
+        .messaging_service
+        .send_messages(account.id, messages, req.ttl.map(Duration::from_secs))
+        .await?
+        .into_iter()
+        .zip(recipient_ids)
+        .map(|(result, recipient_id)| BatchMessageResult::new(recipient_id, result))
+        .collect();
+
+    Ok(Json(SendMessageBatchResponse { results }))
+}
+
 #[utoipa::path(
     get,
     path = "/pending",
+    params(PendingMessagesQuery),
     responses(
-        (status = 200, description = "Messages fetched successfully", body = Vec<Message>),
-        (status = 400, description = "Bad rquest"),
+        (status = 200, description = "Messages fetched successfully", body = PendingMessagesPage),
+        (status = 400, description = "Bad request"),
         (status = 500, description = "Internal server error")
     ),
     tag = MESSAGING_TAG
@@ -75,10 +284,17 @@ async fn send_message(
 async fn fetch_messages(
     State(context): State<Arc<AppContext>>,
     Extension(account): Extension<Account>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
+    Query(query): Query<PendingMessagesQuery>,
+) -> Result<Json<PendingMessagesPage>, MessagingError> {
+    let after = query
+        .after
+        .map(|cursor| cursor.parse::<MessageCursor>())
+        .transpose()?;
+    let limit = query.limit.unwrap_or(DEFAULT_PENDING_MESSAGES_PAGE_SIZE);
+
     context
         .messaging_service
-        .get_pending_messages(account.id)
+        .get_pending_messages(account.id, after, limit)
         .await
         .map(Json)
 }
@@ -89,7 +305,7 @@ async fn fetch_messages(
     request_body = MarkDeliveredRequest,
     responses(
         (status = 200, description = "Messages marked as delivered successfully", body = bool),
-        (status = 400, description = "Bad rquest"),
+        (status = 400, description = "Bad request"),
         (status = 500, description = "Internal server error")
     ),
     tag = MESSAGING_TAG
@@ -105,3 +321,139 @@ async fn mark_delivered(
         .await
         .map(Json)
 }
+
+#[utoipa::path(
+    post,
+    path = "/mark-read",
+    request_body = MarkReadRequest,
+    responses(
+        (status = 200, description = "Read receipts sent successfully", body = bool),
+        (status = 400, description = "Bad request"),
+        (status = 429, description = "Too many pending receipts for this sender"),
+        (status = 500, description = "Internal server error"),
+        (status = 507, description = "Sender's receipt queue is full")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn mark_read(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Json(request): Json<MarkReadRequest>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    context
+        .messaging_service
+        .mark_read(account.id, request.sender_id, request.message_ids)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/requests",
+    responses(
+        (status = 200, description = "Messages of senders the account has yet to accept", body = Vec<Message>),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn get_message_requests(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<Vec<Message>>, MessagingError> {
+    context
+        .messaging_service
+        .get_message_requests(account.id)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/requests/{sender_id}/accept",
+    params(("sender_id" = Uuid, Path, description = "Sender whose messages to accept")),
+    responses(
+        (status = 204, description = "Message request accepted, its messages are pending now"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn accept_message_request(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(sender_id): Path<Uuid>,
+) -> Result<StatusCode, MessagingError> {
+    context
+        .messaging_service
+        .accept_message_request(account.id, sender_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/requests/{sender_id}",
+    params(("sender_id" = Uuid, Path, description = "Sender whose message request to decline")),
+    responses(
+        (status = 204, description = "Message request declined and its messages discarded"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn decline_message_request(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(sender_id): Path<Uuid>,
+) -> Result<StatusCode, MessagingError> {
+    context
+        .messaging_service
+        .decline_message_request(account.id, sender_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/scheduled/{message_id}",
+    params(("message_id" = Uuid, Path, description = "Scheduled message to cancel")),
+    responses(
+        (status = 204, description = "Scheduled message canceled"),
+        (status = 404, description = "No such message scheduled, or it was released already"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn cancel_scheduled_message(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(message_id): Path<Uuid>,
+) -> Result<StatusCode, MessagingError> {
+    context
+        .messaging_service
+        .cancel_scheduled_message(account.id, message_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{message_id}",
+    params(("message_id" = Uuid, Path, description = "Queued message to retract")),
+    responses(
+        (status = 204, description = "Message retracted before it was delivered"),
+        (status = 404, description = "No such message of the sender is queued"),
+        (status = 409, description = "Message handed out to its recipient already"),
+        (status = 500, description = "Internal server error")
+    ),
+    tag = MESSAGING_TAG
+)]
+async fn retract_message(
+    State(context): State<Arc<AppContext>>,
+    Extension(account): Extension<Account>,
+    Path(message_id): Path<Uuid>,
+) -> Result<StatusCode, MessagingError> {
+    context
+        .messaging_service
+        .retract_message(account.id, message_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
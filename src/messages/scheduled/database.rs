@@ -0,0 +1,23 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::messages::{entities::Message, error::MessagingError};
+
+/// Stores messages held back until their delivery time.
+/// The timestamp of a scheduled message is the time it is delivered at.
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait ScheduledMessageDatabase: Send + Sync {
+    async fn insert_scheduled_message(&self, message: Message) -> Result<(), MessagingError>;
+
+    /// Returns the scheduled messages due at or before `now` (epoch milliseconds),
+    /// ordered by delivery time
+    async fn get_due_scheduled_messages(&self, now: u64) -> Result<Vec<Message>, MessagingError>;
+
+    /// Removes a scheduled message of the sender. Returns whether it was still scheduled.
+    async fn remove_scheduled_message(
+        &self,
+        sender_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<bool, MessagingError>;
+}
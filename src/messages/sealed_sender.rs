@@ -0,0 +1,123 @@
+use prism_client::SigningKey;
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use super::{entities::SenderCertificate, error::MessagingError};
+use crate::keys::database::KeyDatabase;
+
+/// Issues the certificates that senders attach to sealed sender messages
+pub struct SenderCertificateService<K>
+where
+    K: KeyDatabase,
+{
+    key_db: Arc<K>,
+    signing_key: SigningKey,
+    validity: Duration,
+}
+
+impl<K> SenderCertificateService<K>
+where
+    K: KeyDatabase,
+{
+    pub fn new(key_db: Arc<K>, signing_key: SigningKey, validity: Duration) -> Self {
+        Self {
+            key_db,
+            signing_key,
+            validity,
+        }
+    }
+
+    /// Certifies that the account owns its registered identity key.
+    /// Certificates are short-lived, clients fetch a new one before it expires.
+    pub async fn issue_certificate(
+        &self,
+        account_id: Uuid,
+    ) -> Result<SenderCertificate, MessagingError> {
+        let identity_key = self
+            .key_db
+            .get_keybundle(account_id)
+            .await
+            .map_err(|e| MessagingError::DatabaseError(e.to_string()))?
+            .ok_or_else(|| MessagingError::UserNotFound(account_id.to_string()))?
+            .identity_key;
+
+        let expires_at =
+            chrono::Utc::now().timestamp_millis() as u64 + self.validity.as_millis() as u64;
+        let signed_data = SenderCertificate::signed_data(account_id, &identity_key, expires_at)?;
+        let signature = self
+            .signing_key
+            .sign(signed_data)
+            .map_err(|e| MessagingError::SendingFailed(e.to_string()))?;
+
+        Ok(SenderCertificate {
+            sender_id: account_id,
+            identity_key,
+            expires_at,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::inmemory::InMemoryDatabase,
+        keys::{database::KeyDatabase, entities::KeyBundle},
+    };
+
+    #[tokio::test]
+    async fn test_issued_certificate_verifies_with_service_key() {
+        let account_id = Uuid::new_v4();
+        let identity_key = SigningKey::new_ed25519();
+        let signed_prekey = SigningKey::new_ed25519().verifying_key();
+
+        let key_db = Arc::new(InMemoryDatabase::new());
+        key_db
+            .insert_keybundle(
+                account_id,
+                KeyBundle {
+                    identity_key: identity_key.verifying_key(),
+                    signed_prekey_id: 1,
+                    signed_prekey_signature: identity_key
+                        .sign(signed_prekey.to_spki_der().unwrap())
+                        .unwrap(),
+                    signed_prekey,
+                    prekeys: vec![],
+                    last_resort_kem_prekey: None,
+                    kem_prekeys: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+        let service_key = SigningKey::new_ed25519();
+        let service =
+            SenderCertificateService::new(key_db, service_key.clone(), Duration::from_secs(3600));
+
+        let certificate = service
+            .issue_certificate(account_id)
+            .await
+            .expect("Failed to issue certificate");
+        assert_eq!(certificate.sender_id, account_id);
+        assert_eq!(certificate.identity_key, identity_key.verifying_key());
+        assert!(certificate.expires_at > chrono::Utc::now().timestamp_millis() as u64);
+
+        let signed_data = SenderCertificate::signed_data(
+            certificate.sender_id,
+            &certificate.identity_key,
+            certificate.expires_at,
+        )
+        .unwrap();
+        assert!(
+            service_key
+                .verifying_key()
+                .verify_signature(&signed_data, &certificate.signature)
+                .is_ok()
+        );
+
+        // Accounts without keys can't be certified
+        let result = service.issue_certificate(Uuid::new_v4()).await;
+        assert!(matches!(result, Err(MessagingError::UserNotFound(_))));
+    }
+}
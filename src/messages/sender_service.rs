@@ -2,33 +2,46 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::interval;
 use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
 
-use super::{database::MessageDatabase, error::MessagingError, gateway::MessageGateway};
+use super::{
+    database::MessageDatabase, entities::Message, error::MessagingError, gateway::MessageGateway,
+    in_flight::InFlightMessages,
+};
 
 pub struct MessageSenderService<D, G>
 where
-    D: MessageDatabase + 'static,
+    D: MessageDatabase + ?Sized + 'static,
     G: MessageGateway + 'static,
 {
     messages_db: Arc<D>,
     message_gateway: Arc<G>,
+    in_flight: Arc<InFlightMessages>,
     poll_interval: Duration,
 }
 
 impl<D, G> MessageSenderService<D, G>
 where
-    D: MessageDatabase + 'static,
+    D: MessageDatabase + ?Sized + 'static,
     G: MessageGateway + 'static,
 {
-    pub fn new(messages_db: Arc<D>, message_gateway: Arc<G>, poll_interval: Duration) -> Self {
+    pub fn new(
+        messages_db: Arc<D>,
+        message_gateway: Arc<G>,
+        in_flight: Arc<InFlightMessages>,
+        poll_interval: Duration,
+    ) -> Self {
         Self {
             messages_db,
             message_gateway,
+            in_flight,
             poll_interval,
         }
     }
 
-    /// Spawn the background task that continuously processes pending messages
+    /// Spawn the background task that periodically retries the pending messages of
+    /// connected accounts. Serves as a safety net only, as messages are usually delivered
+    /// on send or when their recipient connects.
     #[instrument(skip(self))]
     pub fn spawn_message_sender(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
@@ -44,40 +57,131 @@ where
         })
     }
 
-    /// Process all pending messages for all accounts
+    /// Deliver the backlog of a recipient as soon as it connects
+    #[instrument(skip(self))]
+    pub async fn handle_recipient_connections(self: Arc<Self>) {
+        let service = self.clone();
+        self.message_gateway
+            .register_recipient_connected_handler(move |account_id| {
+                let service = service.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = service
+                        .try_send_pending_messages_for_account(account_id)
+                        .await
+                    {
+                        error!(
+                            "Error processing pending messages for {}: {}",
+                            account_id, e
+                        );
+                    }
+                });
+            })
+            .await;
+    }
+
+    /// Process the pending messages of the accounts connected through the gateway,
+    /// skipping those still awaiting an acknowledgement. Absent accounts' messages wait
+    /// for their next connect, so they are not loaded at all.
     #[instrument(skip(self))]
     async fn try_send_pending_messages(&self) -> Result<(), MessagingError> {
-        let messages = self.messages_db.get_all_messages()?;
+        for account_id in self.message_gateway.get_connected_recipient_ids().await {
+            self.try_send_new_messages_for_account(account_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Process the pending messages of a connected account that are not in flight yet,
+    /// e.g. when another server queued messages for it
+    #[instrument(skip(self))]
+    pub async fn try_send_new_messages_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        let messages = self
+            .messages_db
+            .get_messages_for_account(account_id)
+            .await?
+            .into_iter()
+            .filter(|message| self.in_flight.try_mark(message.message_id))
+            .collect();
+        self.send_messages(messages).await
+    }
+
+    /// Process all pending messages and receipts of a single account.
+    /// Messages in flight are sent again, as they were sent to a previous connection.
+    #[instrument(skip(self))]
+    async fn try_send_pending_messages_for_account(
+        &self,
+        account_id: Uuid,
+    ) -> Result<(), MessagingError> {
+        let messages = self
+            .messages_db
+            .get_messages_for_account(account_id)
+            .await?;
+        for message in &messages {
+            self.in_flight.mark(message.message_id);
+        }
+        self.send_messages(messages).await?;
+
+        let receipts = self
+            .messages_db
+            .get_receipts_for_account(account_id)
+            .await?;
+        for receipt in receipts {
+            let receipt_id = receipt.receipt_id;
+            if let Err(e) = self.message_gateway.send_receipt(receipt).await {
+                warn!(
+                    "Failed to send receipt {} to {}: {}",
+                    receipt_id, account_id, e
+                );
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends messages already marked as in flight. They stay queued until acknowledged.
+    /// Sent messages are marked handed out, so their senders can no longer retract them.
+    async fn send_messages(&self, messages: Vec<Message>) -> Result<(), MessagingError> {
+        let mut sent_ids = Vec::new();
+        let mut handed_out_until = 0;
 
         for message in messages {
             let recipient_id = message.recipient_id;
             let message_id = message.message_id;
+            let expires_at = message.expires_at;
             let result = self.message_gateway.send_message(message).await;
 
             match result {
                 Ok(()) => {
                     info!(
-                        "Successfully sent message {} to recipient {}",
+                        "Successfully sent message {} to recipient {}, awaiting ack",
                         message_id, recipient_id
                     );
-                    self.messages_db
-                        .remove_messages(recipient_id, vec![message_id])?;
+                    sent_ids.push(message_id);
+                    handed_out_until = handed_out_until.max(expires_at);
                 }
                 Err(MessagingError::UserNotFound(account_id)) => {
                     // Recipient is not connected, leave message in database for later delivery
                     debug!("Recipient {} not found. Doing nothing", account_id);
-                    continue;
+                    self.in_flight.clear(&[message_id]);
                 }
                 Err(e) => {
                     warn!(
                         "Failed to send message {} to recipient {}: {}",
                         message_id, recipient_id, e
                     );
-                    continue;
+                    self.in_flight.clear(&[message_id]);
                 }
             }
         }
-        Ok(())
+
+        if sent_ids.is_empty() {
+            return Ok(());
+        }
+        self.messages_db
+            .insert_handed_out_messages(sent_ids, handed_out_until)
+            .await
     }
 }
 
@@ -90,46 +194,53 @@ mod tests {
     use mockall::predicate::*;
     use prism_client::SigningKey;
     use std::time::Duration;
-    use tokio::time::sleep;
-    use uuid::Uuid;
+    use tokio::{sync::mpsc, time::sleep};
 
     fn create_test_message(recipient_id: Uuid) -> Message {
         Message {
             message_id: Uuid::new_v4(),
-            sender_id: Uuid::new_v4(),
+            sender_id: Some(Uuid::new_v4()),
             recipient_id,
+            group_id: None,
             message: DoubleRatchetMessage {
                 header: DoubleRatchetHeader {
                     ephemeral_key: SigningKey::new_secp256r1().verifying_key(),
                     message_number: 1,
                     previous_message_number: 0,
                     one_time_prekey_id: Some(1),
+                    signed_prekey_id: None,
                 },
                 ciphertext: vec![1, 2, 3, 4],
                 nonce: vec![5, 6, 7, 8],
             },
             timestamp: 1234567890,
+            expires_at: 1234567890 + 60_000,
         }
     }
 
     #[tokio::test]
-    async fn test_successful_message_sending() {
+    async fn test_successful_message_sending_awaits_ack() {
         let recipient_id = Uuid::new_v4();
         let message = create_test_message(recipient_id);
-        let message_id = message.message_id;
 
         let mut mock_db = MockMessageDatabase::new();
         mock_db
-            .expect_get_all_messages()
-            .once()
-            .returning(move || Ok(vec![message.clone()]));
+            .expect_get_messages_for_account()
+            .times(2)
+            .with(eq(recipient_id))
+            .returning(move |_| Ok(vec![message.clone()]));
         mock_db
-            .expect_remove_messages()
+            .expect_insert_handed_out_messages()
             .once()
-            .with(eq(recipient_id), eq(vec![message_id]))
             .returning(|_, _| Ok(()));
+        // Sent messages are only removed once acknowledged
+        mock_db.expect_remove_messages().never();
 
         let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .times(2)
+            .returning(move || vec![recipient_id]);
         mock_gateway
             .expect_send_message()
             .once()
@@ -139,11 +250,127 @@ mod tests {
         let service = MessageSenderService::new(
             Arc::new(mock_db),
             Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
             Duration::from_millis(100),
         );
 
         let result = service.try_send_pending_messages().await;
         assert!(result.is_ok());
+
+        // The message is in flight, so it is not sent again
+        let result = service.try_send_pending_messages().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_message_is_resent_after_timeout() {
+        let recipient_id = Uuid::new_v4();
+        let message = create_test_message(recipient_id);
+
+        let mut mock_db = MockMessageDatabase::new();
+        mock_db
+            .expect_get_messages_for_account()
+            .times(2)
+            .returning(move |_| Ok(vec![message.clone()]));
+        mock_db
+            .expect_insert_handed_out_messages()
+            .times(2)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .times(2)
+            .returning(move || vec![recipient_id]);
+        mock_gateway
+            .expect_send_message()
+            .times(2)
+            .with(always())
+            .returning(|_| Ok(()));
+
+        let service = MessageSenderService::new(
+            Arc::new(mock_db),
+            Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_millis(10))),
+            Duration::from_millis(100),
+        );
+
+        service.try_send_pending_messages().await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        service.try_send_pending_messages().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recipient_connect_sends_only_its_pending_messages_and_receipts() {
+        let recipient_id = Uuid::new_v4();
+        let message = create_test_message(recipient_id);
+        let message_id = message.message_id;
+        let receipt = Receipt {
+            receipt_id: Uuid::new_v4(),
+            kind: ReceiptKind::Delivered,
+            message_id: Uuid::new_v4(),
+            sender_id: recipient_id,
+            recipient_id: Uuid::new_v4(),
+            timestamp: 1234567890,
+        };
+        let receipt_id = receipt.receipt_id;
+
+        let mut mock_db = MockMessageDatabase::new();
+        mock_db.expect_get_all_messages().never();
+        mock_db
+            .expect_get_messages_for_account()
+            .once()
+            .with(eq(recipient_id))
+            .returning(move |_| Ok(vec![message.clone()]));
+        mock_db
+            .expect_insert_handed_out_messages()
+            .once()
+            .with(eq(vec![message_id]), always())
+            .returning(|_, _| Ok(()));
+        mock_db
+            .expect_get_receipts_for_account()
+            .once()
+            .with(eq(recipient_id))
+            .returning(move |_| Ok(vec![receipt.clone()]));
+
+        let (tx, mut rx) = mpsc::channel(2);
+        let receipt_tx = tx.clone();
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_register_recipient_connected_handler()
+            .once()
+            .returning(move |handler| handler(recipient_id));
+        mock_gateway
+            .expect_send_message()
+            .once()
+            .returning(move |message| {
+                tx.try_send(message.message_id).unwrap();
+                Ok(())
+            });
+        mock_gateway
+            .expect_send_receipt()
+            .once()
+            .returning(move |receipt| {
+                receipt_tx.try_send(receipt.receipt_id).unwrap();
+                Ok(())
+            });
+
+        let service = Arc::new(MessageSenderService::new(
+            Arc::new(mock_db),
+            Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
+            Duration::from_secs(60),
+        ));
+
+        service.handle_recipient_connections().await;
+
+        for expected_id in [message_id, receipt_id] {
+            let sent_id = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .expect("Pending message or receipt was not sent on connect")
+                .unwrap();
+            assert_eq!(sent_id, expected_id);
+        }
     }
 
     #[tokio::test]
@@ -153,11 +380,18 @@ mod tests {
 
         let mut mock_db = MockMessageDatabase::new();
         mock_db
-            .expect_get_all_messages()
+            .expect_get_messages_for_account()
             .once()
-            .returning(move || Ok(vec![message.clone()]));
+            .returning(move |_| Ok(vec![message.clone()]));
+        // The message was not handed out, so it can still be retracted
+        mock_db.expect_insert_handed_out_messages().never();
 
+        // The recipient disconnects while the poll is running
         let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .once()
+            .returning(move || vec![recipient_id]);
         mock_gateway
             .expect_send_message()
             .once()
@@ -167,6 +401,7 @@ mod tests {
         let service = MessageSenderService::new(
             Arc::new(mock_db),
             Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
             Duration::from_millis(100),
         );
 
@@ -185,16 +420,26 @@ mod tests {
 
         let mut mock_db = MockMessageDatabase::new();
         mock_db
-            .expect_get_all_messages()
+            .expect_get_messages_for_account()
             .once()
-            .returning(move || Ok(vec![message1.clone(), message2.clone()]));
+            .with(eq(recipient_id1))
+            .returning(move |_| Ok(vec![message1.clone()]));
         mock_db
-            .expect_remove_messages()
+            .expect_get_messages_for_account()
             .once()
-            .with(eq(recipient_id2), eq(vec![message2_id]))
+            .with(eq(recipient_id2))
+            .returning(move |_| Ok(vec![message2.clone()]));
+        mock_db
+            .expect_insert_handed_out_messages()
+            .once()
+            .with(eq(vec![message2_id]), always())
             .returning(|_, _| Ok(()));
 
         let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .once()
+            .returning(move || vec![recipient_id1, recipient_id2]);
         mock_gateway
             .expect_send_message()
             .once()
@@ -213,6 +458,7 @@ mod tests {
         let service = MessageSenderService::new(
             Arc::new(mock_db),
             Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
             Duration::from_millis(100),
         );
 
@@ -223,17 +469,25 @@ mod tests {
     #[tokio::test]
     async fn test_database_error_propagates() {
         let mut mock_db = MockMessageDatabase::new();
-        mock_db.expect_get_all_messages().once().returning(|| {
-            Err(MessagingError::DatabaseError(
-                "Connection failed".to_string(),
-            ))
-        });
+        mock_db
+            .expect_get_messages_for_account()
+            .once()
+            .returning(|_| {
+                Err(MessagingError::DatabaseError(
+                    "Connection failed".to_string(),
+                ))
+            });
 
-        let mock_gateway = MockMessageGateway::new();
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .once()
+            .returning(|| vec![Uuid::new_v4()]);
 
         let service = MessageSenderService::new(
             Arc::new(mock_db),
             Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
             Duration::from_millis(100),
         );
 
@@ -245,19 +499,47 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn test_poll_skips_accounts_not_connected() {
+        let mut mock_db = MockMessageDatabase::new();
+        mock_db.expect_get_all_messages().never();
+        mock_db.expect_get_messages_for_account().never();
+
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .once()
+            .returning(Vec::new);
+
+        let service = MessageSenderService::new(
+            Arc::new(mock_db),
+            Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
+            Duration::from_millis(100),
+        );
+
+        let result = service.try_send_pending_messages().await;
+        assert!(result.is_ok());
+    }
+
     #[tokio::test]
     async fn test_empty_message_queue() {
         let mut mock_db = MockMessageDatabase::new();
         mock_db
-            .expect_get_all_messages()
+            .expect_get_messages_for_account()
             .once()
-            .returning(|| Ok(vec![]));
+            .returning(|_| Ok(vec![]));
 
-        let mock_gateway = MockMessageGateway::new();
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .once()
+            .returning(|| vec![Uuid::new_v4()]);
 
         let service = MessageSenderService::new(
             Arc::new(mock_db),
             Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
             Duration::from_millis(100),
         );
 
@@ -269,15 +551,20 @@ mod tests {
     async fn test_spawn_message_sender_runs_continuously() {
         let mut mock_db = MockMessageDatabase::new();
         mock_db
-            .expect_get_all_messages()
+            .expect_get_messages_for_account()
             .times(2..)
-            .returning(|| Ok(vec![]));
+            .returning(|_| Ok(vec![]));
 
-        let mock_gateway = MockMessageGateway::new();
+        let mut mock_gateway = MockMessageGateway::new();
+        mock_gateway
+            .expect_get_connected_recipient_ids()
+            .times(2..)
+            .returning(|| vec![Uuid::new_v4()]);
 
         let service = Arc::new(MessageSenderService::new(
             Arc::new(mock_db),
             Arc::new(mock_gateway),
+            Arc::new(InFlightMessages::new(Duration::from_secs(30))),
             Duration::from_millis(100),
         ));
 
@@ -290,46 +577,4 @@ mod tests {
         let result = handle.await;
         assert!(result.is_err()); // Expected because we aborted the task
     }
-
-    #[tokio::test]
-    async fn test_remove_message_failure_stops_processing() {
-        let recipient_id1 = Uuid::new_v4();
-        let recipient_id2 = Uuid::new_v4();
-        let message1 = create_test_message(recipient_id1);
-        let message2 = create_test_message(recipient_id2);
-        let message1_id = message1.message_id;
-
-        let mut mock_db = MockMessageDatabase::new();
-        mock_db
-            .expect_get_all_messages()
-            .once()
-            .returning(move || Ok(vec![message1.clone(), message2.clone()]));
-        mock_db
-            .expect_remove_messages()
-            .once()
-            .with(eq(recipient_id1), eq(vec![message1_id]))
-            .returning(|_, _| Err(MessagingError::DatabaseError("Remove failed".to_string())));
-        // Second remove_messages should NOT be called because the first one fails and returns early
-
-        let mut mock_gateway = MockMessageGateway::new();
-        mock_gateway
-            .expect_send_message()
-            .once()
-            .with(always())
-            .returning(|_| Ok(()));
-        // Second send should NOT be called because processing stops after first remove failure
-
-        let service = MessageSenderService::new(
-            Arc::new(mock_db),
-            Arc::new(mock_gateway),
-            Duration::from_millis(100),
-        );
-
-        let result = service.try_send_pending_messages().await;
-        assert!(result.is_err());
-        assert!(matches!(
-            result.unwrap_err(),
-            MessagingError::DatabaseError(_)
-        ));
-    }
 }
@@ -48,4 +48,10 @@ pub trait TypingGateway: Send + Sync {
     async fn register_typing_handler<H>(&self, handler: H)
     where
         H: Fn(TypingStatus) + Send + Sync + 'static;
+
+    /// Registers a handler that is called with the account ID
+    /// whenever a sender of typing updates disconnects.
+    async fn register_sender_disconnected_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid) + Send + Sync + 'static;
 }
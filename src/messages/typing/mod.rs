@@ -1,2 +1,3 @@
 pub mod gateway;
 pub mod service;
+pub mod state;
@@ -1,38 +1,268 @@
 use std::sync::Arc;
-use tracing::debug;
+use std::time::Duration;
+use tokio::time::interval;
+use tracing::{debug, info, instrument};
 
-use super::gateway::TypingGateway;
+use super::{
+    gateway::{TypingGateway, TypingStatus},
+    state::TypingStates,
+};
+use crate::blocks::database::BlockDatabase;
 
-pub struct TypingService<G>
+pub struct TypingService<G, B>
 where
     G: TypingGateway + 'static,
+    B: BlockDatabase + 'static,
 {
     gateway: Arc<G>,
+    block_db: Arc<B>,
+    states: Arc<TypingStates>,
 }
 
-impl<G> TypingService<G>
+impl<G, B> TypingService<G, B>
 where
     G: TypingGateway + 'static,
+    B: BlockDatabase + 'static,
 {
-    pub fn new(gateway: Arc<G>) -> Self {
-        Self { gateway }
+    /// Typing indicators are cleared after `timeout` without updates.
+    /// Repeated updates are relayed at most once per `min_update_interval`.
+    pub fn new(
+        gateway: Arc<G>,
+        block_db: Arc<B>,
+        timeout: Duration,
+        min_update_interval: Duration,
+    ) -> Self {
+        Self {
+            gateway,
+            block_db,
+            states: Arc::new(TypingStates::new(timeout, min_update_interval)),
+        }
     }
 
+    /// Relays typing updates to their recipient, unless the recipient blocked the sender.
+    /// Typing indicators of disconnecting senders are cleared.
     pub async fn handle_typing_updates(&self) {
         let gateway = self.gateway.clone();
+        let block_db = self.block_db.clone();
+        let states = self.states.clone();
         self.gateway
             .register_typing_handler(move |typing_status| {
                 debug!(
                     "Typing update {} -> {}: {}",
                     typing_status.recipient_id, typing_status.sender_id, typing_status.is_typing
                 );
+                if !states.update(&typing_status) {
+                    debug!(
+                        "Dropping repeated typing update of {}",
+                        typing_status.sender_id
+                    );
+                    return;
+                }
                 let gateway = gateway.clone();
+                let block_db = block_db.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = gateway.send_typing_update(&typing_status).await {
-                        tracing::error!("Failed to send typing update: {:?}", e);
+                    relay_typing_update(gateway.as_ref(), block_db.as_ref(), &typing_status).await;
+                });
+            })
+            .await;
+
+        let gateway = self.gateway.clone();
+        let block_db = self.block_db.clone();
+        let states = self.states.clone();
+        self.gateway
+            .register_sender_disconnected_handler(move |sender_id| {
+                let cleared = states.remove_sender(sender_id);
+                if cleared.is_empty() {
+                    return;
+                }
+                debug!("Clearing typing indicators of disconnected {}", sender_id);
+                let gateway = gateway.clone();
+                let block_db = block_db.clone();
+                tokio::spawn(async move {
+                    for typing_status in cleared {
+                        relay_typing_update(gateway.as_ref(), block_db.as_ref(), &typing_status)
+                            .await;
                     }
                 });
             })
             .await;
     }
+
+    /// Spawn the background task that clears typing indicators of senders
+    /// that stopped sending updates
+    #[instrument(skip(self))]
+    pub fn spawn_typing_expiry_sweeper(
+        self: Arc<Self>,
+        sweep_interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            info!("Starting typing indicator expiry task");
+            let mut ticker = interval(sweep_interval);
+
+            loop {
+                ticker.tick().await;
+                self.clear_expired_typing_indicators().await;
+            }
+        })
+    }
+
+    async fn clear_expired_typing_indicators(&self) {
+        for typing_status in self.states.remove_expired() {
+            debug!(
+                "Typing indicator {} -> {} expired",
+                typing_status.sender_id, typing_status.recipient_id
+            );
+            relay_typing_update(
+                self.gateway.as_ref(),
+                self.block_db.as_ref(),
+                &typing_status,
+            )
+            .await;
+        }
+    }
+}
+
+async fn relay_typing_update<G, B>(gateway: &G, block_db: &B, typing_status: &TypingStatus)
+where
+    G: TypingGateway,
+    B: BlockDatabase,
+{
+    match block_db
+        .is_blocked(typing_status.recipient_id, typing_status.sender_id)
+        .await
+    {
+        Ok(false) => {}
+        Ok(true) => {
+            debug!(
+                "Recipient {} blocked {}, dropping typing update",
+                typing_status.recipient_id, typing_status.sender_id
+            );
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Failed to check block list: {:?}", e);
+            return;
+        }
+    }
+
+    if let Err(e) = gateway.send_typing_update(typing_status).await {
+        tracing::error!("Failed to send typing update: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::database::MockBlockDatabase;
+    use crate::messages::typing::gateway::{MockTypingGateway, TypingStatus};
+    use mockall::predicate::*;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_typing_updates_of_blocked_senders_are_dropped() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let mut mock_gateway = MockTypingGateway::new();
+        mock_gateway
+            .expect_register_typing_handler()
+            .with(always())
+            .times(1)
+            .returning(move |handler| {
+                for sender_id in [mallory_id, bob_id] {
+                    handler(TypingStatus {
+                        recipient_id: alice_id,
+                        sender_id,
+                        is_typing: true,
+                    });
+                }
+            });
+        mock_gateway
+            .expect_register_sender_disconnected_handler()
+            .returning(|_| ());
+        mock_gateway
+            .expect_send_typing_update()
+            .returning(move |typing_status| {
+                tx.send(typing_status.sender_id).unwrap();
+                Ok(())
+            });
+
+        let mut mock_block_db = MockBlockDatabase::new();
+        mock_block_db
+            .expect_is_blocked()
+            .returning(move |account_id, blocked_id| {
+                Ok(account_id == alice_id && blocked_id == mallory_id)
+            });
+
+        let service = TypingService::new(
+            Arc::new(mock_gateway),
+            Arc::new(mock_block_db),
+            Duration::from_secs(10),
+            Duration::from_secs(2),
+        );
+        service.handle_typing_updates().await;
+        // The gateway, and with it the channel, is dropped once both updates are handled
+        drop(service);
+
+        let mut sender_ids = Vec::new();
+        while let Some(sender_id) = rx.recv().await {
+            sender_ids.push(sender_id);
+        }
+        assert_eq!(sender_ids, vec![bob_id]);
+    }
+
+    #[tokio::test]
+    async fn test_typing_indicators_are_cleared_on_disconnect() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let mut mock_gateway = MockTypingGateway::new();
+        mock_gateway
+            .expect_register_typing_handler()
+            .times(1)
+            .returning(move |handler| {
+                // Repeated updates within the update interval are dropped
+                for _ in 0..3 {
+                    handler(TypingStatus {
+                        recipient_id: alice_id,
+                        sender_id: bob_id,
+                        is_typing: true,
+                    });
+                }
+            });
+        mock_gateway
+            .expect_register_sender_disconnected_handler()
+            .times(1)
+            .returning(move |handler| handler(bob_id));
+        mock_gateway
+            .expect_send_typing_update()
+            .returning(move |typing_status| {
+                tx.send(typing_status.is_typing).unwrap();
+                Ok(())
+            });
+
+        let mut mock_block_db = MockBlockDatabase::new();
+        mock_block_db
+            .expect_is_blocked()
+            .returning(|_, _| Ok(false));
+
+        let service = TypingService::new(
+            Arc::new(mock_gateway),
+            Arc::new(mock_block_db),
+            Duration::from_secs(10),
+            Duration::from_secs(2),
+        );
+        service.handle_typing_updates().await;
+        drop(service);
+
+        let mut updates = Vec::new();
+        while let Some(is_typing) = rx.recv().await {
+            updates.push(is_typing);
+        }
+        assert_eq!(updates, vec![true, false]);
+    }
 }
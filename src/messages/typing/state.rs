@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::gateway::TypingStatus;
+
+/// Keeps track of who is typing to whom, so that typing indicators
+/// can be cleared once their sender stops sending updates.
+pub struct TypingStates {
+    timeout: Duration,
+    min_update_interval: Duration,
+    /// Keyed by sender and recipient
+    states: Mutex<HashMap<(Uuid, Uuid), TypingState>>,
+}
+
+struct TypingState {
+    expires_at: Instant,
+    relayed_at: Instant,
+}
+
+impl TypingStates {
+    pub fn new(timeout: Duration, min_update_interval: Duration) -> Self {
+        Self {
+            timeout,
+            min_update_interval,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a typing update. Returns whether it is to be relayed to the recipient,
+    /// which is not the case for repeated updates that don't change anything
+    /// and arrive within the minimum update interval.
+    pub fn update(&self, typing_status: &TypingStatus) -> bool {
+        let now = Instant::now();
+        let key = (typing_status.sender_id, typing_status.recipient_id);
+        let mut states = self.states.lock().unwrap();
+
+        if !typing_status.is_typing {
+            return states.remove(&key).is_some();
+        }
+
+        match states.get_mut(&key) {
+            Some(state) if now.duration_since(state.relayed_at) < self.min_update_interval => {
+                state.expires_at = now + self.timeout;
+                false
+            }
+            _ => {
+                states.insert(
+                    key,
+                    TypingState {
+                        expires_at: now + self.timeout,
+                        relayed_at: now,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Forgets about senders that stopped sending updates.
+    /// Returns the updates that clear their typing indicators.
+    pub fn remove_expired(&self) -> Vec<TypingStatus> {
+        let now = Instant::now();
+        self.remove_where(|_, state| state.expires_at <= now)
+    }
+
+    /// Forgets about everything the sender was typing, e.g. once they disconnected.
+    /// Returns the updates that clear their typing indicators.
+    pub fn remove_sender(&self, sender_id: Uuid) -> Vec<TypingStatus> {
+        self.remove_where(|(typing_sender_id, _), _| *typing_sender_id == sender_id)
+    }
+
+    fn remove_where<F>(&self, predicate: F) -> Vec<TypingStatus>
+    where
+        F: Fn(&(Uuid, Uuid), &TypingState) -> bool,
+    {
+        let mut states = self.states.lock().unwrap();
+        let mut cleared = Vec::new();
+
+        states.retain(|key, state| {
+            if !predicate(key, state) {
+                return true;
+            }
+            let (sender_id, recipient_id) = *key;
+            cleared.push(TypingStatus {
+                recipient_id,
+                sender_id,
+                is_typing: false,
+            });
+            false
+        });
+        cleared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typing_status(sender_id: Uuid, recipient_id: Uuid, is_typing: bool) -> TypingStatus {
+        TypingStatus {
+            recipient_id,
+            sender_id,
+            is_typing,
+        }
+    }
+
+    #[test]
+    fn test_repeated_updates_are_throttled() {
+        let states = TypingStates::new(Duration::from_secs(60), Duration::from_millis(20));
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+
+        assert!(states.update(&typing_status(bob_id, alice_id, true)));
+        assert!(!states.update(&typing_status(bob_id, alice_id, true)));
+        // Typing to someone else is tracked separately
+        assert!(states.update(&typing_status(bob_id, Uuid::new_v4(), true)));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(states.update(&typing_status(bob_id, alice_id, true)));
+
+        // Stopping is relayed once, and only if typing was relayed before
+        assert!(states.update(&typing_status(bob_id, alice_id, false)));
+        assert!(!states.update(&typing_status(bob_id, alice_id, false)));
+    }
+
+    #[test]
+    fn test_expired_and_disconnected_senders_are_cleared() {
+        let states = TypingStates::new(Duration::from_millis(10), Duration::from_secs(60));
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let carol_id = Uuid::new_v4();
+
+        assert!(states.update(&typing_status(bob_id, alice_id, true)));
+        assert!(states.remove_expired().is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(states.update(&typing_status(carol_id, alice_id, true)));
+        let cleared = states.remove_expired();
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].sender_id, bob_id);
+        assert!(!cleared[0].is_typing);
+
+        let cleared = states.remove_sender(carol_id);
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].recipient_id, alice_id);
+        assert!(states.remove_sender(carol_id).is_empty());
+    }
+}
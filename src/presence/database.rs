@@ -1,10 +1,18 @@
 use async_trait::async_trait;
 use uuid::Uuid;
 
-use super::error::PresenceError;
+use super::{entities::PresenceStatus, error::PresenceError};
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait PresenceDatabase: Send + Sync {
     async fn is_present(&self, account_id: &Uuid) -> Result<bool, PresenceError>;
+
+    /// Records a presence change of an account.
+    /// Databases that derive presence from open connections may ignore this.
+    async fn update_presence(
+        &self,
+        account_id: &Uuid,
+        status: &PresenceStatus,
+    ) -> Result<(), PresenceError>;
 }
@@ -5,6 +5,8 @@ use axum::{
 use tracing::error;
 use uuid::Uuid;
 
+use crate::blocks::error::BlockError;
+
 #[derive(Debug, thiserror::Error)]
 pub enum PresenceError {
     #[error("Database error: {0}")]
@@ -15,6 +17,12 @@ pub enum PresenceError {
     SendingFailed(String),
 }
 
+impl From<BlockError> for PresenceError {
+    fn from(err: BlockError) -> Self {
+        PresenceError::Database(err.to_string())
+    }
+}
+
 impl IntoResponse for PresenceError {
     fn into_response(self) -> Response {
         error!("{}", self);
@@ -19,10 +19,11 @@ impl PresenceUpdate {
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait PresenceGateway: Send + Sync {
-    /// Send a presence update to interested parties
+    /// Send a presence update to interested parties, except for the excluded accounts
     async fn send_presence_update(
         &self,
         presence_update: &PresenceUpdate,
+        excluded_account_ids: &[Uuid],
     ) -> Result<(), PresenceError>;
 
     async fn register_presence_handler<H>(&self, handler: H)
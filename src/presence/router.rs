@@ -54,13 +54,13 @@ pub fn router(context: Arc<AppContext>) -> OpenApiRouter<Arc<AppContext>> {
     )
 )]
 async fn get_presence_status(
-    Extension(_account): Extension<Account>,
+    Extension(account): Extension<Account>,
     Path(account_id): Path<Uuid>,
     State(context): State<Arc<AppContext>>,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
     context
         .presence_service
-        .get_presence_status(&account_id)
+        .get_presence_status(&account_id, &account.id)
         .await
         .map(PresenceStatusResponse::new)
         .map(Json)
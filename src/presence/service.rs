@@ -3,24 +3,35 @@ use tracing::instrument;
 use uuid::Uuid;
 
 use super::{database::PresenceDatabase, entities::PresenceStatus, error::PresenceError};
+use crate::blocks::database::BlockDatabase;
 
 #[derive(Clone)]
-pub struct PresenceService<D: PresenceDatabase> {
+pub struct PresenceService<D: PresenceDatabase + ?Sized, B: BlockDatabase> {
     presence_db: Arc<D>,
+    block_db: Arc<B>,
 }
 
-impl<D: PresenceDatabase> PresenceService<D> {
-    pub fn new(presence_db: Arc<D>) -> Self {
-        Self { presence_db }
+impl<D: PresenceDatabase + ?Sized, B: BlockDatabase> PresenceService<D, B> {
+    pub fn new(presence_db: Arc<D>, block_db: Arc<B>) -> Self {
+        Self {
+            presence_db,
+            block_db,
+        }
     }
 }
 
-impl<D: PresenceDatabase> PresenceService<D> {
+impl<D: PresenceDatabase + ?Sized, B: BlockDatabase> PresenceService<D, B> {
+    /// Accounts that blocked the requester always appear offline to it
     #[instrument(skip(self))]
     pub async fn get_presence_status(
         &self,
         account_id: &Uuid,
+        requester_id: &Uuid,
     ) -> Result<PresenceStatus, PresenceError> {
+        if self.block_db.is_blocked(*account_id, *requester_id).await? {
+            return Ok(PresenceStatus::Offline);
+        }
+
         let is_present = self.presence_db.is_present(account_id).await?;
         Ok(PresenceStatus::from(is_present))
     }
@@ -29,6 +40,7 @@ impl<D: PresenceDatabase> PresenceService<D> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blocks::database::MockBlockDatabase;
     use crate::presence::database::MockPresenceDatabase;
 
     #[tokio::test]
@@ -42,8 +54,10 @@ mod tests {
             .times(1)
             .returning(|_| Ok(true));
 
-        let service = PresenceService::new(Arc::new(mock_db));
-        let result = service.get_presence_status(&account_id).await;
+        let service = PresenceService::new(Arc::new(mock_db), Arc::new(no_blocks()));
+        let result = service
+            .get_presence_status(&account_id, &Uuid::new_v4())
+            .await;
 
         assert!(result.is_ok());
         assert!(matches!(result.unwrap(), PresenceStatus::Online));
@@ -60,8 +74,10 @@ mod tests {
             .times(1)
             .returning(|_| Ok(false));
 
-        let service = PresenceService::new(Arc::new(mock_db));
-        let result = service.get_presence_status(&account_id).await;
+        let service = PresenceService::new(Arc::new(mock_db), Arc::new(no_blocks()));
+        let result = service
+            .get_presence_status(&account_id, &Uuid::new_v4())
+            .await;
 
         assert!(result.is_ok());
         assert!(matches!(result.unwrap(), PresenceStatus::Offline));
@@ -78,10 +94,45 @@ mod tests {
             .times(1)
             .returning(|_| Err(PresenceError::Database("Connection failed".to_string())));
 
-        let service = PresenceService::new(Arc::new(mock_db));
-        let result = service.get_presence_status(&account_id).await;
+        let service = PresenceService::new(Arc::new(mock_db), Arc::new(no_blocks()));
+        let result = service
+            .get_presence_status(&account_id, &Uuid::new_v4())
+            .await;
 
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), PresenceError::Database(_)));
     }
+
+    #[tokio::test]
+    async fn test_get_presence_status_blocked_requester() {
+        let account_id = Uuid::new_v4();
+        let requester_id = Uuid::new_v4();
+        let mut mock_db = MockPresenceDatabase::new();
+        mock_db.expect_is_present().times(0);
+
+        let mut mock_block_db = MockBlockDatabase::new();
+        mock_block_db
+            .expect_is_blocked()
+            .with(
+                mockall::predicate::eq(account_id),
+                mockall::predicate::eq(requester_id),
+            )
+            .times(1)
+            .returning(|_, _| Ok(true));
+
+        let service = PresenceService::new(Arc::new(mock_db), Arc::new(mock_block_db));
+        let result = service
+            .get_presence_status(&account_id, &requester_id)
+            .await;
+
+        assert!(matches!(result.unwrap(), PresenceStatus::Offline));
+    }
+
+    fn no_blocks() -> MockBlockDatabase {
+        let mut mock_block_db = MockBlockDatabase::new();
+        mock_block_db
+            .expect_is_blocked()
+            .returning(|_, _| Ok(false));
+        mock_block_db
+    }
 }
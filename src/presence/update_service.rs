@@ -1,30 +1,80 @@
 use std::sync::Arc;
 
-use super::gateway::PresenceGateway;
+use super::{database::PresenceDatabase, gateway::PresenceGateway};
+use crate::blocks::database::BlockDatabase;
 
-pub struct PresenceUpdateService<G>
+pub struct PresenceUpdateService<G, D, B>
 where
     G: PresenceGateway + 'static,
+    D: PresenceDatabase + ?Sized + 'static,
+    B: BlockDatabase + 'static,
 {
     presence_gateway: Arc<G>,
+    presence_db: Arc<D>,
+    block_db: Arc<B>,
 }
 
-impl<G> PresenceUpdateService<G>
+impl<G, D, B> PresenceUpdateService<G, D, B>
 where
     G: PresenceGateway + 'static,
+    D: PresenceDatabase + ?Sized + 'static,
+    B: BlockDatabase + 'static,
 {
-    pub fn new(presence_gateway: Arc<G>) -> Self {
-        Self { presence_gateway }
+    pub fn new(presence_gateway: Arc<G>, presence_db: Arc<D>, block_db: Arc<B>) -> Self {
+        Self {
+            presence_gateway,
+            presence_db,
+            block_db,
+        }
     }
 
+    /// Stores presence updates and fans them out. Accounts blocked by the updated
+    /// account, or that blocked it, don't receive the update.
     #[tracing::instrument(skip(self))]
     pub async fn handle_presence_updates(&self) {
         let gateway = self.presence_gateway.clone();
+        let presence_db = self.presence_db.clone();
+        let block_db = self.block_db.clone();
         self.presence_gateway
             .register_presence_handler(move |presence_update| {
                 let gateway = gateway.clone();
+                let presence_db = presence_db.clone();
+                let block_db = block_db.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = gateway.send_presence_update(&presence_update).await {
+                    if let Err(e) = presence_db
+                        .update_presence(&presence_update.account_id, &presence_update.status)
+                        .await
+                    {
+                        tracing::error!(
+                            error = %e,
+                            "Failed to store presence update"
+                        );
+                    }
+
+                    let account_id = presence_update.account_id;
+                    let excluded_account_ids = match (
+                        block_db.get_blocked_accounts(account_id).await,
+                        block_db.get_blocking_account_ids(account_id).await,
+                    ) {
+                        (Ok(blocked), Ok(blocking)) => blocked
+                            .into_iter()
+                            .map(|blocked| blocked.account_id)
+                            .chain(blocking)
+                            .collect::<Vec<_>>(),
+                        (Err(e), _) | (_, Err(e)) => {
+                            // Rather not send the update than leak it to blocked accounts
+                            tracing::error!(
+                                error = %e,
+                                "Failed to get block list for presence update"
+                            );
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = gateway
+                        .send_presence_update(&presence_update, &excluded_account_ids)
+                        .await
+                    {
                         tracing::error!(
                             error = %e,
                             "Failed to send presence update"
@@ -39,6 +89,9 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blocks::database::MockBlockDatabase;
+    use crate::blocks::entities::BlockedAccount;
+    use crate::presence::database::MockPresenceDatabase;
     use crate::presence::entities::PresenceStatus;
     use crate::presence::error::PresenceError;
     use crate::presence::gateway::{MockPresenceGateway, PresenceUpdate};
@@ -66,11 +119,22 @@ mod tests {
 
         mock_gateway
             .expect_send_presence_update()
-            .with(always())
+            .with(always(), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_db = MockPresenceDatabase::new();
+        mock_db
+            .expect_update_presence()
+            .with(always(), always())
             .times(1)
-            .returning(|_| Ok(()));
+            .returning(|_, _| Ok(()));
 
-        let service = PresenceUpdateService::new(Arc::new(mock_gateway));
+        let service = PresenceUpdateService::new(
+            Arc::new(mock_gateway),
+            Arc::new(mock_db),
+            Arc::new(no_blocks()),
+        );
         service.handle_presence_updates().await;
         rx.recv().await.unwrap();
     }
@@ -95,12 +159,99 @@ mod tests {
 
         mock_gateway
             .expect_send_presence_update()
+            .with(always(), always())
+            .times(1)
+            .returning(|_, _| Err(PresenceError::SendingFailed("test error".to_string())));
+
+        let mut mock_db = MockPresenceDatabase::new();
+        mock_db
+            .expect_update_presence()
+            .with(always(), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let service = PresenceUpdateService::new(
+            Arc::new(mock_gateway),
+            Arc::new(mock_db),
+            Arc::new(no_blocks()),
+        );
+        service.handle_presence_updates().await;
+        rx.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_presence_updates_excludes_blocks() {
+        let alice_id = Uuid::new_v4();
+        let bob_id = Uuid::new_v4();
+        let mallory_id = Uuid::new_v4();
+        let mut mock_gateway = MockPresenceGateway::new();
+        let (tx, mut rx) = mpsc::channel(1);
+
+        mock_gateway
+            .expect_register_presence_handler()
             .with(always())
             .times(1)
-            .returning(|_| Err(PresenceError::SendingFailed("test error".to_string())));
+            .returning(move |handler| {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let update = PresenceUpdate::new(alice_id, PresenceStatus::Online);
+                    handler(update);
+                    tx.send(()).await.unwrap();
+                });
+            });
+
+        // Alice blocked Mallory and Bob blocked Alice
+        let mut excluded = vec![mallory_id, bob_id];
+        excluded.sort();
+        mock_gateway
+            .expect_send_presence_update()
+            .withf(move |update, excluded_account_ids| {
+                let mut excluded_account_ids = excluded_account_ids.to_vec();
+                excluded_account_ids.sort();
+                update.account_id == alice_id && excluded_account_ids == excluded
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_db = MockPresenceDatabase::new();
+        mock_db
+            .expect_update_presence()
+            .with(always(), always())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_block_db = MockBlockDatabase::new();
+        mock_block_db
+            .expect_get_blocked_accounts()
+            .with(eq(alice_id))
+            .returning(move |_| {
+                Ok(vec![BlockedAccount {
+                    account_id: mallory_id,
+                    blocked_at: 0,
+                }])
+            });
+        mock_block_db
+            .expect_get_blocking_account_ids()
+            .with(eq(alice_id))
+            .returning(move |_| Ok(vec![bob_id]));
 
-        let service = PresenceUpdateService::new(Arc::new(mock_gateway));
+        let service = PresenceUpdateService::new(
+            Arc::new(mock_gateway),
+            Arc::new(mock_db),
+            Arc::new(mock_block_db),
+        );
         service.handle_presence_updates().await;
         rx.recv().await.unwrap();
     }
+
+    fn no_blocks() -> MockBlockDatabase {
+        let mut mock_block_db = MockBlockDatabase::new();
+        mock_block_db
+            .expect_get_blocked_accounts()
+            .returning(|_| Ok(Vec::new()));
+        mock_block_db
+            .expect_get_blocking_account_ids()
+            .returning(|_| Ok(Vec::new()));
+        mock_block_db
+    }
 }
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
@@ -16,6 +17,9 @@ pub struct Profile {
     pub display_name: Option<String>,
     /// URL to the profile picture, if one exists
     pub profile_picture_url: Option<String>,
+    /// Key that senders must present to deliver sealed sender messages to this account.
+    /// Derived by the owner's clients and shared only with their contacts.
+    pub delivery_access_key: Option<Vec<u8>>,
     /// Last update timestamp (epoch milliseconds)
     pub updated_at: u64,
 }
@@ -27,6 +31,7 @@ impl Profile {
     /// - id: randomly generated UUID
     /// - display_name: None
     /// - profile_picture_url: None
+    /// - delivery_access_key: None
     /// - updated_at: current timestamp in milliseconds
     pub fn new(account_id: Uuid, username: String) -> Self {
         let now = std::time::SystemTime::now()
@@ -40,6 +45,7 @@ impl Profile {
             username,
             display_name: None,
             profile_picture_url: None,
+            delivery_access_key: None,
             updated_at: now,
         }
     }
@@ -64,6 +70,7 @@ impl Default for ProfilePictureAction {
 }
 
 /// Request to update a profile
+#[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateProfileRequest {
@@ -72,6 +79,11 @@ pub struct UpdateProfileRequest {
     /// Action to perform on the profile picture
     #[serde(default)]
     pub profile_picture_action: ProfilePictureAction,
+    /// New key for sealed sender delivery (optional)
+    #[serde_as(as = "Option<Base64>")]
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub delivery_access_key: Option<Vec<u8>>,
 }
 
 /// Response for profile picture upload URL
@@ -14,6 +14,9 @@ pub enum ProfileError {
     #[error("Profile not found")]
     NotFound,
 
+    #[error("Invalid delivery access key")]
+    InvalidAccessKey,
+
     #[error("Database error: {0}")]
     Database(String),
 
@@ -43,6 +46,7 @@ impl IntoResponse for ProfileError {
         error!("{}", self);
         let status = match &self {
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidAccessKey => StatusCode::UNAUTHORIZED,
             Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
@@ -1,5 +1,6 @@
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use tracing::warn;
 use uuid::Uuid;
 
@@ -59,6 +60,28 @@ where
         Ok(ProfileResponse::new(profile))
     }
 
+    /// Checks the key a sealed sender presented for delivering to the account.
+    /// Accounts without a key accept no sealed sender messages.
+    pub async fn verify_delivery_access_key(
+        &self,
+        account_id: Uuid,
+        access_key: &[u8],
+    ) -> Result<(), ProfileError> {
+        let expected_key = self
+            .profile_db
+            .get_profile_by_account_id(account_id)
+            .await?
+            .and_then(|profile| profile.delivery_access_key)
+            .ok_or(ProfileError::InvalidAccessKey)?;
+
+        // Compare in constant time, so the key can't be guessed byte by byte
+        if bool::from(expected_key.as_slice().ct_eq(access_key)) {
+            Ok(())
+        } else {
+            Err(ProfileError::InvalidAccessKey)
+        }
+    }
+
     /// Updates a user's profile. If profile picture shall be updated, creates a new upload URL.
     pub async fn update_profile(
         &self,
@@ -79,6 +102,9 @@ where
         if let Some(display_name) = update_req.display_name {
             profile.display_name = Some(display_name);
         }
+        if let Some(delivery_access_key) = update_req.delivery_access_key {
+            profile.delivery_access_key = Some(delivery_access_key);
+        }
 
         // Store the action for later use
         let action = update_req.profile_picture_action.clone();
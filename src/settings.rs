@@ -1,8 +1,8 @@
 use std::path::Path;
 
 use config::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
 use prism_telemetry::config::TelemetryConfig;
+use serde::Deserialize;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct WebserverSettings {
@@ -61,11 +61,105 @@ pub enum AssetsDatabaseSettings {
     },
 }
 
+/// Selects which database holds messages that are waiting for delivery
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessagesDatabaseSettings {
+    /// Keep pending messages in the core database, so they survive restarts
+    #[default]
+    Core,
+    /// Keep pending messages in the ephemeral database
+    Ephemeral,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct DatabaseSettings {
     pub core: CoreDatabaseSettings,
     pub ephemeral: EphemeralDatabaseSettings,
     pub assets: AssetsDatabaseSettings,
+    #[serde(default)]
+    pub messages: MessagesDatabaseSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MessagesSettings {
+    /// Seconds after which undelivered messages expire.
+    /// Clients may request a shorter time to live per message.
+    pub ttl: u64,
+    /// Maximum number of pending messages per recipient
+    pub max_pending_messages: usize,
+    /// Maximum total ciphertext bytes pending per recipient
+    pub max_pending_bytes: u64,
+    /// Maximum number of pending messages a single sender may queue for a recipient
+    pub max_pending_messages_per_sender: usize,
+    /// Maximum ciphertext bytes of a single message
+    pub max_ciphertext_size: u64,
+    /// Maximum message counters in double ratchet headers
+    pub max_message_number: u64,
+    /// Seconds after which a typing indicator is cleared if its sender sends no updates
+    pub typing_timeout: u64,
+    /// Milliseconds within which repeated typing updates of a sender are not relayed
+    pub min_typing_update_interval: u64,
+}
+
+impl Default for MessagesSettings {
+    fn default() -> Self {
+        Self {
+            // 30 days
+            ttl: 30 * 24 * 60 * 60,
+            max_pending_messages: 10_000,
+            // 100 MiB
+            max_pending_bytes: 100 * 1024 * 1024,
+            max_pending_messages_per_sender: 1_000,
+            // 256 KiB, larger content is sent as attachment
+            max_ciphertext_size: 256 * 1024,
+            max_message_number: 100_000,
+            typing_timeout: 10,
+            min_typing_update_interval: 2_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AttachmentsSettings {
+    /// Maximum size of a single encrypted attachment in bytes
+    pub max_size: u64,
+    /// Maximum total size of the attachments an account keeps stored
+    pub max_storage_per_account: u64,
+    /// Days after which attachments are deleted
+    pub retention_days: u64,
+}
+
+impl Default for AttachmentsSettings {
+    fn default() -> Self {
+        Self {
+            // 100 MiB
+            max_size: 100 * 1024 * 1024,
+            // 1 GiB
+            max_storage_per_account: 1024 * 1024 * 1024,
+            retention_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KeysSettings {
+    /// Owners are asked to upload more one-time prekeys once fewer are left
+    pub prekey_refill_threshold: usize,
+    /// Days a rotated out signed prekey is kept
+    pub signed_prekey_grace_days: u64,
+}
+
+impl Default for KeysSettings {
+    fn default() -> Self {
+        Self {
+            prekey_refill_threshold: 10,
+            signed_prekey_grace_days: 30,
+        }
+    }
 }
 
 // TODO: Defaults for these settings?
@@ -76,6 +170,12 @@ pub struct Settings {
     pub prism: PrismSettings,
     pub apns: ApnsSettings,
     pub database: DatabaseSettings,
+    #[serde(default)]
+    pub messages: MessagesSettings,
+    #[serde(default)]
+    pub attachments: AttachmentsSettings,
+    #[serde(default)]
+    pub keys: KeysSettings,
     pub telemetry: Option<TelemetryConfig>,
 }
 
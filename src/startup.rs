@@ -1,39 +1,78 @@
 use anyhow::{Result, bail};
 use prism_client::{PendingTransaction, PrismApi, PrismHttpClient, SigningKey};
-use std::{path::Path, sync::Arc};
+use std::{path::Path, sync::Arc, time::Duration};
 
 use crate::{
-    MESSAGE_SENDER_POLL_INTERVAL, PRISM_MESSENGER_SERVICE_ID,
+    ATTACHMENT_RETENTION_SWEEP_INTERVAL, MESSAGE_ACK_TIMEOUT, MESSAGE_EXPIRY_SWEEP_INTERVAL,
+    MESSAGE_SENDER_POLL_INTERVAL, PRISM_MESSENGER_SERVICE_ID, SCHEDULED_MESSAGE_RELEASE_INTERVAL,
+    SENDER_CERTIFICATE_VALIDITY, TYPING_EXPIRY_SWEEP_INTERVAL,
     account::{auth::service::AuthService, service::AccountService},
+    attachments::{
+        entities::AttachmentLimits, retention_service::AttachmentRetentionService,
+        service::AttachmentService,
+    },
+    blocks::service::BlockService,
     database::{
         inmemory::InMemoryDatabase, pool::create_sqlite_pool, s3::S3Storage, sqlite::SqliteDatabase,
     },
+    groups::service::GroupService,
     keys::service::KeyService,
     messages::{
-        messaging_service::MessagingService, sender_service::MessageSenderService,
+        database::MessageDatabase,
+        entities::{MessageLimits, QueueLimits},
+        expiry_service::MessageExpiryService,
+        in_flight::InFlightMessages,
+        messaging_service::MessagingService,
+        sealed_sender::SenderCertificateService,
+        sender_service::MessageSenderService,
         typing::service::TypingService,
     },
     notifications::{gateway::apns::ApnsNotificationGateway, service::NotificationService},
-    presence::{service::PresenceService, update_service::PresenceUpdateService},
+    presence::{
+        database::PresenceDatabase, service::PresenceService, update_service::PresenceUpdateService,
+    },
     profiles::service::ProfileService,
     registration::service::RegistrationService,
-    settings::{AssetsDatabaseSettings, CoreDatabaseSettings, EphemeralDatabaseSettings, Settings},
+    settings::{
+        AssetsDatabaseSettings, CoreDatabaseSettings, EphemeralDatabaseSettings,
+        MessagesDatabaseSettings, Settings,
+    },
     websocket::center::WebSocketCenter,
 };
 
+#[cfg(feature = "redis")]
+use crate::database::redis::{PRESENCE_HEARTBEAT_INTERVAL, RedisDatabase};
+
 pub struct AppContext {
     pub account_service: AccountService<PrismHttpClient, SqliteDatabase>,
+    pub attachment_service: AttachmentService<SqliteDatabase, S3Storage>,
     pub auth_service: AuthService<SqliteDatabase>,
-    pub key_service: KeyService<PrismHttpClient, SqliteDatabase>,
-    pub messaging_service: MessagingService<
-        InMemoryDatabase,
+    pub block_service: BlockService<SqliteDatabase>,
+    pub group_service: GroupService<SqliteDatabase, SqliteDatabase, WebSocketCenter>,
+    pub key_service: KeyService<
+        PrismHttpClient,
+        SqliteDatabase,
+        SqliteDatabase,
         WebSocketCenter,
         SqliteDatabase,
         ApnsNotificationGateway,
     >,
-    pub presence_service: PresenceService<WebSocketCenter>,
+    pub messaging_service: Arc<
+        MessagingService<
+            dyn MessageDatabase,
+            dyn PresenceDatabase,
+            WebSocketCenter,
+            SqliteDatabase,
+            SqliteDatabase,
+            SqliteDatabase,
+            SqliteDatabase,
+            ApnsNotificationGateway,
+        >,
+    >,
+    pub presence_service: PresenceService<dyn PresenceDatabase, SqliteDatabase>,
     pub profile_service: ProfileService<SqliteDatabase, S3Storage>,
     pub registration_service: RegistrationService<PrismHttpClient, SqliteDatabase, SqliteDatabase>,
+    pub sender_certificate_service: SenderCertificateService<SqliteDatabase>,
     pub websocket_center: Arc<WebSocketCenter>,
 }
 
@@ -56,11 +95,53 @@ pub async fn start_application(settings: &Settings) -> Result<AppContext> {
     let core_db = Arc::new(SqliteDatabase::new(db_pool));
     core_db.init().await?;
 
+    let websocket_center = WebSocketCenter::new();
+    let websocket_center_arc = Arc::new(websocket_center);
+
     // Ephemeral Database
-    let EphemeralDatabaseSettings::InMemory = &settings.database.ephemeral else {
-        bail!("Unsupported ephemeral database type. Only in-memory supported for now");
+    // In-memory presence is derived from the connections of this server only
+    #[cfg(feature = "redis")]
+    let mut redis_db_arc = None;
+    let (ephemeral_db, presence_db): (Arc<dyn MessageDatabase>, Arc<dyn PresenceDatabase>) =
+        match &settings.database.ephemeral {
+            EphemeralDatabaseSettings::InMemory => (
+                Arc::new(InMemoryDatabase::new()),
+                websocket_center_arc.clone(),
+            ),
+            #[cfg(feature = "redis")]
+            EphemeralDatabaseSettings::Redis { host, port } => {
+                let redis_db = Arc::new(
+                    RedisDatabase::connect(host, *port, websocket_center_arc.clone()).await?,
+                );
+                redis_db
+                    .clone()
+                    .spawn_presence_heartbeat(PRESENCE_HEARTBEAT_INTERVAL);
+                redis_db_arc = Some(redis_db.clone());
+                (redis_db.clone(), redis_db)
+            }
+        };
+
+    // Messages Database
+    let messages_db: Arc<dyn MessageDatabase> = match &settings.database.messages {
+        MessagesDatabaseSettings::Core => {
+            // Presence is shared, so senders would queue messages on this server
+            // for recipients connected to another one, which never receives them
+            #[cfg(feature = "redis")]
+            if redis_db_arc.is_some() {
+                bail!(
+                    "Pending messages must be shared between replicas when running on redis. \
+                     Set database.messages to \"ephemeral\""
+                );
+            }
+            core_db.clone()
+        }
+        MessagesDatabaseSettings::Ephemeral => {
+            tracing::warn!(
+                "Pending messages are kept in the ephemeral database and may be lost on restart"
+            );
+            ephemeral_db.clone()
+        }
     };
-    let ephemeral_db = Arc::new(InMemoryDatabase::new());
 
     // Assets Database
     let assets_db = match &settings.database.assets {
@@ -106,36 +187,139 @@ pub async fn start_application(settings: &Settings) -> Result<AppContext> {
         core_db.clone(),
         core_db.clone(),
     );
-    let key_service = KeyService::new(prism_arc.clone(), core_db.clone());
+    let key_service = KeyService::new(
+        prism_arc.clone(),
+        core_db.clone(),
+        core_db.clone(),
+        websocket_center_arc.clone(),
+        notification_service_arc.clone(),
+        settings.keys.prekey_refill_threshold,
+        Duration::from_secs(settings.keys.signed_prekey_grace_days * 24 * 60 * 60),
+    );
+    let block_service = BlockService::new(core_db.clone());
+    let group_service = GroupService::new(
+        core_db.clone(),
+        core_db.clone(),
+        websocket_center_arc.clone(),
+    );
 
-    let websocket_center = WebSocketCenter::new();
-    let websocket_center_arc = Arc::new(websocket_center);
+    // Messages sent but not acknowledged yet, shared by all delivery paths
+    let in_flight_messages_arc = Arc::new(InFlightMessages::new(MESSAGE_ACK_TIMEOUT));
 
     // Create messaging service with WebSocket manager
     let messaging_service = MessagingService::new(
-        ephemeral_db.clone(),
+        messages_db.clone(),
+        presence_db.clone(),
+        core_db.clone(),
+        core_db.clone(),
+        core_db.clone(),
+        core_db.clone(),
         websocket_center_arc.clone(),
+        in_flight_messages_arc.clone(),
         notification_service_arc.clone(),
+        Duration::from_secs(settings.messages.ttl),
+        QueueLimits {
+            max_messages: settings.messages.max_pending_messages,
+            max_bytes: settings.messages.max_pending_bytes,
+            max_messages_per_sender: settings.messages.max_pending_messages_per_sender,
+        },
+        MessageLimits {
+            max_ciphertext_size: settings.messages.max_ciphertext_size,
+            max_message_number: settings.messages.max_message_number,
+        },
     );
 
+    let messaging_service_arc = Arc::new(messaging_service);
+
     let message_sender_service = MessageSenderService::new(
-        ephemeral_db.clone(),
+        messages_db.clone(),
         websocket_center_arc.clone(),
+        in_flight_messages_arc.clone(),
         MESSAGE_SENDER_POLL_INTERVAL,
     );
     let message_sender_service_arc = Arc::new(message_sender_service);
 
-    let typing_service = TypingService::new(websocket_center_arc.clone());
+    let message_expiry_service = MessageExpiryService::new(
+        messages_db.clone(),
+        core_db.clone(),
+        websocket_center_arc.clone(),
+        MESSAGE_EXPIRY_SWEEP_INTERVAL,
+    );
+    let message_expiry_service_arc = Arc::new(message_expiry_service);
+
+    let typing_service = TypingService::new(
+        websocket_center_arc.clone(),
+        core_db.clone(),
+        Duration::from_secs(settings.messages.typing_timeout),
+        Duration::from_millis(settings.messages.min_typing_update_interval),
+    );
     let typing_service_arc = Arc::new(typing_service);
 
-    let presence_service = PresenceService::new(websocket_center_arc.clone());
-    let presence_update_service = PresenceUpdateService::new(websocket_center_arc.clone());
+    let presence_service = PresenceService::new(presence_db.clone(), core_db.clone());
+    let presence_update_service = PresenceUpdateService::new(
+        websocket_center_arc.clone(),
+        presence_db.clone(),
+        core_db.clone(),
+    );
     let presence_update_service_arc = Arc::new(presence_update_service);
 
+    let attachment_service = AttachmentService::new(
+        core_db.clone(),
+        assets_db.clone(),
+        AttachmentLimits {
+            max_size: settings.attachments.max_size,
+            max_storage_per_account: settings.attachments.max_storage_per_account,
+            retention: Duration::from_secs(settings.attachments.retention_days * 24 * 60 * 60),
+        },
+    );
+    let attachment_retention_service = AttachmentRetentionService::new(
+        core_db.clone(),
+        assets_db.clone(),
+        ATTACHMENT_RETENTION_SWEEP_INTERVAL,
+    );
+    let attachment_retention_service_arc = Arc::new(attachment_retention_service);
+
     let profile_service = ProfileService::new(core_db.clone(), assets_db.clone());
+    let sender_certificate_service = SenderCertificateService::new(
+        core_db.clone(),
+        signing_key.clone(),
+        SENDER_CERTIFICATE_VALIDITY,
+    );
 
+    message_sender_service_arc
+        .clone()
+        .handle_recipient_connections()
+        .await;
+    messaging_service_arc.clone().handle_acks().await;
+    messaging_service_arc.clone().handle_read_receipts().await;
+    messaging_service_arc.clone().handle_sends().await;
+    // Messages queued by other replicas are delivered to the recipients connected here
+    #[cfg(feature = "redis")]
+    if let Some(redis_db) = redis_db_arc {
+        let service = message_sender_service_arc.clone();
+        redis_db.spawn_inserted_message_listener(move |account_id| {
+            let service = service.clone();
+            tokio::spawn(async move {
+                if let Err(e) = service.try_send_new_messages_for_account(account_id).await {
+                    tracing::error!(
+                        "Error processing pending messages for {}: {}",
+                        account_id,
+                        e
+                    );
+                }
+            });
+        });
+    }
     message_sender_service_arc.spawn_message_sender();
+    message_expiry_service_arc.spawn_expiry_sweeper();
+    messaging_service_arc
+        .clone()
+        .spawn_scheduled_message_releaser(SCHEDULED_MESSAGE_RELEASE_INTERVAL);
+    attachment_retention_service_arc.spawn_retention_sweeper();
     typing_service_arc.handle_typing_updates().await;
+    typing_service_arc
+        .clone()
+        .spawn_typing_expiry_sweeper(TYPING_EXPIRY_SWEEP_INTERVAL);
     presence_update_service_arc
         .clone()
         .handle_presence_updates()
@@ -145,12 +329,16 @@ pub async fn start_application(settings: &Settings) -> Result<AppContext> {
 
     Ok(AppContext {
         account_service,
+        attachment_service,
         auth_service,
+        block_service,
+        group_service,
         registration_service,
         key_service,
-        messaging_service,
+        messaging_service: messaging_service_arc,
         presence_service,
         profile_service,
+        sender_certificate_service,
         websocket_center: websocket_center_arc,
     })
 }
@@ -1,8 +1,11 @@
 use lazy_static::lazy_static;
-use opentelemetry::{global, metrics::{Gauge, Meter}};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Gauge, Meter},
+};
 use parking_lot::Mutex;
-use tracing::info;
 use std::sync::Arc;
+use tracing::info;
 
 use prism_telemetry::telemetry::build_attributes;
 
@@ -13,6 +16,8 @@ pub struct PrismMessengerMetrics {
     meter: Meter,
     // Node info metric
     pub node_info: Gauge<u64>,
+    // Messages discarded because they expired undelivered
+    pub expired_messages: Counter<u64>,
 }
 
 impl Default for PrismMessengerMetrics {
@@ -27,15 +32,21 @@ impl PrismMessengerMetrics {
         let meter = global::meter("prism");
 
         let prefix = "prism_messenger_server_";
-        
+
         let node_info = meter
             .u64_gauge(format!("{}node_info", prefix))
             .with_description("Prism node info")
             .build();
 
+        let expired_messages = meter
+            .u64_counter(format!("{}expired_messages", prefix))
+            .with_description("Messages discarded because they expired undelivered")
+            .build();
+
         PrismMessengerMetrics {
             meter,
             node_info,
+            expired_messages,
         }
     }
 
@@ -44,7 +55,13 @@ impl PrismMessengerMetrics {
     /// # Parameters
     /// * `attributes` - Vector of key-value pairs to attach to the metric
     pub fn record_node_info(&self, attributes: Vec<(String, String)>) {
-        self.node_info.record(1, build_attributes(attributes).as_slice());
+        self.node_info
+            .record(1, build_attributes(attributes).as_slice());
+    }
+
+    /// Records the number of messages that expired undelivered.
+    pub fn record_expired_messages(&self, count: u64) {
+        self.expired_messages.add(count, &[]);
     }
 }
 
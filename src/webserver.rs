@@ -11,8 +11,8 @@ use utoipa_axum::router::OpenApiRouter;
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
-    account, keys, messages, presence, profiles, registration, settings::WebserverSettings,
-    startup::AppContext, websocket,
+    account, attachments, blocks, groups, keys, messages, presence, profiles, registration,
+    settings::WebserverSettings, startup::AppContext, websocket,
 };
 
 #[derive(OpenApi)]
@@ -22,6 +22,9 @@ pub async fn start(settings: &WebserverSettings, context: AppContext) -> Result<
     let context_arc = Arc::new(context);
     let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .nest("/accounts", account::router(context_arc.clone()))
+        .nest("/attachments", attachments::router(context_arc.clone()))
+        .nest("/blocks", blocks::router(context_arc.clone()))
+        .nest("/groups", groups::router(context_arc.clone()))
         .nest("/keys", keys::router(context_arc.clone()))
         .nest("/messages", messages::router(context_arc.clone()))
         .nest("/presence", presence::router(context_arc.clone()))
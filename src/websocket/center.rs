@@ -9,8 +9,10 @@ use tracing::warn;
 use uuid::Uuid;
 
 use crate::{
+    groups::{entities::GroupUpdate, error::GroupError, gateway::GroupGateway},
+    keys::{error::KeyError, gateway::KeyGateway},
     messages::{
-        entities::Message,
+        entities::{Message, MessageExpiry, MessageReceipt, Receipt, ReceiptKind, SendRequest},
         error::MessagingError,
         gateway::MessageGateway,
         typing::gateway::{TypingGateway, TypingGatewayError, TypingStatus},
@@ -109,15 +111,23 @@ impl WebSocketCenter {
         }
     }
 
-    /// Broadcast a message to all connected accounts
-    pub async fn broadcast_to_all<T>(&self, message: &T) -> Result<(), WebSocketError>
+    /// Broadcast a message to all connected accounts except the excluded ones
+    pub async fn broadcast_to_all_except<T>(
+        &self,
+        message: &T,
+        excluded_account_ids: &[Uuid],
+    ) -> Result<(), WebSocketError>
     where
         T: Serialize + Send + Sync,
     {
         // Collect account IDs while holding the read lock
         let account_ids: Vec<Uuid> = {
             let connections = self.connections.read().await;
-            connections.values().map(|conn| conn.account_id).collect()
+            connections
+                .values()
+                .map(|conn| conn.account_id)
+                .filter(|account_id| !excluded_account_ids.contains(account_id))
+                .collect()
         };
 
         // Send messages without holding the lock
@@ -163,6 +173,12 @@ impl WebSocketCenter {
         handlers.push(Box::new(handler));
     }
 
+    /// Returns the IDs of all accounts connected to this server
+    pub async fn connected_account_ids(&self) -> Vec<Uuid> {
+        let connections = self.connections.read().await;
+        connections.keys().copied().collect()
+    }
+
     pub async fn has_connection(&self, account_id: &Uuid) -> bool {
         let connections = self.connections.read().await;
         connections.contains_key(account_id)
@@ -254,6 +270,110 @@ impl MessageWebSocketMessage {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageExpiryWebSocketMessage {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    #[serde(flatten)]
+    pub expiry: MessageExpiry,
+}
+
+impl MessageExpiryWebSocketMessage {
+    pub fn new(expiry: MessageExpiry) -> Self {
+        Self {
+            message_type: "expired".to_string(),
+            expiry,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReceiptWebSocketMessage {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub receipt_id: Uuid,
+    pub message_id: Uuid,
+    /// The recipient of the message, who issued the receipt
+    pub account_id: Uuid,
+    pub timestamp: u64,
+}
+
+impl ReceiptWebSocketMessage {
+    pub fn new(receipt: &Receipt) -> Self {
+        let message_type = match receipt.kind {
+            ReceiptKind::Delivered => "delivered".to_string(),
+            ReceiptKind::Read => "read".to_string(),
+        };
+
+        Self {
+            message_type,
+            receipt_id: receipt.receipt_id,
+            message_id: receipt.message_id,
+            account_id: receipt.recipient_id,
+            timestamp: receipt.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AckWebSocketMessage {
+    pub message_ids: Vec<Uuid>,
+}
+
+/// Sent by a recipient to report messages of a sender as read
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadWebSocketMessage {
+    pub account_id: Uuid,
+    pub message_ids: Vec<Uuid>,
+}
+
+/// Outcome of a send request, either the receipt or the error it failed with
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SendResultWebSocketMessage {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub request_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<MessageReceipt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<SendErrorWebSocketMessage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SendErrorWebSocketMessage {
+    /// The HTTP status code the same error has on `POST /messages/send`
+    pub status: u16,
+    pub message: String,
+}
+
+impl SendResultWebSocketMessage {
+    pub fn new(request_id: Uuid, result: Result<MessageReceipt, MessagingError>) -> Self {
+        let (receipt, error) = match result {
+            Ok(receipt) => (Some(receipt), None),
+            Err(e) => (
+                None,
+                Some(SendErrorWebSocketMessage {
+                    status: e.status_code().as_u16(),
+                    message: e.to_string(),
+                }),
+            ),
+        };
+
+        Self {
+            message_type: "sendResult".to_string(),
+            request_id,
+            receipt,
+            error,
+        }
+    }
+}
+
 #[async_trait]
 impl MessageGateway for WebSocketCenter {
     async fn send_message(&self, message: Message) -> Result<(), MessagingError> {
@@ -262,6 +382,91 @@ impl MessageGateway for WebSocketCenter {
         self.send_to_account(recipient_id, &ws_message).await?;
         Ok(())
     }
+
+    async fn send_message_expiry(
+        &self,
+        sender_id: Uuid,
+        expiry: MessageExpiry,
+    ) -> Result<(), MessagingError> {
+        let ws_message = MessageExpiryWebSocketMessage::new(expiry);
+        self.send_to_account(sender_id, &ws_message).await?;
+        Ok(())
+    }
+
+    async fn send_receipt(&self, receipt: Receipt) -> Result<(), MessagingError> {
+        let ws_message = ReceiptWebSocketMessage::new(&receipt);
+        self.send_to_account(receipt.sender_id, &ws_message).await?;
+        Ok(())
+    }
+
+    async fn reply_to_send(
+        &self,
+        account_id: Uuid,
+        request_id: Uuid,
+        result: Result<MessageReceipt, MessagingError>,
+    ) -> Result<(), MessagingError> {
+        let ws_message = SendResultWebSocketMessage::new(request_id, result);
+        self.send_to_account(account_id, &ws_message).await?;
+        Ok(())
+    }
+
+    async fn get_connected_recipient_ids(&self) -> Vec<Uuid> {
+        self.connected_account_ids().await
+    }
+
+    async fn register_recipient_connected_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid) + Send + Sync + 'static,
+    {
+        self.register_connect_handler(move |account_id| {
+            handler(account_id);
+            Ok(())
+        })
+        .await;
+    }
+
+    async fn register_ack_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid, Vec<Uuid>) + Send + Sync + 'static,
+    {
+        self.register_handler(
+            "ack",
+            move |account_id, ack_message: AckWebSocketMessage| {
+                handler(account_id, ack_message.message_ids);
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    async fn register_read_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid, Uuid, Vec<Uuid>) + Send + Sync + 'static,
+    {
+        self.register_handler(
+            "read",
+            move |account_id, read_message: ReadWebSocketMessage| {
+                handler(
+                    account_id,
+                    read_message.account_id,
+                    read_message.message_ids,
+                );
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    async fn register_send_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid, SendRequest) + Send + Sync + 'static,
+    {
+        self.register_handler("send", move |account_id, request: SendRequest| {
+            handler(account_id, request);
+            Ok(())
+        })
+        .await;
+    }
 }
 
 impl From<WebSocketError> for MessagingError {
@@ -284,6 +489,15 @@ impl PresenceDatabase for WebSocketCenter {
     async fn is_present(&self, account_id: &Uuid) -> Result<bool, PresenceError> {
         Ok(self.has_connection(account_id).await)
     }
+
+    async fn update_presence(
+        &self,
+        _account_id: &Uuid,
+        _status: &PresenceStatus,
+    ) -> Result<(), PresenceError> {
+        // Presence is derived from the open connections, nothing to record
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -315,12 +529,14 @@ impl PresenceGateway for WebSocketCenter {
     async fn send_presence_update(
         &self,
         presence_update: &PresenceUpdate,
+        excluded_account_ids: &[Uuid],
     ) -> Result<(), PresenceError> {
         let ws_message = PresenceWebSocketMessage::new(presence_update);
 
         // For now, we'll broadcast to all connections
         // In a real implementation, you might want to send only to interested parties
-        self.broadcast_to_all(&ws_message).await?;
+        self.broadcast_to_all_except(&ws_message, excluded_account_ids)
+            .await?;
 
         Ok(())
     }
@@ -368,6 +584,83 @@ impl From<WebSocketError> for PresenceError {
     }
 }
 
+// Groups
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupUpdateWebSocketMessage<'a> {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    #[serde(flatten)]
+    pub update: &'a GroupUpdate,
+}
+
+impl<'a> GroupUpdateWebSocketMessage<'a> {
+    pub fn new(update: &'a GroupUpdate) -> Self {
+        Self {
+            message_type: "group".to_string(),
+            update,
+        }
+    }
+}
+
+#[async_trait]
+impl GroupGateway for WebSocketCenter {
+    async fn send_group_update(
+        &self,
+        account_id: Uuid,
+        update: &GroupUpdate,
+    ) -> Result<(), GroupError> {
+        let ws_message = GroupUpdateWebSocketMessage::new(update);
+        self.send_to_account(account_id, &ws_message).await?;
+        Ok(())
+    }
+}
+
+impl From<WebSocketError> for GroupError {
+    fn from(err: WebSocketError) -> Self {
+        GroupError::SendingFailed(err.to_string())
+    }
+}
+
+// Keys
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrekeysLowWebSocketMessage {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub prekey_count: usize,
+}
+
+impl PrekeysLowWebSocketMessage {
+    pub fn new(prekey_count: usize) -> Self {
+        Self {
+            message_type: "prekeysLow".to_string(),
+            prekey_count,
+        }
+    }
+}
+
+#[async_trait]
+impl KeyGateway for WebSocketCenter {
+    async fn send_prekeys_low(
+        &self,
+        account_id: Uuid,
+        prekey_count: usize,
+    ) -> Result<(), KeyError> {
+        let ws_message = PrekeysLowWebSocketMessage::new(prekey_count);
+        self.send_to_account(account_id, &ws_message).await?;
+        Ok(())
+    }
+}
+
+impl From<WebSocketError> for KeyError {
+    fn from(err: WebSocketError) -> Self {
+        KeyError::SendingFailed(err.to_string())
+    }
+}
+
 // Typing
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -420,6 +713,17 @@ impl TypingGateway for WebSocketCenter {
         )
         .await;
     }
+
+    async fn register_sender_disconnected_handler<H>(&self, handler: H)
+    where
+        H: Fn(Uuid) + Send + Sync + 'static,
+    {
+        self.register_disconnect_handler(move |account_id| {
+            handler(account_id);
+            Ok(())
+        })
+        .await;
+    }
 }
 
 impl From<WebSocketError> for TypingGatewayError {
@@ -439,6 +743,8 @@ impl From<WebSocketError> for TypingGatewayError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::messages::entities::{DoubleRatchetHeader, DoubleRatchetMessage};
+    use prism_client::SigningKey;
     use serde_json::json;
     use tokio::sync::mpsc;
 
@@ -468,6 +774,31 @@ mod tests {
         assert_eq!(received_json, test_message);
     }
 
+    #[tokio::test]
+    async fn test_send_prekeys_low() {
+        let center = WebSocketCenter::new();
+        let account_id = Uuid::new_v4();
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        center.add_connection(account_id, tx).await;
+
+        center
+            .send_prekeys_low(account_id, 3)
+            .await
+            .expect("Failed to send prekeys low");
+
+        let received_data = rx.try_recv().unwrap();
+        let received_json: serde_json::Value = serde_json::from_slice(&received_data).unwrap();
+        assert_eq!(
+            received_json,
+            json!({"type": "prekeysLow", "prekeyCount": 3})
+        );
+
+        // Accounts connected elsewhere can't be reached
+        let result = center.send_prekeys_low(Uuid::new_v4(), 3).await;
+        assert!(matches!(result, Err(KeyError::SendingFailed(_))));
+    }
+
     #[tokio::test]
     async fn test_send_to_nonexistent_account() {
         let center = WebSocketCenter::new();
@@ -500,4 +831,64 @@ mod tests {
         // Should no longer have connection
         assert!(!center.has_connection(&account_id).await);
     }
+
+    #[tokio::test]
+    async fn test_send_requests_are_answered_on_the_same_socket() {
+        let center = WebSocketCenter::new();
+        let account_id = Uuid::new_v4();
+        let recipient_id = Uuid::new_v4();
+        let request_id = Uuid::new_v4();
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        center.add_connection(account_id, tx).await;
+
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel();
+        center
+            .register_send_handler(move |sender_id, request| {
+                request_tx.send((sender_id, request)).unwrap();
+            })
+            .await;
+
+        let message = DoubleRatchetMessage {
+            header: DoubleRatchetHeader {
+                ephemeral_key: SigningKey::new_secp256r1().verifying_key(),
+                message_number: 0,
+                previous_message_number: 0,
+                one_time_prekey_id: None,
+                signed_prekey_id: None,
+            },
+            ciphertext: b"Test message".to_vec(),
+            nonce: vec![0; 12],
+        };
+        let raw_request = json!({
+            "type": "send",
+            "requestId": request_id,
+            "recipientId": recipient_id,
+            "message": message,
+        });
+        center
+            .on_message_received(account_id, &serde_json::to_vec(&raw_request).unwrap())
+            .await;
+
+        let (sender_id, request) = request_rx.try_recv().unwrap();
+        assert_eq!(sender_id, account_id);
+        assert_eq!(request.request_id, request_id);
+        assert_eq!(request.recipient_id, recipient_id);
+        assert_eq!(request.ttl, None);
+
+        center
+            .reply_to_send(
+                account_id,
+                request_id,
+                Err(MessagingError::BatchTooLarge(101)),
+            )
+            .await
+            .unwrap();
+
+        let reply: serde_json::Value = serde_json::from_slice(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(reply["type"], "sendResult");
+        assert_eq!(reply["requestId"], json!(request_id));
+        assert_eq!(reply["error"]["status"], 413);
+        assert!(reply.get("receipt").is_none());
+    }
 }